@@ -4,5 +4,12 @@ use structopt::StructOpt;
 #[async_std::main]
 async fn main() {
     let options = Options::from_args();
-    client_main(options).await.expect("running client");
+    let summary = client_main(options).await.expect("running client");
+    if summary.sla_violations > 0 {
+        eprintln!(
+            "{} round trips over the latency budget",
+            summary.sla_violations
+        );
+        std::process::exit(1);
+    }
 }
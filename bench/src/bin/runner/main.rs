@@ -5,5 +5,11 @@ use structopt::StructOpt;
 #[async_std::main]
 async fn main() {
     let options = Options::from_args();
-    println!("{:?}", runner_main(options).await);
+    let report = runner_main(options).await;
+    println!("{:?}", report);
+    if let Ok(report) = report {
+        if report.client_summary.sla_violations > 0 {
+            std::process::exit(1);
+        }
+    }
 }
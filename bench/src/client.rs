@@ -2,30 +2,606 @@ use crate::*;
 
 use async_std::{net::SocketAddr, prelude::*};
 
+use rand::{rngs::StdRng, Rng, SeedableRng};
+
 use futures::{
     self,
-    sink::SinkExt,
-    stream::{select, SelectAll, StreamExt},
+    channel::oneshot,
+    future::{self, Either, FutureExt, LocalBoxFuture, TryFutureExt},
+    sink::{Sink, SinkExt},
+    stream::{
+        self, select, LocalBoxStream, SelectAll, SplitSink, SplitStream,
+        StreamExt,
+    },
 };
 
-use serde::Serialize;
+use serde::{Deserialize, Serialize};
 use std::str::FromStr;
-use std::{collections::HashMap, iter::FromIterator, time::Instant};
+use std::{
+    cell::Cell,
+    collections::HashMap,
+    iter::FromIterator,
+    num::{NonZeroU32, NonZeroU64, NonZeroUsize},
+    path::PathBuf,
+    pin::Pin,
+    rc::Rc,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc,
+    },
+    task::{Context, Poll},
+    time::{Duration, Instant},
+};
 use structopt::StructOpt;
 
 #[derive(Clone, Copy, Debug, Serialize)]
 pub struct TripReport {
     stream_id: StreamId,
     index: u64,
+    payload_size: usize,
     round_trip: f64,
     send_time: f64,
+    /// Which burst of its transfer the datagram was sent in, if the transfer
+    /// sends in bursts.
+    burst: Option<usize>,
+    /// How long the echo was held behind an earlier one on its stream, as
+    /// estimated by `hol_blocking`.
+    hol_blocking: f64,
+}
+
+impl TripReport {
+    pub fn stream_id(&self) -> StreamId {
+        self.stream_id
+    }
+
+    /// Milliseconds from the datagram's send to its echo's return.
+    pub fn round_trip_ms(&self) -> f64 {
+        self.round_trip
+    }
+}
+
+/// A connection's buffers at one moment of a run.
+#[derive(Clone, Copy, Debug, Serialize)]
+pub struct BufferSample {
+    /// Milliseconds since the run began, as send times are measured.
+    time: f64,
+    send_queue_depth: Option<u64>,
+    bytes_in_flight: Option<u64>,
 }
 
 #[derive(Clone)]
 pub struct Summary {
+    /// Zero if no round trip was measured.
     pub mean_ms: f64,
+    /// Zero if fewer than two round trips were measured.
     pub deviation_ms: f64,
+    pub round_trip_p99_ms: f64,
+    /// Percentiles of the gaps between consecutive echoes arriving on the same
+    /// stream. These are zero if no stream received more than one echo.
+    pub interarrival_p50_ms: f64,
+    pub interarrival_p95_ms: f64,
+    pub interarrival_p99_ms: f64,
+    /// Round trip of the first datagram sent, while routes and caches are
+    /// cold. It is excluded from every other statistic.
+    pub first_rtt_ms: f64,
+    /// Time from starting to connect until the connection was ready.
+    pub handshake_ms: f64,
+    /// Bytes the client put on the wire while connecting, before sending any
+    /// application data. Zero if the transport does not count its wire bytes.
+    /// Bytes written by the kernel, such as TCP's, are not counted.
+    pub handshake_bytes: f64,
+    /// Time from closing the connection until the server closed its end too.
+    /// Zero if the server's end did not close within `TEARDOWN_TIMEOUT`, as
+    /// with transports which cannot tell the peer they are finished.
+    pub teardown_ms: f64,
+    /// How many times the client reconnected after losing its connection.
+    pub reconnections: usize,
+    /// Datagrams which were in flight when a connection was lost.
+    pub lost_to_reconnect: usize,
+    /// What paced the client's sends.
+    pub pacing: Pacing,
+    /// Echoes whose payload came back different from the one sent, which are
+    /// left out of every other statistic. See `payload_matches`.
+    pub corrupted_datagrams: usize,
+    /// Unreliable datagrams the client's `--send-buffer` dropped because it
+    /// was full.
+    pub dropped_sends: usize,
+    /// Bytes put on the wire per application byte sent, counting headers and
+    /// retransmissions. Zero if the transport does not count its wire bytes.
+    pub amplification: f64,
+    /// The mean of the round trips less the link's propagation delay, each
+    /// clamped at zero: the part of the round trip spent queueing, which a
+    /// protocol can influence. Zero until filled in by whoever knows the
+    /// link. See `queueing_delay`.
+    pub queueing_delay_ms: f64,
+    /// For each burst of transfers which send in bursts, the time from its
+    /// first send until the echo of its last datagram arrived, averaged over
+    /// the transfers. A burst after an idle gap which takes longer than the
+    /// first has paid for the transport restarting from a small window.
+    pub burst_ramp_ms: Vec<f64>,
+    /// The time echoes were held behind earlier ones on their ordered
+    /// streams, averaged over every echo, and how many were held. See
+    /// `hol_blocking`.
+    pub hol_blocking_ms: f64,
+    pub hol_blocked: usize,
+    /// Round trips over the client's `--rtt-budget-ms`, and the longest run
+    /// of them in a row as echoes arrived. Zero without a budget.
+    pub sla_violations: usize,
+    pub worst_violation_streak: usize,
+    /// The length of each run of at least `--sla-cluster` round trips in a
+    /// row over the budget, in the order they happened. Empty without a
+    /// cluster size.
+    pub violation_clusters: Vec<usize>,
+    /// Datagrams the server pushed which arrived, the mean time they took to
+    /// arrive by the two hosts' clocks, and the mean rate they arrived at.
+    /// See `ServerPush`.
+    pub pushes_received: usize,
+    pub push_latency_ms: f64,
+    pub push_rate_hz: f64,
+    /// 95% confidence intervals on `mean_ms` and on the throughput, over the
+    /// repetitions of a run with `--repeat`. `None` for a single run.
+    pub mean_ms_ci: Option<ConfidenceInterval>,
+    pub throughput_ci: Option<ConfidenceInterval>,
+    /// The peaks the connection's buffers reached, or the highest any of the
+    /// merged runs' connections reached.
+    pub high_water_marks: HighWaterMarks,
+    /// The estimates the statistics came from, when the client streams its
+    /// statistics. See `TripStats`.
+    pub trip_stats: Option<TripStats>,
+    /// Every trip, unless the client streams its statistics without keeping
+    /// trips.
     pub trip_reports: Vec<TripReport>,
+    /// The connection's buffers every `--buffer-sample-interval-ms` through
+    /// the run, or none without an interval.
+    pub buffer_samples: Vec<BufferSample>,
+    /// The stages each datagram's round trip went through, if the client
+    /// traced them.
+    pub timing_trails: Vec<timings::TimingTrail>,
+}
+
+impl Summary {
+    /// How many round trips the statistics were measured from, leaving out
+    /// the first.
+    pub fn round_trips(&self) -> usize {
+        match &self.trip_stats {
+            Some(stats) => stats.round_trips.count() as usize,
+            None => self.trip_reports.len(),
+        }
+    }
+
+    /// The mean of the round trips less `propagation_ms`, the round trip
+    /// delay of the link alone, each clamped at zero.
+    pub fn queueing_delay(&self, propagation_ms: f64) -> f64 {
+        if let Some(stats) = &self.trip_stats {
+            return stats.queueing_delay(propagation_ms);
+        }
+        match self.trip_reports.len() {
+            0 => 0.0,
+            n => {
+                self.trip_reports
+                    .iter()
+                    .map(|r| (r.round_trip - propagation_ms).max(0.0))
+                    .sum::<f64>()
+                    / n as f64
+            }
+        }
+    }
+
+    /// Payload bytes echoed per second, from the first send until the last
+    /// echo arrived. Zero if nothing was echoed.
+    pub fn throughput_bytes_per_second(&self) -> f64 {
+        if let Some(stats) = &self.trip_stats {
+            return stats.throughput_bytes_per_second();
+        }
+        let first_send = self
+            .trip_reports
+            .iter()
+            .map(|r| r.send_time)
+            .fold(f64::INFINITY, f64::min);
+        let last_arrival = self
+            .trip_reports
+            .iter()
+            .map(|r| r.send_time + r.round_trip)
+            .fold(f64::NEG_INFINITY, f64::max);
+        let bytes: usize =
+            self.trip_reports.iter().map(|r| r.payload_size).sum();
+        match last_arrival - first_send {
+            elapsed_ms if elapsed_ms > 0.0 => bytes as f64 / elapsed_ms * 1e3,
+            _ => 0.0,
+        }
+    }
+
+    /// Merges the summaries of flows which ran at the same time, like
+    /// collecting the summaries of repetitions, except that interarrival gaps
+    /// are measured within each flow, since the flows' arrivals interleave.
+    ///
+    /// Streamed statistics already measure gaps within each stream, so they
+    /// merge as usual.
+    pub fn of_concurrent_flows(flows: Vec<Summary>) -> Summary {
+        let streamed = flows.iter().all(|flow| flow.trip_stats.is_some());
+        let mut gaps: Vec<f64> = match streamed {
+            true => vec![],
+            false => flows
+                .iter()
+                .flat_map(|flow| interarrival_gaps(&flow.trip_reports))
+                .collect(),
+        };
+        gaps.sort_by(|a, b| a.partial_cmp(b).unwrap());
+
+        // The flows share the link, so their throughputs add up, while their
+        // mean round trips are averaged.
+        let mean_ms_cis: Option<Vec<ConfidenceInterval>> =
+            flows.iter().map(|flow| flow.mean_ms_ci).collect();
+        let throughput_cis: Option<Vec<ConfidenceInterval>> =
+            flows.iter().map(|flow| flow.throughput_ci).collect();
+
+        let count = flows.len() as f64;
+        let mut summary: Summary = flows.into_iter().collect();
+        if !streamed {
+            summary.interarrival_p50_ms = percentile(&gaps, 50.0);
+            summary.interarrival_p95_ms = percentile(&gaps, 95.0);
+            summary.interarrival_p99_ms = percentile(&gaps, 99.0);
+        }
+        summary.mean_ms_ci = mean_ms_cis
+            .map(|cis| ConfidenceInterval::sum(&cis).scaled(1.0 / count));
+        summary.throughput_ci =
+            throughput_cis.map(|cis| ConfidenceInterval::sum(&cis));
+        summary
+    }
+
+    /// The mean of `burst_ramp_ms`, if any transfer sent in bursts.
+    pub fn mean_burst_ramp_ms(&self) -> Option<f64> {
+        match self.burst_ramp_ms.len() {
+            0 => None,
+            n => Some(self.burst_ramp_ms.iter().sum::<f64>() / n as f64),
+        }
+    }
+}
+
+/// Estimates how long each echo in `trip_reports` was blocked at the head of
+/// the line, behind an earlier echo on its stream which was late, and
+/// records it in the report.
+///
+/// The client only sees echoes as an ordered stream releases them, not as
+/// they reach the transport. An echo released by a late predecessor arrives
+/// right after it, much closer together than the two were sent, with a round
+/// trip inflated by the wait. Such an echo is counted as blocked for as long
+/// as its round trip exceeds its stream's median. Echoes returned in batches
+/// arrive together too, so batching counts as blocking.
+fn hol_blocking(trip_reports: &mut [TripReport]) {
+    let mut streams: HashMap<StreamId, Vec<&mut TripReport>> = HashMap::new();
+    for report in trip_reports.iter_mut() {
+        streams.entry(report.stream_id).or_default().push(report);
+    }
+
+    for reports in streams.values_mut() {
+        reports.sort_by_key(|report| report.index);
+        let mut round_trips: Vec<f64> =
+            reports.iter().map(|report| report.round_trip).collect();
+        round_trips.sort_by(|a, b| a.partial_cmp(b).unwrap());
+        let median = percentile(&round_trips, 50.0);
+
+        for i in 1..reports.len() {
+            let (earlier, later) = reports.split_at_mut(i);
+            let (previous, report) = (&earlier[i - 1], &mut later[0]);
+            let sent_apart = report.send_time - previous.send_time;
+            let arrived_apart = (report.send_time + report.round_trip)
+                - (previous.send_time + previous.round_trip);
+            if arrived_apart < sent_apart / 4.0 && report.round_trip > median {
+                report.hol_blocking = report.round_trip - median;
+            }
+        }
+    }
+}
+
+/// Returns the ramp of each burst in `trip_reports`, averaged over the
+/// streams which sent that burst. See `Summary::burst_ramp_ms`.
+fn burst_ramps(trip_reports: &[TripReport]) -> Vec<f64> {
+    let mut spans = HashMap::new();
+    for report in trip_reports {
+        extend_burst_span(&mut spans, report);
+    }
+    ramps_of(&spans)
+}
+
+/// Extends the span of the burst `report` was sent in, from its first send
+/// to its last echo, to cover `report`.
+fn extend_burst_span(
+    spans: &mut HashMap<(StreamId, usize), (f64, f64)>,
+    report: &TripReport,
+) {
+    let burst = match report.burst {
+        Some(burst) => burst,
+        None => return,
+    };
+    let arrival = report.send_time + report.round_trip;
+    let span = spans
+        .entry((report.stream_id, burst))
+        .or_insert((report.send_time, arrival));
+    span.0 = span.0.min(report.send_time);
+    span.1 = span.1.max(arrival);
+}
+
+/// Returns the ramp of each burst from the spans of each stream's bursts.
+fn ramps_of(spans: &HashMap<(StreamId, usize), (f64, f64)>) -> Vec<f64> {
+    let bursts = spans.keys().map(|(_, burst)| burst + 1).max().unwrap_or(0);
+    let mut ramps = vec![vec![]; bursts];
+    for ((_, burst), (first_send, last_arrival)) in spans {
+        ramps[*burst].push(last_arrival - first_send);
+    }
+    ramps
+        .into_iter()
+        .map(|ramps| ramps.iter().sum::<f64>() / ramps.len().max(1) as f64)
+        .collect()
+}
+
+/// Returns the sorted gaps between consecutive arrivals on each stream.
+fn interarrival_gaps(trip_reports: &[TripReport]) -> Vec<f64> {
+    let mut arrivals: HashMap<StreamId, Vec<f64>> = HashMap::new();
+    for report in trip_reports {
+        arrivals
+            .entry(report.stream_id)
+            .or_default()
+            .push(report.send_time + report.round_trip);
+    }
+
+    let mut gaps: Vec<f64> = arrivals
+        .values_mut()
+        .flat_map(|arrivals| {
+            arrivals.sort_by(|a, b| a.partial_cmp(b).unwrap());
+            arrivals
+                .windows(2)
+                .map(|pair| pair[1] - pair[0])
+                .collect::<Vec<f64>>()
+        })
+        .collect();
+    gaps.sort_by(|a, b| a.partial_cmp(b).unwrap());
+    gaps
+}
+
+/// Returns the sorted round trips of `trip_reports`.
+fn round_trips(trip_reports: &[TripReport]) -> Vec<f64> {
+    let mut round_trips: Vec<f64> = trip_reports
+        .iter()
+        .map(|report| report.round_trip)
+        .collect();
+    round_trips.sort_by(|a, b| a.partial_cmp(b).unwrap());
+    round_trips
+}
+
+/// Returns the nearest-rank `p`th percentile of `sorted`, or zero if it is
+/// empty.
+fn percentile(sorted: &[f64], p: f64) -> f64 {
+    if sorted.is_empty() {
+        return 0.0;
+    }
+
+    let rank = (p / 100.0 * sorted.len() as f64).ceil() as usize;
+    sorted[rank.max(1).min(sorted.len()) - 1]
+}
+
+/// Statistics of trips estimated as their echoes return, in place of keeping
+/// every trip until the end of the run. See `estimator`.
+///
+/// Percentiles are estimates, and head-of-line blocking, which is judged
+/// against the median of a whole stream, is not estimated.
+#[derive(Clone, Debug)]
+pub struct TripStats {
+    round_trips: estimator::Moments,
+    round_trip_percentiles: estimator::Sketch,
+    gaps: estimator::Sketch,
+    /// When the last echo on each stream arrived, to measure the gap to the
+    /// next.
+    last_arrivals: HashMap<StreamId, f64>,
+    /// When the first echo on each stream arrived, to measure the gap from
+    /// a trip added later which arrived before it.
+    first_arrivals: HashMap<StreamId, f64>,
+    payload_bytes: usize,
+    first_send: f64,
+    last_arrival: f64,
+    burst_spans: HashMap<(StreamId, usize), (f64, f64)>,
+}
+
+impl Default for TripStats {
+    fn default() -> Self {
+        Self {
+            round_trips: estimator::Moments::default(),
+            round_trip_percentiles: estimator::Sketch::default(),
+            gaps: estimator::Sketch::default(),
+            last_arrivals: HashMap::new(),
+            first_arrivals: HashMap::new(),
+            payload_bytes: 0,
+            first_send: f64::INFINITY,
+            last_arrival: f64::NEG_INFINITY,
+            burst_spans: HashMap::new(),
+        }
+    }
+}
+
+impl TripStats {
+    /// Adds `report`, whose echo must have arrived no earlier than the last
+    /// one added from its stream.
+    fn push(&mut self, report: &TripReport) {
+        let arrival = report.send_time + report.round_trip;
+        match self.last_arrivals.insert(report.stream_id, arrival) {
+            Some(last_arrival) => self.gaps.push(arrival - last_arrival),
+            None => {
+                self.first_arrivals.insert(report.stream_id, arrival);
+            }
+        }
+        self.push_trip(report);
+    }
+
+    /// Adds `report`, a trip held out while later ones were added, at its
+    /// place among its stream's arrivals. If it arrived between two others,
+    /// the gap between those has already been counted, so it adds no gap.
+    fn push_held(&mut self, report: &TripReport) {
+        let arrival = report.send_time + report.round_trip;
+        let first_arrival = self.first_arrivals.get(&report.stream_id);
+        let last_arrival = self.last_arrivals.get(&report.stream_id);
+        match (first_arrival.copied(), last_arrival.copied()) {
+            (Some(first_arrival), _) if arrival <= first_arrival => {
+                self.gaps.push(first_arrival - arrival);
+                self.first_arrivals.insert(report.stream_id, arrival);
+                self.push_trip(report);
+            }
+            (_, Some(last_arrival)) if arrival < last_arrival => {
+                self.push_trip(report)
+            }
+            _ => self.push(report),
+        }
+    }
+
+    /// Adds everything of `report` but the gap to its neighbours.
+    fn push_trip(&mut self, report: &TripReport) {
+        let arrival = report.send_time + report.round_trip;
+        self.round_trips.push(report.round_trip);
+        self.round_trip_percentiles.push(report.round_trip);
+        self.payload_bytes += report.payload_size;
+        self.first_send = self.first_send.min(report.send_time);
+        self.last_arrival = self.last_arrival.max(arrival);
+        extend_burst_span(&mut self.burst_spans, report);
+    }
+
+    /// Adds `other`'s trips. Gaps are not measured across the two, so that
+    /// merging repetitions or flows does not count the gaps between them.
+    fn merge(&mut self, other: &Self) {
+        self.round_trips.merge(&other.round_trips);
+        self.round_trip_percentiles
+            .merge(&other.round_trip_percentiles);
+        self.gaps.merge(&other.gaps);
+        self.last_arrivals.extend(&other.last_arrivals);
+        for (stream_id, arrival) in &other.first_arrivals {
+            self.first_arrivals.entry(*stream_id).or_insert(*arrival);
+        }
+        self.payload_bytes += other.payload_bytes;
+        self.first_send = self.first_send.min(other.first_send);
+        self.last_arrival = self.last_arrival.max(other.last_arrival);
+        for (burst, span) in &other.burst_spans {
+            let merged = self.burst_spans.entry(*burst).or_insert(*span);
+            merged.0 = merged.0.min(span.0);
+            merged.1 = merged.1.max(span.1);
+        }
+    }
+
+    /// See `Summary::queueing_delay`.
+    fn queueing_delay(&self, propagation_ms: f64) -> f64 {
+        match self.round_trip_percentiles.count() {
+            0 => 0.0,
+            n => {
+                self.round_trip_percentiles
+                    .estimates()
+                    .map(|(round_trip, count)| {
+                        (round_trip - propagation_ms).max(0.0) * count as f64
+                    })
+                    .sum::<f64>()
+                    / n as f64
+            }
+        }
+    }
+
+    /// See `Summary::throughput_bytes_per_second`.
+    fn throughput_bytes_per_second(&self) -> f64 {
+        match self.last_arrival - self.first_send {
+            elapsed_ms if elapsed_ms > 0.0 => {
+                self.payload_bytes as f64 / elapsed_ms * 1e3
+            }
+            _ => 0.0,
+        }
+    }
+}
+
+/// What paced a client's sends.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub enum Pacing {
+    /// Only the client, by its transfers' rates and `--adaptive-rate`.
+    #[default]
+    Application,
+    /// The kernel too, capping the socket's egress at this many bytes per
+    /// second. See `Connection::set_max_pacing_rate`.
+    Kernel(u64),
+}
+
+/// A 95% confidence interval on the mean of a metric over several runs.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct ConfidenceInterval {
+    pub mean: f64,
+    /// Half the interval's width, so that it spans `mean ± half_width`.
+    pub half_width: f64,
+}
+
+impl ConfidenceInterval {
+    /// The interval on the mean of `samples`, from Student's t-distribution
+    /// so that it widens for few samples. `None` with fewer than two.
+    pub fn of(samples: &[f64]) -> Option<Self> {
+        let n = samples.len();
+        if n < 2 {
+            return None;
+        }
+
+        let mean = samples.iter().sum::<f64>() / n as f64;
+        let variance = samples
+            .iter()
+            .map(|sample| (sample - mean).powi(2))
+            .sum::<f64>()
+            / (n - 1) as f64;
+        Some(Self {
+            mean,
+            half_width: t_critical_95(n - 1) * (variance / n as f64).sqrt(),
+        })
+    }
+
+    pub fn low(&self) -> f64 {
+        self.mean - self.half_width
+    }
+
+    pub fn high(&self) -> f64 {
+        self.mean + self.half_width
+    }
+
+    /// Whether the intervals overlap, so that neither mean is significantly
+    /// smaller than the other.
+    pub fn overlaps(&self, other: &Self) -> bool {
+        self.low() <= other.high() && other.low() <= self.high()
+    }
+
+    /// The interval on the sum of independent means.
+    fn sum(intervals: &[Self]) -> Self {
+        Self {
+            mean: intervals.iter().map(|ci| ci.mean).sum(),
+            half_width: intervals
+                .iter()
+                .map(|ci| ci.half_width.powi(2))
+                .sum::<f64>()
+                .sqrt(),
+        }
+    }
+
+    fn scaled(self, factor: f64) -> Self {
+        Self {
+            mean: self.mean * factor,
+            half_width: self.half_width * factor.abs(),
+        }
+    }
+}
+
+/// The two-sided 95% critical value of Student's t-distribution with `df`
+/// degrees of freedom. Past 30 the value of the next tabulated `df` below is
+/// used, which errs on the side of a wider interval.
+fn t_critical_95(df: usize) -> f64 {
+    const TABLE: [f64; 30] = [
+        12.706, 4.303, 3.182, 2.776, 2.571, 2.447, 2.365, 2.306, 2.262, 2.228,
+        2.201, 2.179, 2.160, 2.145, 2.131, 2.120, 2.110, 2.101, 2.093, 2.086,
+        2.080, 2.074, 2.069, 2.064, 2.060, 2.056, 2.052, 2.048, 2.045, 2.042,
+    ];
+    match df {
+        0 => f64::INFINITY,
+        1..=30 => TABLE[df - 1],
+        31..=40 => TABLE[29],
+        41..=60 => 2.021,
+        61..=120 => 2.000,
+        _ => 1.980,
+    }
 }
 
 impl FromIterator<Summary> for Summary {
@@ -33,29 +609,144 @@ impl FromIterator<Summary> for Summary {
     where
         T: IntoIterator<Item = Summary>,
     {
-        let (trip_reports, count, mean_sum, deviation_sum) =
-            iter.into_iter().fold(
-                (vec![], 0, 0.0, 0.0),
-                |(
-                    mut trip_reports,
-                    mut count,
-                    mut mean_sum,
-                    mut deviation_sum,
-                ),
-                 mut result| {
-                    mean_sum += result.mean_ms;
-                    deviation_sum += result.deviation_ms;
-                    count += 1;
-                    trip_reports.append(&mut result.trip_reports);
-
-                    (trip_reports, count, mean_sum, deviation_sum)
-                },
-            );
+        let mut summaries: Vec<Summary> = iter.into_iter().collect();
+        let mean_over = |summaries: &[&Summary], field: fn(&Summary) -> f64| {
+            match summaries.len() {
+                0 => 0.0,
+                count => {
+                    summaries.iter().map(|s| field(s)).sum::<f64>()
+                        / count as f64
+                }
+            }
+        };
+        let all: Vec<&Summary> = summaries.iter().collect();
+        let mean_of = |field| mean_over(&all, field);
+        // A summary without round trips has no latency to average in.
+        let timed: Vec<&Summary> =
+            summaries.iter().filter(|s| s.round_trips() > 0).collect();
+
+        let mean_ms = mean_over(&timed, |s| s.mean_ms);
+        let deviation_ms = mean_over(&timed, |s| s.deviation_ms);
+        let first_rtt_ms = mean_of(|s| s.first_rtt_ms);
+        let handshake_ms = mean_of(|s| s.handshake_ms);
+        let handshake_bytes = mean_of(|s| s.handshake_bytes);
+        let teardown_ms = mean_of(|s| s.teardown_ms);
+        let amplification = mean_of(|s| s.amplification);
+        let queueing_delay_ms = mean_of(|s| s.queueing_delay_ms);
+        let hol_blocking_ms = mean_of(|s| s.hol_blocking_ms);
+        let hol_blocked = summaries.iter().map(|s| s.hol_blocked).sum();
+        let push_latency_ms = mean_of(|s| s.push_latency_ms);
+        let push_rate_hz = mean_of(|s| s.push_rate_hz);
+        let pushes_received = summaries.iter().map(|s| s.pushes_received).sum();
+        let reconnections = summaries.iter().map(|s| s.reconnections).sum();
+        let lost_to_reconnect =
+            summaries.iter().map(|s| s.lost_to_reconnect).sum();
+        let corrupted_datagrams =
+            summaries.iter().map(|s| s.corrupted_datagrams).sum();
+        let dropped_sends = summaries.iter().map(|s| s.dropped_sends).sum();
+        // Every run of a client paces the same way.
+        let pacing = summaries.first().map(|s| s.pacing).unwrap_or_default();
+        let sla_violations = summaries.iter().map(|s| s.sla_violations).sum();
+        let worst_violation_streak = summaries
+            .iter()
+            .map(|s| s.worst_violation_streak)
+            .max()
+            .unwrap_or(0);
+        let violation_clusters = summaries
+            .iter()
+            .flat_map(|s| s.violation_clusters.iter().copied())
+            .collect();
+        // Each summary numbers its bursts from zero, so the runs' bursts are
+        // averaged place by place rather than pooled.
+        let bursts = summaries
+            .iter()
+            .map(|s| s.burst_ramp_ms.len())
+            .max()
+            .unwrap_or(0);
+        let burst_ramp_ms = (0..bursts)
+            .map(|burst| {
+                let ramps: Vec<f64> = summaries
+                    .iter()
+                    .filter_map(|s| s.burst_ramp_ms.get(burst).copied())
+                    .collect();
+                ramps.iter().sum::<f64>() / ramps.len() as f64
+            })
+            .collect();
+        let high_water_marks = summaries
+            .iter()
+            .map(|s| s.high_water_marks)
+            .fold(HighWaterMarks::default(), HighWaterMarks::max);
+        let trip_stats = summaries
+            .iter()
+            .map(|s| s.trip_stats.as_ref())
+            .collect::<Option<Vec<&TripStats>>>()
+            .map(|stats| {
+                let mut merged = TripStats::default();
+                for stats in stats {
+                    merged.merge(stats);
+                }
+                merged
+            });
+        let trip_reports: Vec<TripReport> = summaries
+            .iter_mut()
+            .flat_map(|s| s.trip_reports.drain(..))
+            .collect();
+        let timing_trails = summaries
+            .iter_mut()
+            .flat_map(|s| s.timing_trails.drain(..))
+            .collect();
+        let buffer_samples = summaries
+            .iter_mut()
+            .flat_map(|s| s.buffer_samples.drain(..))
+            .collect();
 
+        let (round_trip_p99_ms, [p50, p95, p99]) = match &trip_stats {
+            Some(stats) => (
+                stats.round_trip_percentiles.percentile(99.0),
+                [50.0, 95.0, 99.0].map(|p| stats.gaps.percentile(p)),
+            ),
+            None => {
+                let gaps = interarrival_gaps(&trip_reports);
+                (
+                    percentile(&round_trips(&trip_reports), 99.0),
+                    [50.0, 95.0, 99.0].map(|p| percentile(&gaps, p)),
+                )
+            }
+        };
         Summary {
-            mean_ms: mean_sum / count as f64,
-            deviation_ms: deviation_sum / count as f64,
+            mean_ms,
+            deviation_ms,
+            round_trip_p99_ms,
+            interarrival_p50_ms: p50,
+            interarrival_p95_ms: p95,
+            interarrival_p99_ms: p99,
+            first_rtt_ms,
+            handshake_ms,
+            handshake_bytes,
+            teardown_ms,
+            reconnections,
+            lost_to_reconnect,
+            pacing,
+            corrupted_datagrams,
+            dropped_sends,
+            amplification,
+            queueing_delay_ms,
+            burst_ramp_ms,
+            hol_blocking_ms,
+            hol_blocked,
+            sla_violations,
+            worst_violation_streak,
+            violation_clusters,
+            pushes_received,
+            push_latency_ms,
+            push_rate_hz,
+            mean_ms_ci: None,
+            throughput_ci: None,
+            high_water_marks,
+            trip_stats,
             trip_reports,
+            buffer_samples,
+            timing_trails,
         }
     }
 }
@@ -65,287 +756,3056 @@ impl std::fmt::Debug for Summary {
         f.debug_struct("Summary")
             .field("Mean", &self.mean_ms)
             .field("Deviation", &self.deviation_ms)
+            .field("Round trip p99", &self.round_trip_p99_ms)
+            .field("Interarrival p50", &self.interarrival_p50_ms)
+            .field("Interarrival p95", &self.interarrival_p95_ms)
+            .field("Interarrival p99", &self.interarrival_p99_ms)
+            .field("First round trip", &self.first_rtt_ms)
+            .field("Handshake", &self.handshake_ms)
+            .field("Handshake bytes", &self.handshake_bytes)
+            .field("Teardown", &self.teardown_ms)
+            .field("Reconnections", &self.reconnections)
+            .field("Lost to reconnect", &self.lost_to_reconnect)
+            .field("Pacing", &self.pacing)
+            .field("Corrupted datagrams", &self.corrupted_datagrams)
+            .field("Dropped sends", &self.dropped_sends)
+            .field("Amplification", &self.amplification)
+            .field("Queueing delay", &self.queueing_delay_ms)
+            .field("Burst ramps", &self.burst_ramp_ms)
+            .field("Head-of-line blocking", &self.hol_blocking_ms)
+            .field("Head-of-line blocked", &self.hol_blocked)
+            .field("SLA violations", &self.sla_violations)
+            .field("Worst violation streak", &self.worst_violation_streak)
+            .field("Violation clusters", &self.violation_clusters)
+            .field("Pushes received", &self.pushes_received)
+            .field("Push latency", &self.push_latency_ms)
+            .field("Push rate", &self.push_rate_hz)
+            .field("Mean confidence interval", &self.mean_ms_ci)
+            .field("Throughput confidence interval", &self.throughput_ci)
+            .field("High-water marks", &self.high_water_marks)
             .finish()
     }
 }
 
 impl From<Vec<TripReport>> for Summary {
-    fn from(src: Vec<TripReport>) -> Self {
+    fn from(mut src: Vec<TripReport>) -> Self {
+        hol_blocking(&mut src);
+
         let sum: f64 = src.iter().map(|r| r.round_trip).sum();
         let n = src.len() as f64;
-        let mean = sum / n;
+        let mean = match src.len() {
+            0 => 0.0,
+            _ => sum / n,
+        };
 
         let square_difference = |r: &TripReport| (r.round_trip - mean).powi(2);
         let sum_of_squares: f64 = src.iter().map(square_difference).sum();
-        let variance = sum_of_squares / (n - 1.0);
-        let deviation = variance.sqrt();
+        let deviation = match src.len() {
+            0 | 1 => 0.0,
+            _ => (sum_of_squares / (n - 1.0)).sqrt(),
+        };
 
+        let gaps = interarrival_gaps(&src);
         Summary {
             mean_ms: mean,
             deviation_ms: deviation,
+            round_trip_p99_ms: percentile(&round_trips(&src), 99.0),
+            interarrival_p50_ms: percentile(&gaps, 50.0),
+            interarrival_p95_ms: percentile(&gaps, 95.0),
+            interarrival_p99_ms: percentile(&gaps, 99.0),
+            first_rtt_ms: 0.0,
+            handshake_ms: 0.0,
+            handshake_bytes: 0.0,
+            teardown_ms: 0.0,
+            reconnections: 0,
+            lost_to_reconnect: 0,
+            pacing: Pacing::default(),
+            corrupted_datagrams: 0,
+            dropped_sends: 0,
+            amplification: 0.0,
+            queueing_delay_ms: 0.0,
+            burst_ramp_ms: burst_ramps(&src),
+            hol_blocking_ms: match src.len() {
+                0 => 0.0,
+                _ => src.iter().map(|r| r.hol_blocking).sum::<f64>() / n,
+            },
+            hol_blocked: src.iter().filter(|r| r.hol_blocking > 0.0).count(),
+            sla_violations: 0,
+            worst_violation_streak: 0,
+            violation_clusters: vec![],
+            pushes_received: 0,
+            push_latency_ms: 0.0,
+            push_rate_hz: 0.0,
+            mean_ms_ci: None,
+            throughput_ci: None,
+            high_water_marks: HighWaterMarks::default(),
+            trip_stats: None,
             trip_reports: src,
+            buffer_samples: vec![],
+            timing_trails: vec![],
+        }
+    }
+}
+
+impl From<TripStats> for Summary {
+    fn from(stats: TripStats) -> Self {
+        let gaps = &stats.gaps;
+        Summary {
+            mean_ms: match stats.round_trips.count() {
+                0 => 0.0,
+                _ => stats.round_trips.mean(),
+            },
+            deviation_ms: match stats.round_trips.count() {
+                0 | 1 => 0.0,
+                _ => stats.round_trips.deviation(),
+            },
+            round_trip_p99_ms: stats.round_trip_percentiles.percentile(99.0),
+            interarrival_p50_ms: gaps.percentile(50.0),
+            interarrival_p95_ms: gaps.percentile(95.0),
+            interarrival_p99_ms: gaps.percentile(99.0),
+            first_rtt_ms: 0.0,
+            handshake_ms: 0.0,
+            handshake_bytes: 0.0,
+            teardown_ms: 0.0,
+            reconnections: 0,
+            lost_to_reconnect: 0,
+            pacing: Pacing::default(),
+            corrupted_datagrams: 0,
+            dropped_sends: 0,
+            amplification: 0.0,
+            queueing_delay_ms: 0.0,
+            burst_ramp_ms: ramps_of(&stats.burst_spans),
+            hol_blocking_ms: 0.0,
+            hol_blocked: 0,
+            sla_violations: 0,
+            worst_violation_streak: 0,
+            violation_clusters: vec![],
+            pushes_received: 0,
+            push_latency_ms: 0.0,
+            push_rate_hz: 0.0,
+            mean_ms_ci: None,
+            throughput_ci: None,
+            high_water_marks: HighWaterMarks::default(),
+            trip_stats: Some(stats),
+            trip_reports: vec![],
+            buffer_samples: vec![],
+            timing_trails: vec![],
         }
     }
 }
 
+/// Removes the earliest sent trip across `returned` and returns its round
+/// trip.
+fn take_first_trip(returned: &mut [Vec<TripReport>]) -> Option<f64> {
+    let (stream, index) = returned
+        .iter()
+        .enumerate()
+        .flat_map(|(stream, reports)| {
+            reports
+                .iter()
+                .enumerate()
+                .map(move |(index, report)| (stream, index, report.send_time))
+        })
+        .min_by(|a, b| a.2.partial_cmp(&b.2).unwrap())
+        .map(|(stream, index, _)| (stream, index))?;
+
+    Some(returned[stream].remove(index).round_trip)
+}
+
 #[derive(Debug)]
 struct TransferTracker {
     epoch: Instant,
     stream_id: StreamId,
     total_expected: usize,
-    live: HashMap<u64, Instant>,
+    /// Whether only `total_expected` datagrams are sent, so that any lost
+    /// ones will never be replaced.
+    fixed_total: bool,
+    /// Whether datagrams are sent unreliably, so that any without an echo
+    /// after `LOSS_TIMEOUT` are lost.
+    unreliable: bool,
+    /// Whether each echo must carry back as much data as its datagram was
+    /// sent with, as replays check against their recording.
+    check_sizes: bool,
+    /// Send time, payload size, and burst of each datagram awaiting its
+    /// echo.
+    live: HashMap<u64, (Instant, usize, Option<usize>)>,
+    /// The trips returned so far, or with streamed statistics, those kept.
     returned: Vec<TripReport>,
+    /// The statistics of the trips returned so far, if they are streamed.
+    streamed: Option<TripStats>,
+    /// Whether to keep every trip in `returned` while streaming statistics.
+    keep_trips: bool,
+    /// The earliest sent trip returned so far, while streaming statistics.
+    /// It is held out of `streamed` until the run ends, in case it is the
+    /// run's first trip.
+    first_trip: Option<TripReport>,
+    /// The stages reached so far by each traced datagram awaiting its echo.
+    tracing: HashMap<u64, Vec<(timings::Stage, Instant)>>,
+    traced: Vec<timings::TimingTrail>,
+    lost: usize,
+    /// Echoes which came back with a different payload than was sent.
+    corrupted: usize,
+    /// The id after the last one sent.
+    next_id: u64,
 }
 
 impl TransferTracker {
-    fn track_send(&mut self, id: u64) {
-        self.live.insert(id, Instant::now());
+    fn track_send(
+        &mut self,
+        id: u64,
+        payload_size: usize,
+        burst: Option<usize>,
+    ) {
+        self.live.insert(id, (clock::now(), payload_size, burst));
+        self.next_id = self.next_id.max(id + 1);
+    }
+
+    /// Fails if the echo of `id` carries a different amount of data than
+    /// was sent, when the tracker checks sizes.
+    fn check_size(&self, id: u64, echoed: usize) -> Result<()> {
+        match self.live.get(&id) {
+            Some((_, sent, _)) if self.check_sizes && *sent != echoed => {
+                Err(format!(
+                    "echo {} on {:?} carried {} bytes, but {} were sent",
+                    id, self.stream_id, echoed, sent
+                )
+                .into())
+            }
+            _ => Ok(()),
+        }
     }
 
-    fn track_return(&mut self, id: u64) {
-        let now = Instant::now();
-        if let Some(sent_time) = self.live.remove(&id) {
-            let round_trip = now.duration_since(sent_time);
-            self.returned.push(TripReport {
+    /// Checks the echo of `id` against the payload sent, and if it differs
+    /// counts it as corrupted instead of returned. Returns whether the echo
+    /// is intact or was not in flight.
+    fn check_payload(&mut self, id: u64, data: &[u8]) -> bool {
+        if !self.live.contains_key(&id)
+            || payload_matches(self.stream_id, id, data)
+        {
+            return true;
+        }
+
+        self.live.remove(&id);
+        self.tracing.remove(&id);
+        self.corrupted += 1;
+        false
+    }
+
+    /// Returns the round trip of `id` if it was in flight. `arrival` is when
+    /// its echo was received and deserialized, if the client traces timings.
+    fn track_return(
+        &mut self,
+        id: u64,
+        arrival: Option<(Instant, Instant)>,
+    ) -> Option<f64> {
+        let now = clock::now();
+        let (sent_time, payload_size, burst) = self.live.remove(&id)?;
+        if let (Some(mut stages), Some((received, deserialized))) =
+            (self.tracing.remove(&id), arrival)
+        {
+            stages.push((timings::Stage::Received, received));
+            stages.push((timings::Stage::Deserialized, deserialized));
+            stages.push((timings::Stage::Delivered, now));
+            let epoch = self.epoch;
+            self.traced.push(timings::TimingTrail {
                 stream_id: self.stream_id,
                 index: id,
-                send_time: (now.duration_since(self.epoch) - round_trip)
-                    .as_secs_f64()
-                    * 1e3,
-                round_trip: round_trip.as_secs_f64() * 1e3,
+                stages: stages
+                    .into_iter()
+                    .map(|(stage, at)| {
+                        (stage, at.duration_since(epoch).as_secs_f64() * 1e3)
+                    })
+                    .collect(),
             });
         }
+        let round_trip = now.duration_since(sent_time);
+        let round_trip_ms = round_trip.as_secs_f64() * 1e3;
+        self.record(TripReport {
+            stream_id: self.stream_id,
+            index: id,
+            payload_size,
+            send_time: (now.duration_since(self.epoch) - round_trip)
+                .as_secs_f64()
+                * 1e3,
+            round_trip: round_trip_ms,
+            burst,
+            hol_blocking: 0.0,
+        });
+        Some(round_trip_ms)
     }
 
-    fn done(&self) -> bool {
-        self.returned.len() >= self.total_expected
+    fn record(&mut self, report: TripReport) {
+        let streamed = match &mut self.streamed {
+            Some(streamed) => streamed,
+            None => return self.returned.push(report),
+        };
+        let held_out = match self.first_trip {
+            Some(first) if first.send_time <= report.send_time => report,
+            _ => match self.first_trip.replace(report) {
+                Some(first) => first,
+                None => return,
+            },
+        };
+        streamed.push_held(&held_out);
+        if self.keep_trips {
+            self.returned.push(held_out);
+        }
     }
-}
 
-async fn run(
-    options: Options,
-    client: impl Connection + Unpin,
-) -> Result<Summary> {
-    enum Input {
-        Transfer(TransferCmd),
-        Wire(Result<Datagram>),
+    /// Counts unreliable datagrams sent longer than `LOSS_TIMEOUT` ago as
+    /// lost.
+    fn expire(&mut self) {
+        if !self.unreliable {
+            return;
+        }
+        let tracing = &mut self.tracing;
+        let before = self.live.len();
+        self.live.retain(|id, (sent, _, _)| {
+            let live = clock::since(*sent) < LOSS_TIMEOUT;
+            if !live {
+                tracing.remove(id);
+            }
+            live
+        });
+        self.lost += before - self.live.len();
     }
 
-    let (mut client_sink, client_stream) = client.split();
-    let returned_datagrams = client_stream.map(Input::Wire);
+    /// How many echoes have returned.
+    fn returned_count(&self) -> usize {
+        match &self.streamed {
+            Some(streamed) => {
+                streamed.round_trips.count() as usize
+                    + self.first_trip.iter().count()
+            }
+            None => self.returned.len(),
+        }
+    }
 
-    let epoch = Instant::now();
-    let mut tracking = options
-        .transfers
-        .iter()
-        .filter_map(|tx| {
-            tx.return_count.map(|total_expected| {
-                (
-                    tx.stream_id,
-                    TransferTracker {
-                        epoch,
-                        stream_id: tx.stream_id,
-                        total_expected,
-                        live: HashMap::new(),
-                        returned: vec![],
-                    },
-                )
-            })
+    fn done(&self) -> bool {
+        let lost = match self.fixed_total {
+            true => self.lost + self.corrupted,
+            false => 0,
+        };
+        self.returned_count() + lost >= self.total_expected
+    }
+}
+
+/// Summarizes the streamed statistics of `tracking`'s trackers, and returns
+/// the round trip of the earliest sent trip, which like `take_first_trip` is
+/// left out of the summaries.
+fn summarize_streamed(
+    tracking: HashMap<StreamId, TransferTracker>,
+) -> (Vec<Summary>, Option<f64>) {
+    let first = tracking
+        .values()
+        .filter_map(|tracker| tracker.first_trip)
+        .min_by(|a, b| a.send_time.partial_cmp(&b.send_time).unwrap())
+        .map(|first| (first.stream_id, first.round_trip));
+
+    let summaries = tracking
+        .into_iter()
+        .map(|(stream_id, mut tracker)| {
+            let mut stats = tracker.streamed.take().unwrap_or_default();
+            match tracker.first_trip.take() {
+                Some(trip) if Some(stream_id) != first.map(|(id, _)| id) => {
+                    stats.push_held(&trip);
+                    if tracker.keep_trips {
+                        let arrival = |trip: &TripReport| {
+                            trip.send_time + trip.round_trip
+                        };
+                        let at = tracker.returned.partition_point(|kept| {
+                            arrival(kept) <= arrival(&trip)
+                        });
+                        tracker.returned.insert(at, trip);
+                    }
+                }
+                _ => {}
+            }
+            let mut summary = Summary::from(stats);
+            summary.trip_reports = tracker.returned;
+            summary
         })
-        .collect::<HashMap<StreamId, TransferTracker>>();
-    let transfers: SelectAll<_> = options
-        .transfers
+        .collect();
+    (summaries, first.map(|(_, round_trip)| round_trip))
+}
+
+/// Summarizes every trip `tracking`'s trackers kept, and returns the round
+/// trip of the earliest sent trip, which `take_first_trip` leaves out of the
+/// summaries.
+fn summarize_returned(
+    tracking: HashMap<StreamId, TransferTracker>,
+) -> (Vec<Summary>, Option<f64>) {
+    let mut returned: Vec<Vec<TripReport>> = tracking
         .into_iter()
-        .map(Transfer::stream)
+        .map(|(_, tracker)| tracker.returned)
         .collect();
+    let first_rtt_ms = take_first_trip(&mut returned);
+    let summaries = returned.into_iter().map(Summary::from).collect();
+    (summaries, first_rtt_ms)
+}
 
-    let mut input_stream =
-        select(transfers.map(Input::Transfer), returned_datagrams);
+/// Tracks the datagrams the server pushes on one stream.
+struct PushTracker {
+    expected: usize,
+    /// When each push arrived, in milliseconds since the epoch, and how long
+    /// it took to arrive.
+    arrivals: Vec<(f64, f64)>,
+    lost: usize,
+}
 
-    loop {
-        let input = input_stream.next().await.unwrap();
-        match input {
-            Input::Wire(returned_datagram) => {
-                let returned_datagram: Datagram =
-                    returned_datagram.expect("datagram");
-                let stream = returned_datagram
-                    .stream_position
-                    .expect("stream position")
-                    .stream_id;
-                let benchmark_datagram =
-                    bincode::deserialize::<BenchmarkDatagram>(
-                        returned_datagram.data.as_slice(),
-                    )?;
+impl PushTracker {
+    fn track(&mut self, data: &[u8], epoch: Instant) {
+        let arrival = clock::since(epoch).as_secs_f64() * 1e3;
+        let latency = match data.get(..8) {
+            Some(sent) => {
+                let mut micros = [0; 8];
+                micros.copy_from_slice(sent);
+                let sent = u64::from_le_bytes(micros);
+                wall_clock_micros().saturating_sub(sent) as f64 / 1e3
+            }
+            None => 0.0,
+        };
+        self.arrivals.push((arrival, latency));
+    }
 
-                if let Some(tracker) = tracking.get_mut(&stream) {
-                    tracker.track_return(benchmark_datagram.id);
-                }
+    fn done(&self) -> bool {
+        self.arrivals.len() + self.lost >= self.expected
+    }
 
-                if tracking.values().all(TransferTracker::done) {
-                    return Ok(tracking
-                        .into_iter()
-                        .map(|(_, tracker)| tracker.returned)
-                        .map(Summary::from)
-                        .collect());
-                }
-            }
-            Input::Transfer(transfer_cmd) => {
-                client_sink.send(transfer_cmd.send_cmd).await?;
-                if let Some((cumulative_tracking, cmd_tracking)) =
-                    transfer_cmd.tracking.and_then(|cmd_tracking| {
-                        let cumulative_tracking =
-                            tracking.get_mut(&cmd_tracking.stream_id)?;
-                        Some((cumulative_tracking, cmd_tracking))
-                    })
-                {
-                    cumulative_tracking.track_send(cmd_tracking.id);
-                }
+    /// Pushes per second from the first arrival to the last, or zero if
+    /// fewer than two arrived.
+    fn rate_hz(&self) -> f64 {
+        match (self.arrivals.first(), self.arrivals.last()) {
+            (Some((first, _)), Some((last, _))) if last > first => {
+                (self.arrivals.len() - 1) as f64 / (last - first) * 1e3
             }
+            _ => 0.0,
         }
     }
 }
 
-#[derive(Clone, Debug, StructOpt)]
-pub struct Options {
-    /// Address of the server to run the benchmark against;
-    #[structopt(short = "a", default_value = "127.0.0.1:33333")]
-    pub address: SocketAddr,
-    /// Periodic transfers, specified in terms of
-    /// `stream_id:size:hertz:[return_count]`.
-    #[structopt(short = "b", long)]
-    pub transfers: Vec<Transfer>,
-    #[structopt(subcommand)]
-    pub protocol: Protocol,
+/// Checks round trips against a latency budget in the order their echoes
+/// arrive, counting those over it and the runs of them in a row.
+#[derive(Debug, Clone, PartialEq)]
+struct SlaCheck {
+    budget_ms: f64,
+    /// How long a run of violations must get before it is recorded in
+    /// `clusters`. Never recorded if `None`.
+    cluster: Option<usize>,
+    violations: usize,
+    streak: usize,
+    worst_streak: usize,
+    /// The length of each run which reached `cluster`, the last growing
+    /// while its run goes on.
+    clusters: Vec<usize>,
 }
 
-struct TransferCmd {
-    send_cmd: SendCmd,
-    tracking: Option<TransferMessageTracking>,
-}
+impl SlaCheck {
+    fn new(budget_ms: f64, cluster: Option<usize>) -> Self {
+        Self {
+            budget_ms,
+            cluster,
+            violations: 0,
+            streak: 0,
+            worst_streak: 0,
+            clusters: vec![],
+        }
+    }
 
-/// Tracking information for a transfer message on the wire.
-struct TransferMessageTracking {
-    stream_id: StreamId,
-    id: u64,
-}
+    fn check(&mut self, round_trip_ms: f64) {
+        if round_trip_ms <= self.budget_ms {
+            self.streak = 0;
+            return;
+        }
 
-#[derive(Clone, Debug, Copy)]
-pub struct Transfer {
-    pub stream_id: StreamId,
-    pub size: usize,
-    pub hertz: u32,
-    pub return_count: Option<usize>,
+        self.violations += 1;
+        self.streak += 1;
+        self.worst_streak = self.worst_streak.max(self.streak);
+        match self.cluster {
+            Some(cluster) if self.streak == cluster => {
+                self.clusters.push(self.streak)
+            }
+            Some(cluster) if self.streak > cluster => {
+                *self.clusters.last_mut().expect("cluster begun") = self.streak
+            }
+            _ => {}
+        }
+    }
 }
 
-impl Transfer {
-    fn stream(self) -> impl Stream<Item = TransferCmd> {
-        let ticker = ticker(self.hertz);
-        let mut id = 0;
-        ticker.map(move |_| {
-            id += 1;
-            match self.return_count {
-                Some(_) => TransferCmd {
-                    send_cmd: self.send_cmd(id),
-                    tracking: Some(TransferMessageTracking {
-                        stream_id: self.stream_id,
-                        id,
-                    }),
-                },
-                None => TransferCmd {
-                    send_cmd: self.send_cmd(ID_DO_NOT_RETURN),
-                    tracking: None,
-                },
+fn metrics_snapshot(
+    tracking: &HashMap<StreamId, TransferTracker>,
+    epoch: Instant,
+) -> metrics::MetricsSnapshot {
+    let outstanding: u64 = tracking
+        .values()
+        .map(|tracker| tracker.live.len() as u64)
+        .sum();
+    let streamed = tracking
+        .values()
+        .map(|tracker| tracker.streamed.as_ref())
+        .collect::<Option<Vec<&TripStats>>>();
+    let (returned, mean, p99) = match streamed {
+        Some(streamed) => {
+            let mut merged = TripStats::default();
+            for stats in streamed {
+                merged.merge(stats);
             }
-        })
+            let returned = merged.round_trips.count();
+            let mean = match returned {
+                0 => 0.0,
+                _ => merged.round_trips.mean(),
+            };
+            (
+                returned,
+                mean,
+                merged.round_trip_percentiles.percentile(99.0),
+            )
+        }
+        None => {
+            let mut round_trips: Vec<f64> = tracking
+                .values()
+                .flat_map(|tracker| {
+                    tracker.returned.iter().map(|r| r.round_trip)
+                })
+                .collect();
+            round_trips.sort_by(|a, b| a.partial_cmp(b).unwrap());
+            let returned = round_trips.len() as u64;
+            let mean = match returned {
+                0 => 0.0,
+                n => round_trips.iter().sum::<f64>() / n as f64,
+            };
+            (returned, mean, percentile(&round_trips, 99.0))
+        }
+    };
+
+    metrics::MetricsSnapshot {
+        round_trip_mean_ms: mean,
+        round_trip_p99_ms: p99,
+        returned_per_second: returned as f64
+            / clock::since(epoch).as_secs_f64(),
+        datagrams_sent: returned + outstanding,
+        datagrams_returned: returned,
+        datagrams_outstanding: outstanding,
     }
+}
 
-    fn send_cmd(&self, id: u64) -> SendCmd {
-        let delivery_mode = DeliveryMode::ReliableOrdered(self.stream_id);
-        SendCmd {
-            delivery_mode,
-            data: bincode::serialize(&BenchmarkDatagram {
-                id,
-                delivery_mode,
-                data: vec![0; self.size],
-            })
-            .expect("to serialize bulk transfer"),
-            ..SendCmd::default()
+/// Thins out transfer commands after the server signals overload, halving the
+/// rate for each quench and creeping back to full rate as commands go out.
+struct Pace {
+    rate: f64,
+    credit: f64,
+}
+
+impl Pace {
+    const MIN_RATE: f64 = 1.0 / 16.0;
+    const RECOVERY: f64 = 0.01;
+
+    fn new() -> Self {
+        Self {
+            rate: 1.0,
+            credit: 0.0,
         }
     }
-}
 
-impl FromStr for Transfer {
-    type Err = std::num::ParseIntError;
-    fn from_str(src: &str) -> std::result::Result<Self, Self::Err> {
-        let args: Vec<&str> = src.split(":").collect();
+    fn quench(&mut self) {
+        self.rate = (self.rate / 2.0).max(Self::MIN_RATE);
+    }
 
-        let stream_id = args[0].parse::<u8>()?;
-        let size = args[1].parse::<usize>()?;
-        let hertz = args[2].parse::<u32>()?;
-        let return_count =
-            args.get(3).map(|a| a.parse::<usize>()).transpose()?;
+    /// Whether to send the next transfer command.
+    fn admit(&mut self) -> bool {
+        self.credit += self.rate;
+        if self.credit < 1.0 {
+            return false;
+        }
+
+        self.credit -= 1.0;
+        self.rate = (self.rate + Self::RECOVERY).min(1.0);
+        true
+    }
+}
+
+enum Input {
+    Transfer(TransferCmd),
+    Wire(Result<Datagram>),
+    FlushTick,
+    /// Time to sample the connection's buffers.
+    BufferTick,
+    /// Time to count unreliable datagrams gone too long without an echo as
+    /// lost.
+    LossTick,
+    /// Every transfer has sent all it will send.
+    TransfersDone,
+    /// The connection's stream of datagrams ended.
+    Disconnected,
+}
+
+/// Reads inputs from the connection, at most `consume_rate` per second if it
+/// is set. Anything unread waits in the transport, so a slow reader pushes
+/// back on reliable streams and loses datagrams on unreliable ones.
+fn wire_inputs<C>(
+    client_stream: SplitStream<Sampled<C>>,
+    consume_rate: Option<NonZeroU32>,
+) -> LocalBoxStream<'static, Input>
+where
+    C: Connection + Unpin + 'static,
+{
+    let inputs = client_stream
+        .map(Input::Wire)
+        .chain(stream::once(future::ready(Input::Disconnected)));
+    match consume_rate {
+        Some(hertz) => inputs
+            .zip(ticker(hertz.get()))
+            .map(|(input, _)| input)
+            .boxed_local(),
+        None => inputs.boxed_local(),
+    }
+}
+
+/// How long an unreliable datagram may go without its echo before it counts
+/// as lost.
+const LOSS_TIMEOUT: Duration = Duration::from_secs(1);
+
+/// How long to wait for the server to close its end of the connection.
+const TEARDOWN_TIMEOUT: Duration = Duration::from_secs(1);
+
+/// Closes the connection and waits for the server to close its end, skipping
+/// any echoes still on their way. Returns how long that took in milliseconds,
+/// or zero if the server's end did not close cleanly within
+/// `TEARDOWN_TIMEOUT`.
+async fn teardown<S>(sink: &mut S, wire: &mut LocalBoxStream<'_, Input>) -> f64
+where
+    S: Sink<SendCmd, Error = Box<dyn std::error::Error>> + Unpin,
+{
+    let teardown_start = clock::now();
+    let closed = async {
+        sink.close().await?;
+        while let Some(input) = wire.next().await {
+            match input {
+                Input::Wire(Err(e)) => return Err(e),
+                Input::Disconnected => break,
+                _ => continue,
+            }
+        }
+        Ok(())
+    };
+
+    match future::select(Box::pin(closed), clock::sleep(TEARDOWN_TIMEOUT)).await
+    {
+        Either::Left((Ok(()), _)) => {
+            clock::since(teardown_start).as_secs_f64() * 1e3
+        }
+        _ => 0.0,
+    }
+}
+
+/// Sends `send_cmd`, flushing if `flush_policy` calls for it.
+async fn send_with_policy<S>(
+    sink: &mut S,
+    send_cmd: SendCmd,
+    flush_policy: FlushPolicy,
+    unflushed: &mut usize,
+) -> Result<()>
+where
+    S: Sink<SendCmd, Error = Box<dyn std::error::Error>> + Unpin,
+{
+    sink.feed(send_cmd).await?;
+    *unflushed += 1;
+
+    let flush = match flush_policy {
+        FlushPolicy::PerSend => true,
+        FlushPolicy::EveryN(n) => *unflushed >= n,
+        FlushPolicy::Tick(_) | FlushPolicy::Window(_) => false,
+    };
+    if flush {
+        sink.flush().await?;
+        *unflushed = 0;
+    } else {
+        // A split sink holds the send back until it is next polled; hand it
+        // on to the connection so that a `--flush-deadline-ms` can see it.
+        future::poll_fn(|ctx| sink.poll_ready_unpin(ctx)).await?;
+    }
+
+    Ok(())
+}
+
+/// A connection which records its high-water marks and transport state each
+/// time it is polled, so that they can be read once it is split into halves
+/// which hide it.
+struct Sampled<C> {
+    inner: C,
+    high_water_marks: Rc<Cell<HighWaterMarks>>,
+    transport_state: Rc<Cell<TransportState>>,
+}
+
+impl<C> Sampled<C>
+where
+    C: Connection + Unpin,
+{
+    fn sample<T>(&mut self, poll: Poll<T>) -> Poll<T> {
+        if poll.is_ready() {
+            self.high_water_marks.set(self.inner.high_water_marks());
+            self.transport_state.set(self.inner.transport_state());
+        }
+        poll
+    }
+}
+
+impl<C> Sink<SendCmd> for Sampled<C>
+where
+    C: Connection + Unpin,
+{
+    type Error = Box<dyn std::error::Error>;
+    fn poll_ready(
+        mut self: Pin<&mut Self>,
+        ctx: &mut Context,
+    ) -> Poll<Result<()>> {
+        Pin::new(&mut self.inner).poll_ready(ctx)
+    }
+    fn start_send(mut self: Pin<&mut Self>, item: SendCmd) -> Result<()> {
+        Pin::new(&mut self.inner).start_send(item)
+    }
+    fn poll_flush(
+        mut self: Pin<&mut Self>,
+        ctx: &mut Context,
+    ) -> Poll<Result<()>> {
+        let poll = Pin::new(&mut self.inner).poll_flush(ctx);
+        self.sample(poll)
+    }
+    fn poll_close(
+        mut self: Pin<&mut Self>,
+        ctx: &mut Context,
+    ) -> Poll<Result<()>> {
+        let poll = Pin::new(&mut self.inner).poll_close(ctx);
+        self.sample(poll)
+    }
+}
+
+impl<C> Stream for Sampled<C>
+where
+    C: Connection + Unpin,
+{
+    type Item = Result<Datagram>;
+    fn poll_next(
+        mut self: Pin<&mut Self>,
+        ctx: &mut Context,
+    ) -> Poll<Option<Self::Item>> {
+        let poll = Pin::new(&mut self.inner).poll_next(ctx);
+        self.sample(poll)
+    }
+}
+
+/// A connection to the server, split so that sends and receives interleave.
+struct Session<C> {
+    sink: SplitSink<Sampled<C>, SendCmd>,
+    wire: LocalBoxStream<'static, Input>,
+    wire_bytes_sent: Option<Arc<AtomicU64>>,
+    high_water_marks: Rc<Cell<HighWaterMarks>>,
+    /// The transport state as of the connection's last poll.
+    transport_state: Rc<Cell<TransportState>>,
+    pacing: Pacing,
+}
+
+impl<C> Session<C>
+where
+    C: Connection + Unpin + 'static,
+{
+    /// Connects and waits for the connection to be ready, then has the kernel
+    /// pace it at `max_pacing_rate` if one is given and the transport allows.
+    /// Returns the session with how long the handshake took and how many
+    /// bytes it sent.
+    async fn open<F>(
+        connect: &mut F,
+        consume_rate: Option<NonZeroU32>,
+        max_pacing_rate: Option<u64>,
+    ) -> Result<(Self, f64, u64)>
+    where
+        F: FnMut() -> LocalBoxFuture<'static, Result<(Instant, C)>>,
+    {
+        let (handshake_start, mut client) = connect().await?;
+        client.ready().await?;
+        let handshake_ms = clock::since(handshake_start).as_secs_f64() * 1e3;
+        let pacing = match max_pacing_rate {
+            Some(rate) if client.set_max_pacing_rate(rate) => {
+                Pacing::Kernel(rate)
+            }
+            _ => Pacing::Application,
+        };
+
+        let wire_bytes_sent = client.wire_bytes_sent();
+        let high_water_marks = Rc::new(Cell::new(client.high_water_marks()));
+        let transport_state = Rc::new(Cell::new(client.transport_state()));
+        let (sink, stream) = Sampled {
+            inner: client,
+            high_water_marks: high_water_marks.clone(),
+            transport_state: transport_state.clone(),
+        }
+        .split();
+        let session = Self {
+            sink,
+            wire: wire_inputs(stream, consume_rate),
+            wire_bytes_sent,
+            high_water_marks,
+            transport_state,
+            pacing,
+        };
+        let handshake_bytes = session.wire_bytes();
+        Ok((session, handshake_ms, handshake_bytes))
+    }
+
+    fn wire_bytes(&self) -> u64 {
+        self.wire_bytes_sent
+            .as_ref()
+            .map(|wire_bytes_sent| wire_bytes_sent.load(Ordering::Relaxed))
+            .unwrap_or(0)
+    }
+
+    /// The datagrams the connection has dropped rather than sent, as of its
+    /// last poll.
+    fn dropped_sends(&self) -> u64 {
+        self.transport_state.get().dropped_sends.unwrap_or(0)
+    }
+}
+
+/// Runs the benchmark over connections from `connect`, which resolves to a
+/// connection and the moment the attempt that established it began.
+async fn run<C, F>(options: Options, mut connect: F) -> Result<Summary>
+where
+    C: Connection + Unpin + 'static,
+    F: FnMut() -> LocalBoxFuture<'static, Result<(Instant, C)>>,
+{
+    if options.repeat == 0 {
+        return Err("cannot repeat a run 0 times".into());
+    }
+    if options.buffer_sample_interval_ms == Some(0) {
+        return Err("cannot sample buffers every 0ms".into());
+    }
+    for transfer in &options.transfers {
+        // Follow the chain of transfers this one waits on, which must end in
+        // a transfer that starts by itself.
+        let mut waiting = transfer;
+        let mut waits = 0;
+        while let Some(condition) = waiting.start_after {
+            let awaited = options
+                .transfers
+                .iter()
+                .find(|tx| tx.stream_id == condition.stream_id)
+                .filter(|tx| {
+                    matches!(
+                        tx.expected_returns(),
+                        Some(expected) if expected >= condition.returned
+                    )
+                });
+            waiting = match awaited {
+                Some(awaited) => awaited,
+                None => {
+                    return Err(format!(
+                        "{:?} waits for {} echoes on {:?}, which does not \
+                         return that many",
+                        waiting.stream_id,
+                        condition.returned,
+                        condition.stream_id
+                    )
+                    .into())
+                }
+            };
+            waits += 1;
+            if waits > options.transfers.len() {
+                return Err(format!(
+                    "{:?} waits on a transfer which waits on it",
+                    transfer.stream_id
+                )
+                .into());
+            }
+        }
+    }
+    let shared_stream = options.pushes.iter().find(|push| {
+        options
+            .transfers
+            .iter()
+            .any(|tx| tx.stream_id == push.stream_id)
+    });
+    if let Some(push) = shared_stream {
+        return Err(format!(
+            "{:?} carries both a transfer and a push",
+            push.stream_id
+        )
+        .into());
+    }
+    let recording = options
+        .replay
+        .as_ref()
+        .map(replay::Recording::load)
+        .transpose()?;
+    if let Some(recording) = &recording {
+        let shared_stream = recording.streams.keys().find(|stream_id| {
+            options
+                .transfers
+                .iter()
+                .any(|tx| tx.stream_id == **stream_id)
+                || options
+                    .pushes
+                    .iter()
+                    .any(|push| push.stream_id == **stream_id)
+        });
+        if let Some(stream_id) = shared_stream {
+            return Err(format!(
+                "{:?} carries both a replay and a transfer or push",
+                stream_id
+            )
+            .into());
+        }
+    }
+
+    // Ids carry on from one repetition to the next, so that the server sees
+    // one unbroken sequence and late echoes are never mistaken for new ones.
+    let mut next_ids = HashMap::new();
+    // Send times are measured from one epoch for every repetition, so their
+    // trips can be merged.
+    let epoch = clock::now();
+    let mut reused = None;
+    let mut summaries = vec![];
+    for repetition in 1..=options.repeat {
+        let (mut session, handshake_ms, handshake_bytes) = match reused.take() {
+            Some(session) => (session, 0.0, 0),
+            None => {
+                Session::open(
+                    &mut connect,
+                    options.consume_rate,
+                    options.max_pacing_rate,
+                )
+                .await?
+            }
+        };
+
+        let mut summary = measure(
+            &options,
+            recording.as_ref(),
+            &mut session,
+            &mut connect,
+            epoch,
+            &mut next_ids,
+        )
+        .await?;
+        summary.handshake_ms = handshake_ms;
+        summary.handshake_bytes = handshake_bytes as f64;
+
+        if options.reuse_connection && repetition < options.repeat {
+            reused = Some(session);
+        } else {
+            summary.teardown_ms =
+                teardown(&mut session.sink, &mut session.wire).await;
+            summary.high_water_marks =
+                summary.high_water_marks.max(session.high_water_marks.get());
+        }
+        summaries.push(summary);
+    }
+
+    let samples = |metric: fn(&Summary) -> f64| -> Vec<f64> {
+        summaries.iter().map(metric).collect()
+    };
+    let mean_ms_ci = ConfidenceInterval::of(&samples(|s| s.mean_ms));
+    let throughput_ci =
+        ConfidenceInterval::of(&samples(Summary::throughput_bytes_per_second));
+    let mut summary: Summary = summaries.into_iter().collect();
+    summary.mean_ms_ci = mean_ms_ci;
+    summary.throughput_ci = throughput_ci;
+    Ok(summary)
+}
+
+/// Runs the transfers and replays `recording`, if any, once over `session`,
+/// until every echo the transfers and replay wait for has returned.
+///
+/// Send times are measured from `epoch`. `next_ids` holds the first id each
+/// stream sends, and is advanced past the ids this run sent.
+async fn measure<C, F>(
+    options: &Options,
+    recording: Option<&replay::Recording>,
+    session: &mut Session<C>,
+    connect: &mut F,
+    epoch: Instant,
+    next_ids: &mut HashMap<StreamId, u64>,
+) -> Result<Summary>
+where
+    C: Connection + Unpin + 'static,
+    F: FnMut() -> LocalBoxFuture<'static, Result<(Instant, C)>>,
+{
+    let mut wire_bytes_at_start = session.wire_bytes();
+    let mut wire_bytes_before_reconnect = 0;
+    let mut dropped_at_start = session.dropped_sends();
+    let mut dropped_before_reconnect = 0;
+    let mut high_water_marks_before_reconnect = HighWaterMarks::default();
+    let mut goodput_bytes = 0;
+
+    let metrics = options.metrics.clone();
+    let protocol = options.protocol;
+    let reconnect = options.reconnect;
+    let adaptive_rate = options.adaptive_rate;
+    let trace_timings = options.trace_timings;
+    let (stream_stats, keep_trips) = (options.stream_stats, options.keep_trips);
+    let mut pace = Pace::new();
+    let mut sla = options
+        .rtt_budget_ms
+        .map(|budget_ms| SlaCheck::new(budget_ms, options.sla_cluster));
+
+    let mut tracking = options
+        .transfers
+        .iter()
+        .filter_map(|tx| {
+            tx.expected_returns().map(|total_expected| {
+                (
+                    tx.stream_id,
+                    TransferTracker {
+                        epoch,
+                        stream_id: tx.stream_id,
+                        total_expected,
+                        fixed_total: tx.fixed_total.is_some(),
+                        unreliable: tx.unreliable,
+                        check_sizes: false,
+                        live: HashMap::new(),
+                        returned: vec![],
+                        streamed: stream_stats.then(TripStats::default),
+                        keep_trips,
+                        first_trip: None,
+                        tracing: HashMap::new(),
+                        traced: vec![],
+                        lost: 0,
+                        corrupted: 0,
+                        next_id: next_ids
+                            .get(&tx.stream_id)
+                            .copied()
+                            .unwrap_or(1),
+                    },
+                )
+            })
+        })
+        .collect::<HashMap<StreamId, TransferTracker>>();
+    let replayed = recording.map(|recording| &recording.streams);
+    for (stream_id, sends) in replayed.into_iter().flatten() {
+        tracking.insert(
+            *stream_id,
+            TransferTracker {
+                epoch,
+                stream_id: *stream_id,
+                total_expected: sends.len(),
+                fixed_total: true,
+                unreliable: false,
+                check_sizes: true,
+                live: HashMap::new(),
+                returned: vec![],
+                streamed: stream_stats.then(TripStats::default),
+                keep_trips,
+                first_trip: None,
+                tracing: HashMap::new(),
+                traced: vec![],
+                lost: 0,
+                corrupted: 0,
+                next_id: next_ids.get(stream_id).copied().unwrap_or(1),
+            },
+        );
+    }
+    let mut push_tracking = HashMap::new();
+    for push in &options.pushes {
+        let delivery_mode = DeliveryMode::ReliableOrdered(push.stream_id);
+        let request = BenchmarkDatagram {
+            stream_id: push.stream_id,
+            delivery_mode,
+            id: ID_PUSH_REQUEST,
+            data: bincode::serialize(push)?,
+        };
+        session
+            .sink
+            .send(SendCmd {
+                delivery_mode,
+                data: bincode::serialize(&request)?,
+                ..SendCmd::default()
+            })
+            .await?;
+        push_tracking.insert(
+            push.stream_id,
+            PushTracker {
+                expected: push.count,
+                arrivals: vec![],
+                lost: 0,
+            },
+        );
+    }
+    let first_id = |stream_id| next_ids.get(&stream_id).copied().unwrap_or(1);
+    let mut gates = Gates::new();
+    let mut transfers: SelectAll<LocalBoxStream<TransferCmd>> = options
+        .transfers
+        .iter()
+        .map(|tx| {
+            let stream = tx.stream(first_id(tx.stream_id), trace_timings);
+            match tx.start_after {
+                Some(condition) => {
+                    let (open, gate) = oneshot::channel();
+                    gates.push((condition, open));
+                    gate.map(move |_| stream).flatten_stream().boxed_local()
+                }
+                None => stream.boxed_local(),
+            }
+        })
+        .collect();
+    open_gates(&mut gates, &tracking);
+    for (stream_id, sends) in replayed.into_iter().flatten() {
+        transfers.push(
+            replay_stream(
+                *stream_id,
+                sends.clone(),
+                first_id(*stream_id),
+                trace_timings,
+            )
+            .boxed_local(),
+        );
+    }
+
+    let flush_policy = options.flush_policy;
+    let flush_ticks = match flush_policy {
+        FlushPolicy::Tick(hertz) => {
+            ticker(hertz).map(|_| Input::FlushTick).boxed_local()
+        }
+        FlushPolicy::Window(period) => {
+            interval(period).map(|_| Input::FlushTick).boxed_local()
+        }
+        _ => stream::pending().boxed_local(),
+    };
+    let mut unflushed = 0;
+    // Samples repeat the last state the connection was polled in, so an
+    // interval shorter than the gaps between polls repeats values.
+    let buffer_ticks = match options.buffer_sample_interval_ms {
+        Some(ms) => interval(Duration::from_millis(ms))
+            .map(|_| Input::BufferTick)
+            .boxed_local(),
+        None => stream::pending().boxed_local(),
+    };
+    let mut buffer_samples = vec![];
+    let loss_ticks = match tracking.values().any(|tracker| tracker.unreliable) {
+        true => interval(LOSS_TIMEOUT / 4)
+            .map(|_| Input::LossTick)
+            .boxed_local(),
+        false => stream::pending().boxed_local(),
+    };
+
+    let mut local_inputs = select(
+        transfers
+            .map(Input::Transfer)
+            .chain(stream::once(future::ready(Input::TransfersDone))),
+        select(flush_ticks, select(buffer_ticks, loss_ticks)),
+    );
+
+    let mut reconnections = 0;
+    let mut lost_to_reconnect = 0;
+
+    loop {
+        if let Some(metrics) = &metrics {
+            metrics.publish(protocol, metrics_snapshot(&tracking, epoch));
+        }
+
+        let input = match future::select(
+            local_inputs.next(),
+            session.wire.next(),
+        )
+        .await
+        {
+            Either::Left((input, _)) | Either::Right((input, _)) => input,
+        };
+
+        let input = input.unwrap();
+        // Only an echo or a loss can finish the run.
+        let settles = matches!(input, Input::Wire(Ok(_)) | Input::LossTick);
+        let failure: Option<Box<dyn std::error::Error>> = match input {
+            Input::Wire(Ok(returned_datagram)) => {
+                let received = trace_timings.then(clock::now);
+                let benchmark_datagram =
+                    bincode::deserialize::<BenchmarkDatagram>(
+                        returned_datagram.data.as_slice(),
+                    )?;
+                let stream = match returned_datagram.stream_position {
+                    Some(position) => position.stream_id,
+                    None => benchmark_datagram.stream_id,
+                };
+                let arrival = received.map(|received| (received, clock::now()));
+
+                if benchmark_datagram.id == ID_SOURCE_QUENCH {
+                    if adaptive_rate {
+                        pace.quench();
+                    }
+                    continue;
+                }
+
+                let mut ack_failure = None;
+                if let Some(pushes) = push_tracking.get_mut(&stream) {
+                    pushes.track(&benchmark_datagram.data, epoch);
+                    let delivery_mode = DeliveryMode::ReliableOrdered(stream);
+                    let ack = BenchmarkDatagram {
+                        stream_id: stream,
+                        delivery_mode,
+                        id: benchmark_datagram.id,
+                        data: vec![],
+                    };
+                    ack_failure = session
+                        .sink
+                        .send(SendCmd {
+                            delivery_mode,
+                            data: bincode::serialize(&ack)?,
+                            ..SendCmd::default()
+                        })
+                        .await
+                        .err();
+                }
+
+                if let Some(tracker) = tracking.get_mut(&stream) {
+                    if benchmark_datagram.id == ID_ECHO_BATCH {
+                        let echoes: Vec<BenchmarkDatagram> =
+                            bincode::deserialize(&benchmark_datagram.data)?;
+                        for echo in echoes {
+                            tracker.check_size(echo.id, echo.data.len())?;
+                            if !tracker.check_payload(echo.id, &echo.data) {
+                                continue;
+                            }
+                            let round_trip =
+                                tracker.track_return(echo.id, arrival);
+                            if let (Some(sla), Some(round_trip)) =
+                                (&mut sla, round_trip)
+                            {
+                                sla.check(round_trip);
+                            }
+                        }
+                    } else {
+                        tracker.check_size(
+                            benchmark_datagram.id,
+                            benchmark_datagram.data.len(),
+                        )?;
+                        if tracker.check_payload(
+                            benchmark_datagram.id,
+                            &benchmark_datagram.data,
+                        ) {
+                            let round_trip = tracker
+                                .track_return(benchmark_datagram.id, arrival);
+                            if let (Some(sla), Some(round_trip)) =
+                                (&mut sla, round_trip)
+                            {
+                                sla.check(round_trip);
+                            }
+                        }
+                    }
+                }
+                open_gates(&mut gates, &tracking);
+
+                ack_failure
+            }
+            Input::Wire(Err(e)) => Some(e),
+            Input::Disconnected => {
+                Some("connection closed before all echoes returned".into())
+            }
+            Input::FlushTick | Input::TransfersDone if unflushed > 0 => {
+                unflushed = 0;
+                session.sink.flush().await.err()
+            }
+            Input::FlushTick | Input::TransfersDone => None,
+            Input::LossTick => {
+                tracking.values_mut().for_each(TransferTracker::expire);
+                None
+            }
+            Input::BufferTick => {
+                let state = session.transport_state.get();
+                buffer_samples.push(BufferSample {
+                    time: clock::since(epoch).as_secs_f64() * 1e3,
+                    send_queue_depth: state.send_queue_depth,
+                    bytes_in_flight: state.bytes_in_flight,
+                });
+                None
+            }
+            Input::Transfer(_) if adaptive_rate && !pace.admit() => None,
+            Input::Transfer(transfer_cmd) => {
+                let len = transfer_cmd.send_cmd.data.len() as u64;
+                match send_with_policy(
+                    &mut session.sink,
+                    transfer_cmd.send_cmd,
+                    flush_policy,
+                    &mut unflushed,
+                )
+                .await
+                {
+                    Ok(()) => {
+                        goodput_bytes += len;
+                        if let Some((cumulative_tracking, cmd_tracking)) =
+                            transfer_cmd.tracking.and_then(|cmd_tracking| {
+                                let cumulative_tracking = tracking
+                                    .get_mut(&cmd_tracking.stream_id)?;
+                                Some((cumulative_tracking, cmd_tracking))
+                            })
+                        {
+                            cumulative_tracking.track_send(
+                                cmd_tracking.id,
+                                cmd_tracking.size,
+                                cmd_tracking.burst,
+                            );
+                            if let Some((enqueued, serialized)) =
+                                cmd_tracking.timings
+                            {
+                                cumulative_tracking.tracing.insert(
+                                    cmd_tracking.id,
+                                    vec![
+                                        (timings::Stage::Enqueued, enqueued),
+                                        (
+                                            timings::Stage::Serialized,
+                                            serialized,
+                                        ),
+                                        (timings::Stage::Sent, clock::now()),
+                                    ],
+                                );
+                            }
+                        }
+                        None
+                    }
+                    Err(e) => Some(e),
+                }
+            }
+        };
+
+        if settles
+            && tracking.values().all(TransferTracker::done)
+            && push_tracking.values().all(PushTracker::done)
+        {
+            next_ids.extend(
+                tracking
+                    .iter()
+                    .map(|(stream, tracker)| (*stream, tracker.next_id)),
+            );
+            let corrupted_datagrams =
+                tracking.values().map(|tracker| tracker.corrupted).sum();
+            let timing_trails = tracking
+                .values_mut()
+                .flat_map(|tracker| tracker.traced.drain(..))
+                .collect();
+            let (summaries, first_rtt_ms) = match stream_stats {
+                true => summarize_streamed(tracking),
+                false => summarize_returned(tracking),
+            };
+
+            let mut summary: Summary = summaries.into_iter().collect();
+            summary.first_rtt_ms = first_rtt_ms.unwrap_or(0.0);
+            summary.reconnections = reconnections;
+            summary.lost_to_reconnect = lost_to_reconnect;
+            summary.corrupted_datagrams = corrupted_datagrams;
+            summary.pacing = session.pacing;
+            summary.timing_trails = timing_trails;
+            summary.buffer_samples = std::mem::take(&mut buffer_samples);
+            let arrivals: Vec<f64> = push_tracking
+                .values()
+                .flat_map(|pushes| pushes.arrivals.iter())
+                .map(|(_, latency)| *latency)
+                .collect();
+            summary.pushes_received = arrivals.len();
+            if !arrivals.is_empty() {
+                summary.push_latency_ms =
+                    arrivals.iter().sum::<f64>() / arrivals.len() as f64;
+                summary.push_rate_hz = push_tracking
+                    .values()
+                    .map(PushTracker::rate_hz)
+                    .sum::<f64>()
+                    / push_tracking.len() as f64;
+            }
+            if let Some(sla) = sla {
+                summary.sla_violations = sla.violations;
+                summary.worst_violation_streak = sla.worst_streak;
+                summary.violation_clusters = sla.clusters;
+            }
+            summary.high_water_marks = high_water_marks_before_reconnect
+                .max(session.high_water_marks.get());
+            summary.dropped_sends =
+                (dropped_before_reconnect + session.dropped_sends()
+                    - dropped_at_start) as usize;
+            if session.wire_bytes_sent.is_some() {
+                let wire_bytes = wire_bytes_before_reconnect
+                    + session.wire_bytes()
+                    - wire_bytes_at_start;
+                if goodput_bytes > 0 {
+                    summary.amplification =
+                        wire_bytes as f64 / goodput_bytes as f64;
+                }
+            }
+            return Ok(summary);
+        }
+
+        if let Some(error) = failure {
+            if !reconnect {
+                return Err(error);
+            }
+
+            // Whatever was in flight on the old connection will never return.
+            reconnections += 1;
+            for tracker in tracking.values_mut() {
+                lost_to_reconnect += tracker.live.len();
+                tracker.lost += tracker.live.len();
+                tracker.live.clear();
+                tracker.tracing.clear();
+            }
+            // The new connection's server was never asked to push, so every
+            // push still to come is lost.
+            for pushes in push_tracking.values_mut() {
+                pushes.lost = pushes.expected - pushes.arrivals.len();
+            }
+            unflushed = 0;
+
+            wire_bytes_before_reconnect +=
+                session.wire_bytes() - wire_bytes_at_start;
+            high_water_marks_before_reconnect =
+                high_water_marks_before_reconnect
+                    .max(session.high_water_marks.get());
+            wire_bytes_at_start = 0;
+            dropped_before_reconnect +=
+                session.dropped_sends() - dropped_at_start;
+            dropped_at_start = 0;
+            let (new_session, _, _) = Session::open(
+                connect,
+                options.consume_rate,
+                options.max_pacing_rate,
+            )
+            .await?;
+            *session = new_session;
+        }
+    }
+}
+
+#[derive(Clone, Debug, StructOpt)]
+pub struct Options {
+    /// Address of the server to run the benchmark against;
+    #[structopt(short = "a", default_value = "127.0.0.1:33333")]
+    pub address: SocketAddr,
+    /// Periodic transfers, specified in terms of
+    /// `stream_id:payload:hertz:[return_count]:[fixed_total]:[phase_offset_ms]:[bursts]:[start_after]`,
+    /// where `payload` is a size in bytes, `uniform/LOW/HIGH`, or
+    /// `keyframe/KEYFRAME_SIZE/EVERY/DELTA_SIZE`, `bursts` is
+    /// `LENGTH/IDLE_MS` to send `LENGTH` datagrams then fall idle, and
+    /// `start_after` is `STREAM_ID/RETURNED` to start once that many echoes
+    /// have returned on another transfer's stream. A `u` after the stream id
+    /// sends the transfer unordered and unreliably.
+    #[structopt(short = "b", long)]
+    pub transfers: Vec<Transfer>,
+    /// Traffic for the server to push, acked by the client, specified as
+    /// `stream_id:payload:hertz:count`. May be given more than once. Push
+    /// streams may not carry transfers.
+    #[structopt(long = "push", number_of_values = 1)]
+    pub pushes: Vec<ServerPush>,
+    /// Replay the sends recorded in this trip report CSV, as written with
+    /// the runner's `--output`, beside any transfers. See `replay`.
+    #[structopt(long)]
+    pub replay: Option<PathBuf>,
+    /// When to flush sends to the connection: `send` flushes every send,
+    /// `every:N` flushes every `N` sends, `tick:HERTZ` flushes whatever has
+    /// been sent `HERTZ` times per second, and `window:MS` flushes it every
+    /// `MS` milliseconds.
+    #[structopt(long, default_value = "send")]
+    pub flush_policy: FlushPolicy,
+    /// Flush any send which has waited this many milliseconds without being
+    /// flushed, bounding the latency a `--flush-policy` other than `send`
+    /// adds. See `flush_deadline`.
+    #[structopt(long)]
+    pub flush_deadline_ms: Option<NonZeroU64>,
+    /// Reconnect and carry on if the connection is lost mid-run, instead of
+    /// failing the run.
+    #[structopt(long)]
+    pub reconnect: bool,
+    /// Slow down when the server signals it is overloaded.
+    #[structopt(long)]
+    pub adaptive_rate: bool,
+    /// Run the transfers this many times and report the mean of the runs.
+    #[structopt(long, default_value = "1")]
+    pub repeat: usize,
+    /// Keep one connection across repetitions instead of connecting for
+    /// each, so the handshake and teardown are paid once and their means are
+    /// spread across the repetitions.
+    #[structopt(long)]
+    pub reuse_connection: bool,
+    /// Read at most this many datagrams per second from the connection, to
+    /// act as a slow consumer.
+    #[structopt(long)]
+    pub consume_rate: Option<NonZeroU32>,
+    /// Have the kernel pace the connection's egress at no more than this
+    /// many bytes per second, by `SO_MAX_PACING_RATE`. Only TCP on Linux can
+    /// be paced this way; elsewhere the option does nothing. The summary
+    /// records which pacing was used.
+    #[structopt(long)]
+    pub max_pacing_rate: Option<u64>,
+    /// Code unreliable transfers with forward error correction, given as a
+    /// block size such as `4` or a code rate such as `4/5`. The server must
+    /// code them the same way.
+    #[structopt(long)]
+    pub fec: Option<fec::Fec>,
+    /// Buffer up to this many datagrams in front of the connection, and
+    /// once it is full apply `--drop-policy` to unreliable transfers.
+    /// Reliable transfers wait for room. The summary counts the drops.
+    #[structopt(long)]
+    pub send_buffer: Option<NonZeroUsize>,
+    /// What unreliable transfers do when the `--send-buffer` is full:
+    /// `drop-oldest` drops the oldest buffered datagram, `drop-newest` the
+    /// one being sent, and `backpressure` waits for room.
+    #[structopt(long, default_value = "backpressure")]
+    pub drop_policy: send_buffer::DropPolicy,
+    /// Count every round trip over this many milliseconds as a violation of
+    /// the latency SLA, and exit with a failure if there were any.
+    #[structopt(long)]
+    pub rtt_budget_ms: Option<f64>,
+    /// Record the length of each run of at least this many round trips in a
+    /// row over the budget in the summary's `violation_clusters`.
+    #[structopt(long, requires = "rtt-budget-ms")]
+    pub sla_cluster: Option<usize>,
+    /// Stamp each datagram as it reaches each stage of its round trip. See
+    /// `timings`.
+    #[structopt(long)]
+    pub trace_timings: bool,
+    /// Estimate statistics as echoes return instead of from every trip at
+    /// the end of the run, so that memory stays bounded on long runs.
+    /// Percentiles are then estimated to within 1%, and head-of-line
+    /// blocking is not estimated. Trips are not kept for `--output` unless
+    /// `--keep-trips` is also given.
+    #[structopt(long)]
+    pub stream_stats: bool,
+    /// Keep every trip while streaming statistics.
+    #[structopt(long, requires = "stream-stats")]
+    pub keep_trips: bool,
+    /// Sample the connection's send queue and bytes in flight every this
+    /// many milliseconds, for a time series of its buffers.
+    #[structopt(long)]
+    pub buffer_sample_interval_ms: Option<u64>,
+    /// The protocol to benchmark, by its registered name: `tcp`, `tcp-tls`,
+    /// `enet`, `kcp`, `kcp-turbo`, `memory`, or any other registered
+    /// transport.
+    pub protocol: Protocol,
+    /// Registry to publish live metrics to while the benchmark runs.
+    #[structopt(skip)]
+    pub metrics: Option<metrics::MetricsRegistry>,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum FlushPolicy {
+    PerSend,
+    EveryN(usize),
+    Tick(u32),
+    Window(Duration),
+}
+
+impl FromStr for FlushPolicy {
+    type Err = anyhow::Error;
+    fn from_str(src: &str) -> std::result::Result<Self, Self::Err> {
+        let args: Vec<&str> = src.split(':').collect();
+        match args.as_slice() {
+            ["send"] => Ok(FlushPolicy::PerSend),
+            ["every", n] => match n.parse::<usize>()? {
+                0 => anyhow::bail!("cannot flush every 0 sends"),
+                n => Ok(FlushPolicy::EveryN(n)),
+            },
+            ["tick", hertz] => match hertz.parse::<u32>()? {
+                0 => anyhow::bail!("cannot flush at 0 hertz"),
+                hertz => Ok(FlushPolicy::Tick(hertz)),
+            },
+            ["window", ms] => match ms.parse::<u64>()? {
+                0 => anyhow::bail!("cannot flush every 0ms, use `send`"),
+                ms => Ok(FlushPolicy::Window(Duration::from_millis(ms))),
+            },
+            _ => anyhow::bail!(
+                "expected `send`, `every:N`, `tick:HERTZ`, or `window:MS`, \
+                 got {:?}",
+                src
+            ),
+        }
+    }
+}
+
+struct TransferCmd {
+    send_cmd: SendCmd,
+    tracking: Option<TransferMessageTracking>,
+}
+
+/// Tracking information for a transfer message on the wire.
+struct TransferMessageTracking {
+    stream_id: StreamId,
+    id: u64,
+    size: usize,
+    burst: Option<usize>,
+    /// When the datagram was enqueued and serialized, if timings are traced.
+    timings: Option<(Instant, Instant)>,
+}
+
+/// How large each datagram of a transfer is.
+#[derive(Clone, Copy, Debug, PartialEq, Serialize, Deserialize)]
+pub enum PayloadModel {
+    /// Every datagram is the same size.
+    Constant(usize),
+    /// Sizes are drawn uniformly from `low..=high`.
+    Uniform { low: usize, high: usize },
+    /// Like game state replication: a full `keyframe` sized snapshot every
+    /// `every` datagrams, starting with the first, and `delta` sized updates
+    /// in between.
+    Keyframe {
+        keyframe: usize,
+        every: usize,
+        delta: usize,
+    },
+}
+
+impl PayloadModel {
+    /// The size of every datagram, if they are all the same size.
+    pub fn constant_size(&self) -> Option<usize> {
+        match self {
+            PayloadModel::Constant(size) => Some(*size),
+            _ => None,
+        }
+    }
+
+    /// The size of the datagram at `index`, counting from zero.
+    fn size(&self, index: usize, rng: &mut StdRng) -> usize {
+        match *self {
+            PayloadModel::Constant(size) => size,
+            PayloadModel::Uniform { low, high } => rng.gen_range(low, high + 1),
+            PayloadModel::Keyframe {
+                keyframe,
+                every,
+                delta,
+            } => match index % every {
+                0 => keyframe,
+                _ => delta,
+            },
+        }
+    }
+}
+
+impl FromStr for PayloadModel {
+    type Err = anyhow::Error;
+    fn from_str(src: &str) -> std::result::Result<Self, Self::Err> {
+        let args: Vec<&str> = src.split('/').collect();
+        match args.as_slice() {
+            [size] => Ok(PayloadModel::Constant(size.parse()?)),
+            ["uniform", low, high] => {
+                let (low, high) = (low.parse()?, high.parse()?);
+                if low > high {
+                    anyhow::bail!("payload range {}..={} is empty", low, high);
+                }
+                Ok(PayloadModel::Uniform { low, high })
+            }
+            ["keyframe", keyframe, every, delta] => match every.parse()? {
+                0 => anyhow::bail!("cannot send a keyframe every 0 datagrams"),
+                every => Ok(PayloadModel::Keyframe {
+                    keyframe: keyframe.parse()?,
+                    every,
+                    delta: delta.parse()?,
+                }),
+            },
+            _ => anyhow::bail!(
+                "expected `SIZE`, `uniform/LOW/HIGH`, or \
+                 `keyframe/KEYFRAME_SIZE/EVERY/DELTA_SIZE`, got {:?}",
+                src
+            ),
+        }
+    }
+}
+
+#[derive(Clone, Debug, Copy, PartialEq, Serialize, Deserialize)]
+pub struct Transfer {
+    pub stream_id: StreamId,
+    pub payload: PayloadModel,
+    pub hertz: u32,
+    pub return_count: Option<usize>,
+    /// Send exactly this many datagrams and wait for every echo, so every
+    /// protocol runs the same workload however long it takes.
+    pub fixed_total: Option<usize>,
+    /// How long to wait before the transfer starts ticking, to offset its
+    /// sends from the other transfers'.
+    pub phase_offset: Duration,
+    /// Whether to alternate bursts of sends with idle gaps.
+    pub bursts: Option<Bursts>,
+    /// Hold the transfer back until another has had some of its echoes
+    /// return.
+    pub start_after: Option<StartAfter>,
+    /// Send unordered and unreliably, so that lost datagrams and echoes are
+    /// never retransmitted. Written as a `u` after the stream id.
+    pub unreliable: bool,
+}
+
+/// A transfer's start, held back until the transfer on `stream_id` has had
+/// `returned` echoes return, as streaming assets waits for a handshake's data
+/// to arrive. Written `STREAM_ID/RETURNED`.
+#[derive(Clone, Copy, Debug, PartialEq, Serialize, Deserialize)]
+pub struct StartAfter {
+    pub stream_id: StreamId,
+    pub returned: usize,
+}
+
+impl FromStr for StartAfter {
+    type Err = anyhow::Error;
+    fn from_str(src: &str) -> std::result::Result<Self, Self::Err> {
+        let args: Vec<&str> = src.split('/').collect();
+        match args.as_slice() {
+            [stream_id, returned] => Ok(Self {
+                stream_id: StreamId(stream_id.parse()?),
+                returned: returned.parse()?,
+            }),
+            _ => anyhow::bail!("expected `STREAM_ID/RETURNED`, got {:?}", src),
+        }
+    }
+}
+
+/// Transfers held back by their `StartAfter`, each with the sender which
+/// starts it.
+type Gates = Vec<(StartAfter, oneshot::Sender<()>)>;
+
+/// Starts each transfer in `gates` whose condition `tracking` meets.
+fn open_gates(
+    gates: &mut Gates,
+    tracking: &HashMap<StreamId, TransferTracker>,
+) {
+    let (open, held): (Gates, Gates) = std::mem::take(gates)
+        .into_iter()
+        .partition(|(condition, _)| {
+            tracking
+                .get(&condition.stream_id)
+                .map(|tracker| tracker.returned_count() >= condition.returned)
+                .unwrap_or(false)
+        });
+    *gates = held;
+    for (_, gate) in open {
+        let _ = gate.send(());
+    }
+}
+
+/// Bursts of `length` datagrams sent at a transfer's rate, each followed by
+/// `idle` without sending, like a level load followed by a menu.
+#[derive(Clone, Copy, Debug, PartialEq, Serialize, Deserialize)]
+pub struct Bursts {
+    pub length: usize,
+    pub idle: Duration,
+}
+
+impl Bursts {
+    /// The shortest idle gap accepted. Transports restart from a small
+    /// window after being idle for a retransmission timeout, and RFC 6298
+    /// puts the least of those at a second, so shorter gaps could leave the
+    /// restart untested.
+    pub const MIN_IDLE: Duration = Duration::from_secs(1);
+
+    /// Which burst the datagram at `index` is sent in.
+    fn burst(&self, index: usize) -> usize {
+        index / self.length
+    }
+
+    /// How long to stay idle before sending the datagram at `index`.
+    fn idle_before(&self, index: usize) -> Duration {
+        match index {
+            0 => Duration::from_secs(0),
+            index if index % self.length == 0 => self.idle,
+            _ => Duration::from_secs(0),
+        }
+    }
+}
+
+impl FromStr for Bursts {
+    type Err = anyhow::Error;
+    fn from_str(src: &str) -> std::result::Result<Self, Self::Err> {
+        let args: Vec<&str> = src.split('/').collect();
+        let (length, idle) = match args.as_slice() {
+            [length, idle_ms] => {
+                (length.parse()?, Duration::from_millis(idle_ms.parse()?))
+            }
+            _ => anyhow::bail!("expected `LENGTH/IDLE_MS`, got {:?}", src),
+        };
+        if length == 0 {
+            anyhow::bail!("bursts must send at least one datagram");
+        }
+        if idle < Self::MIN_IDLE {
+            anyhow::bail!(
+                "idle gaps under {:?} may not outlast a retransmission \
+                 timeout, got {:?}",
+                Self::MIN_IDLE,
+                idle
+            );
+        }
+
+        Ok(Self { length, idle })
+    }
+}
+
+impl Transfer {
+    /// How many echoes the run waits for on this transfer, if any.
+    fn expected_returns(&self) -> Option<usize> {
+        self.fixed_total.or(self.return_count)
+    }
+
+    fn delivery_mode(&self) -> DeliveryMode {
+        match self.unreliable {
+            true => DeliveryMode::UnreliableUnordered,
+            false => DeliveryMode::ReliableOrdered(self.stream_id),
+        }
+    }
+
+    /// Returns the transfer's commands, numbering them from `first_id` and
+    /// stamping their timings if `trace_timings` is set.
+    fn stream(
+        self,
+        first_id: u64,
+        trace_timings: bool,
+    ) -> impl Stream<Item = TransferCmd> {
+        let period = Duration::from_secs(1) / self.hertz;
+        let (phase_offset, bursts) = (self.phase_offset, self.bursts);
+        let ticker = stream::iter(0..).then(move |index| {
+            let mut wait = period;
+            if index == 0 {
+                wait += phase_offset;
+            }
+            if let Some(bursts) = bursts {
+                wait += bursts.idle_before(index);
+            }
+            clock::sleep(wait)
+        });
+        let mut rng = StdRng::seed_from_u64(u64::from(self.stream_id.0));
+        ticker
+            .take(self.fixed_total.unwrap_or(usize::MAX))
+            .enumerate()
+            .map(move |(index, _)| {
+                let size = self.payload.size(index, &mut rng);
+                let id = first_id + index as u64;
+                let burst = self.bursts.map(|bursts| bursts.burst(index));
+                match self.expected_returns() {
+                    Some(_) => tracked_cmd(
+                        self.stream_id,
+                        self.delivery_mode(),
+                        id,
+                        size,
+                        burst,
+                        trace_timings,
+                    ),
+                    None => TransferCmd {
+                        send_cmd: send_cmd(
+                            self.stream_id,
+                            self.delivery_mode(),
+                            ID_DO_NOT_RETURN,
+                            size,
+                        ),
+                        tracking: None,
+                    },
+                }
+            })
+    }
+}
+
+/// Returns the commands replaying `sends` on `stream_id`, each sent as long
+/// after the stream starts as it was after the recording's first send.
+fn replay_stream(
+    stream_id: StreamId,
+    sends: Vec<replay::RecordedSend>,
+    first_id: u64,
+    trace_timings: bool,
+) -> impl Stream<Item = TransferCmd> {
+    let start = clock::now();
+    stream::iter(sends.into_iter().enumerate()).then(
+        move |(index, send)| async move {
+            let due = start + send.offset;
+            clock::sleep(due.saturating_duration_since(clock::now())).await;
+            tracked_cmd(
+                stream_id,
+                DeliveryMode::ReliableOrdered(stream_id),
+                first_id + index as u64,
+                send.payload_size,
+                None,
+                trace_timings,
+            )
+        },
+    )
+}
+
+/// A command sending datagram `id` and tracking its echo, stamping its
+/// timings if `trace_timings` is set.
+fn tracked_cmd(
+    stream_id: StreamId,
+    delivery_mode: DeliveryMode,
+    id: u64,
+    size: usize,
+    burst: Option<usize>,
+    trace_timings: bool,
+) -> TransferCmd {
+    let enqueued = trace_timings.then(clock::now);
+    let send_cmd = send_cmd(stream_id, delivery_mode, id, size);
+    let timings = enqueued.map(|enqueued| (enqueued, clock::now()));
+    TransferCmd {
+        send_cmd,
+        tracking: Some(TransferMessageTracking {
+            stream_id,
+            id,
+            size,
+            burst,
+            timings,
+        }),
+    }
+}
+
+/// The byte at `index` of the payload of datagram `id` on `stream_id`.
+///
+/// Payloads follow a pattern unique to their datagram rather than being
+/// zeroed, so that an echo which comes back with bytes flipped, shifted, or
+/// copied from another datagram can be told apart from the one sent.
+fn payload_byte(stream_id: StreamId, id: u64, index: usize) -> u8 {
+    let seed = (id ^ (u64::from(stream_id.0) << 56))
+        .wrapping_mul(0x9e37_79b9_7f4a_7c15);
+    (seed >> (index % 8 * 8)) as u8 ^ (index / 8) as u8
+}
+
+fn payload(stream_id: StreamId, id: u64, size: usize) -> Vec<u8> {
+    (0..size)
+        .map(|index| payload_byte(stream_id, id, index))
+        .collect()
+}
+
+/// Whether `data` is the payload datagram `id` on `stream_id` was sent with,
+/// whatever its size.
+fn payload_matches(stream_id: StreamId, id: u64, data: &[u8]) -> bool {
+    data.iter()
+        .enumerate()
+        .all(|(index, byte)| *byte == payload_byte(stream_id, id, index))
+}
+
+fn send_cmd(
+    stream_id: StreamId,
+    delivery_mode: DeliveryMode,
+    id: u64,
+    size: usize,
+) -> SendCmd {
+    SendCmd {
+        delivery_mode,
+        data: bincode::serialize(&BenchmarkDatagram {
+            stream_id,
+            id,
+            delivery_mode,
+            data: payload(stream_id, id, size),
+        })
+        .expect("to serialize bulk transfer"),
+        ..SendCmd::default()
+    }
+}
+
+impl FromStr for Transfer {
+    type Err = anyhow::Error;
+    fn from_str(src: &str) -> std::result::Result<Self, Self::Err> {
+        let args: Vec<&str> = src.split(":").collect();
+
+        let (stream_id, unreliable) = match args[0].strip_suffix('u') {
+            Some(stream_id) => (stream_id, true),
+            None => (args[0], false),
+        };
+        let stream_id = stream_id.parse::<u8>()?;
+        let payload = args[1].parse::<PayloadModel>()?;
+        let hertz = args[2].parse::<u32>()?;
+        let optional_count = |i: usize| {
+            args.get(i)
+                .filter(|a| !a.is_empty())
+                .map(|a| a.parse::<usize>())
+                .transpose()
+        };
+        let return_count = optional_count(3)?;
+        let fixed_total = optional_count(4)?;
+        let phase_offset = match args.get(5).filter(|a| !a.is_empty()) {
+            Some(ms) => Duration::from_millis(ms.parse()?),
+            None => Duration::from_secs(0),
+        };
+        let bursts = args
+            .get(6)
+            .filter(|a| !a.is_empty())
+            .map(|a| a.parse::<Bursts>())
+            .transpose()?;
+        let start_after = args
+            .get(7)
+            .filter(|a| !a.is_empty())
+            .map(|a| a.parse::<StartAfter>())
+            .transpose()?;
 
         Ok(Self {
             stream_id: StreamId(stream_id),
-            size,
+            payload,
             hertz,
             return_count,
+            fixed_total,
+            phase_offset,
+            bursts,
+            start_after,
+            unreliable,
         })
     }
 }
 
+/// Connects over TCP, retrying until the server's port is open. With `tls`,
+/// the TLS handshake is part of connecting.
+pub(crate) async fn connect_tcp(
+    address: SocketAddr,
+    tls: bool,
+) -> Result<(Instant, tcp::TcpConnection)> {
+    loop {
+        let handshake_start = clock::now();
+        let result = match tls {
+            true => tls::connect(address).await,
+            false => tcp::TcpConnection::connect(address).await,
+        };
+        let error = match result {
+            Ok(connection) => return Ok((handshake_start, connection)),
+            Err(e) => e,
+        };
+
+        // The server port is not yet open; give it time.
+        if is_connection_refused(&*error) {
+            continue;
+        }
+
+        return Err(error);
+    }
+}
+
+/// Connects over `connections` TCP connections, carrying the streams between
+/// them. Connecting lasts until every connection is open.
+pub(crate) async fn connect_tcp_multiplexed(
+    address: SocketAddr,
+    connections: usize,
+) -> Result<(Instant, tcp_multiplexed::TcpMultiplexedConnection)> {
+    let mut handshake_start = None;
+    let mut tcp_connections = vec![];
+    for _ in 0..connections {
+        let (started, connection) = connect_tcp(address, false).await?;
+        handshake_start.get_or_insert(started);
+        tcp_connections.push(connection);
+    }
+    let connection =
+        tcp_multiplexed::TcpMultiplexedConnection::introduce(tcp_connections)
+            .await?;
+    Ok((handshake_start.unwrap_or_else(clock::now), connection))
+}
+
+/// Connects in memory, retrying until the server has bound.
+pub(crate) async fn connect_memory(
+    address: SocketAddr,
+) -> Result<(Instant, memory::MemoryConnection)> {
+    loop {
+        let handshake_start = clock::now();
+        match memory::MemoryConnection::connect(address).await {
+            Ok(connection) => return Ok((handshake_start, connection)),
+            Err(e) if is_connection_refused(&*e) => {
+                async_std::task::yield_now().await
+            }
+            Err(e) => return Err(e),
+        }
+    }
+}
+
+fn is_connection_refused(error: &(dyn std::error::Error + 'static)) -> bool {
+    error
+        .downcast_ref::<std::io::Error>()
+        .map(std::io::Error::kind)
+        == Some(std::io::ErrorKind::ConnectionRefused)
+}
+
+pub(crate) async fn connect_enet(
+    address: SocketAddr,
+) -> Result<(Instant, enet::EnetConnection)> {
+    let handshake_start = clock::now();
+    let connection = enet::EnetConnection::connect(address).await;
+    Ok((handshake_start, connection))
+}
+
+pub(crate) async fn connect_kcp(
+    mode: kcp::KcpMode,
+    address: SocketAddr,
+) -> Result<(Instant, kcp::KcpConnection)> {
+    let handshake_start = clock::now();
+    let connection = kcp::KcpConnection::connect(mode, address).await?;
+    Ok((handshake_start, connection))
+}
+
 pub async fn client_main(options: Options) -> Result<Summary> {
     let address = options.address;
-    match options.protocol {
-        Protocol::Tcp => {
-            run(
-                options,
-                loop {
-                    let result = tcp::TcpConnection::connect(address).await;
+    let transport = options.protocol.transport();
+    if !transport.unreliable && options.transfers.iter().any(|tx| tx.unreliable)
+    {
+        return Err(format!(
+            "{} cannot send unreliable transfers",
+            options.protocol
+        )
+        .into());
+    }
 
-                    let error = match result {
-                        Ok(results) => break results,
-                        Err(e) => e,
-                    };
+    let connect = transport.connect;
+    let fec = options.fec;
+    let send_buffer = options.send_buffer;
+    let drop_policy = options.drop_policy;
+    let flush_deadline = options
+        .flush_deadline_ms
+        .map(|ms| Duration::from_millis(ms.get()));
+    run(options, move || {
+        connect(address)
+            .map_ok(move |(started, connection)| {
+                // Parity is coded before buffering, so that a full buffer
+                // drops coded datagrams as the link would.
+                let connection = match send_buffer {
+                    Some(capacity) => Box::new(
+                        send_buffer::SendBufferConnection::new(
+                            connection,
+                            capacity.get(),
+                        )
+                        .with_policy(
+                            DeliveryMode::UnreliableUnordered,
+                            drop_policy,
+                        ),
+                    )
+                        as registry::BoxConnection,
+                    None => connection,
+                };
+                let connection = match fec {
+                    Some(fec) => Box::new(fec.wrap(connection))
+                        as registry::BoxConnection,
+                    None => connection,
+                };
+                match flush_deadline {
+                    Some(deadline) => (
+                        started,
+                        Box::new(flush_deadline::FlushDeadline::new(
+                            connection, deadline,
+                        )) as registry::BoxConnection,
+                    ),
+                    None => (started, connection),
+                }
+            })
+            .boxed_local()
+    })
+    .await
+}
 
-                    // The server port is not yet open; give it time.
-                    if error.is::<std::io::Error>()
-                        && error
-                            .downcast_ref::<std::io::Error>()
-                            .map(std::io::Error::kind)
-                            == Some(std::io::ErrorKind::ConnectionRefused)
-                    {
-                        continue;
-                    }
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::memory::MemoryConnection;
+    use std::sync::Mutex;
 
-                    panic!(
-                        "Failed to connect to benchmark server: {:?}",
-                        error
-                    );
-                },
-            )
-            .await
+    #[test]
+    fn flush_policy_rejects_zero_rates() {
+        assert_eq!(
+            "tick:30".parse::<FlushPolicy>().ok(),
+            Some(FlushPolicy::Tick(30))
+        );
+        for src in &["every:0", "tick:0", "window:0"] {
+            assert!(src.parse::<FlushPolicy>().is_err(), "{} parsed", src);
         }
-        Protocol::Enet => {
-            run(options, enet::EnetConnection::connect(address).await).await
+    }
+
+    #[test]
+    fn transfers_marked_u_are_unreliable() {
+        let ordered: Transfer = "3:100:60".parse().unwrap();
+        assert_eq!(
+            ordered.delivery_mode(),
+            DeliveryMode::ReliableOrdered(StreamId(3))
+        );
+        let unreliable: Transfer = "3u:100:60".parse().unwrap();
+        assert_eq!(unreliable.stream_id, StreamId(3));
+        assert_eq!(
+            unreliable.delivery_mode(),
+            DeliveryMode::UnreliableUnordered
+        );
+    }
+
+    fn tracker(stream_id: StreamId, streamed: bool) -> TransferTracker {
+        TransferTracker {
+            epoch: Instant::now(),
+            stream_id,
+            total_expected: 0,
+            fixed_total: false,
+            unreliable: false,
+            check_sizes: false,
+            live: HashMap::new(),
+            returned: vec![],
+            streamed: streamed.then(TripStats::default),
+            keep_trips: false,
+            first_trip: None,
+            tracing: HashMap::new(),
+            traced: vec![],
+            lost: 0,
+            corrupted: 0,
+            next_id: 1,
         }
-        Protocol::Kcp => {
-            run(
-                options,
-                kcp::KcpConnection::connect(kcp::KcpMode::Normal, address)
-                    .await
-                    .expect("Connecting to kcp server"),
-            )
-            .await
-        }
-        Protocol::KcpTurbo => {
-            run(
-                options,
-                kcp::KcpConnection::connect(kcp::KcpMode::Turbo, address)
-                    .await
-                    .expect("Connecting to kcp server"),
-            )
-            .await
+    }
+
+    fn trip(stream_id: u8, send_time: f64, round_trip: f64) -> TripReport {
+        TripReport {
+            stream_id: StreamId(stream_id),
+            index: 0,
+            payload_size: 100,
+            round_trip,
+            send_time,
+            burst: None,
+            hol_blocking: 0.0,
         }
     }
+
+    /// Summarizes `trips`, in the order their echoes arrived, as a run
+    /// would with and without streamed statistics.
+    fn summaries(trips: &[TripReport]) -> (Summary, Summary) {
+        let summarize = |streamed| {
+            let mut tracking = HashMap::new();
+            for trip in trips {
+                tracking
+                    .entry(trip.stream_id)
+                    .or_insert_with(|| tracker(trip.stream_id, streamed))
+                    .record(*trip);
+            }
+            let (summaries, first_rtt_ms) = match streamed {
+                true => summarize_streamed(tracking),
+                false => summarize_returned(tracking),
+            };
+            let mut summary: Summary = summaries.into_iter().collect();
+            summary.first_rtt_ms = first_rtt_ms.unwrap_or(0.0);
+            summary
+        };
+        (summarize(true), summarize(false))
+    }
+
+    fn assert_close(streamed: f64, buffered: f64, what: &str) {
+        assert!(
+            (streamed - buffered).abs()
+                <= estimator::Sketch::RELATIVE_ACCURACY * buffered.abs() + 1e-9,
+            "{}: streamed {} against {}",
+            what,
+            streamed,
+            buffered
+        );
+    }
+
+    #[test]
+    fn streamed_summaries_match_buffered_ones() {
+        let mut trips = vec![];
+        for i in 0..4 {
+            let send_time = f64::from(i) * 10.0;
+            trips.push(trip(0, send_time, 3.0 + f64::from(i % 7)));
+            trips.push(trip(1, send_time + 1.0, 8.0 + f64::from(i % 3)));
+        }
+        // An unordered echo which arrives last but was sent before the rest
+        // of its stream, so it displaces the trip held out first.
+        trips.push(trip(1, 0.5, 60.0));
+        trips.sort_by(|a, b| {
+            (a.send_time + a.round_trip)
+                .partial_cmp(&(b.send_time + b.round_trip))
+                .unwrap()
+        });
+
+        let (streamed, buffered) = summaries(&trips);
+        assert_eq!(streamed.first_rtt_ms, buffered.first_rtt_ms);
+        assert_close(streamed.mean_ms, buffered.mean_ms, "mean");
+        assert_close(streamed.deviation_ms, buffered.deviation_ms, "deviation");
+        assert_close(
+            streamed.round_trip_p99_ms,
+            buffered.round_trip_p99_ms,
+            "round trip p99",
+        );
+        for (streamed, buffered, what) in &[
+            (
+                streamed.interarrival_p50_ms,
+                buffered.interarrival_p50_ms,
+                "p50",
+            ),
+            (
+                streamed.interarrival_p95_ms,
+                buffered.interarrival_p95_ms,
+                "p95",
+            ),
+            (
+                streamed.interarrival_p99_ms,
+                buffered.interarrival_p99_ms,
+                "p99",
+            ),
+        ] {
+            assert!(*streamed > 0.0, "{} gap is not positive", what);
+            assert_close(*streamed, *buffered, what);
+        }
+    }
+
+    #[test]
+    fn summaries_leave_the_first_round_trip_out() {
+        let trips = [
+            trip(0, 0.0, 100.0),
+            trip(0, 10.0, 10.0),
+            trip(0, 20.0, 20.0),
+        ];
+        let (streamed, buffered) = summaries(&trips);
+        for summary in &[streamed, buffered] {
+            assert_eq!(summary.first_rtt_ms, 100.0);
+            assert_eq!(summary.mean_ms, 15.0);
+            assert_eq!(summary.round_trips(), 2);
+        }
+    }
+
+    #[test]
+    fn summaries_of_too_few_trips_are_zero_rather_than_nan() {
+        let empty = Summary::from(vec![]);
+        assert_eq!(empty.round_trips(), 0);
+        assert_eq!(empty.mean_ms, 0.0);
+        assert_eq!(empty.deviation_ms, 0.0);
+        assert_eq!(empty.round_trip_p99_ms, 0.0);
+        assert_eq!(empty.hol_blocking_ms, 0.0);
+
+        let one = Summary::from(vec![trip(0, 0.0, 7.0)]);
+        assert_eq!(one.mean_ms, 7.0);
+        assert_eq!(one.deviation_ms, 0.0);
+
+        let streamed = Summary::from(TripStats::default());
+        assert_eq!(streamed.mean_ms, 0.0);
+        assert_eq!(streamed.deviation_ms, 0.0);
+
+        // A stream without round trips does not drag down the others' mean.
+        let collected: Summary =
+            vec![Summary::from(vec![]), one].into_iter().collect();
+        assert_eq!(collected.mean_ms, 7.0);
+        let none: Summary = Vec::<Summary>::new().into_iter().collect();
+        assert_eq!(none.mean_ms, 0.0);
+        assert_eq!(none.handshake_ms, 0.0);
+    }
+
+    #[test]
+    fn queueing_delay_clamps_at_the_propagation_delay() {
+        // The first trip is held out of the statistics, leaving round trips
+        // of 10, 20, and 50ms.
+        let trips: Vec<TripReport> = [30.0, 10.0, 20.0, 50.0]
+            .iter()
+            .enumerate()
+            .map(|(i, round_trip)| trip(0, i as f64 * 100.0, *round_trip))
+            .collect();
+        let (streamed, buffered) = summaries(&trips);
+        assert_eq!(buffered.queueing_delay(20.0), 10.0);
+        assert_close(streamed.queueing_delay(20.0), 10.0, "queueing delay");
+        assert_eq!(buffered.queueing_delay(60.0), 0.0);
+        assert_eq!(streamed.queueing_delay(60.0), 0.0);
+    }
+
+    #[test]
+    fn percentiles_take_the_nearest_rank() {
+        let sorted: Vec<f64> = (1..=20).map(f64::from).collect();
+        assert_eq!(percentile(&sorted, 50.0), 10.0);
+        assert_eq!(percentile(&sorted, 95.0), 19.0);
+        assert_eq!(percentile(&sorted, 99.0), 20.0);
+        assert_eq!(percentile(&sorted, 0.0), 1.0);
+        assert_eq!(percentile(&[], 50.0), 0.0);
+    }
+
+    #[test]
+    fn interarrival_gaps_are_measured_per_stream() {
+        // Stream 0 arrives every 10ms; stream 1 alternates gaps of 5ms and
+        // 25ms. Interleaving them must not create gaps between the streams.
+        let mut trips = vec![];
+        for i in 0..20 {
+            trips.push(trip(0, f64::from(i) * 10.0, 2.0));
+            let jitter = if i % 2 == 0 { 0.0 } else { -10.0 };
+            trips.push(trip(1, f64::from(i) * 15.0 + jitter, 2.0));
+        }
+
+        let gaps = interarrival_gaps(&trips);
+        assert_eq!(gaps.len(), 38);
+        assert_eq!(gaps.iter().filter(|gap| **gap == 10.0).count(), 19);
+        assert_eq!(gaps.iter().filter(|gap| **gap == 5.0).count(), 10);
+        assert_eq!(gaps.iter().filter(|gap| **gap == 25.0).count(), 9);
+
+        let stream_0: Vec<TripReport> = trips
+            .iter()
+            .copied()
+            .filter(|trip| trip.stream_id.0 == 0)
+            .collect();
+        let summary = Summary::from(stream_0);
+        assert_eq!(summary.interarrival_p50_ms, 10.0);
+        assert_eq!(summary.interarrival_p95_ms, 10.0);
+        assert_eq!(summary.interarrival_p99_ms, 10.0);
+
+        let summary = Summary::from(trips);
+        assert_eq!(summary.interarrival_p50_ms, 10.0);
+        assert_eq!(summary.interarrival_p95_ms, 25.0);
+        assert_eq!(summary.interarrival_p99_ms, 25.0);
+    }
+
+    #[test]
+    fn one_datagram_has_no_interarrival_gap() {
+        let summary = Summary::from(vec![trip(0, 0.0, 4.0)]);
+        assert_eq!(summary.interarrival_p50_ms, 0.0);
+        assert_eq!(summary.interarrival_p99_ms, 0.0);
+    }
+
+    #[test]
+    fn sla_check_counts_violations_and_the_worst_streak() {
+        let mut sla = SlaCheck::new(10.0, Some(2));
+        for round_trip in
+            &[4.0, 12.0, 10.0, 11.0, 15.0, 30.0, 9.0, 10.5, 11.0, 2.0]
+        {
+            sla.check(*round_trip);
+        }
+        // A round trip of exactly the budget is within it.
+        assert_eq!(sla.violations, 6);
+        assert_eq!(sla.worst_streak, 3);
+        assert_eq!(sla.streak, 0);
+        assert_eq!(sla.clusters, vec![3, 2]);
+    }
+
+    #[test]
+    fn sla_check_within_budget() {
+        let mut sla = SlaCheck::new(10.0, None);
+        (0..100).for_each(|i| sla.check(f64::from(i % 10)));
+        assert_eq!((sla.violations, sla.worst_streak), (0, 0));
+        assert!(sla.clusters.is_empty());
+    }
+
+    #[test]
+    fn confidence_intervals_use_students_t() {
+        assert!(ConfidenceInterval::of(&[]).is_none());
+        assert!(ConfidenceInterval::of(&[4.0]).is_none());
+
+        // One degree of freedom: a standard error of 1 around a mean of 2.
+        let ci = ConfidenceInterval::of(&[1.0, 3.0]).unwrap();
+        assert_eq!(ci.mean, 2.0);
+        assert!((ci.half_width - 12.706).abs() < 1e-9);
+
+        // 1 to 30, with a variance of 77.5 and so a standard error of
+        // sqrt(77.5 / 30), over 29 degrees of freedom.
+        let samples: Vec<f64> = (1..=30).map(f64::from).collect();
+        let ci = ConfidenceInterval::of(&samples).unwrap();
+        assert!((ci.mean - 15.5).abs() < 1e-9);
+        let half_width = 2.045 * (77.5f64 / 30.0).sqrt();
+        assert!((ci.half_width - half_width).abs() < 1e-9);
+        assert!((ci.low() - (15.5 - half_width)).abs() < 1e-9);
+        assert!((ci.high() - (15.5 + half_width)).abs() < 1e-9);
+    }
+
+    #[test]
+    fn t_critical_values_match_the_table() {
+        for (df, t) in &[
+            (1, 12.706),
+            (2, 4.303),
+            (10, 2.228),
+            (29, 2.045),
+            (30, 2.042),
+            (35, 2.042),
+            (100, 2.000),
+        ] {
+            assert_eq!(t_critical_95(*df), *t, "df {}", df);
+        }
+        assert!(t_critical_95(0).is_infinite());
+        assert!(t_critical_95(10_000) >= 1.96);
+    }
+
+    #[test]
+    fn consume_rate_rejects_zero() {
+        let options = |rate: &str| {
+            Options::from_iter_safe(&[
+                "client",
+                "--consume-rate",
+                rate,
+                "memory",
+            ])
+        };
+        assert_eq!(
+            options("50").map(|options| options.consume_rate).ok(),
+            Some(NonZeroU32::new(50))
+        );
+        assert!(options("0").is_err());
+    }
+
+    /// The mean round trip of 20 echoes sent at 200Hz, read by a client with
+    /// `args`.
+    async fn mean_round_trip_reading(args: &[&str]) -> f64 {
+        let options = Options::from_iter(
+            ["client", "memory"]
+                .iter()
+                .chain(args)
+                .chain(&["-b", "0:100:200::20"]),
+        );
+        let connect = || {
+            let (client, server) = MemoryConnection::pair(None);
+            async_std::task::spawn(echo(server, None));
+            future::ready(Ok((Instant::now(), client))).boxed_local()
+        };
+        run(options, connect).await.unwrap().mean_ms
+    }
+
+    #[async_std::test]
+    async fn slow_consumers_leave_echoes_waiting() {
+        let eager = mean_round_trip_reading(&[]).await;
+        // Reading 20 a second, the last echo is read around a second after
+        // the first, though all were sent within 100ms.
+        let slow = mean_round_trip_reading(&["--consume-rate", "20"]).await;
+        assert!(eager < 20.0, "eager {}ms", eager);
+        assert!(slow > 200.0, "slow {}ms", slow);
+    }
+
+    #[async_std::test]
+    async fn violation_clusters_reach_the_summary() {
+        // Read slowly, every echo waits longer than the last, so once they
+        // pass the budget they stay past it in one cluster.
+        let options = Options::from_iter(&[
+            "client",
+            "memory",
+            "--consume-rate",
+            "40",
+            "--rtt-budget-ms",
+            "100",
+            "--sla-cluster",
+            "3",
+            "-b",
+            "0:100:200::20",
+        ]);
+        let connect = || {
+            let (client, server) = MemoryConnection::pair(None);
+            async_std::task::spawn(echo(server, None));
+            future::ready(Ok((Instant::now(), client))).boxed_local()
+        };
+        let summary = run(options, connect).await.unwrap();
+        assert!(summary.sla_violations >= 3, "{:?}", summary);
+        assert_eq!(
+            summary.violation_clusters,
+            vec![summary.worst_violation_streak],
+            "{:?}",
+            summary
+        );
+    }
+
+    #[async_std::test]
+    async fn traced_round_trips_pass_every_stage_in_order() {
+        let traced = |args: &[&str]| {
+            let options = Options::from_iter(
+                ["client", "memory"]
+                    .iter()
+                    .chain(args)
+                    .chain(&["-b", "0:100:200::10"]),
+            );
+            let connect = || {
+                let (client, server) = MemoryConnection::pair(None);
+                async_std::task::spawn(echo(server, None));
+                future::ready(Ok((Instant::now(), client))).boxed_local()
+            };
+            run(options, connect)
+        };
+
+        let summary = traced(&["--trace-timings"]).await.unwrap();
+        assert_eq!(summary.timing_trails.len(), 10);
+        for trail in &summary.timing_trails {
+            let stages: Vec<timings::Stage> =
+                trail.stages.iter().map(|(stage, _)| *stage).collect();
+            assert_eq!(
+                stages,
+                vec![
+                    timings::Stage::Enqueued,
+                    timings::Stage::Serialized,
+                    timings::Stage::Sent,
+                    timings::Stage::Received,
+                    timings::Stage::Deserialized,
+                    timings::Stage::Delivered,
+                ]
+            );
+            assert!(
+                trail.stages.windows(2).all(|pair| pair[0].1 <= pair[1].1),
+                "{:?}",
+                trail
+            );
+        }
+
+        // Nothing is stamped without the flag.
+        let summary = traced(&[]).await.unwrap();
+        assert!(summary.timing_trails.is_empty());
+    }
+
+    #[async_std::test]
+    async fn replays_keep_their_recorded_sizes_and_timing() {
+        // (stream, milliseconds into the recorded run, size)
+        let recorded = [
+            (1, 1000.0, 10),
+            (2, 1020.0, 50),
+            (1, 1050.0, 20),
+            (1, 1150.0, 30),
+            (2, 1200.0, 60),
+        ];
+        let path = std::env::temp_dir()
+            .join(format!("bench-replay-{}.csv", std::process::id()));
+        let mut writer = csv::Writer::from_path(&path).unwrap();
+        for &(stream_id, send_time, payload_size) in &recorded {
+            writer
+                .serialize(TripReport {
+                    payload_size,
+                    ..trip(stream_id, send_time, 5.0)
+                })
+                .unwrap();
+        }
+        writer.flush().unwrap();
+
+        let options = Options::from_iter(&[
+            "client",
+            "memory",
+            "--replay",
+            path.to_str().unwrap(),
+        ]);
+        let connect = || {
+            let (client, server) = MemoryConnection::pair(None);
+            async_std::task::spawn(echo(server, None));
+            future::ready(Ok((Instant::now(), client))).boxed_local()
+        };
+        let summary = run(options, connect).await.unwrap();
+        let _ = std::fs::remove_file(&path);
+
+        // The first round trip is left out, so the rest are timed from the
+        // second.
+        let recorded = &recorded[1..];
+        let mut replayed = summary.trip_reports.clone();
+        replayed.sort_by(|a, b| a.send_time.partial_cmp(&b.send_time).unwrap());
+        assert_eq!(replayed.len(), recorded.len());
+        for (trip, &(stream_id, send_time, payload_size)) in
+            replayed.iter().zip(recorded)
+        {
+            assert_eq!(trip.stream_id, StreamId(stream_id));
+            assert_eq!(trip.payload_size, payload_size);
+            let offset = trip.send_time - replayed[0].send_time;
+            let recorded_offset = send_time - recorded[0].1;
+            assert!(
+                (offset - recorded_offset).abs() < 10.0,
+                "{:?} sent {}ms after the second, recorded {}ms after",
+                trip,
+                offset,
+                recorded_offset
+            );
+        }
+    }
+
+    #[async_std::test]
+    async fn flipped_bytes_are_counted_as_corruption() {
+        let options =
+            Options::from_iter(&["client", "memory", "-b", "0:100:200::20"]);
+        // Echoes every datagram, flipping the last byte of every fifth's
+        // payload.
+        let connect = || {
+            let (client, mut server) = MemoryConnection::pair(None);
+            async_std::task::spawn(async move {
+                let mut echoed = 0;
+                loop {
+                    let mut datagram = match server.next().await {
+                        Some(Ok(datagram)) => datagram,
+                        _ => return,
+                    };
+                    echoed += 1;
+                    if echoed % 5 == 0 {
+                        *datagram.data.last_mut().unwrap() ^= 0xff;
+                    }
+                    let benchmark_datagram: BenchmarkDatagram =
+                        bincode::deserialize(&datagram.data).unwrap();
+                    let echo = SendCmd {
+                        data: datagram.data,
+                        delivery_mode: benchmark_datagram.delivery_mode,
+                        ..SendCmd::default()
+                    };
+                    if server.send(echo).await.is_err() {
+                        return;
+                    }
+                }
+            });
+            future::ready(Ok((Instant::now(), client))).boxed_local()
+        };
+        let summary = run(options, connect).await.unwrap();
+        assert_eq!(summary.corrupted_datagrams, 4);
+        // Corrupted echoes are not timed, and the first round trip is left
+        // out.
+        assert_eq!(summary.round_trips(), 15);
+    }
+
+    #[async_std::test]
+    async fn buffers_are_sampled_every_interval() {
+        let options = Options::from_iter(&[
+            "client",
+            "memory",
+            "-b",
+            "0:100:100::20",
+            "--buffer-sample-interval-ms",
+            "10",
+        ]);
+        let connect = || {
+            let (client, server) = MemoryConnection::pair(None);
+            async_std::task::spawn(echo(server, None));
+            let client = send_buffer::SendBufferConnection::new(client, 64);
+            future::ready(Ok((Instant::now(), client))).boxed_local()
+        };
+        let started = Instant::now();
+        let summary = run(options, connect).await.unwrap();
+        let run_ms = started.elapsed().as_secs_f64() * 1e3;
+
+        let samples = &summary.buffer_samples;
+        let expected = run_ms / 10.0;
+        assert!(
+            samples.len() as f64 >= expected * 0.5
+                && samples.len() as f64 <= expected + 1.0,
+            "{} samples over {}ms",
+            samples.len(),
+            run_ms
+        );
+        for pair in samples.windows(2) {
+            let gap = pair[1].time - pair[0].time;
+            assert!(gap >= 9.5, "samples {}ms apart", gap);
+        }
+        let span = samples.last().unwrap().time - samples[0].time;
+        let mean_gap = span / (samples.len() - 1) as f64;
+        assert!(mean_gap < 20.0, "samples {}ms apart on average", mean_gap);
+        // The send buffer tracks its queue, but the memory link tracks
+        // nothing in flight.
+        assert!(samples
+            .iter()
+            .all(|sample| sample.send_queue_depth.is_some()
+                && sample.bytes_in_flight.is_none()));
+    }
+
+    #[async_std::test]
+    async fn held_transfers_start_once_enough_echoes_return() {
+        let options = Options::from_iter(&[
+            "client",
+            "memory",
+            "-b",
+            "0:100:200::20",
+            "-b",
+            "1:100:200::5:::0/10",
+        ]);
+        let connect = || {
+            let (client, server) = MemoryConnection::pair(None);
+            async_std::task::spawn(echo(server, None));
+            future::ready(Ok((Instant::now(), client))).boxed_local()
+        };
+        let summary = run(options, connect).await.unwrap();
+        let trips = |stream_id| {
+            summary
+                .trip_reports
+                .iter()
+                .filter(move |trip| trip.stream_id() == StreamId(stream_id))
+        };
+        assert_eq!(trips(1).count(), 5);
+
+        // The run's first trip, stream 0's, is left out, so the ninth echo
+        // reported is the tenth to return.
+        let mut returns: Vec<f64> = trips(0)
+            .map(|trip| trip.send_time + trip.round_trip)
+            .collect();
+        returns.sort_by(|a, b| a.partial_cmp(b).unwrap());
+        let tenth_return = returns[8];
+        let first_send = trips(1)
+            .map(|trip| trip.send_time)
+            .fold(f64::INFINITY, f64::min);
+        assert!(
+            first_send >= tenth_return,
+            "stream 1 started at {}ms, before the tenth echo at {}ms",
+            first_send,
+            tenth_return
+        );
+        // It does not wait for the rest of stream 0.
+        assert!(first_send < *returns.last().unwrap());
+    }
+
+    /// Echoes every datagram `connection` receives back to its sender, until
+    /// `limit` datagrams have been echoed, then drops the connection.
+    async fn echo(mut connection: MemoryConnection, limit: Option<usize>) {
+        let mut echoed = 0;
+        while limit != Some(echoed) {
+            let datagram = match connection.next().await {
+                Some(Ok(datagram)) => datagram,
+                _ => return,
+            };
+            let benchmark_datagram: BenchmarkDatagram =
+                bincode::deserialize(&datagram.data).unwrap();
+            let echo = SendCmd {
+                data: datagram.data,
+                delivery_mode: benchmark_datagram.delivery_mode,
+                ..SendCmd::default()
+            };
+            if connection.send(echo).await.is_err() {
+                return;
+            }
+            echoed += 1;
+        }
+    }
+
+    #[async_std::test]
+    async fn clients_reconnect_after_losing_their_connection() {
+        let options = Options::from_iter(&[
+            "client",
+            "memory",
+            "--reconnect",
+            "-b",
+            "0:100:50::20",
+        ]);
+        // The first connection dies after echoing five datagrams, and every
+        // later one lives out the run.
+        let mut connections = 0;
+        let connect = move || {
+            let limit = match connections {
+                0 => Some(5),
+                _ => None,
+            };
+            connections += 1;
+            let (client, server) = MemoryConnection::pair(None);
+            async_std::task::spawn(echo(server, limit));
+            future::ready(Ok((Instant::now(), client))).boxed_local()
+        };
+
+        let summary = run(options, connect).await.unwrap();
+        assert_eq!(summary.reconnections, 1);
+        assert!(
+            summary.lost_to_reconnect <= 1,
+            "{} lost",
+            summary.lost_to_reconnect
+        );
+    }
+
+    /// Runs `client` against a memory server at `port` which spends 5ms on
+    /// each echo, and returns how many datagrams the server received.
+    async fn against_a_slow_server(port: u16, client: &[&str]) -> usize {
+        let address = format!("127.0.0.1:{}", port);
+        let stats = server::ServerStats::default();
+        let server = server::server_main(server::Options {
+            stats: Some(stats.clone()),
+            ..server::Options::from_iter(&[
+                "server",
+                "memory",
+                "-a",
+                &address,
+                "--processing-delay",
+                "constant:5",
+            ])
+        })
+        .boxed_local();
+        let client = client_main(Options::from_iter(
+            ["client", "memory", "-a", &address].iter().chain(client),
+        ))
+        .boxed_local();
+
+        match future::select(server, client).await {
+            Either::Left((result, _)) => panic!("server stopped: {:?}", result),
+            Either::Right((result, _)) => result.unwrap(),
+        };
+        stats.summary().streams[&StreamId(0)].datagrams_received
+    }
+
+    #[async_std::test]
+    async fn quenched_clients_back_off() {
+        // 500 sends a second against a server which echoes 200 a second.
+        let transfer = ["-b", "0:100:500:100"];
+        let steady = against_a_slow_server(47709, &transfer).await;
+        let adaptive = against_a_slow_server(
+            47710,
+            &[&transfer[..], &["--adaptive-rate"]].concat(),
+        )
+        .await;
+        assert!(
+            adaptive * 3 < steady * 2,
+            "{} sends adapting against {} steady",
+            adaptive,
+            steady
+        );
+    }
+
+    #[async_std::test]
+    async fn keyframes_recur_between_deltas() {
+        let model: PayloadModel = "keyframe/400/3/40".parse().unwrap();
+        let mut rng = StdRng::seed_from_u64(0);
+        let sizes: Vec<usize> =
+            (0..7).map(|i| model.size(i, &mut rng)).collect();
+        assert_eq!(sizes, vec![400, 40, 40, 400, 40, 40, 400]);
+
+        // Each trip records the size it was sent with. The first trip, a
+        // keyframe, is measured apart from the rest.
+        let options = Options::from_iter(&[
+            "client",
+            "memory",
+            "-b",
+            "0:keyframe/400/3/40:200::7",
+        ]);
+        let connect = || {
+            let (client, server) = MemoryConnection::pair(None);
+            async_std::task::spawn(echo(server, None));
+            future::ready(Ok((Instant::now(), client))).boxed_local()
+        };
+        let mut trips = run(options, connect).await.unwrap().trip_reports;
+        trips.sort_by_key(|trip| trip.index);
+        let sizes: Vec<usize> =
+            trips.iter().map(|trip| trip.payload_size).collect();
+        assert_eq!(sizes, vec![40, 40, 400, 40, 40, 400]);
+    }
+
+    #[async_std::test]
+    async fn bytes_sent_while_connecting_count_as_the_handshake() {
+        let options =
+            Options::from_iter(&["client", "memory", "-b", "0:100:200::5"]);
+        // The client greets the server before its connection is ready, as a
+        // transport's handshake would.
+        let connect = || {
+            async {
+                let (mut client, mut server) = MemoryConnection::pair(None);
+                async_std::task::spawn(async move {
+                    server.next().await.unwrap().unwrap();
+                    echo(server, None).await
+                });
+                let greeting = SendCmd {
+                    data: b"hello".to_vec(),
+                    delivery_mode: DeliveryMode::ReliableOrdered(StreamId(0)),
+                    ..SendCmd::default()
+                };
+                client.send(greeting).await?;
+                Ok((Instant::now(), client))
+            }
+            .boxed_local()
+        };
+        let summary = run(options, connect).await.unwrap();
+        assert_eq!(summary.handshake_bytes, 5.0);
+    }
+
+    #[async_std::test]
+    async fn tcp_teardown_is_timed_on_loopback() {
+        let address = "127.0.0.1:47721";
+        let server = server::server_main(server::Options::from_iter(&[
+            "server", "tcp", "-a", address,
+        ]))
+        .boxed_local();
+        let client = client_main(Options::from_iter(&[
+            "client",
+            "tcp",
+            "-a",
+            address,
+            "-b",
+            "0:100:200::5",
+        ]))
+        .boxed_local();
+
+        let summary = match future::select(server, client).await {
+            Either::Left((result, _)) => panic!("server stopped: {:?}", result),
+            Either::Right((result, _)) => result.unwrap(),
+        };
+        // The FIN exchange on loopback is well under a millisecond; zero
+        // would mean the server's end never closed.
+        assert!(summary.teardown_ms > 0.0, "{:?}", summary);
+        assert!(summary.teardown_ms < 100.0, "{:?}", summary);
+    }
+
+    #[cfg(target_os = "linux")]
+    #[async_std::test]
+    async fn kernel_pacing_caps_the_egress_rate() {
+        // 200kB offered at 500kB/s, against a cap of 100kB/s.
+        let throughput = |port: u16, args: &'static [&'static str]| async move {
+            let address = format!("127.0.0.1:{}", port);
+            let server = server::server_main(server::Options::from_iter(&[
+                "server", "tcp", "-a", &address,
+            ]))
+            .boxed_local();
+            let client = client_main(Options::from_iter(
+                ["client", "tcp", "-a", &address, "-b", "0:1000:500::200"]
+                    .iter()
+                    .chain(args),
+            ))
+            .boxed_local();
+            match future::select(server, client).await {
+                Either::Left((result, _)) => {
+                    panic!("server stopped: {:?}", result)
+                }
+                Either::Right((result, _)) => result.unwrap(),
+            }
+        };
+
+        let unpaced = throughput(47744, &[]).await;
+        let paced = throughput(47745, &["--max-pacing-rate", "100000"]).await;
+        assert_eq!(unpaced.pacing, Pacing::Application);
+        assert_eq!(paced.pacing, Pacing::Kernel(100_000));
+        let paced_rate = paced.throughput_bytes_per_second();
+        assert!(
+            (80_000.0..150_000.0).contains(&paced_rate),
+            "paced at {}B/s",
+            paced_rate
+        );
+        assert!(
+            unpaced.throughput_bytes_per_second() > 2.0 * paced_rate,
+            "unpaced at {}B/s",
+            unpaced.throughput_bytes_per_second()
+        );
+    }
+
+    /// How many times a client connects to run `args` three times.
+    async fn connections_over_three_repetitions(args: &[&str]) -> usize {
+        let options = Options::from_iter(
+            ["client", "memory", "--repeat", "3"]
+                .iter()
+                .chain(args)
+                .chain(&["-b", "0:100:200::5"]),
+        );
+        let connects = Rc::new(Cell::new(0));
+        let connect = {
+            let connects = connects.clone();
+            move || {
+                connects.set(connects.get() + 1);
+                let (client, server) = MemoryConnection::pair(None);
+                async_std::task::spawn(echo(server, None));
+                future::ready(Ok((Instant::now(), client))).boxed_local()
+            }
+        };
+        let summary = run(options, connect).await.unwrap();
+        // Each repetition measures its own trips, on a fresh connection or
+        // not.
+        assert_eq!(summary.round_trips(), 3 * 4);
+        connects.get()
+    }
+
+    #[async_std::test]
+    async fn reused_connections_handshake_once_across_repetitions() {
+        assert_eq!(connections_over_three_repetitions(&[]).await, 3);
+        assert_eq!(
+            connections_over_three_repetitions(&["--reuse-connection"]).await,
+            1
+        );
+    }
+
+    #[async_std::test]
+    async fn phase_offsets_hold_back_a_transfers_first_send() {
+        let options = Options::from_iter(&[
+            "client",
+            "memory",
+            "-b",
+            "0:100:60::3",
+            "-b",
+            "1:100:60::3:80",
+        ]);
+        // When the server first hears from each stream.
+        let first_arrivals = Arc::new(Mutex::new(HashMap::new()));
+        let connect = {
+            let first_arrivals = first_arrivals.clone();
+            move || {
+                let (client, mut server) = MemoryConnection::pair(None);
+                let first_arrivals = first_arrivals.clone();
+                async_std::task::spawn(async move {
+                    loop {
+                        let datagram = match server.next().await {
+                            Some(Ok(datagram)) => datagram,
+                            _ => return,
+                        };
+                        let benchmark_datagram: BenchmarkDatagram =
+                            bincode::deserialize(&datagram.data).unwrap();
+                        first_arrivals
+                            .lock()
+                            .unwrap()
+                            .entry(benchmark_datagram.stream_id)
+                            .or_insert_with(Instant::now);
+                        let echo = SendCmd {
+                            data: datagram.data,
+                            delivery_mode: benchmark_datagram.delivery_mode,
+                            ..SendCmd::default()
+                        };
+                        if server.send(echo).await.is_err() {
+                            return;
+                        }
+                    }
+                });
+                future::ready(Ok((Instant::now(), client))).boxed_local()
+            }
+        };
+        run(options, connect).await.unwrap();
+
+        let first_arrivals = first_arrivals.lock().unwrap();
+        let offset = first_arrivals[&StreamId(1)]
+            .duration_since(first_arrivals[&StreamId(0)]);
+        assert!(offset >= Duration::from_millis(80), "{:?}", offset);
+        assert!(offset < Duration::from_millis(120), "{:?}", offset);
+    }
 }
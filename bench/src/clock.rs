@@ -0,0 +1,255 @@
+//! Where the benchmark reads the time and waits for it.
+//!
+//! The client, the server, and the in-memory transport time everything
+//! through `now`, `since`, and `sleep`, which follow the thread's current
+//! `TimeSource`. That is the wall clock, unless the code runs under
+//! `simulate`, which runs it in virtual time instead.
+//!
+//! A virtual clock stands still while anything in the simulation can make
+//! progress, and jumps to the next deadline once everything is waiting on
+//! it. Delays the in-memory transport models then pass instantly, yet are
+//! measured in full, so a run over a long round trip reports its latency in
+//! a fraction of the time. Virtual time only governs what is polled by the
+//! simulated future itself, so the simulation must not wait on anything
+//! outside it, such as a socket or a spawned task.
+
+use futures::{
+    future::{BoxFuture, FutureExt},
+    task::{waker, ArcWake, Context, Poll},
+    Future,
+};
+use futures_timer::Delay;
+
+use std::{
+    cell::RefCell,
+    collections::BTreeMap,
+    pin::Pin,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc, Mutex,
+    },
+    task::Waker,
+    time::{Duration, Instant},
+};
+
+/// A clock to time by and wait on.
+pub trait TimeSource {
+    fn now(&self) -> Instant;
+    /// Resolves once `duration` has passed on this clock.
+    fn sleep(&self, duration: Duration) -> BoxFuture<'static, ()>;
+}
+
+/// The wall clock.
+pub struct RealTime;
+
+impl TimeSource for RealTime {
+    fn now(&self) -> Instant {
+        Instant::now()
+    }
+
+    fn sleep(&self, duration: Duration) -> BoxFuture<'static, ()> {
+        Delay::new(duration).boxed()
+    }
+}
+
+/// A clock which only moves when `simulate` moves it.
+#[derive(Clone)]
+pub struct VirtualTime {
+    state: Arc<Mutex<VirtualState>>,
+}
+
+struct VirtualState {
+    now: Instant,
+    /// The sleeps still waiting, by deadline and then the order they began.
+    sleepers: BTreeMap<(Instant, u64), Waker>,
+    next_sleeper: u64,
+}
+
+impl VirtualTime {
+    fn new() -> Self {
+        Self {
+            state: Arc::new(Mutex::new(VirtualState {
+                now: Instant::now(),
+                sleepers: BTreeMap::new(),
+                next_sleeper: 0,
+            })),
+        }
+    }
+
+    fn state(&self) -> std::sync::MutexGuard<'_, VirtualState> {
+        self.state.lock().expect("virtual clock lock")
+    }
+
+    /// Moves the clock to the earliest deadline and wakes the sleeps due
+    /// then. Returns false if nothing is sleeping.
+    fn advance(&self) -> bool {
+        let mut state = self.state();
+        let deadline = match state.sleepers.keys().next() {
+            Some(&(deadline, _)) => deadline,
+            None => return false,
+        };
+        state.now = state.now.max(deadline);
+        while let Some(entry) = state.sleepers.first_entry() {
+            if entry.key().0 > deadline {
+                break;
+            }
+            entry.remove().wake();
+        }
+        true
+    }
+}
+
+impl TimeSource for VirtualTime {
+    fn now(&self) -> Instant {
+        self.state().now
+    }
+
+    fn sleep(&self, duration: Duration) -> BoxFuture<'static, ()> {
+        let mut state = self.state();
+        let key = (state.now + duration, state.next_sleeper);
+        state.next_sleeper += 1;
+        VirtualSleep {
+            clock: self.clone(),
+            key,
+        }
+        .boxed()
+    }
+}
+
+struct VirtualSleep {
+    clock: VirtualTime,
+    key: (Instant, u64),
+}
+
+impl Future for VirtualSleep {
+    type Output = ();
+    fn poll(self: Pin<&mut Self>, ctx: &mut Context) -> Poll<()> {
+        let mut state = self.clock.state();
+        if state.now >= self.key.0 {
+            return Poll::Ready(());
+        }
+        state.sleepers.insert(self.key, ctx.waker().clone());
+        Poll::Pending
+    }
+}
+
+impl Drop for VirtualSleep {
+    fn drop(&mut self) {
+        self.clock.state().sleepers.remove(&self.key);
+    }
+}
+
+thread_local! {
+    /// The virtual clock of the simulation being polled on this thread.
+    static SIMULATED: RefCell<Option<VirtualTime>> =
+        const { RefCell::new(None) };
+}
+
+fn simulated() -> Option<VirtualTime> {
+    SIMULATED.with(|simulated| simulated.borrow().clone())
+}
+
+/// The current time on this thread's clock.
+pub fn now() -> Instant {
+    match simulated() {
+        Some(clock) => clock.now(),
+        None => RealTime.now(),
+    }
+}
+
+/// How long it has been since `earlier` on this thread's clock.
+pub fn since(earlier: Instant) -> Duration {
+    now().saturating_duration_since(earlier)
+}
+
+/// Resolves once `duration` has passed on this thread's clock.
+pub fn sleep(duration: Duration) -> BoxFuture<'static, ()> {
+    match simulated() {
+        Some(clock) => clock.sleep(duration),
+        None => RealTime.sleep(duration),
+    }
+}
+
+/// Runs `future` in virtual time.
+pub async fn simulate<F: Future>(future: F) -> F::Output {
+    Simulation {
+        clock: VirtualTime::new(),
+        future: Box::pin(future),
+    }
+    .await
+}
+
+struct Simulation<F> {
+    clock: VirtualTime,
+    future: Pin<Box<F>>,
+}
+
+/// Notes that something in the simulation can make progress.
+struct Woken {
+    woken: AtomicBool,
+    outer: Waker,
+}
+
+impl ArcWake for Woken {
+    fn wake_by_ref(arc_self: &Arc<Self>) {
+        arc_self.woken.store(true, Ordering::SeqCst);
+        arc_self.outer.wake_by_ref();
+    }
+}
+
+impl<F: Future> Future for Simulation<F> {
+    type Output = F::Output;
+    fn poll(mut self: Pin<&mut Self>, ctx: &mut Context) -> Poll<F::Output> {
+        loop {
+            let woken = Arc::new(Woken {
+                woken: AtomicBool::new(false),
+                outer: ctx.waker().clone(),
+            });
+            let inner_waker = waker(woken.clone());
+            let clock = self.clock.clone();
+            let previous = SIMULATED
+                .with(|simulated| simulated.borrow_mut().replace(clock));
+            let polled = self
+                .future
+                .as_mut()
+                .poll(&mut Context::from_waker(&inner_waker));
+            SIMULATED.with(|simulated| *simulated.borrow_mut() = previous);
+
+            if polled.is_ready() {
+                return polled;
+            }
+            // Time only moves once nothing can go on without it.
+            if woken.woken.load(Ordering::SeqCst) || self.clock.advance() {
+                continue;
+            }
+            return Poll::Pending;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use futures::future;
+
+    #[async_std::test]
+    async fn simulated_sleeps_wake_in_order_without_waiting() {
+        let started = Instant::now();
+        let (early, late) = simulate(async {
+            let start = now();
+            let sleep_for = |ms| async move {
+                sleep(Duration::from_millis(ms)).await;
+                since(start)
+            };
+            future::join(sleep_for(100), sleep_for(300)).await
+        })
+        .await;
+
+        assert_eq!(early, Duration::from_millis(100));
+        assert_eq!(late, Duration::from_millis(300));
+        assert!(started.elapsed() < Duration::from_millis(100));
+        // Outside the simulation, the wall clock is back.
+        assert!(since(started) < Duration::from_millis(100));
+    }
+}
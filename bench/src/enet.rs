@@ -1,3 +1,4 @@
+use crate::header;
 use async_std::net::*;
 use futures::channel::mpsc;
 use futures::prelude::*;
@@ -6,7 +7,10 @@ use nhanh::*;
 use std::collections::HashMap;
 use std::ffi::c_void;
 use std::pin::Pin;
-use std::sync::Arc;
+use std::sync::{
+    atomic::{AtomicU64, Ordering},
+    Arc,
+};
 use std::task::{Context, Poll};
 
 pub const MAX_CHANNELS: u64 = 256;
@@ -19,6 +23,7 @@ mod enet {
 #[derive(Debug)]
 struct NewPeer {
     peer: u64,
+    address: SocketAddr,
     peer_event_stream: mpsc::UnboundedReceiver<Datagram>,
 }
 
@@ -26,6 +31,7 @@ struct EnetCmd {
     peer: u64,
     channel: u8,
     data: Vec<u8>,
+    notify: Option<SendNotifier>,
 }
 
 pub struct EnetServer {
@@ -58,6 +64,11 @@ async fn socket_addr_to_enet_addr(
     }
 }
 
+fn enet_addr_to_socket_addr(address: enet::ENetAddress) -> SocketAddr {
+    let ip = Ipv4Addr::from(address.host.to_le_bytes());
+    SocketAddr::new(IpAddr::V4(ip), address.port)
+}
+
 impl EnetServer {
     pub async fn bind(address: impl ToSocketAddrs) -> Self {
         let address = socket_addr_to_enet_addr(address).await;
@@ -73,6 +84,8 @@ impl EnetServer {
             new_peer_sink,
             HostType::Server,
             address,
+            // Every peer shares the host, so its total is not per connection.
+            Arc::new(AtomicU64::new(0)),
         ));
 
         Self {
@@ -107,8 +120,10 @@ impl Stream for EnetServer {
         Poll::Ready(Some(Ok(EnetConnection {
             marker: self.marker.clone(),
             peer: new_peer.peer,
+            peer_addr: new_peer.address,
             command_sink: self.command_sink.clone(),
             peer_event_stream: new_peer.peer_event_stream,
+            wire_bytes_sent: None,
         })))
     }
 }
@@ -118,8 +133,11 @@ pub struct EnetConnection {
     #[allow(unused)]
     marker: Arc<()>,
     peer: u64,
+    peer_addr: SocketAddr,
     command_sink: mpsc::Sender<EnetCmd>,
     peer_event_stream: mpsc::UnboundedReceiver<Datagram>,
+    /// Only known for client connections, which own their host.
+    wire_bytes_sent: Option<Arc<AtomicU64>>,
 }
 
 impl EnetConnection {
@@ -130,6 +148,7 @@ impl EnetConnection {
         let (new_peer_sink, mut new_peer_stream) = mpsc::unbounded();
 
         let marker = Arc::new(());
+        let wire_bytes_sent = Arc::new(AtomicU64::new(0));
 
         std::thread::spawn(enet_service_loop(
             marker.clone(),
@@ -137,19 +156,30 @@ impl EnetConnection {
             new_peer_sink,
             HostType::Client,
             address,
+            wire_bytes_sent.clone(),
         ));
 
         let peer = new_peer_stream.next().await.expect("connection to server");
         Self {
             marker,
             peer: peer.peer,
+            peer_addr: peer.address,
             command_sink,
             peer_event_stream: peer.peer_event_stream,
+            wire_bytes_sent: Some(wire_bytes_sent),
         }
     }
 }
 
-impl Connection for EnetConnection {}
+impl Connection for EnetConnection {
+    fn wire_bytes_sent(&self) -> Option<Arc<AtomicU64>> {
+        self.wire_bytes_sent.clone()
+    }
+
+    fn peer_addr(&self) -> Option<SocketAddr> {
+        Some(self.peer_addr)
+    }
+}
 
 impl FusedStream for EnetConnection {
     fn is_terminated(&self) -> bool {
@@ -186,11 +216,13 @@ impl Sink<SendCmd> for EnetConnection {
         };
 
         let peer = self.peer;
+        let data = header::frame(item.header, item.data)?;
         Pin::new(&mut self.command_sink)
             .start_send(EnetCmd {
                 peer,
                 channel,
-                data: item.data,
+                data,
+                notify: item.notify,
             })
             .map_err(Into::into)
     }
@@ -250,6 +282,21 @@ impl HostType {
     }
 }
 
+/// Reports the `SendEvent`s of a packet ENet is done with, from the
+/// notifier its `userData` holds.
+///
+/// ENet frees a reliable packet once the peer acknowledges it, marking it
+/// sent, or unmarked if it is dropped first. It says nothing of when the
+/// packet leaves its send window, so `SendEvent::Sent` is never reported.
+unsafe extern "C" fn packet_freed(packet: *mut enet::ENetPacket) {
+    let packet = &mut *packet;
+    let notifier = Box::from_raw(packet.userData as *mut SendNotifier);
+    packet.userData = std::ptr::null_mut();
+    if packet.flags & enet::_ENetPacketFlag_ENET_PACKET_FLAG_SENT != 0 {
+        notifier.notify(SendEvent::Acked);
+    }
+}
+
 fn enet_service_command(command: EnetCmd) {
     let packet = unsafe {
         enet::enet_packet_create(
@@ -258,10 +305,21 @@ fn enet_service_command(command: EnetCmd) {
             enet::_ENetPacketFlag_ENET_PACKET_FLAG_RELIABLE,
         )
     };
+    if let Some(notifier) = command.notify {
+        unsafe {
+            (*packet).userData =
+                Box::into_raw(Box::new(notifier)) as *mut c_void;
+            (*packet).freeCallback = Some(packet_freed);
+        }
+    }
 
     unsafe {
         let peer = command.peer as *mut enet::ENetPeer;
-        enet::enet_peer_send(peer, command.channel, packet)
+        // A packet ENet refuses is still ours to free, which closes its
+        // notifier without reporting anything.
+        if enet::enet_peer_send(peer, command.channel, packet) < 0 {
+            enet::enet_packet_destroy(packet);
+        }
     };
 }
 
@@ -271,6 +329,7 @@ fn enet_service_loop(
     new_peer_sink: mpsc::UnboundedSender<NewPeer>,
     host_type: HostType,
     server_addr: enet::ENetAddress,
+    wire_bytes_sent: Arc<AtomicU64>,
 ) -> impl FnOnce() {
     move || {
         assert_eq!(unsafe { enet::enet_initialize() }, 0);
@@ -313,6 +372,9 @@ fn enet_service_loop(
                         new_peer_sink
                             .unbounded_send(NewPeer {
                                 peer: event.peer as u64,
+                                address: enet_addr_to_socket_addr(unsafe {
+                                    (*event.peer).address
+                                }),
                                 peer_event_stream,
                             })
                             .expect("sending new peer event");
@@ -325,14 +387,26 @@ fn enet_service_loop(
                             peers.get_mut(&event.peer).expect("peer sink");
                         let data: &'static [u8] = unsafe {
                             let packet = &mut *event.packet;
-                            std::slice::from_raw_parts(
-                                packet.data,
-                                packet.dataLength as usize,
-                            )
+                            // ENet allocates no data for empty packets.
+                            match packet.dataLength {
+                                0 => &[],
+                                _ => std::slice::from_raw_parts(
+                                    packet.data,
+                                    packet.dataLength as usize,
+                                ),
+                            }
+                        };
+                        let (header, data) = match header::unframe(data) {
+                            Ok(unframed) => unframed,
+                            Err(e) => {
+                                println!("dropping malformed packet: {}", e);
+                                continue;
+                            }
                         };
                         if sink
                             .unbounded_send(Datagram {
-                                data: data.to_vec(),
+                                data,
+                                header,
                                 stream_position: Some(StreamPosition {
                                     stream_id: StreamId(event.channelID),
                                     index: StreamIndex::Ordinal(total_sent),
@@ -349,6 +423,9 @@ fn enet_service_loop(
                     e => println!("other event type: {:?}", e),
                 }
             }
+
+            let host_sent = unsafe { (*host).totalSentData };
+            wire_bytes_sent.store(host_sent as u64, Ordering::Relaxed);
         }
     }
 }
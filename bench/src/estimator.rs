@@ -0,0 +1,261 @@
+//! Statistics estimated as samples arrive, so that the memory they take stays
+//! bounded however long a run goes on.
+//!
+//! `Moments` keeps a running mean and variance by Welford's algorithm, which
+//! matches the two pass computation up to rounding. `Sketch` estimates
+//! percentiles from a histogram of logarithmically sized buckets: each
+//! estimate is within `Sketch::RELATIVE_ACCURACY` of the sample which truly
+//! ranks there, and the number of buckets grows with the logarithm of the
+//! range of the samples rather than with their count.
+//!
+//! Both merge, so that estimates of separate streams or runs combine as their
+//! samples would have.
+
+use std::collections::BTreeMap;
+
+/// The running count, mean and sum of squared differences from the mean of
+/// some samples.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct Moments {
+    count: u64,
+    mean: f64,
+    m2: f64,
+}
+
+impl Moments {
+    pub fn push(&mut self, sample: f64) {
+        self.count += 1;
+        let delta = sample - self.mean;
+        self.mean += delta / self.count as f64;
+        self.m2 += delta * (sample - self.mean);
+    }
+
+    /// Adds `other`'s samples, by Chan et al.'s pairwise update.
+    pub fn merge(&mut self, other: &Self) {
+        if other.count == 0 {
+            return;
+        }
+        if self.count == 0 {
+            *self = *other;
+            return;
+        }
+
+        let count = self.count + other.count;
+        let delta = other.mean - self.mean;
+        self.mean += delta * other.count as f64 / count as f64;
+        self.m2 += other.m2
+            + delta.powi(2) * (self.count as f64 * other.count as f64)
+                / count as f64;
+        self.count = count;
+    }
+
+    pub fn count(&self) -> u64 {
+        self.count
+    }
+
+    /// The mean of the samples, or NaN without any, like dividing their sum
+    /// by their count.
+    pub fn mean(&self) -> f64 {
+        match self.count {
+            0 => f64::NAN,
+            _ => self.mean,
+        }
+    }
+
+    /// The sample variance, which is NaN for fewer than two samples.
+    pub fn variance(&self) -> f64 {
+        self.m2 / (self.count as f64 - 1.0)
+    }
+
+    pub fn deviation(&self) -> f64 {
+        self.variance().sqrt()
+    }
+}
+
+/// A histogram of non-negative samples for estimating their percentiles.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct Sketch {
+    /// How many samples fell in each bucket, keyed by the power of `gamma`
+    /// which bounds the bucket from above.
+    buckets: BTreeMap<i32, u64>,
+    /// Samples too small to bucket, which are estimated as zero.
+    zeros: u64,
+    count: u64,
+}
+
+impl Sketch {
+    /// The most an estimate differs from the true sample, relative to it.
+    pub const RELATIVE_ACCURACY: f64 = 0.01;
+
+    /// Samples at or below this are counted as zero.
+    const SMALLEST: f64 = 1e-9;
+
+    /// The ratio of each bucket's upper bound to its lower bound.
+    fn gamma() -> f64 {
+        (1.0 + Self::RELATIVE_ACCURACY) / (1.0 - Self::RELATIVE_ACCURACY)
+    }
+
+    /// The estimate of every sample in the bucket at `key`: the value within
+    /// `RELATIVE_ACCURACY` of both of its bounds.
+    fn estimate(key: i32) -> f64 {
+        let gamma = Self::gamma();
+        2.0 * gamma.powi(key) / (gamma + 1.0)
+    }
+
+    pub fn push(&mut self, sample: f64) {
+        self.count += 1;
+        if sample <= Self::SMALLEST {
+            self.zeros += 1;
+            return;
+        }
+
+        let key = (sample.ln() / Self::gamma().ln()).ceil() as i32;
+        *self.buckets.entry(key).or_insert(0) += 1;
+    }
+
+    pub fn merge(&mut self, other: &Self) {
+        for (key, count) in &other.buckets {
+            *self.buckets.entry(*key).or_insert(0) += count;
+        }
+        self.zeros += other.zeros;
+        self.count += other.count;
+    }
+
+    pub fn count(&self) -> u64 {
+        self.count
+    }
+
+    /// Estimates of the samples, each with how many samples it stands for, in
+    /// ascending order.
+    pub fn estimates(&self) -> impl Iterator<Item = (f64, u64)> + '_ {
+        std::iter::once((0.0, self.zeros))
+            .filter(|(_, count)| *count > 0)
+            .chain(
+                self.buckets
+                    .iter()
+                    .map(|(key, count)| (Self::estimate(*key), *count)),
+            )
+    }
+
+    /// Estimates the nearest-rank `p`th percentile, or zero if there are no
+    /// samples.
+    pub fn percentile(&self, p: f64) -> f64 {
+        if self.count == 0 {
+            return 0.0;
+        }
+
+        let rank = ((p / 100.0 * self.count as f64).ceil() as u64)
+            .max(1)
+            .min(self.count);
+        let mut seen = 0;
+        for (estimate, count) in self.estimates() {
+            seen += count;
+            if seen >= rank {
+                return estimate;
+            }
+        }
+        unreachable!("rank {} is past the last of {} samples", rank, seen)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SAMPLES: [f64; 8] = [2.0, 4.0, 4.0, 4.0, 5.0, 5.0, 7.0, 9.0];
+
+    fn moments(samples: &[f64]) -> Moments {
+        let mut moments = Moments::default();
+        samples.iter().for_each(|sample| moments.push(*sample));
+        moments
+    }
+
+    fn close(a: f64, b: f64) -> bool {
+        (a - b).abs() < 1e-12
+    }
+
+    #[test]
+    fn moments_match_the_two_pass_computation() {
+        let moments = moments(&SAMPLES);
+        assert_eq!(moments.count(), 8);
+        assert!(close(moments.mean(), 5.0));
+        assert!(close(moments.variance(), 32.0 / 7.0));
+        assert!(close(moments.deviation(), (32.0f64 / 7.0).sqrt()));
+    }
+
+    #[test]
+    fn moments_without_samples() {
+        assert!(Moments::default().mean().is_nan());
+        assert!(moments(&[3.0]).variance().is_nan());
+    }
+
+    #[test]
+    fn merged_moments_match_a_single_pass() {
+        for split in 0..=SAMPLES.len() {
+            let mut merged = moments(&SAMPLES[..split]);
+            merged.merge(&moments(&SAMPLES[split..]));
+            let single = moments(&SAMPLES);
+            assert_eq!(merged.count(), single.count());
+            assert!(close(merged.mean(), single.mean()), "split {}", split);
+            assert!(
+                close(merged.variance(), single.variance()),
+                "split {}",
+                split
+            );
+        }
+    }
+
+    /// The sample at the nearest rank for `p` in sorted `samples`.
+    fn exact_percentile(samples: &[f64], p: f64) -> f64 {
+        let rank = ((p / 100.0 * samples.len() as f64).ceil() as usize)
+            .max(1)
+            .min(samples.len());
+        samples[rank - 1]
+    }
+
+    #[test]
+    fn sketch_percentiles_are_within_the_relative_accuracy() {
+        let samples: Vec<f64> =
+            (1..=1000).map(|i| f64::from(i) * 0.37).collect();
+        let mut sketch = Sketch::default();
+        samples.iter().for_each(|sample| sketch.push(*sample));
+
+        assert_eq!(sketch.count(), 1000);
+        for p in &[1.0, 25.0, 50.0, 90.0, 99.0, 99.9, 100.0] {
+            let exact = exact_percentile(&samples, *p);
+            let estimate = sketch.percentile(*p);
+            assert!(
+                (estimate - exact).abs() <= Sketch::RELATIVE_ACCURACY * exact,
+                "p{} estimated {} for {}",
+                p,
+                estimate,
+                exact
+            );
+        }
+    }
+
+    #[test]
+    fn sketch_counts_tiny_samples_as_zero() {
+        let mut sketch = Sketch::default();
+        assert_eq!(sketch.percentile(50.0), 0.0);
+        sketch.push(0.0);
+        sketch.push(10.0);
+        assert_eq!(sketch.percentile(50.0), 0.0);
+        assert!((sketch.percentile(100.0) - 10.0).abs() <= 0.1);
+    }
+
+    #[test]
+    fn merged_sketches_match_a_single_pass() {
+        let mut single = Sketch::default();
+        let mut halves = (Sketch::default(), Sketch::default());
+        for (i, sample) in SAMPLES.iter().enumerate() {
+            single.push(*sample);
+            match i % 2 {
+                0 => halves.0.push(*sample),
+                _ => halves.1.push(*sample),
+            }
+        }
+        halves.0.merge(&halves.1);
+        assert_eq!(halves.0, single);
+    }
+}
@@ -0,0 +1,612 @@
+//! Forward error correction for the unordered unreliable stream.
+//!
+//! Datagrams sent with `DeliveryMode::UnreliableUnordered` are grouped into
+//! blocks of `block_size`. After each block the sender transmits one parity
+//! datagram, the XOR of every datagram in the block, so the receiver can
+//! rebuild any single datagram lost from the block without a retransmit. The
+//! code rate is `block_size / (block_size + 1)`.
+//!
+//! Sequenced streams are not coded because a recovered datagram would surface
+//! after newer ones. All other delivery modes pass through untouched.
+//!
+//! The client and server turn it on with `--fec`, giving the block size or
+//! the code rate, and the `memory-fec-4` transport codes blocks of four.
+
+use crate::*;
+
+use futures::{
+    future::LocalBoxFuture,
+    stream::FusedStream,
+    task::{Context, Poll},
+    Sink, Stream,
+};
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::{HashMap, VecDeque},
+    pin::Pin,
+    str::FromStr,
+    sync::{atomic::AtomicU64, Arc},
+};
+
+/// How many blocks behind the newest one the receiver keeps state for.
+const BLOCK_HISTORY: u32 = 16;
+
+/// Forward error correction with one parity datagram after every
+/// `block_size` unordered unreliable datagrams. Written as the block size,
+/// such as `4`, or as the code rate, such as `4/5`.
+#[derive(Clone, Copy, Debug, PartialEq, Serialize)]
+pub struct Fec {
+    pub block_size: u8,
+}
+
+impl Fec {
+    /// The share of the datagrams sent which carry data.
+    pub fn code_rate(&self) -> f64 {
+        f64::from(self.block_size) / (f64::from(self.block_size) + 1.0)
+    }
+
+    pub fn wrap<C>(self, connection: C) -> FecConnection<C>
+    where
+        C: Connection + Unpin,
+    {
+        FecConnection::new(connection, self.block_size)
+    }
+}
+
+impl FromStr for Fec {
+    type Err = anyhow::Error;
+    fn from_str(src: &str) -> std::result::Result<Self, Self::Err> {
+        let args: Vec<&str> = src.split('/').collect();
+        let block_size = match args.as_slice() {
+            [block_size] => block_size.parse::<u8>()?,
+            [data, total] => {
+                let (data, total) =
+                    (data.parse::<u8>()?, total.parse::<u16>()?);
+                if total != u16::from(data) + 1 {
+                    anyhow::bail!(
+                        "XOR parity codes rates of `N/N+1`, got {:?}",
+                        src
+                    );
+                }
+                data
+            }
+            _ => anyhow::bail!(
+                "expected `BLOCK_SIZE` or `BLOCK_SIZE/BLOCK_SIZE+1`, got {:?}",
+                src
+            ),
+        };
+        if block_size == 0 {
+            anyhow::bail!("FEC blocks must hold at least one datagram");
+        }
+        Ok(Self { block_size })
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+enum FecFrame {
+    /// A datagram which is not covered by any block.
+    Plain(Vec<u8>),
+    Data {
+        block: u32,
+        index: u8,
+        data: Vec<u8>,
+    },
+    Parity {
+        block: u32,
+        /// XOR of the lengths of every datagram in the block.
+        length: u32,
+        /// XOR of every datagram in the block, zero padded to the longest.
+        parity: Vec<u8>,
+    },
+}
+
+fn xor_into(accumulator: &mut Vec<u8>, data: &[u8]) {
+    if accumulator.len() < data.len() {
+        accumulator.resize(data.len(), 0);
+    }
+    accumulator
+        .iter_mut()
+        .zip(data)
+        .for_each(|(acc, byte)| *acc ^= byte);
+}
+
+#[derive(Default)]
+struct Encoder {
+    block: u32,
+    index: u8,
+    length: u32,
+    parity: Vec<u8>,
+}
+
+impl Encoder {
+    /// Returns the frames to put on the wire for `data`.
+    fn encode(&mut self, block_size: u8, data: Vec<u8>) -> Vec<FecFrame> {
+        xor_into(&mut self.parity, &data);
+        self.length ^= data.len() as u32;
+
+        let mut frames = vec![FecFrame::Data {
+            block: self.block,
+            index: self.index,
+            data,
+        }];
+
+        self.index += 1;
+        if self.index == block_size {
+            frames.push(FecFrame::Parity {
+                block: self.block,
+                length: self.length,
+                parity: std::mem::take(&mut self.parity),
+            });
+            self.block = self.block.wrapping_add(1);
+            self.index = 0;
+            self.length = 0;
+        }
+
+        frames
+    }
+}
+
+#[derive(Default)]
+struct BlockState {
+    received: HashMap<u8, Vec<u8>>,
+    parity: Option<(u32, Vec<u8>)>,
+    recovered: bool,
+}
+
+impl BlockState {
+    fn try_recover(&mut self, block_size: u8) -> Option<Vec<u8>> {
+        if self.recovered || self.received.len() + 1 != block_size as usize {
+            return None;
+        }
+
+        let (mut length, mut data) = self.parity.clone()?;
+        for received in self.received.values() {
+            xor_into(&mut data, received);
+            length ^= received.len() as u32;
+        }
+        data.truncate(length as usize);
+
+        self.recovered = true;
+        Some(data)
+    }
+}
+
+#[derive(Default)]
+struct Decoder {
+    newest_block: u32,
+    blocks: HashMap<u32, BlockState>,
+}
+
+impl Decoder {
+    /// Returns the datagrams `frame` surfaces, including any it recovers.
+    fn decode(&mut self, block_size: u8, frame: FecFrame) -> Vec<Vec<u8>> {
+        let block = match frame {
+            FecFrame::Plain(data) => return vec![data],
+            FecFrame::Data { block, .. } | FecFrame::Parity { block, .. } => {
+                block
+            }
+        };
+
+        let ahead = block.wrapping_sub(self.newest_block);
+        if ahead != 0 && ahead < u32::MAX / 2 {
+            self.newest_block = block;
+            self.blocks.retain(|candidate, _| {
+                block.wrapping_sub(*candidate) <= BLOCK_HISTORY
+            });
+        } else if self.newest_block.wrapping_sub(block) > BLOCK_HISTORY {
+            return vec![];
+        }
+
+        let state = self.blocks.entry(block).or_default();
+        let mut surfaced = vec![];
+        match frame {
+            FecFrame::Data { index, data, .. } => {
+                if state.recovered || state.received.contains_key(&index) {
+                    return vec![];
+                }
+                state.received.insert(index, data.clone());
+                surfaced.push(data);
+            }
+            FecFrame::Parity { length, parity, .. } => {
+                state.parity = Some((length, parity));
+            }
+            FecFrame::Plain(_) => unreachable!(),
+        }
+
+        surfaced.extend(state.try_recover(block_size));
+        surfaced
+    }
+}
+
+/// Wraps a connection with forward error correction on its unordered
+/// unreliable stream.
+///
+/// Both endpoints must wrap their connections with the same `block_size`.
+pub struct FecConnection<C> {
+    inner: C,
+    block_size: u8,
+    encoder: Encoder,
+    decoder: Decoder,
+    outgoing: VecDeque<SendCmd>,
+    incoming: VecDeque<Datagram>,
+}
+
+impl<C> FecConnection<C>
+where
+    C: Connection + Unpin,
+{
+    /// Wraps `inner`, sending a parity datagram after every `block_size`
+    /// unordered unreliable datagrams.
+    pub fn new(inner: C, block_size: u8) -> Self {
+        assert!(block_size > 0, "FEC blocks must hold at least one datagram");
+        Self {
+            inner,
+            block_size,
+            encoder: Encoder::default(),
+            decoder: Decoder::default(),
+            outgoing: VecDeque::new(),
+            incoming: VecDeque::new(),
+        }
+    }
+
+    fn poll_drain_outgoing(&mut self, ctx: &mut Context) -> Poll<Result<()>> {
+        while !self.outgoing.is_empty() {
+            match Pin::new(&mut self.inner).poll_ready(ctx) {
+                Poll::Ready(Ok(())) => {}
+                Poll::Ready(Err(e)) => return Poll::Ready(Err(e)),
+                Poll::Pending => return Poll::Pending,
+            }
+            let cmd = self.outgoing.pop_front().unwrap();
+            Pin::new(&mut self.inner).start_send(cmd)?;
+        }
+
+        Poll::Ready(Ok(()))
+    }
+}
+
+impl<C> Connection for FecConnection<C>
+where
+    C: Connection + Unpin,
+{
+    fn ready(&mut self) -> LocalBoxFuture<'_, Result<()>> {
+        self.inner.ready()
+    }
+
+    fn wire_bytes_sent(&self) -> Option<Arc<AtomicU64>> {
+        self.inner.wire_bytes_sent()
+    }
+
+    fn peer_addr(&self) -> Option<std::net::SocketAddr> {
+        self.inner.peer_addr()
+    }
+
+    fn peer_finished(&self) -> bool {
+        self.incoming.is_empty() && self.inner.peer_finished()
+    }
+
+    fn transport_state(&self) -> TransportState {
+        self.inner.transport_state()
+    }
+
+    fn high_water_marks(&self) -> HighWaterMarks {
+        self.inner.high_water_marks()
+    }
+
+    fn set_max_pacing_rate(&self, bytes_per_second: u64) -> bool {
+        self.inner.set_max_pacing_rate(bytes_per_second)
+    }
+
+    fn pause_stream(&mut self, stream_id: StreamId) -> bool {
+        self.inner.pause_stream(stream_id)
+    }
+
+    fn resume_stream(&mut self, stream_id: StreamId) {
+        self.inner.resume_stream(stream_id)
+    }
+
+    fn is_paused(&self, stream_id: StreamId) -> bool {
+        self.inner.is_paused(stream_id)
+    }
+}
+
+impl<C> Sink<SendCmd> for FecConnection<C>
+where
+    C: Connection + Unpin,
+{
+    type Error = Box<dyn std::error::Error>;
+    fn poll_ready(
+        mut self: Pin<&mut Self>,
+        ctx: &mut Context,
+    ) -> Poll<Result<()>> {
+        match self.poll_drain_outgoing(ctx) {
+            Poll::Ready(Ok(())) => Pin::new(&mut self.inner).poll_ready(ctx),
+            other => other,
+        }
+    }
+    fn start_send(mut self: Pin<&mut Self>, item: SendCmd) -> Result<()> {
+        let delivery_mode = item.delivery_mode;
+        // The header is coded along with the data, so a rebuilt datagram
+        // gets its header back too.
+        let data = header::frame(item.header, item.data)?;
+        let frames = match delivery_mode {
+            DeliveryMode::UnreliableUnordered => {
+                let block_size = self.block_size;
+                self.encoder.encode(block_size, data)
+            }
+            _ => vec![FecFrame::Plain(data)],
+        };
+
+        // The datagram's events are those of the first frame it goes out in.
+        let mut notify = item.notify;
+        for frame in frames {
+            let cmd = SendCmd {
+                delivery_mode,
+                data: bincode::serialize(&frame)?,
+                notify: notify.take(),
+                ..SendCmd::default()
+            };
+            self.outgoing.push_back(cmd);
+        }
+
+        Ok(())
+    }
+    fn poll_flush(
+        mut self: Pin<&mut Self>,
+        ctx: &mut Context,
+    ) -> Poll<Result<()>> {
+        match self.poll_drain_outgoing(ctx) {
+            Poll::Ready(Ok(())) => Pin::new(&mut self.inner).poll_flush(ctx),
+            other => other,
+        }
+    }
+    fn poll_close(
+        mut self: Pin<&mut Self>,
+        ctx: &mut Context,
+    ) -> Poll<Result<()>> {
+        match self.poll_drain_outgoing(ctx) {
+            Poll::Ready(Ok(())) => Pin::new(&mut self.inner).poll_close(ctx),
+            other => other,
+        }
+    }
+}
+
+impl<C> Stream for FecConnection<C>
+where
+    C: Connection + Unpin,
+{
+    type Item = Result<Datagram>;
+    fn poll_next(
+        mut self: Pin<&mut Self>,
+        ctx: &mut Context,
+    ) -> Poll<Option<Self::Item>> {
+        loop {
+            if let Some(datagram) = self.incoming.pop_front() {
+                return Poll::Ready(Some(Ok(datagram)));
+            }
+
+            let wire_datagram = match Pin::new(&mut self.inner).poll_next(ctx) {
+                Poll::Ready(Some(Ok(wire_datagram))) => wire_datagram,
+                other => return other,
+            };
+
+            let frame: FecFrame =
+                match bincode::deserialize(&wire_datagram.data) {
+                    Ok(frame) => frame,
+                    Err(e) => return Poll::Ready(Some(Err(e.into()))),
+                };
+
+            let block_size = self.block_size;
+            let stream_position = wire_datagram.stream_position;
+            let surfaced = self.decoder.decode(block_size, frame);
+            for data in surfaced {
+                let (header, data) = match header::unframe(&data) {
+                    Ok(unframed) => unframed,
+                    Err(e) => return Poll::Ready(Some(Err(e))),
+                };
+                self.incoming.push_back(Datagram {
+                    stream_position,
+                    data,
+                    header,
+                });
+            }
+        }
+    }
+}
+
+impl<C> FusedStream for FecConnection<C>
+where
+    C: Connection + Unpin,
+{
+    fn is_terminated(&self) -> bool {
+        self.incoming.is_empty() && self.inner.is_terminated()
+    }
+}
+
+/// A server whose connections are wrapped with forward error correction.
+pub struct FecServer<S> {
+    inner: S,
+    fec: Fec,
+}
+
+impl<S> FecServer<S> {
+    pub fn new(inner: S, fec: Fec) -> Self {
+        Self { inner, fec }
+    }
+}
+
+impl<S, C> Server<FecConnection<C>> for FecServer<S>
+where
+    S: Server<C> + Unpin,
+    C: Connection + Unpin,
+{
+}
+
+impl<S, C> Stream for FecServer<S>
+where
+    S: Stream<Item = Result<C>> + Unpin,
+    C: Connection + Unpin,
+{
+    type Item = Result<FecConnection<C>>;
+    fn poll_next(
+        mut self: Pin<&mut Self>,
+        ctx: &mut Context,
+    ) -> Poll<Option<Self::Item>> {
+        let fec = self.fec;
+        Pin::new(&mut self.inner)
+            .poll_next(ctx)
+            .map(|connection| connection.map(|c| c.map(|c| fec.wrap(c))))
+    }
+}
+
+impl<S, C> FusedStream for FecServer<S>
+where
+    S: FusedStream<Item = Result<C>> + Unpin,
+    C: Connection + Unpin,
+{
+    fn is_terminated(&self) -> bool {
+        self.inner.is_terminated()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use futures::FutureExt;
+
+    /// Encodes `datagrams` in blocks of `block_size`, dropping the wire
+    /// frames at each of `lost`, and returns what the decoder surfaces.
+    fn round_trip(
+        block_size: u8,
+        datagrams: &[&[u8]],
+        lost: &[usize],
+    ) -> Vec<Vec<u8>> {
+        let mut encoder = Encoder::default();
+        let frames: Vec<FecFrame> = datagrams
+            .iter()
+            .flat_map(|data| encoder.encode(block_size, data.to_vec()))
+            .collect();
+
+        let mut decoder = Decoder::default();
+        frames
+            .into_iter()
+            .enumerate()
+            .filter(|(index, _)| !lost.contains(index))
+            .flat_map(|(_, frame)| decoder.decode(block_size, frame))
+            .collect()
+    }
+
+    #[test]
+    fn one_lost_datagram_is_rebuilt_from_parity() {
+        // The frames are a, bb, ccc, then the block's parity.
+        let surfaced = round_trip(3, &[b"a", b"bb", b"ccc"], &[1]);
+        assert_eq!(
+            surfaced,
+            vec![b"a".to_vec(), b"ccc".to_vec(), b"bb".to_vec()]
+        );
+    }
+
+    #[test]
+    fn a_full_block_needs_no_parity() {
+        let surfaced = round_trip(2, &[b"a", b"bb"], &[2]);
+        assert_eq!(surfaced, vec![b"a".to_vec(), b"bb".to_vec()]);
+    }
+
+    #[test]
+    fn two_losses_in_a_block_are_not_rebuilt() {
+        let surfaced = round_trip(3, &[b"a", b"bb", b"ccc"], &[0, 2]);
+        assert_eq!(surfaced, vec![b"bb".to_vec()]);
+    }
+
+    #[test]
+    fn losses_are_rebuilt_in_each_block() {
+        // Blocks of two send a, b, parity, c, d, parity.
+        let surfaced = round_trip(2, &[b"a", b"b", b"c", b"d"], &[0, 4]);
+        assert_eq!(
+            surfaced,
+            vec![b"b".to_vec(), b"a".to_vec(), b"c".to_vec(), b"d".to_vec()]
+        );
+    }
+
+    #[test]
+    fn fec_parses_block_sizes_and_code_rates() {
+        assert_eq!("4".parse::<Fec>().unwrap(), Fec { block_size: 4 });
+        assert_eq!("4/5".parse::<Fec>().unwrap(), Fec { block_size: 4 });
+        assert_eq!(Fec { block_size: 4 }.code_rate(), 0.8);
+        for src in &["0", "0/1", "4/6", "4/5/6"] {
+            assert!(src.parse::<Fec>().is_err(), "{} parsed", src);
+        }
+    }
+
+    #[async_std::test]
+    async fn lossy_links_lose_less_with_fec() {
+        use futures::{SinkExt, StreamExt};
+
+        // Blocks of one repeat every datagram, so any loss of one frame of
+        // the pair is rebuilt.
+        let fec = Fec { block_size: 1 };
+        let address = "127.0.0.1:47631".parse().unwrap();
+        let link = |random_loss| runner::LinkParams {
+            delay: 0.0,
+            jitter: 0.0,
+            random_loss,
+            rate_limit_kbps: 0,
+        };
+        let mut server = FecServer::new(
+            memory::MemoryServer::bind(address).await.unwrap(),
+            fec,
+        );
+        memory::impair(
+            address,
+            Some(memory::Impairments {
+                uplink: link(20.0),
+                downlink: link(0.0),
+                queue_limit: 1000,
+            }),
+        );
+        let client = memory::MemoryConnection::connect(address).await.unwrap();
+        let mut client = fec.wrap(client);
+        let server = server.next().await.unwrap().unwrap();
+        memory::impair(address, None);
+        for i in 0..200u8 {
+            client
+                .send(SendCmd {
+                    data: vec![i],
+                    delivery_mode: DeliveryMode::UnreliableUnordered,
+                    ..SendCmd::default()
+                })
+                .await
+                .unwrap();
+        }
+        client.close().await.unwrap();
+        let received = server.count().await;
+
+        // 200 datagrams lose about 40 of their frames to 20% loss, but only
+        // about 8 datagrams lose both copies.
+        assert!(received > 180, "{} of 200 datagrams received", received);
+    }
+
+    #[async_std::test]
+    async fn fec_waits_for_the_inner_connection_to_be_ready() {
+        let state = DeliveryMode::UnreliableSequenced(StreamId(1));
+        let (client, _server) = memory::MemoryConnection::pair(None);
+        // A paused stream holds two datagrams in a buffer of one, so the
+        // buffer is not ready until the stream resumes.
+        let mut buffer = send_buffer::SendBufferConnection::new(client, 1);
+        buffer.pause_stream(StreamId(1));
+        for data in 0..2 {
+            Pin::new(&mut buffer)
+                .start_send(SendCmd {
+                    data: vec![data],
+                    delivery_mode: state,
+                    ..SendCmd::default()
+                })
+                .unwrap();
+        }
+
+        let mut fec = FecConnection::new(buffer, 4);
+        assert!(fec.ready().now_or_never().is_none());
+
+        fec.resume_stream(StreamId(1));
+        fec.ready().await.unwrap();
+    }
+}
@@ -0,0 +1,246 @@
+//! A connection adapter which flushes sends no later than a deadline.
+//!
+//! A transport which buffers writes so it can coalesce them only puts them on
+//! the wire when it is flushed. `FlushDeadline` bounds the latency that adds
+//! for an application which does not flush after every send: once the oldest
+//! unflushed datagram has waited for the deadline, the connection flushes
+//! itself.
+//!
+//! The flush is driven from the connection's own polls, so the connection
+//! must be polled for datagrams or readiness while sends are buffered, as an
+//! application reading its echoes does. The deadline's timer wakes whoever
+//! last polled it.
+//!
+//! The halves of a connection split with `StreamExt::split` hold back each
+//! send until the next poll of the sink half, so a split connection should
+//! be wrapped before it is split and its sink polled for readiness after a
+//! send.
+//!
+//! The client flushes its sends by a deadline with `--flush-deadline-ms`.
+
+use crate::*;
+
+use futures::{
+    future::LocalBoxFuture,
+    stream::FusedStream,
+    task::{Context, Poll},
+    Future, Sink, Stream,
+};
+use futures_timer::Delay;
+use std::{
+    pin::Pin,
+    sync::{atomic::AtomicU64, Arc},
+    time::Duration,
+};
+
+/// Wraps a connection to flush it once its oldest unflushed send has waited
+/// for the deadline.
+pub struct FlushDeadline<C> {
+    inner: C,
+    deadline: Duration,
+    /// Fires when the oldest unflushed datagram reaches the deadline. `None`
+    /// while nothing is waiting to be flushed.
+    timer: Option<Delay>,
+    /// Set once the timer fires, until the flush it calls for completes.
+    due: bool,
+}
+
+impl<C> FlushDeadline<C>
+where
+    C: Connection + Unpin,
+{
+    /// Wraps `inner`, flushing any send which has waited `deadline` without
+    /// a flush.
+    pub fn new(inner: C, deadline: Duration) -> Self {
+        Self {
+            inner,
+            deadline,
+            timer: None,
+            due: false,
+        }
+    }
+
+    pub fn into_inner(self) -> C {
+        self.inner
+    }
+
+    fn flushed(&mut self) {
+        self.timer = None;
+        self.due = false;
+    }
+
+    /// Flushes the connection if the deadline has passed.
+    fn poll_deadline(&mut self, ctx: &mut Context) -> Poll<Result<()>> {
+        if let Some(timer) = &mut self.timer {
+            if Pin::new(timer).poll(ctx).is_ready() {
+                self.due = true;
+            }
+        }
+        if !self.due {
+            return Poll::Ready(Ok(()));
+        }
+
+        match Pin::new(&mut self.inner).poll_flush(ctx) {
+            Poll::Ready(Ok(())) => {
+                self.flushed();
+                Poll::Ready(Ok(()))
+            }
+            other => other,
+        }
+    }
+}
+
+impl<C> Connection for FlushDeadline<C>
+where
+    C: Connection + Unpin,
+{
+    fn ready(&mut self) -> LocalBoxFuture<'_, Result<()>> {
+        self.inner.ready()
+    }
+
+    fn wire_bytes_sent(&self) -> Option<Arc<AtomicU64>> {
+        self.inner.wire_bytes_sent()
+    }
+
+    fn peer_addr(&self) -> Option<std::net::SocketAddr> {
+        self.inner.peer_addr()
+    }
+
+    fn peer_finished(&self) -> bool {
+        self.inner.peer_finished()
+    }
+
+    fn transport_state(&self) -> TransportState {
+        self.inner.transport_state()
+    }
+
+    fn high_water_marks(&self) -> HighWaterMarks {
+        self.inner.high_water_marks()
+    }
+
+    fn set_max_pacing_rate(&self, bytes_per_second: u64) -> bool {
+        self.inner.set_max_pacing_rate(bytes_per_second)
+    }
+
+    fn pause_stream(&mut self, stream_id: StreamId) -> bool {
+        self.inner.pause_stream(stream_id)
+    }
+
+    fn resume_stream(&mut self, stream_id: StreamId) {
+        self.inner.resume_stream(stream_id)
+    }
+
+    fn is_paused(&self, stream_id: StreamId) -> bool {
+        self.inner.is_paused(stream_id)
+    }
+}
+
+impl<C> Sink<SendCmd> for FlushDeadline<C>
+where
+    C: Connection + Unpin,
+{
+    type Error = Box<dyn std::error::Error>;
+    fn poll_ready(
+        mut self: Pin<&mut Self>,
+        ctx: &mut Context,
+    ) -> Poll<Result<()>> {
+        if let Poll::Ready(Err(e)) = self.poll_deadline(ctx) {
+            return Poll::Ready(Err(e));
+        }
+        Pin::new(&mut self.inner).poll_ready(ctx)
+    }
+    fn start_send(mut self: Pin<&mut Self>, item: SendCmd) -> Result<()> {
+        if self.timer.is_none() {
+            self.timer = Some(Delay::new(self.deadline));
+        }
+        Pin::new(&mut self.inner).start_send(item)
+    }
+    fn poll_flush(
+        mut self: Pin<&mut Self>,
+        ctx: &mut Context,
+    ) -> Poll<Result<()>> {
+        let flushed = Pin::new(&mut self.inner).poll_flush(ctx);
+        if let Poll::Ready(Ok(())) = flushed {
+            self.flushed();
+        }
+        flushed
+    }
+    fn poll_close(
+        mut self: Pin<&mut Self>,
+        ctx: &mut Context,
+    ) -> Poll<Result<()>> {
+        let closed = Pin::new(&mut self.inner).poll_close(ctx);
+        if let Poll::Ready(Ok(())) = closed {
+            self.flushed();
+        }
+        closed
+    }
+}
+
+impl<C> Stream for FlushDeadline<C>
+where
+    C: Connection + Unpin,
+{
+    type Item = Result<Datagram>;
+    fn poll_next(
+        mut self: Pin<&mut Self>,
+        ctx: &mut Context,
+    ) -> Poll<Option<Self::Item>> {
+        if let Poll::Ready(Err(e)) = self.poll_deadline(ctx) {
+            return Poll::Ready(Some(Err(e)));
+        }
+        Pin::new(&mut self.inner).poll_next(ctx)
+    }
+}
+
+impl<C> FusedStream for FlushDeadline<C>
+where
+    C: Connection + Unpin,
+{
+    fn is_terminated(&self) -> bool {
+        self.inner.is_terminated()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{memory::MemoryConnection, send_buffer::SendBufferConnection};
+    use futures::{
+        future::{self, Either},
+        FutureExt, SinkExt, StreamExt,
+    };
+    use std::time::Instant;
+
+    #[async_std::test]
+    async fn unflushed_sends_are_flushed_by_the_deadline() {
+        // The send buffer holds sends until it is flushed, as a coalescing
+        // transport would.
+        let (client, mut server) = MemoryConnection::pair(None);
+        let mut client = FlushDeadline::new(
+            SendBufferConnection::new(client, 8),
+            Duration::from_millis(5),
+        );
+        let sent = Instant::now();
+        client
+            .feed(SendCmd {
+                data: b"a".to_vec(),
+                delivery_mode: DeliveryMode::ReliableOrdered(StreamId(1)),
+                ..SendCmd::default()
+            })
+            .await
+            .unwrap();
+        assert!(server.next().now_or_never().is_none());
+
+        // Reading from the connection drives the deadline, as the client
+        // reading its echoes does.
+        let arrived = match future::select(client.next(), server.next()).await {
+            Either::Right((datagram, _)) => datagram.unwrap().unwrap(),
+            Either::Left(_) => panic!("the client received a datagram"),
+        };
+        let waited = sent.elapsed();
+        assert_eq!(arrived.data, b"a");
+        assert!(waited >= Duration::from_millis(5), "{:?}", waited);
+        assert!(waited < Duration::from_millis(50), "{:?}", waited);
+    }
+}
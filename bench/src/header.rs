@@ -0,0 +1,63 @@
+//! Carries datagram headers over transports which only move bytes.
+//!
+//! A frame starts with a tag byte. `0` means the rest of the frame is the
+//! payload. `1` means a bincode serialized header follows, then the payload.
+
+use crate::*;
+
+const NO_HEADER: u8 = 0;
+const WITH_HEADER: u8 = 1;
+
+/// Packs `header` and `data` into one frame.
+pub fn frame(header: Option<Vec<u8>>, data: Vec<u8>) -> Result<Vec<u8>> {
+    let mut frame = match header {
+        Some(header) => {
+            let mut frame = vec![WITH_HEADER];
+            bincode::serialize_into(&mut frame, &header)?;
+            frame
+        }
+        None => Vec::with_capacity(data.len() + 1),
+    };
+    if frame.is_empty() {
+        frame.push(NO_HEADER);
+    }
+    frame.extend_from_slice(&data);
+    Ok(frame)
+}
+
+/// Splits a frame made by `frame` back into its header and data. An empty
+/// frame is an empty datagram without a header.
+pub fn unframe(frame: &[u8]) -> Result<(Option<Vec<u8>>, Vec<u8>)> {
+    match frame.split_first() {
+        None => Ok((None, vec![])),
+        Some((&NO_HEADER, data)) => Ok((None, data.to_vec())),
+        Some((&WITH_HEADER, rest)) => {
+            let header: Vec<u8> = bincode::deserialize(rest)?;
+            let header_len = bincode::serialized_size(&header)? as usize;
+            Ok((Some(header), rest[header_len..].to_vec()))
+        }
+        Some((tag, _)) => Err(format!("unknown frame tag {}", tag).into()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn frames_unframe_to_their_header_and_data() {
+        let cases = vec![
+            (Some(b"type=input".to_vec()), b"payload".to_vec()),
+            (Some(vec![]), b"payload".to_vec()),
+            (Some(b"keepalive".to_vec()), vec![]),
+            (None, b"payload".to_vec()),
+            (None, vec![]),
+        ];
+        for (header, data) in cases {
+            let frame = frame(header.clone(), data.clone()).unwrap();
+            assert_eq!(unframe(&frame).unwrap(), (header, data));
+        }
+        assert_eq!(unframe(&[]).unwrap(), (None, vec![]));
+        assert!(unframe(&[2, 0]).is_err());
+    }
+}
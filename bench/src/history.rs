@@ -0,0 +1,237 @@
+//! A SQLite database of results, for tracking them across sweeps.
+//!
+//! Every sweep appends one row per scenario and protocol to the `runs` table,
+//! so regressions can be found with SQL instead of by diffing CSVs. Rows from
+//! one sweep share its `recorded_at` time and the git commit it was built
+//! from, if it was run from a checkout.
+
+use crate::*;
+
+use rusqlite::{params, Connection};
+use std::{path::Path, process::Command, time::SystemTime};
+
+const SCHEMA: &str = "
+    CREATE TABLE IF NOT EXISTS runs (
+        id INTEGER PRIMARY KEY,
+        -- When the sweep started, in seconds since the unix epoch.
+        recorded_at INTEGER NOT NULL,
+        git_commit TEXT,
+        scenario TEXT NOT NULL,
+        protocol TEXT NOT NULL,
+        -- The scenario's transfers, network config, and transport config,
+        -- as JSON.
+        scenario_params TEXT NOT NULL,
+        mean_ms REAL,
+        deviation_ms REAL,
+        round_trip_p99_ms REAL,
+        interarrival_p50_ms REAL,
+        interarrival_p95_ms REAL,
+        interarrival_p99_ms REAL,
+        first_rtt_ms REAL,
+        handshake_ms REAL,
+        handshake_bytes REAL,
+        teardown_ms REAL,
+        reconnections INTEGER,
+        lost_to_reconnect INTEGER,
+        amplification REAL,
+        queueing_delay_ms REAL,
+        burst_ramp_ms REAL,
+        hol_blocking_ms REAL,
+        sla_violations INTEGER,
+        pushes_received INTEGER,
+        push_latency_ms REAL,
+        fairness REAL,
+        cpu_ms REAL,
+        syscalls INTEGER
+    );
+    CREATE INDEX IF NOT EXISTS runs_by_scenario
+        ON runs (scenario, protocol, recorded_at);
+";
+
+pub struct History {
+    connection: Connection,
+    recorded_at: i64,
+    git_commit: Option<String>,
+}
+
+impl History {
+    /// Opens the database at `path`, creating it and its schema if they do
+    /// not exist yet.
+    pub fn open(path: impl AsRef<Path>) -> Result<Self> {
+        let connection = Connection::open(path)?;
+        connection.execute_batch(SCHEMA)?;
+
+        let recorded_at = SystemTime::now()
+            .duration_since(SystemTime::UNIX_EPOCH)
+            .map(|since| since.as_secs() as i64)
+            .unwrap_or(0);
+        Ok(Self {
+            connection,
+            recorded_at,
+            git_commit: git_commit(),
+        })
+    }
+
+    /// Appends one protocol's run of a scenario. `scenario_params` describes
+    /// the scenario as JSON.
+    pub fn record(
+        &self,
+        scenario: &str,
+        scenario_params: &str,
+        protocol: Protocol,
+        report: &runner::Report,
+    ) -> Result<()> {
+        let summary = &report.client_summary;
+        let profile = report.profile.as_ref();
+        self.connection.execute(
+            "INSERT INTO runs (
+                recorded_at, git_commit, scenario, protocol, scenario_params,
+                mean_ms, deviation_ms, round_trip_p99_ms, interarrival_p50_ms,
+                interarrival_p95_ms, interarrival_p99_ms, first_rtt_ms,
+                handshake_ms, handshake_bytes, teardown_ms, reconnections,
+                lost_to_reconnect, amplification, queueing_delay_ms,
+                burst_ramp_ms, hol_blocking_ms, sla_violations,
+                pushes_received, push_latency_ms, fairness, cpu_ms, syscalls
+            ) VALUES (
+                ?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9, ?10, ?11, ?12, ?13, ?14,
+                ?15, ?16, ?17, ?18, ?19, ?20, ?21, ?22, ?23, ?24, ?25, ?26,
+                ?27
+            )",
+            params![
+                self.recorded_at,
+                self.git_commit,
+                scenario,
+                protocol.name(),
+                scenario_params,
+                summary.mean_ms,
+                summary.deviation_ms,
+                summary.round_trip_p99_ms,
+                summary.interarrival_p50_ms,
+                summary.interarrival_p95_ms,
+                summary.interarrival_p99_ms,
+                summary.first_rtt_ms,
+                summary.handshake_ms,
+                summary.handshake_bytes,
+                summary.teardown_ms,
+                summary.reconnections as i64,
+                summary.lost_to_reconnect as i64,
+                summary.amplification,
+                summary.queueing_delay_ms,
+                summary.mean_burst_ramp_ms(),
+                summary.hol_blocking_ms,
+                summary.sla_violations as i64,
+                summary.pushes_received as i64,
+                summary.push_latency_ms,
+                report.fairness,
+                profile.map(profile::ProcessCounters::cpu_ms),
+                profile
+                    .and_then(|profile| profile.syscalls)
+                    .map(|syscalls| syscalls as i64),
+            ],
+        )?;
+        Ok(())
+    }
+}
+
+/// The commit of the checkout the working directory is in, if any.
+fn git_commit() -> Option<String> {
+    let output = Command::new("git")
+        .args(["rev-parse", "HEAD"])
+        .output()
+        .ok()?;
+    if !output.status.success() {
+        return None;
+    }
+    String::from_utf8(output.stdout)
+        .ok()
+        .map(|commit| commit.trim().to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn report(mean_ms: f64) -> runner::Report {
+        let mut client_summary =
+            client::Summary::from(client::TripStats::default());
+        client_summary.mean_ms = mean_ms;
+        client_summary.sla_violations = 3;
+        runner::Report {
+            client_summary,
+            fairness: Some(0.75),
+            server_summary: None,
+            effective_network: None,
+            profile: None,
+        }
+    }
+
+    fn protocol() -> Protocol {
+        "memory".parse().unwrap()
+    }
+
+    #[test]
+    fn recorded_runs_read_back() {
+        let history = History::open(":memory:").unwrap();
+        history
+            .record("bulk", "{\"hertz\":60}", protocol(), &report(12.5))
+            .unwrap();
+
+        let row: (String, String, String, f64, i64, Option<f64>, i64) = history
+            .connection
+            .query_row(
+                "SELECT scenario, protocol, scenario_params, mean_ms,
+                            sla_violations, fairness, recorded_at
+                     FROM runs",
+                params![],
+                |row| {
+                    Ok((
+                        row.get(0)?,
+                        row.get(1)?,
+                        row.get(2)?,
+                        row.get(3)?,
+                        row.get(4)?,
+                        row.get(5)?,
+                        row.get(6)?,
+                    ))
+                },
+            )
+            .unwrap();
+        assert_eq!(
+            row,
+            (
+                String::from("bulk"),
+                String::from("memory"),
+                String::from("{\"hertz\":60}"),
+                12.5,
+                3,
+                Some(0.75),
+                history.recorded_at,
+            )
+        );
+    }
+
+    #[test]
+    fn reopening_keeps_the_schema_and_rows() {
+        let path = std::env::temp_dir()
+            .join(format!("bench-history-{}.sqlite", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+        for mean_ms in &[1.0, 2.0] {
+            History::open(&path)
+                .unwrap()
+                .record("bulk", "{}", protocol(), &report(*mean_ms))
+                .unwrap();
+        }
+
+        let history = History::open(&path).unwrap();
+        let means: Vec<f64> = history
+            .connection
+            .prepare("SELECT mean_ms FROM runs ORDER BY id")
+            .unwrap()
+            .query_map(params![], |row| row.get(0))
+            .unwrap()
+            .collect::<std::result::Result<_, _>>()
+            .unwrap();
+        assert_eq!(means, vec![1.0, 2.0]);
+        std::fs::remove_file(&path).unwrap();
+    }
+}
@@ -22,7 +22,12 @@ use futures::{
 use std::ffi::c_void;
 use std::os::raw::c_int;
 
+use std::collections::VecDeque;
 use std::pin::Pin;
+use std::sync::{
+    atomic::{AtomicU64, Ordering},
+    Arc, Mutex,
+};
 
 use std::task::{Context, Poll};
 use std::time::*;
@@ -87,7 +92,9 @@ impl KcpServer {
                 let client_port: u16 =
                     deserialize(client_port.data.as_slice())?;
 
-                let mut client_addr = tcp_connection.peer_addr();
+                let mut client_addr = tcp_connection
+                    .peer_addr()
+                    .expect("tcp connections know their peer");
                 client_addr.set_port(client_port);
 
                 Ok(KcpConnection::from_socket(
@@ -125,6 +132,10 @@ impl FusedStream for KcpServer {
 pub struct KcpConnection {
     #[allow(unused)]
     tcp_connection: tcp::TcpConnection,
+    wire_bytes_sent: Arc<AtomicU64>,
+    /// Copied out of the control block by the driver after each service.
+    telemetry: Arc<Mutex<Telemetry>>,
+    peer_addr: SocketAddr,
     receiver: mpsc::Receiver<Datagram>,
     sender:
         Pin<Box<dyn Sink<SendCmd, Error = Box<dyn std::error::Error>> + Unpin>>,
@@ -164,14 +175,27 @@ impl KcpConnection {
     ) -> Self {
         let (command_sink, command_stream) = mpsc::channel(100);
         let (datagram_sink, datagram_stream) = mpsc::channel(100);
+        let wire_bytes_sent = Arc::new(AtomicU64::new(0));
+        let telemetry = Arc::new(Mutex::new(Telemetry::default()));
 
         async_std::task::spawn(
-            Self::driver(mode, socket, peer, command_stream, datagram_sink)
-                .map(drop),
+            Self::driver(
+                mode,
+                socket,
+                peer,
+                command_stream,
+                datagram_sink,
+                wire_bytes_sent.clone(),
+                telemetry.clone(),
+            )
+            .map(drop),
         );
 
         Self {
             tcp_connection,
+            wire_bytes_sent,
+            telemetry,
+            peer_addr: peer,
             receiver: datagram_stream,
             sender: Pin::new(Box::new(command_sink.sink_err_into())),
         }
@@ -183,6 +207,8 @@ impl KcpConnection {
         peer: SocketAddr,
         mut command_stream: mpsc::Receiver<SendCmd>,
         datagram_sink: mpsc::Sender<Datagram>,
+        wire_bytes_sent: Arc<AtomicU64>,
+        telemetry: Arc<Mutex<Telemetry>>,
     ) -> Result<()> {
         socket.connect(peer).await?;
 
@@ -193,7 +219,10 @@ impl KcpConnection {
                 };
                 match async_std::task::block_on(socket.send(data)) {
                     Err(_) => -1,
-                    Ok(v) => v as i32,
+                    Ok(v) => {
+                        wire_bytes_sent.fetch_add(v as u64, Ordering::Relaxed);
+                        v as i32
+                    }
                 }
             };
         let cb = {
@@ -223,6 +252,9 @@ impl KcpConnection {
             datagram_sink,
             cb,
             sequence_number: 0,
+            telemetry,
+            queued_segments: 0,
+            notifications: VecDeque::new(),
         };
 
         let mut buffer = [0u8; 65535];
@@ -254,15 +286,18 @@ impl KcpConnection {
                             };
 
                             {
-                                let data = send_cmd.data.as_ptr() as *const i8;
+                                let SendCmd { header, data, notify, .. } = send_cmd;
+                                let frame = header::frame(header, data)?;
+                                let data = frame.as_ptr() as *const i8;
                                 let code = kcp::ikcp_send(
                                    cb.0,
                                    data,
-                                   send_cmd.data.len() as i32
+                                   frame.len() as i32
                                 );
                                 if code < 0 {
                                     panic!("kcp input failed: {:?}", code);
                                 }
+                                servicer.track_send(frame.len(), notify);
                             }
 
                             servicer.service().await;
@@ -276,6 +311,13 @@ impl KcpConnection {
 #[derive(Copy, Clone)]
 struct Cb(*mut kcp::ikcpcb);
 
+/// What the control block says about the connection, as last published.
+#[derive(Default)]
+struct Telemetry {
+    transport_state: TransportState,
+    high_water_marks: HighWaterMarks,
+}
+
 unsafe impl Send for Cb {}
 
 struct KcpServicer {
@@ -283,6 +325,20 @@ struct KcpServicer {
     datagram_sink: mpsc::Sender<Datagram>,
     cb: Cb,
     sequence_number: u32,
+    telemetry: Arc<Mutex<Telemetry>>,
+    /// How many segments have been queued with `ikcp_send`.
+    queued_segments: u32,
+    /// Notifiers of sends which have not been acked, in the order they were
+    /// sent.
+    notifications: VecDeque<PendingNotification>,
+}
+
+/// A send awaiting its `SendEvent`s.
+struct PendingNotification {
+    /// The sequence number KCP gives the send's last segment.
+    last_sn: u32,
+    notifier: SendNotifier,
+    sent: bool,
 }
 
 impl KcpServicer {
@@ -290,8 +346,79 @@ impl KcpServicer {
         Instant::now().duration_since(self.epoch).as_millis() as u32
     }
 
+    /// KCP counts its windows and buffers in segments, which are converted to
+    /// bytes at the segment size.
+    fn publish_telemetry(&self) {
+        let cb = unsafe { &*self.cb.0 };
+        let mss = u64::from(cb.mss);
+        let ms = |ms: i32| Duration::from_millis(ms.max(0) as u64);
+        if let Ok(mut telemetry) = self.telemetry.lock() {
+            let bytes_in_flight = u64::from(cb.nsnd_buf) * mss;
+            telemetry.transport_state = TransportState {
+                cwnd: Some(u64::from(cb.cwnd) * mss),
+                ssthresh: Some(u64::from(cb.ssthresh) * mss),
+                rwnd: Some(u64::from(cb.rmt_wnd) * mss),
+                bytes_in_flight: Some(bytes_in_flight),
+                send_queue_depth: Some(u64::from(cb.nsnd_que)),
+                srtt: Some(ms(cb.rx_srtt)),
+                rto: Some(ms(cb.rx_rto)),
+                dropped_sends: None,
+            };
+            telemetry.high_water_marks =
+                telemetry.high_water_marks.max(HighWaterMarks {
+                    bytes_in_flight: Some(bytes_in_flight),
+                    send_queue_depth: Some(u64::from(cb.nsnd_que)),
+                    reassembly_bytes: Some(u64::from(cb.nrcv_buf) * mss),
+                });
+        }
+    }
+
+    /// Tracks a datagram of `len` bytes just queued with `ikcp_send`. KCP
+    /// numbers the segments it splits datagrams into in the order they were
+    /// queued, so the number of the datagram's last segment is known before
+    /// KCP gives it out.
+    fn track_send(&mut self, len: usize, notify: Option<SendNotifier>) {
+        let mss = unsafe { (*self.cb.0).mss } as usize;
+        let segments = len.div_ceil(mss).max(1);
+        self.queued_segments =
+            self.queued_segments.wrapping_add(segments as u32);
+        if let Some(notifier) = notify {
+            self.notifications.push_back(PendingNotification {
+                last_sn: self.queued_segments.wrapping_sub(1),
+                notifier,
+                sent: false,
+            });
+        }
+    }
+
+    /// Reports the sends whose segments have all been put on the wire, which
+    /// KCP does as it numbers them, and those whose segments have all been
+    /// acked, which KCP tracks as the first unacked number.
+    fn notify_progress(&mut self) {
+        let cb = unsafe { &*self.cb.0 };
+        let passed = |sn: u32, next: u32| next.wrapping_sub(sn) as i32 > 0;
+        for pending in &mut self.notifications {
+            if !passed(pending.last_sn, cb.snd_nxt) {
+                break;
+            }
+            if !pending.sent {
+                pending.notifier.notify(SendEvent::Sent);
+                pending.sent = true;
+            }
+        }
+        while let Some(pending) = self.notifications.front() {
+            if !passed(pending.last_sn, cb.snd_una) {
+                break;
+            }
+            pending.notifier.notify(SendEvent::Acked);
+            self.notifications.pop_front();
+        }
+    }
+
     async fn service(&mut self) {
         unsafe { kcp::ikcp_update(self.cb.0, self.current_time_ms()) };
+        self.publish_telemetry();
+        self.notify_progress();
 
         let mut buffer = [0; 65535];
         #[allow(unused_assignments)]
@@ -302,12 +429,23 @@ impl KcpServicer {
             len = unsafe {
                 kcp::ikcp_recv(self.cb.0, buffer_ptr, buffer.len() as i32)
             };
-            len > 0
+            // Empty datagrams are messages too; only negative lengths mean
+            // there are none left.
+            len >= 0
         } {
+            let (header, data) =
+                match header::unframe(&buffer[0..(len as usize)]) {
+                    Ok(unframed) => unframed,
+                    Err(e) => {
+                        println!("dropping malformed message: {}", e);
+                        continue;
+                    }
+                };
             let _ = self
                 .datagram_sink
                 .send(Datagram {
-                    data: buffer[0..(len as usize)].to_vec(),
+                    data,
+                    header,
                     stream_position: Some(StreamPosition {
                         stream_id: StreamId(0),
                         index: StreamIndex::Ordinal(self.sequence_number),
@@ -319,7 +457,29 @@ impl KcpServicer {
     }
 }
 
-impl Connection for KcpConnection {}
+impl Connection for KcpConnection {
+    fn wire_bytes_sent(&self) -> Option<Arc<AtomicU64>> {
+        Some(self.wire_bytes_sent.clone())
+    }
+
+    fn peer_addr(&self) -> Option<SocketAddr> {
+        Some(self.peer_addr)
+    }
+
+    fn transport_state(&self) -> TransportState {
+        self.telemetry
+            .lock()
+            .map(|telemetry| telemetry.transport_state)
+            .unwrap_or_default()
+    }
+
+    fn high_water_marks(&self) -> HighWaterMarks {
+        self.telemetry
+            .lock()
+            .map(|telemetry| telemetry.high_water_marks)
+            .unwrap_or_default()
+    }
+}
 
 impl Sink<SendCmd> for KcpConnection {
     type Error = Box<dyn std::error::Error>;
@@ -398,3 +558,45 @@ where
 
     (trampoline::<F>, f as *const F as *mut c_void)
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[async_std::test]
+    async fn slow_start_opens_the_congestion_window() {
+        // KCP starts with a window of one segment: its default 1400 byte MTU
+        // less its 24 byte header.
+        const INITIAL_WINDOW: u64 = 1376;
+
+        let address = SocketAddr::from(([127, 0, 0, 1], 47725));
+        let mut server =
+            KcpServer::bind(KcpMode::Normal, address).await.unwrap();
+        let (peer, client) = future::join(
+            server.next(),
+            KcpConnection::connect(KcpMode::Normal, address),
+        )
+        .await;
+        let mut peer = peer.unwrap().unwrap();
+        let mut client = client.unwrap();
+
+        for id in 0..200u32 {
+            client
+                .send(SendCmd {
+                    data: vec![id as u8; 1000],
+                    delivery_mode: DeliveryMode::ReliableOrdered(StreamId(0)),
+                    ..SendCmd::default()
+                })
+                .await
+                .unwrap();
+        }
+        for _ in 0..200 {
+            peer.next().await.unwrap().unwrap();
+        }
+        // Give the last acks time to arrive and be published.
+        async_std::task::sleep(Duration::from_millis(50)).await;
+
+        let cwnd = client.transport_state().cwnd.unwrap();
+        assert!(cwnd > INITIAL_WINDOW, "cwnd {}", cwnd);
+    }
+}
@@ -4,45 +4,190 @@ use async_std::net::SocketAddr;
 use nhanh::*;
 use serde::{Deserialize, Serialize};
 
-use structopt::StructOpt;
+use std::{fmt, str::FromStr};
 
+pub mod clock;
 pub mod enet;
+pub mod estimator;
+pub mod fec;
+pub mod flush_deadline;
+pub mod header;
+pub mod history;
 pub mod kcp;
+pub mod memory;
+pub mod metrics;
+pub mod profile;
+pub mod registry;
+pub mod replay;
+pub mod send_buffer;
 pub mod tcp;
+pub mod tcp_multiplexed;
+pub mod tee;
+pub mod timings;
+pub mod tls;
 
 pub mod client;
 pub mod runner;
 pub mod server;
 
-pub const ALL_PROTOCOLS: [Protocol; 3] =
-    [Protocol::Tcp, Protocol::Enet, Protocol::Kcp];
-
 pub const ID_DO_NOT_RETURN: u64 = u64::max_value();
 
+/// Sent by an overloaded server to ask the client to slow down.
+pub const ID_SOURCE_QUENCH: u64 = ID_DO_NOT_RETURN - 1;
+
+/// Sent by a server which batches its echoes. The data is the batched echoes,
+/// serialized as a `Vec<BenchmarkDatagram>`.
+pub const ID_ECHO_BATCH: u64 = ID_DO_NOT_RETURN - 2;
+
+/// Sent by a client to have the server push to it on the stream it is sent
+/// on. The data is the `ServerPush`, serialized.
+pub const ID_PUSH_REQUEST: u64 = ID_DO_NOT_RETURN - 3;
+
 pub fn default_server_address() -> SocketAddr {
     "127.0.0.1:33333".parse().unwrap()
 }
 
 /// Returns a stream that yields `()` `hertz` times per second.
 pub fn ticker(hertz: u32) -> impl futures::stream::Stream<Item = ()> {
+    interval(std::time::Duration::from_secs(1) / hertz)
+}
+
+/// Returns a stream that yields `()` once every `period`.
+pub fn interval(
+    period: std::time::Duration,
+) -> impl futures::stream::Stream<Item = ()> {
     use futures::stream::StreamExt;
 
-    let tick_rate = std::time::Duration::from_secs(1) / hertz;
-    futures::stream::repeat(0u8)
-        .then(move |_| futures_timer::Delay::new(tick_rate))
+    futures::stream::repeat(0u8).then(move |_| clock::sleep(period))
+}
+
+/// A registered transport, identified by its name. See `registry`.
+#[derive(Serialize, Eq, PartialEq, Hash, Copy, Clone, Debug)]
+pub struct Protocol(&'static str);
+
+impl Protocol {
+    pub fn name(&self) -> &'static str {
+        self.0
+    }
+
+    pub fn transport(&self) -> registry::Transport {
+        registry::lookup(self.0)
+            .expect("protocols are only made from registered transports")
+    }
+}
+
+impl fmt::Display for Protocol {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(self.0)
+    }
 }
 
-#[derive(Serialize, Eq, PartialEq, Hash, Copy, Clone, Debug, StructOpt)]
-pub enum Protocol {
-    Tcp,
-    Enet,
-    Kcp,
-    KcpTurbo,
+impl FromStr for Protocol {
+    type Err = anyhow::Error;
+    fn from_str(src: &str) -> std::result::Result<Self, Self::Err> {
+        match registry::lookup(src) {
+            Some(transport) => Ok(transport.protocol()),
+            None => anyhow::bail!(
+                "expected one of {}, or tcp-multiplexed-<n> for n of at \
+                 least 1, got {:?}",
+                registry::names().join(", "),
+                src
+            ),
+        }
+    }
 }
 
 #[derive(Debug, Serialize, Deserialize)]
 pub struct BenchmarkDatagram {
+    /// The stream the datagram belongs to, which its position only carries
+    /// if it was sent on an ordered or sequenced stream.
+    pub stream_id: StreamId,
     pub delivery_mode: DeliveryMode,
     pub id: u64,
     pub data: Vec<u8>,
 }
+
+/// Traffic the server pushes to a client at a fixed rate, as a
+/// server-authoritative game pushes its state: the inverse of a client's
+/// transfer. Written `stream_id:payload:hertz:count`.
+///
+/// Pushed datagrams are numbered from 1, and the client acks each by sending
+/// its id back on the same stream. The data of each begins with the time the
+/// server sent it, from `wall_clock_micros`, so the client can tell how long
+/// it took to arrive. That is only meaningful when the two clocks agree, as
+/// they do when the server runs in the client's process.
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub struct ServerPush {
+    pub stream_id: StreamId,
+    /// Size of each pushed datagram's data, at least the 8 bytes of its send
+    /// time.
+    pub payload_size: usize,
+    pub hertz: u32,
+    pub count: usize,
+}
+
+impl FromStr for ServerPush {
+    type Err = anyhow::Error;
+    fn from_str(src: &str) -> std::result::Result<Self, Self::Err> {
+        let args: Vec<&str> = src.split(':').collect();
+        let (stream_id, payload_size, hertz, count) = match args.as_slice() {
+            [stream_id, payload_size, hertz, count] => (
+                stream_id.parse()?,
+                payload_size.parse()?,
+                hertz.parse()?,
+                count.parse()?,
+            ),
+            _ => anyhow::bail!(
+                "expected `stream_id:payload:hertz:count`, got {:?}",
+                src
+            ),
+        };
+        if hertz == 0 {
+            anyhow::bail!("cannot push at 0 hertz");
+        }
+
+        Ok(Self {
+            stream_id: StreamId(stream_id),
+            payload_size: usize::max(payload_size, 8),
+            hertz,
+            count,
+        })
+    }
+}
+
+/// Caps the egress of `socket` at `bytes_per_second` with
+/// `SO_MAX_PACING_RATE`, returning whether the kernel accepted it. TCP paces
+/// itself to the cap; other sockets are only paced under the `fq` qdisc.
+#[cfg(target_os = "linux")]
+pub(crate) fn set_max_pacing_rate(
+    socket: &impl std::os::unix::io::AsRawFd,
+    bytes_per_second: u64,
+) -> bool {
+    let result = unsafe {
+        libc::setsockopt(
+            socket.as_raw_fd(),
+            libc::SOL_SOCKET,
+            libc::SO_MAX_PACING_RATE,
+            &bytes_per_second as *const u64 as *const libc::c_void,
+            std::mem::size_of::<u64>() as libc::socklen_t,
+        )
+    };
+    result == 0
+}
+
+/// Kernel pacing is Linux's, so elsewhere no socket can be paced.
+#[cfg(not(target_os = "linux"))]
+pub(crate) fn set_max_pacing_rate<S>(
+    _socket: &S,
+    _bytes_per_second: u64,
+) -> bool {
+    false
+}
+
+/// Microseconds since the unix epoch, by this host's clock.
+pub fn wall_clock_micros() -> u64 {
+    std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .map(|since| since.as_micros() as u64)
+        .unwrap_or(0)
+}
@@ -4,14 +4,15 @@ use std::fs;
 use structopt::StructOpt;
 
 use float_ord::FloatOrd;
+use futures::stream::StreamExt;
 
 use nhanh::*;
 
 use serde::{
     ser::{SerializeStruct, Serializer},
-    Serialize,
+    Deserialize, Serialize,
 };
-use std::collections::HashMap;
+use std::{collections::HashMap, fmt::Write, time::Duration};
 
 fn local_address(port: u16) -> SocketAddr {
     format!("127.0.0.1:{}", port)
@@ -19,77 +20,327 @@ fn local_address(port: u16) -> SocketAddr {
         .expect("local address")
 }
 
-#[derive(Debug, Clone, Serialize)]
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
 struct NetcodeScenario {
-    scenario_name: &'static str,
-    #[serde(skip_serializing)]
+    #[serde(deserialize_with = "leaked")]
+    scenario_name: Name,
     transfers: Vec<client::Transfer>,
+    /// Traffic the server pushes to each client, which acks it.
+    pushes: Vec<ServerPush>,
+    /// How many clients run the transfers at once, sharing the link.
+    flows: usize,
+    /// The head-of-line blocking pair the scenario is half of, if any.
+    hol_pair: Option<HolPair>,
 }
 
-#[derive(Debug, Clone, Serialize)]
+/// A scenario's name, which lives as long as the process. serde borrows
+/// fields it sees written as `&str` from its input, which a scenario's name
+/// cannot be, so they are written as a `Name` and read by `leaked`.
+type Name = &'static str;
+
+/// Reads a name for the life of the process, as the built in scenarios'
+/// names are.
+fn leaked<'de, D>(deserializer: D) -> std::result::Result<Name, D::Error>
+where
+    D: serde::Deserializer<'de>,
+{
+    let name = String::deserialize(deserializer)?;
+    Ok(Box::leak(name.into_boxed_str()))
+}
+
+/// How a head-of-line blocking pair's half sends its workload. Both halves
+/// send the same datagrams over the same link, but `Ordered` sends them all on
+/// one ordered stream, so a loss holds up every datagram behind it, while
+/// `Spread` deals them round robin over several, so a loss only holds up its
+/// own stream.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+enum HolVariant {
+    Ordered,
+    Spread,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+struct HolPair {
+    #[serde(deserialize_with = "leaked")]
+    name: Name,
+    variant: HolVariant,
+}
+
+impl NetcodeScenario {
+    /// Appends `suffix` to the scenario's name, and to its pair's so the
+    /// pair's halves still match.
+    fn add_suffix(&mut self, suffix: &str) {
+        fn suffixed(name: &str, suffix: &str) -> &'static str {
+            Box::leak(Box::new(format!("{}-{}", name, suffix)))
+        }
+        self.scenario_name = suffixed(self.scenario_name, suffix);
+        if let Some(pair) = &mut self.hol_pair {
+            pair.name = suffixed(pair.name, suffix);
+        }
+    }
+
+    /// The size every transfer sends, if they all send the same size.
+    fn payload_size(&self) -> Option<usize> {
+        let size = self.transfers.first()?.payload.constant_size()?;
+        if self
+            .transfers
+            .iter()
+            .all(|transfer| transfer.payload.constant_size() == Some(size))
+        {
+            Some(size)
+        } else {
+            None
+        }
+    }
+}
+
+/// Settings of the transports themselves, as opposed to the traffic they
+/// carry or the network under them.
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+struct TransportConfig {
+    /// How long the client gathers sends before flushing them together. Zero
+    /// flushes every send.
+    coalesce_window_ms: u64,
+}
+
+impl TransportConfig {
+    fn flush_policy(&self) -> client::FlushPolicy {
+        match self.coalesce_window_ms {
+            0 => client::FlushPolicy::PerSend,
+            ms => client::FlushPolicy::Window(Duration::from_millis(ms)),
+        }
+    }
+}
+
+/// How the sweep measures every run, whatever its scenario.
+#[derive(Clone)]
+struct Measurement {
+    /// How many times each protocol runs each scenario.
+    repeat: usize,
+    metrics: Option<metrics::MetricsRegistry>,
+    /// How often to sample each run's buffers, if at all.
+    buffer_sample_interval_ms: Option<u64>,
+}
+
+/// A protocol's run of a scenario, or why the protocol could not run it.
+type Run = std::result::Result<runner::Report, runner::RunnerError>;
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
 struct Scenario {
     #[serde(flatten)]
     netcode_scenario: NetcodeScenario,
     #[serde(flatten)]
     network_config: runner::NetworkConfig,
+    #[serde(flatten)]
+    transport_config: TransportConfig,
 }
 
 impl Scenario {
-    async fn run(&self, port: u16, protocol: Protocol) -> client::Summary {
+    async fn run(
+        &self,
+        port: u16,
+        protocol: Protocol,
+        measurement: &Measurement,
+        skip_network_config: bool,
+        profile: bool,
+    ) -> Run {
         let server_address = local_address(port);
         let client_options = client::Options {
             address: server_address,
             protocol,
             transfers: self.netcode_scenario.transfers.clone(),
+            pushes: self.netcode_scenario.pushes.clone(),
+            replay: None,
+            flush_policy: self.transport_config.flush_policy(),
+            flush_deadline_ms: None,
+            reconnect: false,
+            adaptive_rate: false,
+            repeat: measurement.repeat,
+            reuse_connection: false,
+            consume_rate: None,
+            max_pacing_rate: None,
+            fec: None,
+            send_buffer: None,
+            drop_policy: send_buffer::DropPolicy::Backpressure,
+            rtt_budget_ms: None,
+            sla_cluster: None,
+            trace_timings: false,
+            stream_stats: false,
+            keep_trips: false,
+            buffer_sample_interval_ms: measurement.buffer_sample_interval_ms,
+            metrics: measurement.metrics.clone(),
         };
 
         let runner_options = runner::Options {
             network_config: self.network_config.clone(),
             client_options,
             start_server: true,
+            return_batch: 1,
+            stream_policies: vec![],
             output: None,
+            timings_output: None,
+            buffers_output: None,
+            metrics_addr: None,
+            skip_network_config,
+            profile,
+            flows: self.netcode_scenario.flows,
+            virtual_time: false,
         };
 
-        runner::runner_main(runner_options).await.expect(&format!(
-            "running scenario {} against protocol {:?}",
-            self.netcode_scenario.scenario_name, protocol
-        ))
+        match runner::runner_main(runner_options).await {
+            Ok(report) => Ok(report),
+            Err(e) => match e.downcast::<runner::RunnerError>() {
+                Ok(e) => Err(*e),
+                Err(e) => panic!(
+                    "running scenario {} against protocol {}: {}",
+                    self.netcode_scenario.scenario_name, protocol, e
+                ),
+            },
+        }
+    }
+
+    /// Runs up to `jobs` protocols at once, each against its own server port.
+    ///
+    /// The interface is configured once for every port, giving each server
+    /// its own queues so the runs do not share a rate limit. They still share
+    /// the host's CPU. Runs off the network leave the interface alone.
+    async fn run_concurrently(
+        &self,
+        runs: &[(u16, Protocol)],
+        jobs: usize,
+        measurement: &Measurement,
+    ) -> (HashMap<Protocol, Run>, Option<runner::EffectiveNetwork>) {
+        let configure_network = runs
+            .iter()
+            .any(|(_, protocol)| protocol.transport().network);
+        let mut effective_network = None;
+        if configure_network {
+            let ports: Vec<u16> = runs.iter().map(|(port, _)| *port).collect();
+            self.network_config.reset();
+            self.network_config.apply(&ports);
+            // Every port gets the same settings, so the first speaks for all.
+            effective_network = self.network_config.read_back(0);
+        }
+
+        let reports = futures::stream::iter(runs)
+            .map(|(port, protocol)| async move {
+                let report =
+                    self.run(*port, *protocol, measurement, true, false).await;
+                (*protocol, report)
+            })
+            .buffer_unordered(jobs)
+            .collect()
+            .await;
+
+        if configure_network {
+            self.network_config.reset();
+        }
+        (reports, effective_network)
     }
 }
 
 #[derive(Debug)]
 struct Comparison {
     scenario: Scenario,
+    /// The netem settings the scenario actually ran under, if they could be
+    /// read back.
+    effective_network: Option<runner::EffectiveNetwork>,
     reports: HashMap<Protocol, client::Summary>,
-    least_latent: Protocol,
-    least_variant: Protocol,
+    /// Why each protocol which could not run the scenario could not.
+    unsupported: HashMap<Protocol, String>,
+    /// What each protocol's run cost the process, if the runs were profiled.
+    profiles: HashMap<Protocol, profile::ProcessCounters>,
+    /// How fairly each protocol's flows shared the link, if the scenario ran
+    /// several.
+    fairness: HashMap<Protocol, f64>,
+    /// The least latent and least variant protocols, unless none could run
+    /// the scenario.
+    least_latent: Option<Protocol>,
+    least_variant: Option<Protocol>,
+    /// Protocols whose mean round trip is not significantly longer than the
+    /// least latent's, as their confidence intervals overlap.
+    tied_with_least_latent: Vec<Protocol>,
 }
 
 impl Comparison {
     fn from_reports(
         scenario: Scenario,
-        reports: HashMap<Protocol, client::Summary>,
+        effective_network: Option<runner::EffectiveNetwork>,
+        runs: HashMap<Protocol, Run>,
     ) -> Self {
-        let (least_latent, least_variant) = {
-            let (least_latent, _) = reports
-                .iter()
-                .min_by_key(|(_, report)| FloatOrd(report.mean_ms))
-                .unwrap();
-            let (least_variant, _) = reports
-                .iter()
-                .min_by_key(|(_, report)| FloatOrd(report.deviation_ms))
-                .unwrap();
+        let mut reports = HashMap::new();
+        let mut unsupported = HashMap::new();
+        let mut profiles = HashMap::new();
+        let mut fairness = HashMap::new();
+        for (protocol, run) in runs {
+            let run = match run {
+                Ok(run) => run,
+                Err(runner::RunnerError::Unsupported { reason, .. }) => {
+                    unsupported.insert(protocol, reason);
+                    continue;
+                }
+            };
+            if let Some(profile) = run.profile {
+                profiles.insert(protocol, profile);
+            }
+            if let Some(index) = run.fairness {
+                fairness.insert(protocol, index);
+            }
+            reports.insert(protocol, run.client_summary);
+        }
 
-            (*least_latent, *least_variant)
-        };
+        // A protocol which measured no round trips has no latency to rank.
+        let least_latent = reports
+            .iter()
+            .filter(|(_, report)| report.round_trips() > 0)
+            .min_by_key(|(_, report)| FloatOrd(report.mean_ms))
+            .map(|(protocol, _)| *protocol);
+        let least_variant = reports
+            .iter()
+            .filter(|(_, report)| report.round_trips() > 1)
+            .min_by_key(|(_, report)| FloatOrd(report.deviation_ms))
+            .map(|(protocol, _)| *protocol);
+        let best_ci =
+            least_latent.and_then(|protocol| reports[&protocol].mean_ms_ci);
+        let mut tied_with_least_latent: Vec<Protocol> = reports
+            .iter()
+            .filter(|(protocol, report)| {
+                Some(**protocol) != least_latent
+                    && matches!(
+                        (best_ci, report.mean_ms_ci),
+                        (Some(best), Some(ci)) if best.overlaps(&ci)
+                    )
+            })
+            .map(|(protocol, _)| *protocol)
+            .collect();
+        tied_with_least_latent.sort_by_key(|protocol| protocol.name());
 
         Self {
             scenario,
+            effective_network,
             reports,
+            unsupported,
+            profiles,
+            fairness,
             least_latent,
             least_variant,
+            tied_with_least_latent,
         }
     }
+
+    /// Every protocol the scenario was tried against, whether or not it
+    /// could run it, in a stable order.
+    fn protocols(&self) -> Vec<Protocol> {
+        let mut protocols: Vec<Protocol> = self
+            .reports
+            .keys()
+            .chain(self.unsupported.keys())
+            .copied()
+            .collect();
+        protocols.sort_by_key(|protocol| protocol.name());
+        protocols
+    }
 }
 
 impl Serialize for Comparison {
@@ -100,11 +351,15 @@ impl Serialize for Comparison {
     where
         S: Serializer,
     {
-        let network_config_fields = 6;
-        let report_fields = 2;
-        let summary_fields = 2;
+        let network_config_fields = 12;
+        let effective_network_fields = 14;
+        let transport_config_fields = 1;
+        let report_fields = 21;
+        let summary_fields = 3;
         let total_fields = network_config_fields
-            + report_fields * self.reports.len()
+            + effective_network_fields
+            + transport_config_fields
+            + report_fields * self.protocols().len()
             + summary_fields;
 
         let mut state =
@@ -113,42 +368,219 @@ impl Serialize for Comparison {
         let cfg = &self.scenario.network_config;
 
         // Conditions
-        state.serialize_field("network_delay_ms", &cfg.delay)?;
-        state.serialize_field("network_jitter_ms", &cfg.delay)?;
+        state.serialize_field(
+            "payload_size",
+            &self.scenario.netcode_scenario.payload_size(),
+        )?;
+        for (direction, link) in
+            &[("uplink", cfg.uplink()), ("downlink", cfg.downlink())]
+        {
+            state.serialize_field(
+                Box::leak(Box::new(format!("network_{}_delay_ms", direction))),
+                &link.delay,
+            )?;
+            state.serialize_field(
+                Box::leak(Box::new(format!("network_{}_jitter_ms", direction))),
+                &link.jitter,
+            )?;
+            state.serialize_field(
+                Box::leak(Box::new(format!(
+                    "network_{}_random_loss",
+                    direction
+                ))),
+                &link.random_loss,
+            )?;
+            state.serialize_field(
+                Box::leak(Box::new(format!(
+                    "network_{}_rate_limit_kbits",
+                    direction
+                ))),
+                &link.rate_limit_kbps,
+            )?;
+        }
         state.serialize_field(
             "network_delay_correlation",
             &cfg.delay_correlation,
         )?;
-        state.serialize_field("network_random_loss", &cfg.random_loss)?;
         state.serialize_field(
             "network_random_loss_correlation",
             &cfg.random_loss_correlation,
         )?;
+        state.serialize_field("network_packet_limit", &cfg.packet_limit)?;
+        let effective = self.effective_network.as_ref();
+        for (direction, settings) in &[
+            ("uplink", effective.map(|effective| effective.uplink)),
+            ("downlink", effective.map(|effective| effective.downlink)),
+        ] {
+            let field = |name: &str| -> &'static str {
+                Box::leak(Box::new(format!(
+                    "effective_network_{}_{}",
+                    direction, name
+                )))
+            };
+            state.serialize_field(
+                field("delay_ms"),
+                &settings.map(|s| s.delay_ms),
+            )?;
+            state.serialize_field(
+                field("jitter_ms"),
+                &settings.map(|s| s.jitter_ms),
+            )?;
+            state.serialize_field(
+                field("delay_correlation"),
+                &settings.map(|s| s.delay_correlation),
+            )?;
+            state.serialize_field(
+                field("random_loss"),
+                &settings.map(|s| s.random_loss),
+            )?;
+            state.serialize_field(
+                field("random_loss_correlation"),
+                &settings.map(|s| s.random_loss_correlation),
+            )?;
+            state.serialize_field(
+                field("rate_limit_kbits"),
+                &settings.map(|s| s.rate_limit_kbps),
+            )?;
+            state.serialize_field(
+                field("packet_limit"),
+                &settings.map(|s| s.packet_limit),
+            )?;
+        }
         state.serialize_field(
-            "network_rate_limit_kbits",
-            &cfg.rate_limit_kbps,
+            "coalesce_window_ms",
+            &self.scenario.transport_config.coalesce_window_ms,
         )?;
 
         // Results
-        for (protocol, report) in &self.reports {
+        for protocol in &self.protocols() {
+            let report = self.reports.get(protocol);
+            let profile = self.profiles.get(protocol);
+            state.serialize_field(
+                Box::leak(Box::new(format!("{}_unsupported", protocol))),
+                &self.unsupported.get(protocol),
+            )?;
+            state.serialize_field(
+                Box::leak(Box::new(format!("{}_mean_round_trip_ms", protocol))),
+                &report.map(|report| report.mean_ms),
+            )?;
+            state.serialize_field(
+                Box::leak(Box::new(format!(
+                    "{}_round_trip_deviation_ms",
+                    protocol
+                ))),
+                &report.map(|report| report.deviation_ms),
+            )?;
+            state.serialize_field(
+                Box::leak(Box::new(format!(
+                    "{}_mean_round_trip_ci95_ms",
+                    protocol
+                ))),
+                &report.and_then(|report| {
+                    report.mean_ms_ci.map(|ci| ci.half_width)
+                }),
+            )?;
+            state.serialize_field(
+                Box::leak(Box::new(format!(
+                    "{}_throughput_bytes_per_second",
+                    protocol
+                ))),
+                &report.map(|report| match report.throughput_ci {
+                    Some(ci) => ci.mean,
+                    None => report.throughput_bytes_per_second(),
+                }),
+            )?;
+            state.serialize_field(
+                Box::leak(Box::new(format!(
+                    "{}_throughput_ci95_bytes_per_second",
+                    protocol
+                ))),
+                &report.and_then(|report| {
+                    report.throughput_ci.map(|ci| ci.half_width)
+                }),
+            )?;
             state.serialize_field(
                 Box::leak(Box::new(format!(
-                    "{:?}_mean_round_trip_ms",
+                    "{}_interarrival_p50_ms",
                     protocol
                 ))),
-                &report.mean_ms,
+                &report.map(|report| report.interarrival_p50_ms),
             )?;
             state.serialize_field(
                 Box::leak(Box::new(format!(
-                    "{:?}_round_trip_deviation_ms",
+                    "{}_interarrival_p95_ms",
                     protocol
                 ))),
-                &report.deviation_ms,
+                &report.map(|report| report.interarrival_p95_ms),
+            )?;
+            state.serialize_field(
+                Box::leak(Box::new(format!(
+                    "{}_interarrival_p99_ms",
+                    protocol
+                ))),
+                &report.map(|report| report.interarrival_p99_ms),
+            )?;
+            state.serialize_field(
+                Box::leak(Box::new(format!("{}_first_rtt_ms", protocol))),
+                &report.map(|report| report.first_rtt_ms),
+            )?;
+            state.serialize_field(
+                Box::leak(Box::new(format!("{}_handshake_ms", protocol))),
+                &report.map(|report| report.handshake_ms),
+            )?;
+            state.serialize_field(
+                Box::leak(Box::new(format!("{}_handshake_bytes", protocol))),
+                &report.map(|report| report.handshake_bytes),
+            )?;
+            state.serialize_field(
+                Box::leak(Box::new(format!("{}_teardown_ms", protocol))),
+                &report.map(|report| report.teardown_ms),
+            )?;
+            state.serialize_field(
+                Box::leak(Box::new(format!("{}_amplification", protocol))),
+                &report.map(|report| report.amplification),
+            )?;
+            state.serialize_field(
+                Box::leak(Box::new(format!("{}_queueing_delay_ms", protocol))),
+                &report.map(|report| report.queueing_delay_ms),
+            )?;
+            state.serialize_field(
+                Box::leak(Box::new(format!("{}_burst_ramp_ms", protocol))),
+                &report.and_then(client::Summary::mean_burst_ramp_ms),
+            )?;
+            state.serialize_field(
+                Box::leak(Box::new(format!("{}_hol_blocking_ms", protocol))),
+                &report.map(|report| report.hol_blocking_ms),
+            )?;
+            state.serialize_field(
+                Box::leak(Box::new(format!("{}_push_latency_ms", protocol))),
+                &report.map(|report| report.push_latency_ms),
+            )?;
+            state.serialize_field(
+                Box::leak(Box::new(format!("{}_fairness", protocol))),
+                &self.fairness.get(protocol),
+            )?;
+            state.serialize_field(
+                Box::leak(Box::new(format!("{}_cpu_ms", protocol))),
+                &profile.map(|profile| profile.cpu_ms()),
+            )?;
+            state.serialize_field(
+                Box::leak(Box::new(format!("{}_syscalls", protocol))),
+                &profile.and_then(|profile| profile.syscalls),
             )?;
         }
 
         state.serialize_field("least_latent", &self.least_latent)?;
         state.serialize_field("least_variant", &self.least_variant)?;
+        state.serialize_field(
+            "tied_with_least_latent",
+            &self
+                .tied_with_least_latent
+                .iter()
+                .map(Protocol::name)
+                .collect::<Vec<&str>>()
+                .join(" "),
+        )?;
 
         state.end()
     }
@@ -160,61 +592,338 @@ struct Report {
     ping_deviation_ms: f64,
 }
 
-#[derive(Debug, Default)]
+/// One scenario's row in the summary table.
+struct TableRow {
+    scenario: &'static str,
+    /// Mean and 99th percentile round trip of each protocol.
+    round_trips: HashMap<Protocol, (f64, f64)>,
+    /// Protocols which could not run the scenario.
+    unsupported: Vec<Protocol>,
+    least_latent: Option<Protocol>,
+    tied_with_least_latent: Vec<Protocol>,
+    hol_pair: Option<HolPair>,
+}
+
+impl From<&Comparison> for TableRow {
+    fn from(comparison: &Comparison) -> Self {
+        Self {
+            scenario: comparison.scenario.netcode_scenario.scenario_name,
+            round_trips: comparison
+                .reports
+                .iter()
+                .map(|(protocol, report)| {
+                    (*protocol, (report.mean_ms, report.round_trip_p99_ms))
+                })
+                .collect(),
+            unsupported: comparison.unsupported.keys().copied().collect(),
+            least_latent: comparison.least_latent,
+            tied_with_least_latent: comparison.tied_with_least_latent.clone(),
+            hol_pair: comparison.scenario.netcode_scenario.hol_pair,
+        }
+    }
+}
+
+/// What head-of-line blocking cost a protocol in one pair: how much longer
+/// its mean round trip was with the pair's workload on one ordered stream than
+/// spread over several.
+#[derive(Debug, Serialize)]
+struct HolCost {
+    pair: &'static str,
+    protocol: Protocol,
+    ordered_mean_ms: f64,
+    spread_mean_ms: f64,
+    hol_cost_ms: f64,
+}
+
+/// The head-of-line blocking cost of each protocol which ran both halves of a
+/// pair among `rows`.
+fn hol_costs(rows: &[TableRow]) -> Vec<HolCost> {
+    let half = |pair: &str, variant| {
+        rows.iter().find(|row| {
+            matches!(
+                row.hol_pair,
+                Some(HolPair { name, variant: v }) if name == pair && v == variant
+            )
+        })
+    };
+
+    let mut costs = vec![];
+    for row in rows {
+        let pair = match row.hol_pair {
+            Some(HolPair {
+                name,
+                variant: HolVariant::Ordered,
+            }) => name,
+            _ => continue,
+        };
+        let spread = match half(pair, HolVariant::Spread) {
+            Some(spread) => spread,
+            None => continue,
+        };
+        let mut protocols: Vec<Protocol> =
+            row.round_trips.keys().copied().collect();
+        protocols.sort_by_key(|protocol| protocol.name());
+        for protocol in protocols {
+            let (ordered_mean_ms, _) = row.round_trips[&protocol];
+            let (spread_mean_ms, _) = match spread.round_trips.get(&protocol) {
+                Some(round_trips) => *round_trips,
+                None => continue,
+            };
+            costs.push(HolCost {
+                pair,
+                protocol,
+                ordered_mean_ms,
+                spread_mean_ms,
+                hol_cost_ms: ordered_mean_ms - spread_mean_ms,
+            });
+        }
+    }
+    costs
+}
+
+/// Renders `rows` as aligned text with a column per protocol. If the columns
+/// would not fit in `width`, the protocols are split across several tables.
+fn render_table(rows: &[TableRow], width: usize) -> String {
+    let mut protocols: Vec<Protocol> = rows
+        .iter()
+        .flat_map(|row| {
+            row.round_trips
+                .keys()
+                .chain(row.unsupported.iter())
+                .copied()
+        })
+        .collect();
+    protocols.sort_by_key(|protocol| protocol.name());
+    protocols.dedup();
+
+    let cell = |row: &TableRow, protocol: &Protocol| match row
+        .round_trips
+        .get(protocol)
+    {
+        Some((mean, p99)) => format!(
+            "{:.2}/{:.2}{}",
+            mean,
+            p99,
+            if Some(*protocol) == row.least_latent {
+                "*"
+            } else if row.tied_with_least_latent.contains(protocol) {
+                "~"
+            } else {
+                ""
+            }
+        ),
+        None if row.unsupported.contains(protocol) => {
+            String::from("unsupported")
+        }
+        None => String::from("-"),
+    };
+
+    let scenario_width = rows
+        .iter()
+        .map(|row| row.scenario.len())
+        .chain(std::iter::once("scenario".len()))
+        .max()
+        .unwrap_or(0);
+    let column_widths: Vec<usize> = protocols
+        .iter()
+        .map(|protocol| {
+            rows.iter()
+                .map(|row| cell(row, protocol).len())
+                .chain(std::iter::once(protocol.name().len()))
+                .max()
+                .unwrap_or(0)
+        })
+        .collect();
+
+    // Each table takes as many protocols as fit, and always at least one.
+    let mut tables: Vec<Vec<usize>> = vec![];
+    let mut used = scenario_width;
+    for (i, column_width) in column_widths.iter().enumerate() {
+        match tables.last_mut() {
+            Some(table) if used + 2 + column_width <= width => table.push(i),
+            _ => {
+                tables.push(vec![i]);
+                used = scenario_width;
+            }
+        }
+        used += 2 + column_width;
+    }
+
+    let mut out = String::new();
+    for table in tables {
+        let mut header = format!("{:<1$}", "scenario", scenario_width);
+        for &i in &table {
+            header +=
+                &format!("  {:>1$}", protocols[i].name(), column_widths[i]);
+        }
+        let _ = writeln!(out, "{}", header);
+        let _ = writeln!(out, "{}", "-".repeat(header.len()));
+        for row in rows {
+            let mut line = format!("{:<1$}", row.scenario, scenario_width);
+            for &i in &table {
+                line += &format!(
+                    "  {:>1$}",
+                    cell(row, &protocols[i]),
+                    column_widths[i]
+                );
+            }
+            let _ = writeln!(out, "{}", line);
+        }
+        let _ = writeln!(out);
+    }
+    let _ = writeln!(
+        out,
+        "Cells are mean/p99 round trip in ms. * marks the least latent, and ~ \
+         any not significantly more latent over repeated runs. unsupported \
+         marks a protocol which could not run the scenario."
+    );
+    out
+}
+
+/// Writes results to disk as they complete, so an interrupted sweep keeps
+/// everything that finished before it stopped.
 struct SimulationData {
-    scenarios:
-        HashMap<&'static str, (Comparison, HashMap<Protocol, client::Summary>)>,
+    dir: String,
+    comparison_writer: csv::Writer<fs::File>,
+    table_rows: Vec<TableRow>,
 }
 
 impl SimulationData {
-    fn write_out(self, dir: String) {
+    fn create(dir: String) -> Self {
         let _ = fs::create_dir(&dir);
 
         let comparison_writer =
             fs::File::create(format!("{}/comparison.csv", dir))
                 .expect("opening comparison output file");
-        let mut comparison_writer = csv::Writer::from_writer(comparison_writer);
-
-        for (scenario, (comparison, protocols)) in self.scenarios {
-            let _ = fs::create_dir(format!("{}/{}", dir, scenario));
-
-            comparison_writer
-                .serialize(comparison)
-                .expect("writing comparison to file");
-
-            for (protocol, summary) in protocols {
-                let writer = fs::File::create(format!(
-                    "{}/{}/{:?}.csv",
-                    dir, scenario, protocol
-                ))
-                .expect("opening file to write protocol report");
-                let mut writer = csv::Writer::from_writer(writer);
-
-                summary.trip_reports.into_iter().for_each(|report| {
-                    writer
-                        .serialize(report)
-                        .expect("serializing trip reports to file")
-                });
-            }
+        let comparison_writer = csv::Writer::from_writer(comparison_writer);
+
+        Self {
+            dir,
+            comparison_writer,
+            table_rows: vec![],
+        }
+    }
+
+    /// Writes the trip reports from one protocol's run of a scenario.
+    fn write_protocol(
+        &self,
+        scenario: &str,
+        protocol: Protocol,
+        summary: &client::Summary,
+    ) {
+        let _ = fs::create_dir(format!("{}/{}", self.dir, scenario));
+
+        let writer = fs::File::create(format!(
+            "{}/{}/{}.csv",
+            self.dir, scenario, protocol
+        ))
+        .expect("opening file to write protocol report");
+        let mut writer = csv::Writer::from_writer(writer);
+
+        summary.trip_reports.iter().for_each(|report| {
+            writer
+                .serialize(report)
+                .expect("serializing trip reports to file")
+        });
+        writer.flush().expect("flushing trip reports to file");
+
+        if summary.buffer_samples.is_empty() {
+            return;
+        }
+        let writer = fs::File::create(format!(
+            "{}/{}/{}_buffers.csv",
+            self.dir, scenario, protocol
+        ))
+        .expect("opening file to write buffer samples");
+        let mut writer = csv::Writer::from_writer(writer);
+        for sample in &summary.buffer_samples {
+            writer
+                .serialize(sample)
+                .expect("serializing buffer samples to file");
+        }
+        writer.flush().expect("flushing buffer samples to file");
+    }
+
+    /// Writes the configuration a scenario ran with, so its results describe
+    /// how to reproduce them.
+    fn write_scenario(&self, scenario: &Scenario) {
+        let name = scenario.netcode_scenario.scenario_name;
+        let _ = fs::create_dir(format!("{}/{}", self.dir, name));
+
+        let writer =
+            fs::File::create(format!("{}/{}/scenario.json", self.dir, name))
+                .expect("opening file to write scenario");
+        serde_json::to_writer_pretty(writer, scenario)
+            .expect("serializing scenario to file");
+    }
+
+    /// Writes a scenario's comparison, once all of its protocols have run.
+    fn write_comparison(&mut self, comparison: Comparison) {
+        self.write_scenario(&comparison.scenario);
+        self.table_rows.push(TableRow::from(&comparison));
+        self.comparison_writer
+            .serialize(comparison)
+            .expect("writing comparison to file");
+        self.comparison_writer
+            .flush()
+            .expect("flushing comparison to file");
+    }
+
+    /// Renders a table of every comparison written so far.
+    fn summary_table(&self, width: usize) -> String {
+        render_table(&self.table_rows, width)
+    }
+
+    /// Writes the head-of-line blocking cost of every pair both of whose
+    /// halves have run, and returns them.
+    fn write_hol_costs(&self) -> Vec<HolCost> {
+        let costs = hol_costs(&self.table_rows);
+        if costs.is_empty() {
+            return costs;
+        }
+
+        let writer = fs::File::create(format!("{}/hol_cost.csv", self.dir))
+            .expect("opening head-of-line blocking cost file");
+        let mut writer = csv::Writer::from_writer(writer);
+        for cost in &costs {
+            writer
+                .serialize(cost)
+                .expect("writing head-of-line blocking cost to file");
         }
+        writer
+            .flush()
+            .expect("flushing head-of-line blocking cost to file");
+        costs
     }
 }
 
 const DEFAULT_RETURN_COUNT: Option<usize> = Some(200);
 
+/// Width of the summary table when the terminal's is unknown.
+const DEFAULT_TABLE_WIDTH: usize = 100;
+
 fn scenarios() -> Vec<Scenario> {
-    vec![
+    let mut scenarios = vec![
         Scenario {
             netcode_scenario: NetcodeScenario {
                 scenario_name: "transfer_0_200B_60Hz-full_bandwith",
                 transfers: vec![client::Transfer {
                     stream_id: StreamId(0),
-                    size: 200,
+                    payload: client::PayloadModel::Constant(200),
                     hertz: 60,
                     return_count: DEFAULT_RETURN_COUNT,
+                    fixed_total: None,
+                    phase_offset: Duration::from_secs(0),
+                    bursts: None,
+                    start_after: None,
+                    unreliable: false,
                 }],
+                pushes: vec![],
+                flows: 1,
+                hol_pair: None,
             },
             network_config: runner::NetworkConfig::default(),
+            transport_config: TransportConfig::default(),
         },
         Scenario {
             netcode_scenario: NetcodeScenario {
@@ -223,19 +932,33 @@ fn scenarios() -> Vec<Scenario> {
                 transfers: vec![
                     client::Transfer {
                         stream_id: StreamId(0),
-                        size: 200,
+                        payload: client::PayloadModel::Constant(200),
                         hertz: 60,
                         return_count: DEFAULT_RETURN_COUNT,
+                        fixed_total: None,
+                        phase_offset: Duration::from_secs(0),
+                        bursts: None,
+                        start_after: None,
+                        unreliable: false,
                     },
                     client::Transfer {
                         stream_id: StreamId(1),
-                        size: 200,
+                        payload: client::PayloadModel::Constant(200),
                         hertz: 240,
                         return_count: None,
+                        fixed_total: None,
+                        phase_offset: Duration::from_secs(0),
+                        bursts: None,
+                        start_after: None,
+                        unreliable: false,
                     },
                 ],
+                pushes: vec![],
+                flows: 1,
+                hol_pair: None,
             },
             network_config: runner::NetworkConfig::default(),
+            transport_config: TransportConfig::default(),
         },
         Scenario {
             netcode_scenario: NetcodeScenario {
@@ -244,41 +967,385 @@ fn scenarios() -> Vec<Scenario> {
                 transfers: vec![
                     client::Transfer {
                         stream_id: StreamId(0),
-                        size: 200,
+                        payload: client::PayloadModel::Constant(200),
                         hertz: 60,
                         return_count: DEFAULT_RETURN_COUNT,
+                        fixed_total: None,
+                        phase_offset: Duration::from_secs(0),
+                        bursts: None,
+                        start_after: None,
+                        unreliable: false,
                     },
                     client::Transfer {
                         stream_id: StreamId(1),
-                        size: 200,
+                        payload: client::PayloadModel::Constant(200),
                         hertz: 240,
                         return_count: None,
+                        fixed_total: None,
+                        phase_offset: Duration::from_secs(0),
+                        bursts: None,
+                        start_after: None,
+                        unreliable: false,
                     },
                 ],
+                pushes: vec![],
+                flows: 1,
+                hol_pair: None,
             },
             network_config: runner::NetworkConfig {
                 rate_limit_kbps: 1024,
                 ..Default::default()
             },
+            transport_config: TransportConfig::default(),
         },
         Scenario {
             netcode_scenario: NetcodeScenario {
                 scenario_name: "transfer_0_200B_60Hz-half_bandwidth",
                 transfers: vec![client::Transfer {
                     stream_id: StreamId(0),
-                    size: 200,
+                    payload: client::PayloadModel::Constant(200),
                     hertz: 60,
                     return_count: DEFAULT_RETURN_COUNT,
+                    fixed_total: None,
+                    phase_offset: Duration::from_secs(0),
+                    bursts: None,
+                    start_after: None,
+                    unreliable: false,
                 }],
+                pushes: vec![],
+                flows: 1,
+                hol_pair: None,
             },
             network_config: runner::NetworkConfig {
                 rate_limit_kbps: 12,
                 ..Default::default()
             },
+            transport_config: TransportConfig::default(),
         },
+        Scenario {
+            netcode_scenario: NetcodeScenario {
+                scenario_name: "transfer_0_200B_60Hz-bufferbloat",
+                transfers: vec![client::Transfer {
+                    stream_id: StreamId(0),
+                    payload: client::PayloadModel::Constant(200),
+                    hertz: 60,
+                    return_count: DEFAULT_RETURN_COUNT,
+                    fixed_total: None,
+                    phase_offset: Duration::from_secs(0),
+                    bursts: None,
+                    start_after: None,
+                    unreliable: false,
+                }],
+                pushes: vec![],
+                flows: 1,
+                hol_pair: None,
+            },
+            // The link carries less than is sent, and the queue is deep
+            // enough to hold the excess for the whole run, so datagrams wait
+            // longer and longer instead of being dropped.
+            network_config: runner::NetworkConfig {
+                rate_limit_kbps: 64,
+                packet_limit: 100_000,
+                ..Default::default()
+            },
+            transport_config: TransportConfig::default(),
+        },
+        Scenario {
+            netcode_scenario: NetcodeScenario {
+                scenario_name: "transfer_0_200B_60Hz-fast_down_slow_up",
+                transfers: vec![client::Transfer {
+                    stream_id: StreamId(0),
+                    payload: client::PayloadModel::Constant(200),
+                    hertz: 60,
+                    return_count: DEFAULT_RETURN_COUNT,
+                    fixed_total: None,
+                    phase_offset: Duration::from_secs(0),
+                    bursts: None,
+                    start_after: None,
+                    unreliable: false,
+                }],
+                pushes: vec![],
+                flows: 1,
+                hol_pair: None,
+            },
+            network_config: runner::NetworkConfig {
+                uplink: Some(runner::LinkParams {
+                    delay: 40.,
+                    jitter: 0.,
+                    random_loss: 0.,
+                    rate_limit_kbps: 256,
+                }),
+                downlink: Some(runner::LinkParams {
+                    delay: 10.,
+                    jitter: 0.,
+                    random_loss: 0.,
+                    rate_limit_kbps: 8192,
+                }),
+                ..Default::default()
+            },
+            transport_config: TransportConfig::default(),
+        },
+        Scenario {
+            netcode_scenario: NetcodeScenario {
+                scenario_name: "transfer_0_200B_60Hz-fixed_total-5pct_loss",
+                transfers: vec![client::Transfer {
+                    stream_id: StreamId(0),
+                    payload: client::PayloadModel::Constant(200),
+                    hertz: 60,
+                    return_count: None,
+                    fixed_total: DEFAULT_RETURN_COUNT,
+                    phase_offset: Duration::from_secs(0),
+                    bursts: None,
+                    start_after: None,
+                    unreliable: false,
+                }],
+                pushes: vec![],
+                flows: 1,
+                hol_pair: None,
+            },
+            network_config: runner::NetworkConfig {
+                random_loss: 5.,
+                ..Default::default()
+            },
+            transport_config: TransportConfig::default(),
+        },
+        Scenario {
+            netcode_scenario: NetcodeScenario {
+                scenario_name: "transfer_0_1200B_keyframes_60Hz-full_bandwith",
+                transfers: vec![client::Transfer {
+                    stream_id: StreamId(0),
+                    payload: client::PayloadModel::Keyframe {
+                        keyframe: 1200,
+                        every: 30,
+                        delta: 100,
+                    },
+                    hertz: 60,
+                    return_count: DEFAULT_RETURN_COUNT,
+                    fixed_total: None,
+                    phase_offset: Duration::from_secs(0),
+                    bursts: None,
+                    start_after: None,
+                    unreliable: false,
+                }],
+                pushes: vec![],
+                flows: 1,
+                hol_pair: None,
+            },
+            network_config: runner::NetworkConfig::default(),
+            transport_config: TransportConfig::default(),
+        },
+        Scenario {
+            netcode_scenario: NetcodeScenario {
+                scenario_name: "transfer_0_800B_bursts_240Hz-idle_3s",
+                // Each idle gap outlasts the retransmission timeout, so the
+                // bursts after the first show what restarting costs.
+                transfers: vec![client::Transfer {
+                    stream_id: StreamId(0),
+                    payload: client::PayloadModel::Constant(800),
+                    hertz: 240,
+                    return_count: None,
+                    fixed_total: Some(300),
+                    phase_offset: Duration::from_secs(0),
+                    bursts: Some(client::Bursts {
+                        length: 60,
+                        idle: Duration::from_secs(3),
+                    }),
+                    start_after: None,
+                    unreliable: false,
+                }],
+                pushes: vec![],
+                flows: 1,
+                hol_pair: None,
+            },
+            network_config: runner::NetworkConfig::default(),
+            transport_config: TransportConfig::default(),
+        },
+        Scenario {
+            netcode_scenario: NetcodeScenario {
+                scenario_name: "two_flows_800B_240Hz-1024kbps",
+                // Each flow alone would fill the link, so they compete for
+                // it and the fairness index shows how evenly it was shared.
+                transfers: vec![client::Transfer {
+                    stream_id: StreamId(0),
+                    payload: client::PayloadModel::Constant(800),
+                    hertz: 240,
+                    return_count: DEFAULT_RETURN_COUNT,
+                    fixed_total: None,
+                    phase_offset: Duration::from_secs(0),
+                    bursts: None,
+                    start_after: None,
+                    unreliable: false,
+                }],
+                pushes: vec![],
+                flows: 2,
+                hol_pair: None,
+            },
+            network_config: runner::NetworkConfig {
+                rate_limit_kbps: 1024,
+                ..Default::default()
+            },
+            transport_config: TransportConfig::default(),
+        },
+        Scenario {
+            netcode_scenario: NetcodeScenario {
+                scenario_name: "server_push_800B_60Hz-inputs_100B_60Hz",
+                // A server-authoritative game: the client sends its inputs
+                // and the server pushes the state they lead to.
+                transfers: vec![client::Transfer {
+                    stream_id: StreamId(0),
+                    payload: client::PayloadModel::Constant(100),
+                    hertz: 60,
+                    return_count: DEFAULT_RETURN_COUNT,
+                    fixed_total: None,
+                    phase_offset: Duration::from_secs(0),
+                    bursts: None,
+                    start_after: None,
+                    unreliable: false,
+                }],
+                pushes: vec![ServerPush {
+                    stream_id: StreamId(1),
+                    payload_size: 800,
+                    hertz: 60,
+                    count: 200,
+                }],
+                flows: 1,
+                hol_pair: None,
+            },
+            network_config: runner::NetworkConfig::default(),
+            transport_config: TransportConfig::default(),
+        },
+    ];
+    scenarios.extend(hol_pair(
+        "hol_200B_240Hz-20ms-2pct_loss",
+        200,
+        240,
+        960,
+        4,
+        runner::NetworkConfig {
+            delay: 20,
+            random_loss: 2.,
+            ..Default::default()
+        },
+    ));
+    scenarios
+}
+
+/// The two halves of the head-of-line blocking pair `name`, each sending
+/// `total` datagrams of `size` bytes at `hertz` over `network_config`. The
+/// spread half sends on `streams` streams, each at its share of the rate and
+/// offset from the others so that together they tick as the ordered half
+/// does.
+fn hol_pair(
+    name: &'static str,
+    size: usize,
+    hertz: u32,
+    total: usize,
+    streams: u8,
+    network_config: runner::NetworkConfig,
+) -> Vec<Scenario> {
+    assert!(
+        hertz.is_multiple_of(u32::from(streams))
+            && total.is_multiple_of(usize::from(streams)),
+        "{}'s rate and total must split evenly over {} streams",
+        name,
+        streams
+    );
+
+    let transfer = |stream: u8, hertz: u32, total: usize| client::Transfer {
+        stream_id: StreamId(stream),
+        payload: client::PayloadModel::Constant(size),
+        hertz,
+        return_count: None,
+        fixed_total: Some(total),
+        phase_offset: Duration::from_secs(1) / hertz * u32::from(stream)
+            / u32::from(streams),
+        bursts: None,
+        start_after: None,
+        unreliable: false,
+    };
+    let half = |variant, scenario_name: String, transfers| Scenario {
+        netcode_scenario: NetcodeScenario {
+            scenario_name: Box::leak(Box::new(scenario_name)),
+            transfers,
+            pushes: vec![],
+            flows: 1,
+            hol_pair: Some(HolPair { name, variant }),
+        },
+        network_config: network_config.clone(),
+        transport_config: TransportConfig::default(),
+    };
+
+    vec![
+        half(
+            HolVariant::Ordered,
+            format!("{}-ordered", name),
+            vec![transfer(0, hertz, total)],
+        ),
+        half(
+            HolVariant::Spread,
+            format!("{}-spread_{}_streams", name, streams),
+            (0..streams)
+                .map(|stream| {
+                    transfer(
+                        stream,
+                        hertz / u32::from(streams),
+                        total / usize::from(streams),
+                    )
+                })
+                .collect(),
+        ),
     ]
 }
 
+/// Returns a copy of each scenario for each of `sizes`, with every transfer
+/// sending datagrams of that size. Returns `scenarios` as is if `sizes` is
+/// empty.
+fn sweep_sizes(scenarios: Vec<Scenario>, sizes: &[usize]) -> Vec<Scenario> {
+    if sizes.is_empty() {
+        return scenarios;
+    }
+
+    scenarios
+        .iter()
+        .flat_map(|scenario| {
+            sizes.iter().map(move |size| {
+                let mut sized = scenario.clone();
+                sized.netcode_scenario.add_suffix(&format!("{}B", size));
+                for transfer in &mut sized.netcode_scenario.transfers {
+                    transfer.payload = client::PayloadModel::Constant(*size);
+                }
+                sized
+            })
+        })
+        .collect()
+}
+
+/// Returns a copy of each scenario for each of `windows`, with the client
+/// coalescing its sends over that many milliseconds. Returns `scenarios` as is
+/// if `windows` is empty.
+fn sweep_coalescing(
+    scenarios: Vec<Scenario>,
+    windows: &[u64],
+) -> Vec<Scenario> {
+    if windows.is_empty() {
+        return scenarios;
+    }
+
+    scenarios
+        .iter()
+        .flat_map(|scenario| {
+            windows.iter().map(move |window| {
+                let mut coalesced = scenario.clone();
+                coalesced
+                    .netcode_scenario
+                    .add_suffix(&format!("coalesce_{}ms", window));
+                coalesced.transport_config.coalesce_window_ms = *window;
+                coalesced
+            })
+        })
+        .collect()
+}
+
 #[derive(Debug, StructOpt)]
 struct Options {
     #[structopt(long, short = "f")]
@@ -286,11 +1353,48 @@ struct Options {
     /// Name of the directory in which to write CSV results.
     #[structopt(long, short = "o")]
     output: String,
+    /// Address on which to serve live Prometheus metrics during the sweep.
+    #[structopt(long)]
+    metrics_addr: Option<SocketAddr>,
+    /// How many protocols to run at once. Only runs of the same scenario are
+    /// concurrent, because a scenario's network config applies to the whole
+    /// interface.
+    #[structopt(long, short = "j", default_value = "1")]
+    jobs: usize,
+    /// Run every protocol this many times per scenario, for confidence
+    /// intervals on its mean round trip and throughput.
+    #[structopt(long, default_value = "1")]
+    repeat: usize,
+    /// Comma separated payload sizes. Every scenario runs once per size, with
+    /// all of its transfers sending datagrams of that size.
+    #[structopt(long, use_delimiter = true)]
+    sweep_size: Vec<usize>,
+    /// Comma separated coalescing windows in milliseconds. Every scenario runs
+    /// once per window, with the client flushing its sends together once per
+    /// window. A window of 0 flushes every send.
+    #[structopt(long, use_delimiter = true)]
+    sweep_coalesce: Vec<u64>,
+    /// Sample the CPU time and syscalls each protocol's run costs. The
+    /// counters cover the whole process, so this needs runs one at a time.
+    #[structopt(long)]
+    profile: bool,
+    /// SQLite database to append every protocol's results to, creating it if
+    /// needed, so they can be compared across sweeps.
+    #[structopt(long)]
+    db: Option<String>,
+    /// Sample each protocol's send queue and bytes in flight every this many
+    /// milliseconds, writing them beside its trip reports.
+    #[structopt(long)]
+    buffer_sample_interval_ms: Option<u64>,
 }
 
 #[async_std::main]
 async fn main() {
     let options = Options::from_args();
+    assert!(
+        !options.profile || options.jobs == 1,
+        "--profile needs --jobs 1, as concurrent runs share the process"
+    );
 
     let scenarios = scenarios();
     let scenarios = scenarios.into_iter().filter(|s| {
@@ -306,25 +1410,549 @@ async fn main() {
             })
             .unwrap_or(true)
     });
+    let scenarios = sweep_sizes(scenarios.collect(), &options.sweep_size);
+    let scenarios = sweep_coalescing(scenarios, &options.sweep_coalesce);
+
+    let metrics = match options.metrics_addr {
+        Some(metrics_addr) => {
+            let registry = metrics::MetricsRegistry::default();
+            metrics::serve(metrics_addr, registry.clone())
+                .await
+                .expect("serving metrics");
+            Some(registry)
+        }
+        None => None,
+    };
+    let measurement = Measurement {
+        repeat: options.repeat,
+        metrics,
+        buffer_sample_interval_ms: options.buffer_sample_interval_ms,
+    };
 
-    let mut simulation_data = SimulationData::default();
+    let mut simulation_data = SimulationData::create(options.output);
+    let history = options
+        .db
+        .as_ref()
+        .map(|path| history::History::open(path).expect("opening results db"));
 
     let mut port = 1025;
     for scenario in scenarios {
-        let mut reports = HashMap::new();
-        for protocol in &ALL_PROTOCOLS {
-            let report = scenario.run(port, *protocol).await;
-            reports.insert(*protocol, report);
-            port += 1;
-            std::thread::sleep(std::time::Duration::from_secs(1));
+        let runs: Vec<(u16, Protocol)> =
+            (port..).zip(registry::sweep_protocols()).collect();
+        port += runs.len() as u16;
+
+        let scenario_name = scenario.netcode_scenario.scenario_name;
+        let (reports, effective_network) = if options.jobs > 1 {
+            scenario
+                .run_concurrently(&runs, options.jobs, &measurement)
+                .await
+        } else {
+            let mut reports = HashMap::new();
+            let mut effective_network = None;
+            for (port, protocol) in &runs {
+                let report = scenario
+                    .run(*port, *protocol, &measurement, false, options.profile)
+                    .await;
+                if let Ok(report) = &report {
+                    effective_network =
+                        effective_network.or(report.effective_network);
+                }
+                reports.insert(*protocol, report);
+                std::thread::sleep(std::time::Duration::from_secs(1));
+            }
+            (reports, effective_network)
+        };
+        for (protocol, report) in &reports {
+            let report = match report {
+                Ok(report) => report,
+                Err(e) => {
+                    eprintln!("skipping {}: {}", scenario_name, e);
+                    continue;
+                }
+            };
+            simulation_data.write_protocol(
+                scenario_name,
+                *protocol,
+                &report.client_summary,
+            );
+        }
+        if let Some(history) = &history {
+            let scenario_params =
+                serde_json::to_string(&scenario).expect("serializing scenario");
+            for (protocol, report) in &reports {
+                if let Ok(report) = report {
+                    history
+                        .record(
+                            scenario_name,
+                            &scenario_params,
+                            *protocol,
+                            report,
+                        )
+                        .expect("recording results to db");
+                }
+            }
+        }
+        simulation_data.write_comparison(Comparison::from_reports(
+            scenario,
+            effective_network,
+            reports,
+        ));
+    }
+
+    let width = std::env::var("COLUMNS")
+        .ok()
+        .and_then(|columns| columns.parse().ok())
+        .unwrap_or(DEFAULT_TABLE_WIDTH);
+    print!("{}", simulation_data.summary_table(width));
+    for cost in simulation_data.write_hol_costs() {
+        println!(
+            "Head-of-line blocking cost {} {:.2}ms in {} ({:.2}ms ordered, \
+             {:.2}ms spread).",
+            cost.protocol,
+            cost.hol_cost_ms,
+            cost.pair,
+            cost.ordered_mean_ms,
+            cost.spread_mean_ms
+        );
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn some_scenarios() -> Vec<Scenario> {
+        scenarios().into_iter().take(2).collect()
+    }
+
+    #[test]
+    fn tables_have_a_row_per_scenario_marking_the_least_latent() {
+        let tcp: Protocol = "tcp".parse().unwrap();
+        let memory: Protocol = "memory".parse().unwrap();
+        let row = |scenario, tcp_ms: f64, memory_ms: f64| TableRow {
+            scenario,
+            round_trips: vec![
+                (tcp, (tcp_ms, tcp_ms * 2.0)),
+                (memory, (memory_ms, memory_ms * 2.0)),
+            ]
+            .into_iter()
+            .collect(),
+            unsupported: vec![],
+            least_latent: Some(if tcp_ms < memory_ms { tcp } else { memory }),
+            tied_with_least_latent: vec![],
+            hol_pair: None,
+        };
+        let rows = [row("fast", 1.0, 3.0), row("slow", 40.0, 20.0)];
+
+        let table = render_table(&rows, 80);
+        let lines: Vec<&str> = table.lines().collect();
+        assert!(lines[0].starts_with("scenario"), "{}", table);
+        assert!(lines[0].contains("memory") && lines[0].contains("tcp"));
+        assert!(lines[2].starts_with("fast"), "{}", table);
+        assert!(
+            lines[2].contains("3.00/6.00 ") && lines[2].contains("1.00/2.00*")
+        );
+        assert!(lines[3].starts_with("slow"), "{}", table);
+        assert!(lines[3].contains("20.00/40.00*"), "{}", table);
+
+        // Too narrow for both protocols, each gets a table of its own, which
+        // still has every scenario.
+        let narrow = render_table(&rows, 24);
+        let headers: Vec<&str> = narrow
+            .lines()
+            .filter(|line| line.starts_with("scenario"))
+            .collect();
+        assert_eq!(headers.len(), 2, "{}", narrow);
+        assert_eq!(
+            narrow
+                .lines()
+                .filter(|line| line.starts_with("fast"))
+                .count(),
+            2
+        );
+    }
+
+    #[test]
+    fn size_sweep_runs_each_scenario_once_per_size() {
+        let base = some_scenarios();
+        let sizes = [100, 400, 1400];
+        let swept = sweep_sizes(base.clone(), &sizes);
+
+        assert_eq!(swept.len(), base.len() * sizes.len());
+        for (scenario, chunk) in base.iter().zip(swept.chunks(sizes.len())) {
+            for (size, sized) in sizes.iter().zip(chunk) {
+                assert_eq!(sized.netcode_scenario.payload_size(), Some(*size));
+                assert_eq!(
+                    sized.netcode_scenario.scenario_name,
+                    format!(
+                        "{}-{}B",
+                        scenario.netcode_scenario.scenario_name, size
+                    )
+                );
+                assert_eq!(
+                    sized.netcode_scenario.transfers.len(),
+                    scenario.netcode_scenario.transfers.len()
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn coalescing_sweep_runs_each_scenario_once_per_window() {
+        let base = some_scenarios();
+        let windows = [0, 2, 5];
+        let swept = sweep_coalescing(base.clone(), &windows);
+
+        assert_eq!(swept.len(), base.len() * windows.len());
+        for (scenario, chunk) in base.iter().zip(swept.chunks(windows.len())) {
+            for (window, coalesced) in windows.iter().zip(chunk) {
+                assert_eq!(
+                    coalesced.transport_config.coalesce_window_ms,
+                    *window
+                );
+                assert_eq!(
+                    coalesced.netcode_scenario.scenario_name,
+                    format!(
+                        "{}-coalesce_{}ms",
+                        scenario.netcode_scenario.scenario_name, window
+                    )
+                );
+            }
+        }
+        assert_eq!(
+            swept[0].transport_config.flush_policy(),
+            client::FlushPolicy::PerSend
+        );
+        assert_eq!(
+            swept[1].transport_config.flush_policy(),
+            client::FlushPolicy::Window(Duration::from_millis(2))
+        );
+    }
+
+    #[test]
+    fn sweeps_compose() {
+        let base = some_scenarios();
+        let swept =
+            sweep_coalescing(sweep_sizes(base.clone(), &[200, 800]), &[0, 1]);
+        assert_eq!(swept.len(), base.len() * 4);
+        assert_eq!(
+            swept[1].netcode_scenario.scenario_name,
+            format!(
+                "{}-200B-coalesce_1ms",
+                base[0].netcode_scenario.scenario_name
+            )
+        );
+    }
+
+    fn scenario_named(name: &str) -> Scenario {
+        scenarios()
+            .into_iter()
+            .find(|s| s.netcode_scenario.scenario_name == name)
+            .unwrap()
+    }
+
+    /// The header and single row `comparison` writes as CSV.
+    fn comparison_csv(comparison: Comparison) -> HashMap<String, String> {
+        let mut writer = csv::Writer::from_writer(vec![]);
+        writer.serialize(comparison).unwrap();
+        let csv = String::from_utf8(writer.into_inner().unwrap()).unwrap();
+        let mut lines = csv.lines();
+        let header = lines.next().unwrap().split(',');
+        let row = lines.next().unwrap().split(',');
+        header
+            .map(String::from)
+            .zip(row.map(String::from))
+            .collect()
+    }
+
+    #[test]
+    fn comparisons_record_each_direction_of_an_asymmetric_link() {
+        let scenario = scenario_named("transfer_0_200B_60Hz-fast_down_slow_up");
+        let fields = comparison_csv(Comparison::from_reports(
+            scenario,
+            None,
+            HashMap::new(),
+        ));
+        assert_eq!(fields["network_uplink_delay_ms"], "40.0");
+        assert_eq!(fields["network_uplink_rate_limit_kbits"], "256");
+        assert_eq!(fields["network_downlink_delay_ms"], "10.0");
+        assert_eq!(fields["network_downlink_rate_limit_kbits"], "8192");
+    }
+
+    #[async_std::test]
+    async fn protocols_off_the_network_run_concurrently() {
+        // Twenty sends at 20Hz take a second, so two runs back to back would
+        // take two.
+        let scenario = Scenario {
+            netcode_scenario: NetcodeScenario {
+                scenario_name: "concurrent",
+                transfers: vec!["0:100:20::20".parse().unwrap()],
+                pushes: vec![],
+                flows: 1,
+                hol_pair: None,
+            },
+            network_config: runner::NetworkConfig::default(),
+            transport_config: TransportConfig::default(),
+        };
+        let measurement = Measurement {
+            repeat: 1,
+            metrics: None,
+            buffer_sample_interval_ms: None,
+        };
+        let runs = [
+            (47702, "memory".parse().unwrap()),
+            (47703, "memory-fec-4".parse().unwrap()),
+        ];
+
+        let started = std::time::Instant::now();
+        let (reports, effective_network) =
+            scenario.run_concurrently(&runs, 2, &measurement).await;
+        let elapsed = started.elapsed();
+
+        assert!(effective_network.is_none());
+        assert_eq!(reports.len(), 2);
+        for (_, protocol) in &runs {
+            let summary = &reports[protocol].as_ref().unwrap().client_summary;
+            assert!(summary.throughput_bytes_per_second() > 0.);
         }
+        assert!(elapsed < Duration::from_millis(1800), "took {:?}", elapsed);
+    }
+
+    #[async_std::test]
+    async fn protocols_without_round_trips_are_not_ranked() {
+        let memory = "memory".parse().unwrap();
+        let options = runner::Options::from_iter(&[
+            "runner",
+            "memory",
+            "-a",
+            "127.0.0.1:47706",
+            "--start-server",
+            "--skip-network-config",
+            "-b",
+            "0:100:200::5",
+        ]);
+        let measured = runner::runner_main(options).await.unwrap();
+        assert!(measured.client_summary.round_trips() > 1);
+        // A run which measured nothing has a mean of zero, yet is no faster.
+        let unmeasured = runner::Report {
+            client_summary: Vec::new().into(),
+            fairness: None,
+            server_summary: None,
+            effective_network: None,
+            profile: None,
+        };
+        assert_eq!(unmeasured.client_summary.mean_ms, 0.0);
+
+        let mut runs = HashMap::new();
+        runs.insert(memory, Ok(measured));
+        runs.insert("memory-fec-4".parse().unwrap(), Ok(unmeasured));
         let comparison =
-            Comparison::from_reports(scenario.clone(), reports.clone());
-        simulation_data.scenarios.insert(
-            scenario.netcode_scenario.scenario_name,
-            (comparison, reports),
+            Comparison::from_reports(some_scenarios().remove(0), None, runs);
+        assert_eq!(comparison.least_latent, Some(memory));
+        assert_eq!(comparison.least_variant, Some(memory));
+    }
+
+    #[async_std::test]
+    async fn unsupported_protocols_are_skipped_not_failed() {
+        let tcp: Protocol = "tcp".parse().unwrap();
+        let memory: Protocol = "memory".parse().unwrap();
+        let scenario = Scenario {
+            netcode_scenario: NetcodeScenario {
+                scenario_name: "unreliable",
+                transfers: vec!["0u:100:200::10".parse().unwrap()],
+                pushes: vec![],
+                flows: 1,
+                hol_pair: None,
+            },
+            network_config: runner::NetworkConfig::default(),
+            transport_config: TransportConfig::default(),
+        };
+        let measurement = Measurement {
+            repeat: 1,
+            metrics: None,
+            buffer_sample_interval_ms: None,
+        };
+
+        let mut runs = HashMap::new();
+        for (port, protocol) in [(47738, tcp), (47739, memory)] {
+            let run = scenario
+                .run(port, protocol, &measurement, true, false)
+                .await;
+            runs.insert(protocol, run);
+        }
+        assert!(matches!(
+            runs[&tcp],
+            Err(runner::RunnerError::Unsupported { protocol, .. }) if protocol == tcp
+        ));
+
+        let comparison = Comparison::from_reports(scenario, None, runs);
+        assert!(comparison.unsupported.contains_key(&tcp));
+        assert!(!comparison.reports.contains_key(&tcp));
+        assert_eq!(comparison.least_latent, Some(memory));
+        assert_eq!(comparison.protocols(), vec![memory, tcp]);
+
+        let row = TableRow::from(&comparison);
+        let table = render_table(&[row], 80);
+        let line = table.lines().nth(2).unwrap();
+        assert!(line.starts_with("unreliable"), "{}", table);
+        assert!(line.contains("unsupported"), "{}", table);
+        assert!(line.contains('*'), "{}", table);
+
+        let fields = comparison_csv(comparison);
+        assert!(!fields["tcp_unsupported"].is_empty());
+        assert_eq!(fields["tcp_mean_round_trip_ms"], "");
+        assert_eq!(fields["memory_unsupported"], "");
+        assert_ne!(fields["memory_mean_round_trip_ms"], "");
+    }
+
+    #[async_std::test]
+    async fn losses_cost_ordered_halves_of_hol_pairs_more() {
+        let memory: Protocol = "memory".parse().unwrap();
+        let pair = hol_pair(
+            "hol_test",
+            100,
+            400,
+            400,
+            4,
+            runner::NetworkConfig {
+                delay: 20,
+                random_loss: 20.,
+                ..Default::default()
+            },
         );
+        let measurement = Measurement {
+            repeat: 1,
+            metrics: None,
+            buffer_sample_interval_ms: None,
+        };
+        let (ordered, spread) = futures::future::join(
+            pair[0].run(47746, memory, &measurement, true, false),
+            pair[1].run(47747, memory, &measurement, true, false),
+        )
+        .await;
+
+        let rows: Vec<TableRow> = pair
+            .into_iter()
+            .zip([ordered, spread])
+            .map(|(scenario, run)| {
+                let runs = vec![(memory, run)].into_iter().collect();
+                TableRow::from(&Comparison::from_reports(scenario, None, runs))
+            })
+            .collect();
+        let costs = hol_costs(&rows);
+        assert_eq!(costs.len(), 1);
+        let cost = &costs[0];
+        assert_eq!((cost.pair, cost.protocol), ("hol_test", memory));
+        assert!(cost.ordered_mean_ms > cost.spread_mean_ms, "{:?}", cost);
+        assert_eq!(
+            cost.hol_cost_ms,
+            cost.ordered_mean_ms - cost.spread_mean_ms
+        );
+    }
+
+    #[async_std::test]
+    async fn interrupted_sweeps_keep_the_scenarios_already_written() {
+        let options = runner::Options::from_iter(&[
+            "runner",
+            "memory",
+            "-a",
+            "127.0.0.1:47717",
+            "--start-server",
+            "--skip-network-config",
+            "-b",
+            "0:100:200::5",
+        ]);
+        let summary =
+            runner::runner_main(options).await.unwrap().client_summary;
+        let memory = "memory".parse().unwrap();
+
+        let dir = std::env::temp_dir()
+            .join(format!("bench-interrupted-{}", std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        let mut simulation_data =
+            SimulationData::create(dir.to_str().unwrap().to_string());
+        let written = some_scenarios();
+        for scenario in &written {
+            let name = scenario.netcode_scenario.scenario_name;
+            simulation_data.write_protocol(name, memory, &summary);
+            let mut runs = HashMap::new();
+            runs.insert(
+                memory,
+                Ok(runner::Report {
+                    client_summary: summary.clone(),
+                    fairness: None,
+                    server_summary: None,
+                    effective_network: None,
+                    profile: None,
+                }),
+            );
+            simulation_data.write_comparison(Comparison::from_reports(
+                scenario.clone(),
+                None,
+                runs,
+            ));
+        }
+        // The sweep dies without running anything further.
+        std::mem::forget(simulation_data);
+
+        let mut comparisons =
+            csv::Reader::from_path(dir.join("comparison.csv")).unwrap();
+        let headers = comparisons.headers().unwrap().clone();
+        let rows: Vec<csv::StringRecord> =
+            comparisons.records().map(|row| row.unwrap()).collect();
+        assert_eq!(rows.len(), written.len());
+        for row in &rows {
+            let fields: HashMap<&str, &str> =
+                headers.iter().zip(row.iter()).collect();
+            assert_eq!(fields["least_latent"], "memory");
+        }
+        for scenario in &written {
+            let scenario_dir =
+                dir.join(scenario.netcode_scenario.scenario_name);
+            let trips: Vec<csv::StringRecord> =
+                csv::Reader::from_path(scenario_dir.join("memory.csv"))
+                    .unwrap()
+                    .records()
+                    .map(|trip| trip.unwrap())
+                    .collect();
+            assert_eq!(trips.len(), summary.trip_reports.len());
+            assert!(scenario_dir.join("scenario.json").exists());
+        }
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn size_sweep_without_sizes_keeps_the_scenarios() {
+        let base = some_scenarios();
+        let swept = sweep_sizes(base.clone(), &[]);
+        let names = |scenarios: &[Scenario]| {
+            scenarios
+                .iter()
+                .map(|s| s.netcode_scenario.scenario_name)
+                .collect::<Vec<_>>()
+        };
+        assert_eq!(names(&swept), names(&base));
     }
 
-    simulation_data.write_out(options.output);
+    #[test]
+    fn written_scenarios_read_back_as_they_ran() {
+        let dir = std::env::temp_dir()
+            .join(format!("bench-scenarios-{}", std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        let simulation_data =
+            SimulationData::create(dir.to_str().unwrap().to_string());
+
+        // Every scenario, so that each kind of transfer, link, and pair is
+        // covered.
+        for scenario in scenarios() {
+            simulation_data.write_scenario(&scenario);
+            let path = dir
+                .join(scenario.netcode_scenario.scenario_name)
+                .join("scenario.json");
+            let json = fs::read_to_string(path).unwrap();
+            let read: Scenario = serde_json::from_str(&json).unwrap();
+            assert_eq!(read, scenario);
+        }
+        let _ = fs::remove_dir_all(&dir);
+    }
 }
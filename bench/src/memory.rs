@@ -0,0 +1,793 @@
+//! In-memory implementation of the nhanh API
+//!
+//! Connections are pairs of channels inside the process, so runs against it
+//! measure the client and server alone, with no network in the way. Every
+//! datagram arrives, and those of each stream in order.
+//!
+//! Servers bind to a `SocketAddr` like the other transports, but it only
+//! names the server within this process. Each client gets a synthetic
+//! loopback address of its own, which is its peer address at the server.
+//!
+//! Since its traffic never reaches netem, the transport impairs itself:
+//! connections to a server given `Impairments` with `impair` delay, lose,
+//! and rate limit their datagrams as netem would. Each direction shares one
+//! queue and rate limit, but orders only the datagrams of each stream, as a
+//! transport multiplexing streams would. Reliable datagrams are never lost;
+//! a reliable datagram which would have been is instead delayed by a
+//! retransmission a round trip later, holding back those behind it on its
+//! stream. Every transmission counts towards the connection's wire bytes, so
+//! retransmissions show up as amplification.
+
+use crate::{runner::LinkParams, *};
+
+use futures::{
+    channel::mpsc,
+    future::BoxFuture,
+    stream::{FusedStream, Stream},
+    task::{Context, Poll},
+    Sink,
+};
+use rand::{rngs::StdRng, Rng, SeedableRng};
+
+use std::{
+    cmp::Reverse,
+    collections::{BinaryHeap, HashMap},
+    io,
+    net::{IpAddr, Ipv4Addr},
+    pin::Pin,
+    sync::{
+        atomic::{AtomicU16, AtomicU64, Ordering},
+        Arc, Mutex,
+    },
+    time::{Duration, Instant},
+};
+
+type Listener = (SocketAddr, mpsc::UnboundedSender<MemoryConnection>);
+
+/// Servers bound in this process, by address.
+static LISTENERS: Mutex<Vec<Listener>> = Mutex::new(Vec::new());
+
+/// The impairments on connections to each server, by address.
+static IMPAIRMENTS: Mutex<Vec<(SocketAddr, Impairments)>> =
+    Mutex::new(Vec::new());
+
+/// The port of the next client's synthetic address, counting through the
+/// ephemeral range.
+static NEXT_CLIENT_PORT: AtomicU16 = AtomicU16::new(EPHEMERAL_PORTS);
+
+const EPHEMERAL_PORTS: u16 = 49152;
+
+/// How many times a reliable datagram is retransmitted before it is let
+/// through regardless, as a sender would give up on a link losing all of
+/// its traffic.
+const MAX_RETRANSMISSIONS: usize = 15;
+
+/// What impairs the connections to one server.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Impairments {
+    /// Impairs client to server traffic.
+    pub uplink: LinkParams,
+    /// Impairs server to client traffic.
+    pub downlink: LinkParams,
+    /// How many datagrams each direction holds in flight. Unreliable
+    /// datagrams sent while it is full are dropped; reliable ones wait for
+    /// room.
+    pub queue_limit: usize,
+}
+
+impl Impairments {
+    /// The impairments netem would apply under `config`.
+    pub fn of(config: &runner::NetworkConfig) -> Self {
+        Self {
+            uplink: config.uplink(),
+            downlink: config.downlink(),
+            queue_limit: config.packet_limit / 2,
+        }
+    }
+}
+
+/// Impairs connections made from now on to the server at `address`, or
+/// stops impairing them if `impairments` is `None`.
+pub fn impair(address: SocketAddr, impairments: Option<Impairments>) {
+    let mut all = IMPAIRMENTS.lock().expect("memory impairments lock");
+    all.retain(|(impaired, _)| *impaired != address);
+    if let Some(impairments) = impairments {
+        all.push((address, impairments));
+    }
+}
+
+/// One direction of a connection, which decides when each datagram sent
+/// on it arrives.
+struct Link {
+    params: Option<LinkParams>,
+    queue_limit: usize,
+    rng: StdRng,
+    /// When the link finishes serializing the datagrams sent so far.
+    free_at: Instant,
+    /// When each datagram still in flight arrives.
+    in_flight: BinaryHeap<Reverse<Instant>>,
+    /// When the last datagram sent on each ordered and sequenced stream
+    /// arrives, which those sent after it on the stream cannot overtake.
+    last_arrivals: HashMap<DeliveryMode, Instant>,
+    /// Bytes put on the link, retransmissions included.
+    wire_bytes_sent: Arc<AtomicU64>,
+}
+
+impl Link {
+    fn new(params: Option<LinkParams>, queue_limit: usize) -> Self {
+        Self {
+            params,
+            queue_limit: queue_limit.max(1),
+            rng: StdRng::from_entropy(),
+            free_at: clock::now(),
+            in_flight: BinaryHeap::new(),
+            last_arrivals: HashMap::new(),
+            wire_bytes_sent: Arc::new(AtomicU64::new(0)),
+        }
+    }
+
+    fn transmit(&self, len: usize) {
+        self.wire_bytes_sent
+            .fetch_add(len as u64, Ordering::Relaxed);
+    }
+
+    /// When a datagram of `len` bytes sent now with `delivery_mode` arrives,
+    /// or `None` if it is lost.
+    fn arrival(
+        &mut self,
+        len: usize,
+        delivery_mode: DeliveryMode,
+    ) -> Option<Instant> {
+        let reliable = delivery_mode.is_reliable();
+        let now = clock::now();
+        let params = match self.params {
+            Some(params) => params,
+            None => {
+                self.transmit(len);
+                return Some(now);
+            }
+        };
+
+        while self.in_flight.peek().is_some_and(|&Reverse(a)| a <= now) {
+            self.in_flight.pop();
+        }
+        let mut departure = now;
+        if self.in_flight.len() >= self.queue_limit {
+            if !reliable {
+                return None;
+            }
+            departure = self.in_flight.pop().expect("a full queue").0;
+        }
+
+        if params.rate_limit_kbps > 0 {
+            let bits = len as f64 * 8.0;
+            let rate = params.rate_limit_kbps as f64 * 1e3;
+            departure = departure.max(self.free_at)
+                + Duration::from_secs_f64(bits / rate);
+            self.free_at = departure;
+        }
+
+        let jitter = match params.jitter > 0.0 {
+            true => self.rng.gen_range(-params.jitter, params.jitter),
+            false => 0.0,
+        };
+        let delay = |ms: f64| Duration::from_secs_f64(ms.max(0.0) / 1e3);
+        let mut arrival = departure + delay(params.delay + jitter);
+        self.transmit(len);
+        for _ in 0..MAX_RETRANSMISSIONS {
+            if self.rng.gen::<f32>() * 100.0 >= params.random_loss {
+                break;
+            }
+            if !reliable {
+                return None;
+            }
+            arrival += delay(2.0 * params.delay);
+            self.transmit(len);
+        }
+
+        let arrival = match delivery_mode {
+            DeliveryMode::ReliableUnordered
+            | DeliveryMode::UnreliableUnordered => arrival,
+            _ => {
+                let last =
+                    self.last_arrivals.entry(delivery_mode).or_insert(now);
+                *last = arrival.max(*last);
+                *last
+            }
+        };
+        self.in_flight.push(Reverse(arrival));
+        Some(arrival)
+    }
+}
+
+pub struct MemoryServer {
+    address: SocketAddr,
+    incoming: mpsc::UnboundedReceiver<MemoryConnection>,
+}
+
+impl MemoryServer {
+    pub async fn bind(address: SocketAddr) -> Result<Self> {
+        let mut listeners = LISTENERS.lock().expect("memory listeners lock");
+        if listeners.iter().any(|(bound, _)| *bound == address) {
+            return Err(io::Error::from(io::ErrorKind::AddrInUse).into());
+        }
+
+        let (sender, incoming) = mpsc::unbounded();
+        listeners.push((address, sender));
+        Ok(Self { address, incoming })
+    }
+}
+
+impl Drop for MemoryServer {
+    fn drop(&mut self) {
+        if let Ok(mut listeners) = LISTENERS.lock() {
+            listeners.retain(|(bound, _)| *bound != self.address);
+        }
+    }
+}
+
+impl Server<MemoryConnection> for MemoryServer {}
+
+impl Stream for MemoryServer {
+    type Item = Result<MemoryConnection>;
+    fn poll_next(
+        mut self: Pin<&mut Self>,
+        ctx: &mut Context,
+    ) -> Poll<Option<Self::Item>> {
+        Pin::new(&mut self.incoming)
+            .poll_next(ctx)
+            .map(|connection| connection.map(Ok))
+    }
+}
+
+impl FusedStream for MemoryServer {
+    fn is_terminated(&self) -> bool {
+        self.incoming.is_terminated()
+    }
+}
+
+/// A datagram received from the channel, ordered by when it arrives and
+/// then by when it was sent.
+struct Arriving {
+    arrival: Instant,
+    order: u64,
+    datagram: Datagram,
+}
+
+impl PartialEq for Arriving {
+    fn eq(&self, other: &Self) -> bool {
+        (self.arrival, self.order) == (other.arrival, other.order)
+    }
+}
+
+impl Eq for Arriving {}
+
+impl PartialOrd for Arriving {
+    fn partial_cmp(&self, other: &Self) -> Option<std::cmp::Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl Ord for Arriving {
+    fn cmp(&self, other: &Self) -> std::cmp::Ordering {
+        (self.arrival, self.order).cmp(&(other.arrival, other.order))
+    }
+}
+
+pub struct MemoryConnection {
+    sender: mpsc::UnboundedSender<(Instant, Datagram)>,
+    receiver: mpsc::UnboundedReceiver<(Instant, Datagram)>,
+    link: Link,
+    /// The datagrams received from the channel, by when they arrive and
+    /// then the order they were sent.
+    arriving: BinaryHeap<Reverse<Arriving>>,
+    /// How many datagrams the channel has given `arriving`.
+    received: u64,
+    /// Fires when the first datagram in `arriving` arrives, at the instant
+    /// it is set for.
+    timer: Option<(Instant, BoxFuture<'static, ()>)>,
+    /// How many datagrams have been sent on each ordered and sequenced stream.
+    sent: HashMap<DeliveryMode, u32>,
+    peer_addr: Option<SocketAddr>,
+}
+
+impl MemoryConnection {
+    /// Connects to the `MemoryServer` bound to `address`. Fails with
+    /// `ConnectionRefused` if there is none.
+    pub async fn connect(address: SocketAddr) -> Result<Self> {
+        let listeners = LISTENERS.lock().expect("memory listeners lock");
+        let listener = listeners
+            .iter()
+            .find(|(bound, _)| *bound == address)
+            .map(|(_, listener)| listener)
+            .ok_or_else(|| io::Error::from(io::ErrorKind::ConnectionRefused))?;
+
+        let impairments = IMPAIRMENTS
+            .lock()
+            .expect("memory impairments lock")
+            .iter()
+            .find(|(impaired, _)| *impaired == address)
+            .map(|(_, impairments)| *impairments);
+        let (mut client, mut server) = Self::pair(impairments);
+        client.peer_addr = Some(address);
+        server.peer_addr = Some(Self::client_address());
+        listener
+            .unbounded_send(server)
+            .map_err(|_| io::Error::from(io::ErrorKind::ConnectionRefused))?;
+        Ok(client)
+    }
+
+    /// A loopback address no other client in this process has, until the
+    /// ephemeral ports wrap around.
+    fn client_address() -> SocketAddr {
+        let port = NEXT_CLIENT_PORT
+            .fetch_update(Ordering::Relaxed, Ordering::Relaxed, |port| {
+                Some(port.checked_add(1).unwrap_or(EPHEMERAL_PORTS))
+            })
+            .expect("the update always succeeds");
+        SocketAddr::new(IpAddr::V4(Ipv4Addr::LOCALHOST), port)
+    }
+
+    /// Returns the client and server ends of a connection, without a
+    /// listener, or peer addresses.
+    pub(crate) fn pair(impairments: Option<Impairments>) -> (Self, Self) {
+        let (a_sender, b_receiver) = mpsc::unbounded();
+        let (b_sender, a_receiver) = mpsc::unbounded();
+        let queue_limit = impairments.map_or(1, |i| i.queue_limit);
+        let end = |sender, receiver, params| Self {
+            sender,
+            receiver,
+            link: Link::new(params, queue_limit),
+            arriving: BinaryHeap::new(),
+            received: 0,
+            timer: None,
+            sent: HashMap::new(),
+            peer_addr: None,
+        };
+        let a = end(a_sender, a_receiver, impairments.map(|i| i.uplink));
+        let b = end(b_sender, b_receiver, impairments.map(|i| i.downlink));
+        (a, b)
+    }
+
+    fn stream_position(
+        &mut self,
+        delivery_mode: DeliveryMode,
+    ) -> Option<StreamPosition> {
+        let (stream_id, index): (StreamId, fn(u32) -> StreamIndex) =
+            match delivery_mode {
+                DeliveryMode::ReliableOrdered(stream_id) => {
+                    (stream_id, StreamIndex::Ordinal)
+                }
+                DeliveryMode::ReliableSequenced(stream_id)
+                | DeliveryMode::UnreliableSequenced(stream_id) => {
+                    (stream_id, StreamIndex::Sequence)
+                }
+                DeliveryMode::ReliableUnordered
+                | DeliveryMode::UnreliableUnordered => return None,
+            };
+
+        let sent = self.sent.entry(delivery_mode).or_insert(0);
+        *sent += 1;
+        Some(StreamPosition {
+            stream_id,
+            index: index(*sent),
+        })
+    }
+}
+
+impl Connection for MemoryConnection {
+    fn wire_bytes_sent(&self) -> Option<Arc<AtomicU64>> {
+        Some(self.link.wire_bytes_sent.clone())
+    }
+
+    fn peer_addr(&self) -> Option<SocketAddr> {
+        self.peer_addr
+    }
+
+    fn peer_finished(&self) -> bool {
+        self.receiver.is_terminated()
+    }
+}
+
+impl Sink<SendCmd> for MemoryConnection {
+    type Error = Box<dyn std::error::Error>;
+    fn poll_ready(self: Pin<&mut Self>, _: &mut Context) -> Poll<Result<()>> {
+        Poll::Ready(Ok(()))
+    }
+    /// A datagram is sent and, if reliable, acked as soon as it is in the
+    /// peer's channel, since it cannot be lost from there. Unreliable
+    /// datagrams the link loses are still sent.
+    fn start_send(mut self: Pin<&mut Self>, item: SendCmd) -> Result<()> {
+        let arrival = self.link.arrival(item.data.len(), item.delivery_mode);
+        let stream_position = self.stream_position(item.delivery_mode);
+        if let Some(arrival) = arrival {
+            let datagram = Datagram {
+                stream_position,
+                data: item.data,
+                header: item.header,
+            };
+            self.sender
+                .unbounded_send((arrival, datagram))
+                .map_err(|e| e.into_send_error())?;
+        }
+        if let Some(notifier) = item.notify {
+            notifier.notify(SendEvent::Sent);
+            if item.delivery_mode.is_reliable() {
+                notifier.notify(SendEvent::Acked);
+            }
+        }
+        Ok(())
+    }
+    fn poll_flush(self: Pin<&mut Self>, _: &mut Context) -> Poll<Result<()>> {
+        Poll::Ready(Ok(()))
+    }
+    fn poll_close(self: Pin<&mut Self>, _: &mut Context) -> Poll<Result<()>> {
+        self.sender.close_channel();
+        Poll::Ready(Ok(()))
+    }
+}
+
+impl Stream for MemoryConnection {
+    type Item = Result<Datagram>;
+    fn poll_next(
+        mut self: Pin<&mut Self>,
+        ctx: &mut Context,
+    ) -> Poll<Option<Self::Item>> {
+        // Datagrams are in the channel from when they are sent, so once it
+        // is drained, nothing still to come can arrive before those in it.
+        while !self.receiver.is_terminated() {
+            match Pin::new(&mut self.receiver).poll_next(ctx) {
+                Poll::Ready(Some((arrival, datagram))) => {
+                    let order = self.received;
+                    self.received += 1;
+                    self.arriving.push(Reverse(Arriving {
+                        arrival,
+                        order,
+                        datagram,
+                    }));
+                }
+                Poll::Ready(None) | Poll::Pending => break,
+            }
+        }
+
+        loop {
+            let arrival = match self.arriving.peek() {
+                Some(Reverse(next)) => next.arrival,
+                None if self.receiver.is_terminated() => {
+                    return Poll::Ready(None)
+                }
+                None => return Poll::Pending,
+            };
+
+            let now = clock::now();
+            if arrival <= now {
+                self.timer = None;
+                let Reverse(next) = self.arriving.pop().expect("next datagram");
+                return Poll::Ready(Some(Ok(next.datagram)));
+            }
+            if self.timer.as_ref().map(|(at, _)| *at) != Some(arrival) {
+                self.timer = Some((arrival, clock::sleep(arrival - now)));
+            }
+            let (_, timer) = self.timer.as_mut().expect("a timer");
+            if timer.as_mut().poll(ctx).is_pending() {
+                return Poll::Pending;
+            }
+            self.timer = None;
+        }
+    }
+}
+
+impl FusedStream for MemoryConnection {
+    fn is_terminated(&self) -> bool {
+        self.arriving.is_empty() && self.receiver.is_terminated()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use futures::{SinkExt, StreamExt};
+
+    fn link(
+        delay: f64,
+        random_loss: f32,
+        rate_limit_kbps: usize,
+    ) -> LinkParams {
+        LinkParams {
+            delay,
+            jitter: 0.0,
+            random_loss,
+            rate_limit_kbps,
+        }
+    }
+
+    fn impaired(
+        uplink: LinkParams,
+        queue_limit: usize,
+    ) -> (MemoryConnection, MemoryConnection) {
+        MemoryConnection::pair(Some(Impairments {
+            uplink,
+            downlink: link(0.0, 0.0, 0),
+            queue_limit,
+        }))
+    }
+
+    fn send(data: &[u8], delivery_mode: DeliveryMode) -> SendCmd {
+        SendCmd {
+            data: data.to_vec(),
+            delivery_mode,
+            ..SendCmd::default()
+        }
+    }
+
+    /// Closes `sender` and reads everything `receiver` gets.
+    async fn received(
+        mut sender: MemoryConnection,
+        receiver: MemoryConnection,
+    ) -> Vec<Vec<u8>> {
+        sender.close().await.unwrap();
+        receiver
+            .map(|datagram| datagram.unwrap().data)
+            .collect()
+            .await
+    }
+
+    #[async_std::test]
+    async fn only_reliable_sends_are_acked_and_never_before_they_are_sent() {
+        let (mut client, server) = MemoryConnection::pair(None);
+        let mut events = vec![];
+        for delivery_mode in [
+            DeliveryMode::ReliableOrdered(StreamId(1)),
+            DeliveryMode::UnreliableUnordered,
+        ] {
+            let (notifier, receiver) = SendNotifier::new();
+            client
+                .send(SendCmd {
+                    notify: Some(notifier),
+                    ..send(&[1], delivery_mode)
+                })
+                .await
+                .unwrap();
+            events.push(receiver);
+        }
+        assert_eq!(received(client, server).await.len(), 2);
+
+        // Each channel closes once the transport is done with its datagram.
+        let unreliable = events.pop().unwrap().collect::<Vec<_>>().await;
+        let reliable = events.pop().unwrap().collect::<Vec<_>>().await;
+        assert_eq!(reliable, vec![SendEvent::Sent, SendEvent::Acked]);
+        assert_eq!(unreliable, vec![SendEvent::Sent]);
+    }
+
+    #[async_std::test]
+    async fn connections_know_their_peers_addresses() {
+        fn peer_addr(connection: &impl Connection) -> Option<SocketAddr> {
+            connection.peer_addr()
+        }
+
+        let address = "127.0.0.1:47716".parse().unwrap();
+        let mut server = MemoryServer::bind(address).await.unwrap();
+        let first = MemoryConnection::connect(address).await.unwrap();
+        let second = MemoryConnection::connect(address).await.unwrap();
+        assert_eq!(peer_addr(&first), Some(address));
+        assert_eq!(peer_addr(&second), Some(address));
+
+        let first = peer_addr(&server.next().await.unwrap().unwrap()).unwrap();
+        let second = peer_addr(&server.next().await.unwrap().unwrap()).unwrap();
+        assert!(first.ip().is_loopback(), "{}", first);
+        assert_ne!(first, second);
+    }
+
+    #[async_std::test]
+    async fn empty_datagrams_keep_their_place_in_the_stream() {
+        let (mut client, server) = MemoryConnection::pair(None);
+        let ordered = DeliveryMode::ReliableOrdered(StreamId(3));
+        for data in &[&b"a"[..], b"", b"b"] {
+            client.send(send(data, ordered)).await.unwrap();
+        }
+        client.close().await.unwrap();
+
+        let received: Vec<Datagram> =
+            server.map(|datagram| datagram.unwrap()).collect().await;
+        let data: Vec<&[u8]> =
+            received.iter().map(|datagram| &datagram.data[..]).collect();
+        assert_eq!(data, vec![&b"a"[..], b"", b"b"]);
+        let index = |datagram: &Datagram| match datagram.stream_position {
+            Some(StreamPosition {
+                stream_id: StreamId(3),
+                index: StreamIndex::Ordinal(index),
+            }) => index,
+            ref position => panic!("unexpected position {:?}", position),
+        };
+        assert_eq!(index(&received[1]), index(&received[0]) + 1);
+        assert_eq!(index(&received[2]), index(&received[1]) + 1);
+    }
+
+    #[async_std::test]
+    async fn delay_holds_back_arrival() {
+        let (mut client, mut server) = impaired(link(20.0, 0.0, 0), 100);
+        let sent = Instant::now();
+        client
+            .send(send(b"a", DeliveryMode::UnreliableUnordered))
+            .await
+            .unwrap();
+        server.next().await.unwrap().unwrap();
+        assert!(sent.elapsed() >= Duration::from_millis(20));
+    }
+
+    #[async_std::test]
+    async fn rate_limit_serializes_datagrams() {
+        // 80kbit/s puts 100 bytes on the link every 10ms.
+        let (mut client, server) = impaired(link(0.0, 0.0, 80), 100);
+        let sent = Instant::now();
+        for _ in 0..3 {
+            client
+                .send(send(
+                    &[0; 100],
+                    DeliveryMode::ReliableOrdered(StreamId(1)),
+                ))
+                .await
+                .unwrap();
+        }
+        assert_eq!(received(client, server).await.len(), 3);
+        assert!(sent.elapsed() >= Duration::from_millis(30));
+    }
+
+    #[async_std::test]
+    async fn loss_drops_only_unreliable_datagrams() {
+        let (mut client, server) = impaired(link(0.0, 100.0, 0), 100);
+        client
+            .send(send(b"lost", DeliveryMode::UnreliableUnordered))
+            .await
+            .unwrap();
+        client
+            .send(send(b"kept", DeliveryMode::ReliableOrdered(StreamId(1))))
+            .await
+            .unwrap();
+        assert_eq!(received(client, server).await, vec![b"kept".to_vec()]);
+    }
+
+    #[async_std::test]
+    async fn datagrams_in_flight_when_the_peer_drops_still_surface() {
+        let received = clock::simulate(async {
+            let (mut client, server) = impaired(link(20.0, 0.0, 0), 100);
+            for data in 0..3u8 {
+                client
+                    .send(send(
+                        &[data],
+                        DeliveryMode::ReliableOrdered(StreamId(1)),
+                    ))
+                    .await
+                    .unwrap();
+            }
+            // The client goes without closing, while its datagrams are
+            // still on the link.
+            drop(client);
+            server
+                .map(|datagram| datagram.unwrap().data)
+                .collect::<Vec<_>>()
+                .await
+        })
+        .await;
+        assert_eq!(received, vec![vec![0], vec![1], vec![2]]);
+    }
+
+    #[async_std::test]
+    async fn retransmissions_hold_back_only_their_own_stream() {
+        let received = clock::simulate(async {
+            let (mut client, server) = impaired(link(10.0, 100.0, 0), 100);
+            let ordered = DeliveryMode::ReliableOrdered(StreamId(1));
+            client.send(send(b"retransmitted", ordered)).await.unwrap();
+            client.link.params = Some(link(10.0, 0.0, 0));
+            for (data, delivery_mode) in [
+                (&b"behind"[..], ordered),
+                (b"other stream", DeliveryMode::ReliableOrdered(StreamId(2))),
+                (b"unordered", DeliveryMode::ReliableUnordered),
+            ] {
+                client.send(send(data, delivery_mode)).await.unwrap();
+            }
+            received(client, server).await
+        })
+        .await;
+        assert_eq!(
+            received,
+            vec![
+                b"other stream".to_vec(),
+                b"unordered".to_vec(),
+                b"retransmitted".to_vec(),
+                b"behind".to_vec(),
+            ]
+        );
+    }
+
+    #[async_std::test]
+    async fn retransmissions_count_as_wire_bytes() {
+        let (mut client, server) = impaired(link(0.0, 100.0, 0), 100);
+        let wire_bytes_sent = client.wire_bytes_sent().unwrap();
+        client
+            .send(send(b"lost", DeliveryMode::UnreliableUnordered))
+            .await
+            .unwrap();
+        assert_eq!(wire_bytes_sent.load(Ordering::Relaxed), 4);
+
+        // A link losing everything lets a reliable datagram through after
+        // its last retransmission.
+        client
+            .send(send(b"kept", DeliveryMode::ReliableOrdered(StreamId(1))))
+            .await
+            .unwrap();
+        let transmissions = 1 + MAX_RETRANSMISSIONS as u64;
+        assert_eq!(
+            wire_bytes_sent.load(Ordering::Relaxed),
+            4 + 4 * transmissions
+        );
+        assert_eq!(received(client, server).await, vec![b"kept".to_vec()]);
+    }
+
+    #[async_std::test]
+    async fn full_queues_drop_unreliable_datagrams() {
+        let (mut client, server) = impaired(link(50.0, 0.0, 0), 2);
+        for data in [b"a", b"b", b"c"] {
+            client
+                .send(send(data, DeliveryMode::UnreliableUnordered))
+                .await
+                .unwrap();
+        }
+        assert_eq!(
+            received(client, server).await,
+            vec![b"a".to_vec(), b"b".to_vec()]
+        );
+    }
+
+    #[async_std::test]
+    async fn full_queues_hold_back_reliable_datagrams() {
+        let (mut client, server) = impaired(link(20.0, 0.0, 0), 1);
+        let sent = Instant::now();
+        for data in [b"a", b"b"] {
+            client
+                .send(send(data, DeliveryMode::ReliableOrdered(StreamId(1))))
+                .await
+                .unwrap();
+        }
+        assert_eq!(
+            received(client, server).await,
+            vec![b"a".to_vec(), b"b".to_vec()]
+        );
+        // The second waited for the first to leave the queue.
+        assert!(sent.elapsed() >= Duration::from_millis(40));
+    }
+
+    #[async_std::test]
+    async fn impairments_apply_per_direction() {
+        let address = "127.0.0.1:47611".parse().unwrap();
+        let mut server = MemoryServer::bind(address).await.unwrap();
+        impair(
+            address,
+            Some(Impairments {
+                uplink: link(0.0, 0.0, 0),
+                downlink: link(20.0, 0.0, 0),
+                queue_limit: 100,
+            }),
+        );
+        let mut client = MemoryConnection::connect(address).await.unwrap();
+        let mut accepted = server.next().await.unwrap().unwrap();
+        impair(address, None);
+
+        let sent = Instant::now();
+        client
+            .send(send(b"up", DeliveryMode::UnreliableUnordered))
+            .await
+            .unwrap();
+        accepted.next().await.unwrap().unwrap();
+        assert!(sent.elapsed() < Duration::from_millis(20));
+
+        let sent = Instant::now();
+        accepted
+            .send(send(b"down", DeliveryMode::UnreliableUnordered))
+            .await
+            .unwrap();
+        client.next().await.unwrap().unwrap();
+        assert!(sent.elapsed() >= Duration::from_millis(20));
+    }
+}
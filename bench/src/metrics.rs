@@ -0,0 +1,234 @@
+//! Live benchmark metrics in the Prometheus text format.
+//!
+//! Clients publish a `MetricsSnapshot` to a shared `MetricsRegistry` as a run
+//! progresses, and `serve` answers scrapes of `/metrics` with the latest
+//! snapshot of every protocol.
+
+use crate::*;
+
+use async_std::{
+    net::{SocketAddr, TcpListener, TcpStream},
+    prelude::*,
+    task,
+};
+use std::{
+    collections::HashMap,
+    fmt::Write,
+    sync::{Arc, Mutex},
+};
+
+/// The state of a benchmark run at one moment.
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct MetricsSnapshot {
+    pub round_trip_mean_ms: f64,
+    pub round_trip_p99_ms: f64,
+    /// Echoes returned per second since the run started.
+    pub returned_per_second: f64,
+    pub datagrams_sent: u64,
+    pub datagrams_returned: u64,
+    /// Datagrams sent which have not been echoed back yet.
+    pub datagrams_outstanding: u64,
+}
+
+/// The name, type, help text, and value of each exported metric.
+type Metric = (
+    &'static str,
+    &'static str,
+    &'static str,
+    fn(&MetricsSnapshot) -> f64,
+);
+
+const METRICS: &[Metric] = &[
+    (
+        "bench_round_trip_mean_ms",
+        "gauge",
+        "Mean round trip time of echoed datagrams.",
+        |s| s.round_trip_mean_ms,
+    ),
+    (
+        "bench_round_trip_p99_ms",
+        "gauge",
+        "99th percentile round trip time of echoed datagrams.",
+        |s| s.round_trip_p99_ms,
+    ),
+    (
+        "bench_returned_per_second",
+        "gauge",
+        "Echoes returned per second since the run started.",
+        |s| s.returned_per_second,
+    ),
+    (
+        "bench_datagrams_sent_total",
+        "counter",
+        "Datagrams sent which expect an echo.",
+        |s| s.datagrams_sent as f64,
+    ),
+    (
+        "bench_datagrams_returned_total",
+        "counter",
+        "Echoes received.",
+        |s| s.datagrams_returned as f64,
+    ),
+    (
+        "bench_datagrams_outstanding",
+        "gauge",
+        "Datagrams sent which have not been echoed back yet.",
+        |s| s.datagrams_outstanding as f64,
+    ),
+];
+
+#[derive(Debug, Clone, Default)]
+pub struct MetricsRegistry {
+    snapshots: Arc<Mutex<HashMap<Protocol, MetricsSnapshot>>>,
+}
+
+impl MetricsRegistry {
+    pub fn publish(&self, protocol: Protocol, snapshot: MetricsSnapshot) {
+        self.snapshots
+            .lock()
+            .expect("metrics registry lock")
+            .insert(protocol, snapshot);
+    }
+
+    /// Renders every protocol's latest snapshot in the Prometheus text format.
+    pub fn render(&self) -> String {
+        let snapshots = self.snapshots.lock().expect("metrics registry lock");
+        let mut protocols: Vec<_> = snapshots.iter().collect();
+        protocols.sort_by_key(|(protocol, _)| protocol.name());
+
+        let mut out = String::new();
+        for (name, kind, help, value) in METRICS {
+            let _ = writeln!(out, "# HELP {} {}", name, help);
+            let _ = writeln!(out, "# TYPE {} {}", name, kind);
+            for (protocol, snapshot) in &protocols {
+                let _ = writeln!(
+                    out,
+                    "{}{{protocol=\"{}\"}} {}",
+                    name,
+                    protocol,
+                    value(snapshot)
+                );
+            }
+        }
+
+        out
+    }
+}
+
+async fn respond(
+    mut stream: TcpStream,
+    registry: MetricsRegistry,
+) -> std::io::Result<()> {
+    let mut request = vec![];
+    let mut buffer = [0u8; 1024];
+    while !request.windows(4).any(|w| w == b"\r\n\r\n") {
+        let len = stream.read(&mut buffer).await?;
+        if len == 0 || request.len() > 16 * 1024 {
+            return Ok(());
+        }
+        request.extend_from_slice(&buffer[..len]);
+    }
+
+    let request = String::from_utf8_lossy(&request);
+    let path = request.split_whitespace().nth(1).unwrap_or("");
+    let (status, body) = match path {
+        "/metrics" => ("200 OK", registry.render()),
+        _ => ("404 Not Found", String::new()),
+    };
+
+    let response = format!(
+        "HTTP/1.1 {}\r\n\
+         Content-Type: text/plain; version=0.0.4\r\n\
+         Content-Length: {}\r\n\
+         Connection: close\r\n\r\n{}",
+        status,
+        body.len(),
+        body
+    );
+    stream.write_all(response.as_bytes()).await
+}
+
+/// Binds `address` and serves scrapes of `registry` in the background.
+pub async fn serve(
+    address: SocketAddr,
+    registry: MetricsRegistry,
+) -> Result<()> {
+    let listener = TcpListener::bind(address).await?;
+    task::spawn(async move {
+        let mut incoming = listener.incoming();
+        while let Some(stream) = incoming.next().await {
+            if let Ok(stream) = stream {
+                task::spawn(respond(stream, registry.clone()));
+            }
+        }
+    });
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Sends `GET path` to `address` and returns the status line and body.
+    async fn scrape(address: SocketAddr, path: &str) -> (String, String) {
+        let mut stream = TcpStream::connect(address).await.unwrap();
+        let request = format!("GET {} HTTP/1.1\r\nHost: bench\r\n\r\n", path);
+        stream.write_all(request.as_bytes()).await.unwrap();
+        let mut response = String::new();
+        stream.read_to_string(&mut response).await.unwrap();
+
+        let (head, body) = response.split_once("\r\n\r\n").unwrap();
+        let status = head.lines().next().unwrap().to_string();
+        (status, body.to_string())
+    }
+
+    #[async_std::test]
+    async fn scrapes_return_well_formed_metrics() {
+        let address = "127.0.0.1:47701".parse().unwrap();
+        let registry = MetricsRegistry::default();
+        registry.publish(
+            "tcp".parse().unwrap(),
+            MetricsSnapshot {
+                round_trip_mean_ms: 12.5,
+                round_trip_p99_ms: 40.0,
+                returned_per_second: 60.0,
+                datagrams_sent: 100,
+                datagrams_returned: 98,
+                datagrams_outstanding: 2,
+            },
+        );
+        serve(address, registry).await.unwrap();
+
+        let (status, body) = scrape(address, "/metrics").await;
+        assert_eq!(status, "HTTP/1.1 200 OK");
+
+        let mut samples = HashMap::new();
+        for line in body.lines() {
+            if let Some(comment) = line.strip_prefix("# ") {
+                let mut words = comment.splitn(3, ' ');
+                let keyword = words.next().unwrap();
+                assert!(keyword == "HELP" || keyword == "TYPE", "{}", line);
+                let name = words.next().unwrap();
+                assert!(METRICS.iter().any(|metric| metric.0 == name));
+                continue;
+            }
+            let (series, value) = line.rsplit_once(' ').unwrap();
+            let name = series
+                .strip_suffix("{protocol=\"tcp\"}")
+                .unwrap_or_else(|| panic!("unlabelled sample {:?}", line));
+            samples.insert(name.to_string(), value.parse::<f64>().unwrap());
+        }
+
+        assert_eq!(samples.len(), METRICS.len());
+        assert_eq!(samples["bench_round_trip_mean_ms"], 12.5);
+        assert_eq!(samples["bench_round_trip_p99_ms"], 40.0);
+        assert_eq!(samples["bench_returned_per_second"], 60.0);
+        assert_eq!(samples["bench_datagrams_sent_total"], 100.0);
+        assert_eq!(samples["bench_datagrams_returned_total"], 98.0);
+        assert_eq!(samples["bench_datagrams_outstanding"], 2.0);
+
+        let (status, _) = scrape(address, "/").await;
+        assert_eq!(status, "HTTP/1.1 404 Not Found");
+    }
+}
@@ -0,0 +1,177 @@
+//! Process CPU time and syscall counts, to attribute the cost of a run.
+//!
+//! CPU time and context switches come from `getrusage`, so they cover every
+//! thread in the process, server and client alike when both run here.
+//!
+//! Syscalls are counted with perf's `raw_syscalls:sys_enter` tracepoint, one
+//! counter per thread, which needs Linux with tracefs mounted and permission
+//! to open perf events. Where any of that is missing the count is left out
+//! rather than failing the run.
+
+use crate::*;
+
+use serde::Serialize;
+use std::{fs::File, io, time::Duration};
+
+/// What the process spent between two samples.
+#[derive(Serialize, Debug, Default, Clone, Copy, PartialEq)]
+pub struct ProcessCounters {
+    pub cpu_user_ms: f64,
+    pub cpu_system_ms: f64,
+    pub voluntary_context_switches: u64,
+    pub involuntary_context_switches: u64,
+    /// Syscalls made by the process, if they could be counted.
+    pub syscalls: Option<u64>,
+}
+
+impl ProcessCounters {
+    /// User and system CPU time together.
+    pub fn cpu_ms(&self) -> f64 {
+        self.cpu_user_ms + self.cpu_system_ms
+    }
+}
+
+/// Samples the process's counters from when it is started until it is
+/// finished.
+pub struct Profiler {
+    start: ProcessCounters,
+    syscall_counters: Option<Vec<File>>,
+}
+
+impl Profiler {
+    pub fn start() -> Result<Self> {
+        let syscall_counters = syscall_counters().ok();
+        Ok(Self {
+            start: rusage()?,
+            syscall_counters,
+        })
+    }
+
+    pub fn finish(self) -> Result<ProcessCounters> {
+        let end = rusage()?;
+        let syscalls = match &self.syscall_counters {
+            Some(counters) => Some(count_syscalls(counters)?),
+            None => None,
+        };
+
+        Ok(ProcessCounters {
+            cpu_user_ms: end.cpu_user_ms - self.start.cpu_user_ms,
+            cpu_system_ms: end.cpu_system_ms - self.start.cpu_system_ms,
+            voluntary_context_switches: end.voluntary_context_switches
+                - self.start.voluntary_context_switches,
+            involuntary_context_switches: end.involuntary_context_switches
+                - self.start.involuntary_context_switches,
+            syscalls,
+        })
+    }
+}
+
+fn rusage() -> Result<ProcessCounters> {
+    fn ms(time: libc::timeval) -> f64 {
+        let time = Duration::from_secs(time.tv_sec as u64)
+            + Duration::from_micros(time.tv_usec as u64);
+        time.as_secs_f64() * 1000.
+    }
+
+    // `rusage` has private padding on some targets, so it cannot be built
+    // field by field.
+    let mut usage: libc::rusage = unsafe { std::mem::zeroed() };
+    if unsafe { libc::getrusage(libc::RUSAGE_SELF, &mut usage) } != 0 {
+        return Err(io::Error::last_os_error().into());
+    }
+
+    Ok(ProcessCounters {
+        cpu_user_ms: ms(usage.ru_utime),
+        cpu_system_ms: ms(usage.ru_stime),
+        voluntary_context_switches: usage.ru_nvcsw as u64,
+        involuntary_context_switches: usage.ru_nivcsw as u64,
+        syscalls: None,
+    })
+}
+
+fn count_syscalls(counters: &[File]) -> Result<u64> {
+    use std::io::Read;
+
+    let mut syscalls = 0;
+    for mut counter in counters {
+        let mut count = [0u8; 8];
+        counter.read_exact(&mut count)?;
+        syscalls += u64::from_ne_bytes(count);
+    }
+
+    Ok(syscalls)
+}
+
+/// The leading fields of the kernel's `perf_event_attr`, which are all
+/// `PERF_ATTR_SIZE_VER0` asks for.
+#[cfg(target_os = "linux")]
+#[repr(C)]
+#[derive(Default)]
+struct PerfEventAttr {
+    kind: u32,
+    size: u32,
+    config: u64,
+    sample_period: u64,
+    sample_type: u64,
+    read_format: u64,
+    flags: u64,
+    wakeup_events: u32,
+    bp_type: u32,
+    config1: u64,
+}
+
+/// Opens a syscall counter on every thread in the process. Threads they go on
+/// to spawn are counted with them.
+#[cfg(target_os = "linux")]
+fn syscall_counters() -> Result<Vec<File>> {
+    use std::os::unix::io::FromRawFd;
+
+    const PERF_TYPE_TRACEPOINT: u32 = 2;
+    const PERF_ATTR_SIZE_VER0: u32 = 64;
+    const INHERIT: u64 = 1 << 1;
+
+    let tracepoint = [
+        "/sys/kernel/tracing/events/raw_syscalls/sys_enter/id",
+        "/sys/kernel/debug/tracing/events/raw_syscalls/sys_enter/id",
+    ]
+    .iter()
+    .find_map(|path| std::fs::read_to_string(path).ok())
+    .ok_or_else(|| io::Error::from(io::ErrorKind::NotFound))?;
+
+    let attr = PerfEventAttr {
+        kind: PERF_TYPE_TRACEPOINT,
+        size: PERF_ATTR_SIZE_VER0,
+        config: tracepoint.trim().parse()?,
+        flags: INHERIT,
+        ..PerfEventAttr::default()
+    };
+
+    let mut counters = vec![];
+    for task in std::fs::read_dir("/proc/self/task")? {
+        let thread: libc::pid_t = match task?.file_name().to_str() {
+            Some(thread) => thread.parse()?,
+            None => continue,
+        };
+        let fd = unsafe {
+            libc::syscall(
+                libc::SYS_perf_event_open,
+                &attr as *const PerfEventAttr,
+                thread,
+                -1 as libc::c_int,
+                -1 as libc::c_int,
+                0 as libc::c_ulong,
+            )
+        };
+        if fd < 0 {
+            return Err(io::Error::last_os_error().into());
+        }
+        counters.push(unsafe { File::from_raw_fd(fd as libc::c_int) });
+    }
+
+    Ok(counters)
+}
+
+#[cfg(not(target_os = "linux"))]
+fn syscall_counters() -> Result<Vec<File>> {
+    Err(io::Error::from(io::ErrorKind::Unsupported).into())
+}
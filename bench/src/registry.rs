@@ -0,0 +1,393 @@
+//! The transports which can be benchmarked.
+//!
+//! Each transport registers a name, a way to connect to its server, and a way
+//! to bind its server. The client, server, and sweep look protocols up here,
+//! so a new backend only needs to register itself to be benchmarked.
+
+use crate::*;
+
+use futures::{
+    future::{FutureExt, LocalBoxFuture, TryFutureExt},
+    stream::{FusedStream, Stream, TryStreamExt},
+    task::{Context, Poll},
+};
+
+use std::{
+    num::NonZeroUsize,
+    pin::Pin,
+    sync::{Mutex, MutexGuard},
+    time::Instant,
+};
+
+/// A connection of any transport.
+pub type BoxConnection = Box<dyn Connection + Unpin>;
+
+/// Connects to the server at an address, resolving to the time connecting
+/// started and the connection.
+pub type ConnectFn = &'static (dyn Fn(
+    SocketAddr,
+) -> LocalBoxFuture<'static, Result<(Instant, BoxConnection)>>
+              + Send
+              + Sync);
+
+/// Binds a server to an address.
+pub type BindFn = fn(SocketAddr) -> LocalBoxFuture<'static, Result<BoxServer>>;
+
+/// Impairs connections to the server at an address as the network config
+/// would, or stops impairing them given `None`.
+pub type ImpairFn = fn(SocketAddr, Option<&runner::NetworkConfig>);
+
+#[derive(Clone, Copy)]
+pub struct Transport {
+    /// The name the transport is selected by on the command line and reported
+    /// under in results.
+    pub name: &'static str,
+    pub connect: ConnectFn,
+    pub bind: BindFn,
+    /// Whether the benchmark sweep runs against this transport.
+    pub in_sweep: bool,
+    /// Whether the transport's traffic crosses the network interface, so
+    /// that the runner can impair it.
+    pub network: bool,
+    /// Whether the transport delivers `DeliveryMode::UnreliableUnordered`
+    /// datagrams, as unreliable transfers need.
+    pub unreliable: bool,
+    /// How a transport whose traffic stays off the network impairs itself.
+    /// The runner cannot impair such a transport without it.
+    pub impair: Option<ImpairFn>,
+}
+
+impl Transport {
+    pub fn protocol(&self) -> Protocol {
+        Protocol(self.name)
+    }
+}
+
+/// A server of any transport.
+pub struct BoxServer {
+    connections: Pin<Box<dyn FusedStream<Item = Result<BoxConnection>>>>,
+}
+
+impl BoxServer {
+    pub fn new<S, C>(server: S) -> Self
+    where
+        S: Server<C> + 'static,
+        C: Connection + Unpin + 'static,
+    {
+        let connections =
+            server.map_ok(|connection| Box::new(connection) as BoxConnection);
+        Self {
+            connections: Box::pin(connections),
+        }
+    }
+}
+
+impl Server<BoxConnection> for BoxServer {}
+
+impl Stream for BoxServer {
+    type Item = Result<BoxConnection>;
+    fn poll_next(
+        mut self: Pin<&mut Self>,
+        ctx: &mut Context,
+    ) -> Poll<Option<Self::Item>> {
+        self.connections.as_mut().poll_next(ctx)
+    }
+}
+
+impl FusedStream for BoxServer {
+    fn is_terminated(&self) -> bool {
+        self.connections.is_terminated()
+    }
+}
+
+static TRANSPORTS: Mutex<Vec<Transport>> = Mutex::new(Vec::new());
+
+fn transports() -> MutexGuard<'static, Vec<Transport>> {
+    let mut transports = TRANSPORTS.lock().expect("transport registry lock");
+    if transports.is_empty() {
+        transports.extend(builtin());
+    }
+    transports
+}
+
+/// Makes `transport` available to benchmark.
+///
+/// Panics if a transport is already registered under the same name.
+pub fn register(transport: Transport) {
+    let mut transports = transports();
+    assert!(
+        transports.iter().all(|other| other.name != transport.name),
+        "transport {:?} is already registered",
+        transport.name
+    );
+    transports.push(transport);
+}
+
+/// Finds the transport registered under `name`.
+///
+/// `tcp-multiplexed-<n>` names a multiplexed TCP transport over `n`
+/// connections for any `n` of at least one, which registers on first lookup.
+pub fn lookup(name: &str) -> Option<Transport> {
+    let mut transports = transports();
+    if let Some(transport) =
+        transports.iter().find(|transport| transport.name == name)
+    {
+        return Some(*transport);
+    }
+    let count = name.strip_prefix(MULTIPLEXED_PREFIX)?;
+    let connections = count.parse::<NonZeroUsize>().ok()?;
+    // Only the canonical spelling of a count, without leading zeros, names
+    // the transport, so that a count is never registered twice.
+    if connections.to_string() != count {
+        return None;
+    }
+    let transport = tcp_multiplexed(connections, false);
+    transports.push(transport);
+    Some(transport)
+}
+
+/// The names of every registered transport, in the order they registered.
+pub fn names() -> Vec<&'static str> {
+    transports()
+        .iter()
+        .map(|transport| transport.name)
+        .collect()
+}
+
+/// The protocols the benchmark sweep runs against.
+pub fn sweep_protocols() -> Vec<Protocol> {
+    transports()
+        .iter()
+        .filter(|transport| transport.in_sweep)
+        .map(Transport::protocol)
+        .collect()
+}
+
+fn boxed<C>((started, connection): (Instant, C)) -> (Instant, BoxConnection)
+where
+    C: Connection + Unpin + 'static,
+{
+    (started, Box::new(connection))
+}
+
+const MULTIPLEXED_PREFIX: &str = "tcp-multiplexed-";
+
+/// The multiplexed TCP transport over `connections` connections.
+fn tcp_multiplexed(connections: NonZeroUsize, in_sweep: bool) -> Transport {
+    let name = format!("{}{}", MULTIPLEXED_PREFIX, connections);
+    Transport {
+        name: Box::leak(name.into_boxed_str()),
+        connect: Box::leak(Box::new(move |address| {
+            client::connect_tcp_multiplexed(address, connections.get())
+                .map_ok(boxed)
+                .boxed_local()
+        })),
+        bind: |address| {
+            tcp_multiplexed::TcpMultiplexedServer::bind(address)
+                .map_ok(BoxServer::new)
+                .boxed_local()
+        },
+        in_sweep,
+        network: true,
+        unreliable: false,
+        impair: None,
+    }
+}
+
+/// The forward error correction of the `memory-fec-4` transport.
+const FEC_4: fec::Fec = fec::Fec { block_size: 4 };
+
+fn builtin() -> Vec<Transport> {
+    vec![
+        Transport {
+            name: "tcp",
+            connect: &|address| {
+                client::connect_tcp(address, false)
+                    .map_ok(boxed)
+                    .boxed_local()
+            },
+            bind: |address| {
+                tcp::TcpServer::bind(address)
+                    .map_ok(BoxServer::new)
+                    .boxed_local()
+            },
+            in_sweep: true,
+            network: true,
+            unreliable: false,
+            impair: None,
+        },
+        Transport {
+            name: "tcp-tls",
+            connect: &|address| {
+                client::connect_tcp(address, true)
+                    .map_ok(boxed)
+                    .boxed_local()
+            },
+            bind: |address| {
+                tls::TlsServer::bind(address)
+                    .map_ok(BoxServer::new)
+                    .boxed_local()
+            },
+            in_sweep: true,
+            network: true,
+            unreliable: false,
+            impair: None,
+        },
+        tcp_multiplexed(NonZeroUsize::new(4).unwrap(), true),
+        Transport {
+            name: "enet",
+            connect: &|address| {
+                client::connect_enet(address).map_ok(boxed).boxed_local()
+            },
+            bind: |address| {
+                enet::EnetServer::bind(address)
+                    .map(|server| Ok(BoxServer::new(server)))
+                    .boxed_local()
+            },
+            in_sweep: true,
+            network: true,
+            unreliable: false,
+            impair: None,
+        },
+        Transport {
+            name: "kcp",
+            connect: &|address| {
+                client::connect_kcp(kcp::KcpMode::Normal, address)
+                    .map_ok(boxed)
+                    .boxed_local()
+            },
+            bind: |address| {
+                kcp::KcpServer::bind(kcp::KcpMode::Normal, address)
+                    .map_ok(BoxServer::new)
+                    .boxed_local()
+            },
+            in_sweep: true,
+            network: true,
+            unreliable: false,
+            impair: None,
+        },
+        Transport {
+            name: "kcp-turbo",
+            connect: &|address| {
+                client::connect_kcp(kcp::KcpMode::Turbo, address)
+                    .map_ok(boxed)
+                    .boxed_local()
+            },
+            bind: |address| {
+                kcp::KcpServer::bind(kcp::KcpMode::Turbo, address)
+                    .map_ok(BoxServer::new)
+                    .boxed_local()
+            },
+            in_sweep: false,
+            network: true,
+            unreliable: false,
+            impair: None,
+        },
+        Transport {
+            name: "memory",
+            connect: &|address| {
+                client::connect_memory(address).map_ok(boxed).boxed_local()
+            },
+            bind: |address| {
+                memory::MemoryServer::bind(address)
+                    .map_ok(BoxServer::new)
+                    .boxed_local()
+            },
+            in_sweep: false,
+            network: false,
+            unreliable: true,
+            impair: Some(|address, config| {
+                memory::impair(address, config.map(memory::Impairments::of))
+            }),
+        },
+        Transport {
+            name: "memory-fec-4",
+            connect: &|address| {
+                client::connect_memory(address)
+                    .map_ok(|(started, connection)| {
+                        (started, FEC_4.wrap(connection))
+                    })
+                    .map_ok(boxed)
+                    .boxed_local()
+            },
+            bind: |address| {
+                memory::MemoryServer::bind(address)
+                    .map_ok(|server| {
+                        BoxServer::new(fec::FecServer::new(server, FEC_4))
+                    })
+                    .boxed_local()
+            },
+            in_sweep: false,
+            network: false,
+            unreliable: true,
+            impair: Some(|address, config| {
+                memory::impair(address, config.map(memory::Impairments::of))
+            }),
+        },
+    ]
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use structopt::StructOpt;
+
+    #[test]
+    fn multiplexed_tcp_takes_any_positive_connection_count() {
+        let transport = lookup("tcp-multiplexed-2").unwrap();
+        assert_eq!(transport.name, "tcp-multiplexed-2");
+        assert!(!transport.in_sweep);
+        assert!(names().contains(&"tcp-multiplexed-2"));
+        assert_eq!(
+            "tcp-multiplexed-2".parse::<Protocol>().unwrap().name(),
+            "tcp-multiplexed-2"
+        );
+        assert!(lookup("tcp-multiplexed-4").unwrap().in_sweep);
+
+        assert!(lookup("tcp-multiplexed-0").is_none());
+        assert!(lookup("tcp-multiplexed-02").is_none());
+        assert!(lookup("tcp-multiplexed-").is_none());
+        assert!("tcp-multiplexed-0".parse::<Protocol>().is_err());
+    }
+
+    #[async_std::test]
+    async fn registered_transports_can_be_benchmarked() {
+        register(Transport {
+            name: "dummy",
+            connect: &|address| {
+                client::connect_memory(address).map_ok(boxed).boxed_local()
+            },
+            bind: |address| {
+                memory::MemoryServer::bind(address)
+                    .map_ok(BoxServer::new)
+                    .boxed_local()
+            },
+            in_sweep: false,
+            network: false,
+            unreliable: true,
+            impair: None,
+        });
+        assert_eq!(
+            lookup("dummy").map(|transport| transport.name),
+            Some("dummy")
+        );
+        assert!(names().contains(&"dummy"));
+        assert!(!sweep_protocols().contains(&Protocol("dummy")));
+        let protocol: Protocol = "dummy".parse().unwrap();
+        assert_eq!(protocol.name(), "dummy");
+
+        let options = runner::Options::from_iter(&[
+            "runner",
+            "dummy",
+            "-a",
+            "127.0.0.1:47719",
+            "--start-server",
+            "--skip-network-config",
+            "-b",
+            "0:100:200::10",
+        ]);
+        let report = runner::runner_main(options).await.unwrap();
+        assert_eq!(report.client_summary.round_trips(), 9);
+    }
+}
@@ -0,0 +1,83 @@
+//! Recorded traffic, for replaying captured send patterns in place of
+//! synthetic transfers.
+//!
+//! A recording is a trip report CSV as the runner writes with `--output`,
+//! or as a sweep writes for each protocol. Each row gives a datagram's
+//! stream, payload size and send time, so replaying it sends datagrams of
+//! the same sizes on the same streams, spaced as they were when they were
+//! recorded. Only datagrams whose echoes returned are in a trip report, so
+//! any the recorded run lost are not replayed.
+//!
+//! Replayed streams wait for every echo, and each echo must carry back as
+//! much data as its datagram was recorded with.
+
+use crate::*;
+
+use serde::Deserialize;
+use std::{collections::BTreeMap, path::Path, time::Duration};
+
+/// The columns of a trip report which a replay needs.
+#[derive(Deserialize)]
+struct Row {
+    stream_id: StreamId,
+    payload_size: usize,
+    /// Milliseconds since the recorded run began.
+    send_time: f64,
+}
+
+/// One recorded datagram.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct RecordedSend {
+    /// When the datagram was sent, measured from the recording's first send.
+    pub offset: Duration,
+    pub payload_size: usize,
+}
+
+/// The datagrams of a recording, in the order each stream sent them.
+#[derive(Clone, Debug, Default)]
+pub struct Recording {
+    pub streams: BTreeMap<StreamId, Vec<RecordedSend>>,
+}
+
+impl Recording {
+    /// Reads the trip report CSV at `path`.
+    pub fn load(path: impl AsRef<Path>) -> Result<Self> {
+        let path = path.as_ref();
+        let mut rows = vec![];
+        for row in csv::Reader::from_path(path)?.deserialize() {
+            let row: Row = row?;
+            if !row.send_time.is_finite() {
+                return Err(format!(
+                    "{} records a send at {}ms",
+                    path.display(),
+                    row.send_time
+                )
+                .into());
+            }
+            rows.push(row);
+        }
+        if rows.is_empty() {
+            return Err(format!("{} records no sends", path.display()).into());
+        }
+
+        let start = rows
+            .iter()
+            .map(|row| row.send_time)
+            .fold(f64::INFINITY, f64::min);
+        rows.sort_by(|a, b| a.send_time.partial_cmp(&b.send_time).unwrap());
+        let mut streams: BTreeMap<StreamId, Vec<RecordedSend>> =
+            BTreeMap::new();
+        for row in rows {
+            streams
+                .entry(row.stream_id)
+                .or_default()
+                .push(RecordedSend {
+                    offset: Duration::from_secs_f64(
+                        (row.send_time - start) / 1e3,
+                    ),
+                    payload_size: row.payload_size,
+                });
+        }
+        Ok(Self { streams })
+    }
+}
@@ -1,11 +1,168 @@
 use crate::*;
+use async_std::net::SocketAddr;
 use futures::future::{self, Either, FutureExt};
-use serde::Serialize;
-use std::fs;
+use serde::{Deserialize, Serialize};
 use std::process::Command;
+use std::str::FromStr;
+use std::{fmt, fs, io};
 use structopt::StructOpt;
 
-#[derive(Serialize, Debug, Clone, StructOpt)]
+/// Impairments applied to traffic travelling in one direction.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq)]
+pub struct LinkParams {
+    /// One way delay in milliseconds
+    pub delay: f64,
+    /// One way jitter in milliseconds
+    pub jitter: f64,
+    /// Independent chance of packet loss (range: [0.0-100.0])
+    pub random_loss: f32,
+    pub rate_limit_kbps: usize,
+}
+
+impl FromStr for LinkParams {
+    type Err = anyhow::Error;
+    fn from_str(src: &str) -> std::result::Result<Self, Self::Err> {
+        let args: Vec<&str> = src.split(':').collect();
+        if args.len() != 4 {
+            anyhow::bail!(
+                "expected `delay:jitter:random_loss:rate_limit_kbps`, got {:?}",
+                src
+            );
+        }
+
+        Ok(Self {
+            delay: args[0].parse()?,
+            jitter: args[1].parse()?,
+            random_loss: args[2].parse()?,
+            rate_limit_kbps: args[3].parse()?,
+        })
+    }
+}
+
+/// The settings netem reports for one of its qdiscs, which may differ from
+/// what was asked for where the kernel or `tc` rounds or clamps a value.
+#[derive(Serialize, Debug, Default, Clone, Copy, PartialEq)]
+pub struct NetemSettings {
+    pub delay_ms: f64,
+    pub jitter_ms: f64,
+    pub delay_correlation: f32,
+    pub random_loss: f32,
+    pub random_loss_correlation: f32,
+    pub rate_limit_kbps: f64,
+    pub packet_limit: usize,
+}
+
+impl FromStr for NetemSettings {
+    type Err = anyhow::Error;
+    /// Parses a netem qdisc's line of `tc qdisc show`, such as
+    /// `qdisc netem 10: parent 1:1 limit 500 delay 5ms  1ms 25% loss 1% 0%
+    /// rate 1Mbit`. Settings netem leaves out are zero.
+    fn from_str(src: &str) -> std::result::Result<Self, Self::Err> {
+        fn time_ms(token: &str) -> Option<f64> {
+            let (value, scale) = if let Some(us) = token.strip_suffix("us") {
+                (us, 1e-3)
+            } else if let Some(ns) = token.strip_suffix("ns") {
+                (ns, 1e-6)
+            } else if let Some(ms) = token.strip_suffix("ms") {
+                (ms, 1.)
+            } else {
+                (token.strip_suffix('s')?, 1e3)
+            };
+            value.parse::<f64>().ok().map(|value| value * scale)
+        }
+        fn percent(token: &str) -> Option<f32> {
+            token.strip_suffix('%')?.parse().ok()
+        }
+        fn rate_kbps(token: &str) -> Option<f64> {
+            let units = [
+                ("Tibit", 1024f64.powi(4)),
+                ("Gibit", 1024f64.powi(3)),
+                ("Mibit", 1024f64.powi(2)),
+                ("Kibit", 1024.),
+                ("Tbit", 1e12),
+                ("Gbit", 1e9),
+                ("Mbit", 1e6),
+                ("Kbit", 1e3),
+                ("bit", 1.),
+            ];
+            units.iter().find_map(|(unit, bits)| {
+                let value: f64 = token.strip_suffix(unit)?.parse().ok()?;
+                Some(value * bits / 1e3)
+            })
+        }
+
+        let mut tokens = src.split_whitespace().peekable();
+        if tokens.next() != Some("qdisc") || tokens.next() != Some("netem") {
+            anyhow::bail!("expected a netem qdisc, got {:?}", src);
+        }
+
+        let bad = |setting: &str| {
+            anyhow::anyhow!("bad netem {} in {:?}", setting, src)
+        };
+        let mut settings = Self::default();
+        while let Some(token) = tokens.next() {
+            match token {
+                "limit" => {
+                    settings.packet_limit = tokens
+                        .next()
+                        .and_then(|limit| limit.parse().ok())
+                        .ok_or_else(|| bad("limit"))?;
+                }
+                "delay" => {
+                    settings.delay_ms = tokens
+                        .next()
+                        .and_then(time_ms)
+                        .ok_or_else(|| bad("delay"))?;
+                    if let Some(jitter_ms) =
+                        tokens.peek().and_then(|t| time_ms(t))
+                    {
+                        tokens.next();
+                        settings.jitter_ms = jitter_ms;
+                        if let Some(correlation) =
+                            tokens.peek().and_then(|t| percent(t))
+                        {
+                            tokens.next();
+                            settings.delay_correlation = correlation;
+                        }
+                    }
+                }
+                "loss" => {
+                    if tokens.peek() == Some(&"random") {
+                        tokens.next();
+                    }
+                    settings.random_loss = tokens
+                        .next()
+                        .and_then(percent)
+                        .ok_or_else(|| bad("loss"))?;
+                    if let Some(correlation) =
+                        tokens.peek().and_then(|t| percent(t))
+                    {
+                        tokens.next();
+                        settings.random_loss_correlation = correlation;
+                    }
+                }
+                "rate" => {
+                    settings.rate_limit_kbps = tokens
+                        .next()
+                        .and_then(rate_kbps)
+                        .ok_or_else(|| bad("rate"))?;
+                }
+                _ => {}
+            }
+        }
+
+        Ok(settings)
+    }
+}
+
+/// The netem settings in force for one server, read back from the interface.
+#[derive(Serialize, Debug, Clone, Copy, PartialEq)]
+pub struct EffectiveNetwork {
+    pub uplink: NetemSettings,
+    pub downlink: NetemSettings,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, StructOpt)]
 pub struct NetworkConfig {
     /// Delay in milliseconds
     #[structopt(long, default_value = "0")]
@@ -22,15 +179,29 @@ pub struct NetworkConfig {
     /// Random packet loss correlation (range: [0.0-100.0])
     #[structopt(long, default_value = "0")]
     pub random_loss_correlation: f32,
-    /// Network loopback interface.
+    /// Interface to impair: loopback for a server on this host, or the
+    /// interface on the path to a remote one.
     #[structopt(long, default_value = "lo")]
-    #[serde(skip)]
+    #[serde(skip, default = "loopback")]
     pub interface: String,
     /// Rate limit of simulated wire. Defaults to 1Gigabit.
     #[structopt(long, default_value = "1073741824")]
     pub rate_limit_kbps: usize,
-    #[structopt(long, default_value = "1000")]
+    /// Packets netem may queue, split evenly between the two directions. A
+    /// deep queue on a rate limited link emulates bufferbloat: traffic beyond
+    /// the rate waits in the queue instead of being dropped.
+    #[structopt(long, alias = "queue-limit", default_value = "1000")]
     pub packet_limit: usize,
+    /// Impairments for client to server traffic, specified in terms of
+    /// `delay:jitter:random_loss:rate_limit_kbps`. Overrides the symmetric
+    /// settings in that direction.
+    #[structopt(long)]
+    pub uplink: Option<LinkParams>,
+    /// Impairments for server to client traffic, specified in terms of
+    /// `delay:jitter:random_loss:rate_limit_kbps`. Overrides the symmetric
+    /// settings in that direction.
+    #[structopt(long)]
+    pub downlink: Option<LinkParams>,
 }
 
 impl Default for NetworkConfig {
@@ -41,52 +212,210 @@ impl Default for NetworkConfig {
             delay_correlation: 0.0,
             random_loss: 0.0,
             random_loss_correlation: 0.0,
-            interface: String::from("lo"),
+            interface: loopback(),
             rate_limit_kbps: 1073741824,
             packet_limit: 1000,
+            uplink: None,
+            downlink: None,
         }
     }
 }
 
+/// The interface a config impairs unless told otherwise, which is also the one
+/// a config read back from its results is given, as they leave it out.
+fn loopback() -> String {
+    String::from("lo")
+}
+
 impl NetworkConfig {
-    fn reset(&self) {
+    /// The most servers `apply` can impair at once. `prio` offers 16 bands,
+    /// each server needs two, and one is left for all other traffic.
+    pub const MAX_SERVERS: usize = 7;
+
+    pub fn reset(&self) {
         Command::new("tc")
-            .args(&["qdisc", "del", "dev", self.interface.as_str(), "root"])
+            .args(["qdisc", "del", "dev", self.interface.as_str(), "root"])
             .output()
             .expect("resetting network loopback interface");
     }
 
-    fn apply(&self) {
+    /// Whether `interface` is a loopback interface, which both directions of
+    /// a run leave through. Any other interface only carries what this host
+    /// sends, so of a remote server's traffic only the uplink is impaired.
+    pub fn is_loopback(&self) -> bool {
+        const ARPHRD_LOOPBACK: &str = "772";
+        fs::read_to_string(format!("/sys/class/net/{}/type", self.interface))
+            .map(|kind| kind.trim() == ARPHRD_LOOPBACK)
+            .unwrap_or(self.interface == "lo")
+    }
+
+    /// The symmetric settings describe a round trip, so each direction gets
+    /// half of the delay, jitter, and loss.
+    fn symmetric_link(&self) -> LinkParams {
+        LinkParams {
+            delay: self.delay as f64 / 2.,
+            jitter: self.jitter as f64 / 2.,
+            random_loss: self.random_loss / 2.,
+            rate_limit_kbps: self.rate_limit_kbps,
+        }
+    }
+
+    /// Whether netem would impair traffic at all under these settings.
+    pub fn impairs(&self) -> bool {
+        let unlimited = Self::default().rate_limit_kbps;
+        [self.uplink(), self.downlink()].iter().any(|link| {
+            link.delay > 0.
+                || link.jitter > 0.
+                || link.random_loss > 0.
+                || link.rate_limit_kbps < unlimited
+        })
+    }
+
+    pub fn uplink(&self) -> LinkParams {
+        self.uplink.unwrap_or_else(|| self.symmetric_link())
+    }
+
+    pub fn downlink(&self) -> LinkParams {
+        self.downlink.unwrap_or_else(|| self.symmetric_link())
+    }
+
+    fn tc(&self, args: &[&str]) {
         let output = Command::new("tc")
-            .args(&[
-                "qdisc",
-                "add",
-                "dev",
-                &self.interface,
-                "root",
-                "netem",
-                "delay",
-                &format!("{}", self.delay / 2 * 1000),
-                &format!("{}", self.jitter / 2 * 1000),
-                &format!("{}", self.delay_correlation),
-                "loss",
-                "random",
-                &format!("{}%", self.random_loss / 2.),
-                &format!("{}%", self.random_loss_correlation),
-                "rate",
-                &format!("{}kbit", self.rate_limit_kbps),
-                "limit",
-                &format!("{}", self.packet_limit / 2),
-            ])
+            .args(args)
             .output()
-            .expect("applying delay");
+            .expect("configuring network interface");
         assert_eq!(
             output.status.code(),
             Some(0),
-            "Failure apllying: {:#?}",
+            "Failure applying {:?}: {:#?}",
+            args,
             output
         )
     }
+
+    /// The netem handles `apply` gives the uplink and downlink of the
+    /// `index`th server.
+    fn netem_handles(index: usize) -> (String, String) {
+        (
+            format!("{:x}:", 0x10 + 2 * index),
+            format!("{:x}:", 0x11 + 2 * index),
+        )
+    }
+
+    /// Reads back the settings netem applied for the `index`th of the
+    /// servers passed to `apply`. Returns `None` if the interface has no
+    /// netem qdiscs for that server or `tc` cannot be run.
+    pub fn read_back(&self, index: usize) -> Option<EffectiveNetwork> {
+        let output = Command::new("tc")
+            .args(["qdisc", "show", "dev", self.interface.as_str()])
+            .output()
+            .ok()?;
+        let shown = String::from_utf8(output.stdout).ok()?;
+        Self::find_netem(&shown, index)
+    }
+
+    /// Finds the settings of the `index`th server's netem qdiscs in `shown`,
+    /// the output of `tc qdisc show`.
+    fn find_netem(shown: &str, index: usize) -> Option<EffectiveNetwork> {
+        let (uplink, downlink) = Self::netem_handles(index);
+        let settings = |handle: &str| {
+            shown
+                .lines()
+                .find(|line| {
+                    line.split_whitespace().nth(2) == Some(handle)
+                        && line.starts_with("qdisc netem")
+                })?
+                .parse()
+                .ok()
+        };
+        Some(EffectiveNetwork {
+            uplink: settings(&uplink)?,
+            downlink: settings(&downlink)?,
+        })
+    }
+
+    fn apply_netem(&self, parent: &str, handle: &str, link: LinkParams) {
+        self.tc(&[
+            "qdisc",
+            "add",
+            "dev",
+            &self.interface,
+            "parent",
+            parent,
+            "handle",
+            handle,
+            "netem",
+            "delay",
+            &format!("{}ms", link.delay),
+            &format!("{}ms", link.jitter),
+            &format!("{}", self.delay_correlation),
+            "loss",
+            "random",
+            &format!("{}%", link.random_loss),
+            &format!("{}%", self.random_loss_correlation),
+            "rate",
+            &format!("{}kbit", link.rate_limit_kbps),
+            "limit",
+            &format!("{}", self.packet_limit / 2),
+        ]);
+    }
+
+    /// Applies the uplink settings to traffic headed to each of
+    /// `server_ports` and the downlink settings to traffic coming from them.
+    /// Every server gets its own queues, so concurrent runs do not share a
+    /// rate limit. All other traffic on the interface is left alone.
+    pub fn apply(&self, server_ports: &[u16]) {
+        assert!(
+            server_ports.len() <= Self::MAX_SERVERS,
+            "netem can only impair {} servers at once",
+            Self::MAX_SERVERS
+        );
+
+        let interface = self.interface.as_str();
+        let bands = format!("{}", 2 * server_ports.len() + 1);
+        let default_band = format!("{}", 2 * server_ports.len());
+
+        let mut root = vec![
+            "qdisc", "add", "dev", interface, "root", "handle", "1:", "prio",
+            "bands", &bands, "priomap",
+        ];
+        root.extend(vec![default_band.as_str(); 16]);
+        self.tc(&root);
+
+        for (i, server_port) in server_ports.iter().enumerate() {
+            let server_port = format!("{}", server_port);
+            let uplink = format!("1:{:x}", 2 * i + 1);
+            let downlink = format!("1:{:x}", 2 * i + 2);
+
+            let (uplink_handle, downlink_handle) = Self::netem_handles(i);
+            self.apply_netem(&uplink, &uplink_handle, self.uplink());
+            self.apply_netem(&downlink, &downlink_handle, self.downlink());
+
+            for (direction, flow) in &[("dport", &uplink), ("sport", &downlink)]
+            {
+                self.tc(&[
+                    "filter",
+                    "add",
+                    "dev",
+                    interface,
+                    "parent",
+                    "1:",
+                    "protocol",
+                    "ip",
+                    "prio",
+                    "1",
+                    "u32",
+                    "match",
+                    "ip",
+                    direction,
+                    &server_port,
+                    "0xffff",
+                    "flowid",
+                    flow,
+                ]);
+            }
+        }
+    }
 }
 
 #[derive(StructOpt)]
@@ -96,21 +425,124 @@ pub struct Options {
     #[structopt(flatten)]
     pub client_options: client::Options,
     /// Whether to launch a server in this process at the client's expected
-    /// server address.
+    /// server address. Without it the runner only runs the client, against a
+    /// `server` started separately on this host or another, and the report
+    /// has no server summary.
     #[structopt(long)]
     pub start_server: bool,
+    /// How many echoes the server started in this process returns in one
+    /// datagram.
+    #[structopt(long, default_value = "1")]
+    pub return_batch: usize,
+    /// How the server started in this process returns one stream's
+    /// datagrams, as with the server's `--stream-policy`.
+    #[structopt(long = "stream-policy", number_of_values = 1)]
+    pub stream_policies: Vec<server::StreamPolicy>,
     #[structopt(long, short = "o")]
     pub output: Option<String>,
+    /// Trace the timings of each datagram's round trip and write them here
+    /// as a Chrome trace.
+    #[structopt(long)]
+    pub timings_output: Option<String>,
+    /// Write the client's buffer samples here as CSV. Needs
+    /// `--buffer-sample-interval-ms`.
+    #[structopt(long, requires = "buffer-sample-interval-ms")]
+    pub buffers_output: Option<String>,
+    /// Address on which to serve live Prometheus metrics during the run.
+    #[structopt(long)]
+    pub metrics_addr: Option<SocketAddr>,
+    /// Leave the network interface alone, because the caller has already
+    /// configured it for this run.
+    #[structopt(long)]
+    pub skip_network_config: bool,
+    /// Sample the process's CPU time and syscalls over the run.
+    #[structopt(long)]
+    pub profile: bool,
+    /// How many clients to run at once against the server. They share the
+    /// server's port, and so its link and rate limit.
+    #[structopt(long, default_value = "1")]
+    pub flows: usize,
+    /// Run in virtual time: the delays a transport inside this process
+    /// models pass instantly, but are measured in full. Needs
+    /// `--start-server`, so that the server runs in the simulation too.
+    #[structopt(long, requires = "start-server")]
+    pub virtual_time: bool,
+}
+
+/// Jain's fairness index of the flows' `throughputs`: 1 when every flow gets
+/// the same share, falling towards `1 / n` as one flow takes it all. Flows
+/// which all got nothing count as fair.
+pub fn jains_fairness_index(throughputs: &[f64]) -> f64 {
+    let sum: f64 = throughputs.iter().sum();
+    let sum_of_squares: f64 = throughputs.iter().map(|t| t * t).sum();
+    if sum_of_squares == 0.0 {
+        return 1.0;
+    }
+
+    sum * sum / (throughputs.len() as f64 * sum_of_squares)
+}
+
+/// Runs every flow's client, returning each flow's summary.
+async fn run_clients(options: &Options) -> Result<Vec<client::Summary>> {
+    let clients = (0..options.flows)
+        .map(|_| client::client_main(options.client_options.clone()));
+    future::join_all(clients).await.into_iter().collect()
+}
+
+/// The results of one run.
+#[derive(Debug)]
+pub struct Report {
+    /// What the client saw, or the mean of what each client saw with
+    /// several flows.
+    pub client_summary: client::Summary,
+    /// Jain's fairness index of the flows' throughputs, if there were several
+    /// flows.
+    pub fairness: Option<f64>,
+    /// What the server saw, if it was started in this process. A server
+    /// started separately collects the same statistics but has no way to
+    /// ship them back to the runner, so they are only available here with
+    /// `--start-server`.
+    pub server_summary: Option<server::ServerSummary>,
+    /// The netem settings in force during the run, if this run configured
+    /// the interface and could read them back.
+    pub effective_network: Option<EffectiveNetwork>,
+    /// What the process spent on the run, if it was profiled.
+    pub profile: Option<profile::ProcessCounters>,
 }
 
-async fn run_client(options: &Options) -> Result<client::Summary> {
-    client::client_main(options.client_options.clone()).await
+/// Why a run could not be made, as opposed to failing partway.
+#[derive(Debug)]
+pub enum RunnerError {
+    /// The protocol cannot run the scenario as configured, so its results
+    /// would not measure what the scenario asks for.
+    Unsupported { protocol: Protocol, reason: String },
 }
 
-async fn run(options: &Options) -> Result<client::Summary> {
+impl fmt::Display for RunnerError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            RunnerError::Unsupported { protocol, reason } => {
+                write!(f, "{} cannot run this scenario: {}", protocol, reason)
+            }
+        }
+    }
+}
+
+impl std::error::Error for RunnerError {}
+
+async fn run(options: &Options) -> Result<Report> {
+    let stats = server::ServerStats::default();
     let server_options = server::Options {
         address: options.client_options.address,
         protocol: options.client_options.protocol,
+        processing_delay: server::ProcessingDelay::Constant(0.0),
+        seed: 0,
+        return_batch: options.return_batch,
+        stream_policies: options.stream_policies.clone(),
+        fec: options.client_options.fec,
+        address_filter: server::AddressFilter::default(),
+        accept_limit: server::AcceptLimit::default(),
+        stats: Some(stats.clone()),
     };
 
     let run_server = async move {
@@ -122,32 +554,745 @@ async fn run(options: &Options) -> Result<client::Summary> {
     }
     .boxed_local();
 
-    let run_client = run_client(&options).boxed_local();
+    let run_clients = run_clients(options).boxed_local();
 
-    match future::select(run_server, run_client).await {
-        Either::Left((_, client)) => client.await,
+    let flows = match future::select(run_server, run_clients).await {
+        Either::Left((_, clients)) => clients.await,
         Either::Right((result, _)) => result,
-    }
+    }?;
+    let fairness = match flows.len() {
+        1 => None,
+        _ => {
+            let throughputs: Vec<f64> = flows
+                .iter()
+                .map(client::Summary::throughput_bytes_per_second)
+                .collect();
+            Some(jains_fairness_index(&throughputs))
+        }
+    };
+
+    Ok(Report {
+        client_summary: client::Summary::of_concurrent_flows(flows),
+        fairness,
+        server_summary: if options.start_server {
+            Some(stats.summary())
+        } else {
+            None
+        },
+        effective_network: None,
+        profile: None,
+    })
 }
 
-pub async fn runner_main(options: Options) -> Result<client::Summary> {
-    options.network_config.reset();
-    options.network_config.apply();
+pub async fn runner_main(mut options: Options) -> Result<Report> {
+    if options.flows == 0 {
+        return Err("cannot run with 0 flows".into());
+    }
 
-    let results = run(&options).await;
+    let protocol = options.client_options.protocol;
+    let transport = protocol.transport();
+    let transfers = &options.client_options.transfers;
+    if !transport.unreliable && transfers.iter().any(|tx| tx.unreliable) {
+        return Err(RunnerError::Unsupported {
+            protocol,
+            reason: String::from("it cannot send unreliable datagrams"),
+        }
+        .into());
+    }
+    if !transport.network
+        && transport.impair.is_none()
+        && options.network_config.impairs()
+    {
+        return Err(RunnerError::Unsupported {
+            protocol,
+            reason: String::from(
+                "its traffic does not cross the network, so netem cannot \
+                 impair it",
+            ),
+        }
+        .into());
+    }
 
-    options.network_config.reset();
+    if options.virtual_time && transport.network {
+        return Err(RunnerError::Unsupported {
+            protocol,
+            reason: String::from(
+                "its traffic crosses the network, which does not keep \
+                 virtual time",
+            ),
+        }
+        .into());
+    }
 
-    let results = results?;
+    if let Some(metrics_addr) = options.metrics_addr {
+        let registry = options
+            .client_options
+            .metrics
+            .get_or_insert_with(Default::default)
+            .clone();
+        metrics::serve(metrics_addr, registry).await?;
+    }
+
+    if options.timings_output.is_some() {
+        options.client_options.trace_timings = true;
+    }
+
+    let profiler = if options.profile {
+        Some(profile::Profiler::start()?)
+    } else {
+        None
+    };
+
+    let configure_network = !options.skip_network_config
+        && options.client_options.protocol.transport().network;
+    let server_address = options.client_options.address;
+    if configure_network
+        && !server_address.ip().is_loopback()
+        && options.network_config.is_loopback()
+    {
+        return Err(format!(
+            "netem on {} cannot impair the path to {}; pass the interface \
+             on that path with --interface",
+            options.network_config.interface, server_address
+        )
+        .into());
+    }
+    let mut effective_network = None;
+    if configure_network {
+        options.network_config.reset();
+        options
+            .network_config
+            .apply(&[options.client_options.address.port()]);
+        effective_network = options.network_config.read_back(0);
+    }
+
+    // A transport off the network impairs itself, for this run alone.
+    let impair = transport
+        .impair
+        .filter(|_| options.network_config.impairs());
+    if let Some(impair) = impair {
+        impair(server_address, Some(&options.network_config));
+    }
+
+    let results = match options.virtual_time {
+        true => clock::simulate(run(&options)).await,
+        false => run(&options).await,
+    };
+    let profile = profiler.map(profile::Profiler::finish).transpose();
+
+    if configure_network {
+        options.network_config.reset();
+    }
+    if let Some(impair) = impair {
+        impair(server_address, None);
+    }
+
+    let mut results = results?;
+    results.effective_network = effective_network;
+    results.profile = profile?;
+    // Transports off the network which cannot impair themselves never pass
+    // through netem, so all of their round trip is queueing and processing.
+    // Off loopback, only the uplink passes through this host's netem.
+    let propagation_ms = match transport.network {
+        true if !options.network_config.is_loopback() => {
+            options.network_config.uplink().delay
+        }
+        false if impair.is_none() => 0.0,
+        _ => {
+            options.network_config.uplink().delay
+                + options.network_config.downlink().delay
+        }
+    };
+    results.client_summary.queueing_delay_ms =
+        results.client_summary.queueing_delay(propagation_ms);
 
     if let Some(output) = options.output {
         let writer = fs::File::create(output)?;
         let mut writer = csv::Writer::from_writer(writer);
 
-        for report in &results.trip_reports {
+        for report in &results.client_summary.trip_reports {
             writer.serialize(report)?;
         }
     }
+    if let Some(buffers_output) = options.buffers_output {
+        let writer = fs::File::create(buffers_output)?;
+        let mut writer = csv::Writer::from_writer(writer);
+
+        for sample in &results.client_summary.buffer_samples {
+            writer.serialize(sample)?;
+        }
+    }
+    if let Some(timings_output) = options.timings_output {
+        let writer = fs::File::create(timings_output)?;
+        timings::write_chrome_trace(
+            &results.client_summary.timing_trails,
+            io::BufWriter::new(writer),
+        )?;
+    }
 
     Ok(results)
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::{Duration, Instant};
+
+    #[test]
+    fn equal_throughputs_are_fair() {
+        assert_eq!(jains_fairness_index(&[250.0, 250.0]), 1.0);
+        assert_eq!(jains_fairness_index(&[7.0, 7.0, 7.0, 7.0]), 1.0);
+        assert_eq!(jains_fairness_index(&[0.0, 0.0]), 1.0);
+    }
+
+    #[test]
+    fn unequal_throughputs_are_less_fair() {
+        assert_eq!(jains_fairness_index(&[300.0, 100.0]), 0.8);
+        // One flow taking everything is as unfair as `n` flows can be.
+        assert_eq!(jains_fairness_index(&[500.0, 0.0]), 0.5);
+        assert_eq!(jains_fairness_index(&[500.0, 0.0, 0.0, 0.0]), 0.25);
+    }
+
+    #[test]
+    fn netem_settings_parse() {
+        let settings: NetemSettings = "qdisc netem 10: parent 1:1 limit 500 \
+                                       delay 5ms  1ms 25% loss 1% 0.5% \
+                                       rate 1Mbit"
+            .parse()
+            .unwrap();
+        assert_eq!(
+            settings,
+            NetemSettings {
+                delay_ms: 5.0,
+                jitter_ms: 1.0,
+                delay_correlation: 25.0,
+                random_loss: 1.0,
+                random_loss_correlation: 0.5,
+                rate_limit_kbps: 1000.0,
+                packet_limit: 500,
+            }
+        );
+    }
+
+    #[test]
+    fn netem_settings_convert_units() {
+        let settings: NetemSettings =
+            "qdisc netem 11: parent 1:2 limit 1000 delay 1.5s 250us \
+             loss random 2% rate 2Gibit"
+                .parse()
+                .unwrap();
+        assert_eq!(settings.delay_ms, 1500.0);
+        assert_eq!(settings.jitter_ms, 0.25);
+        assert_eq!(settings.delay_correlation, 0.0);
+        assert_eq!(settings.random_loss, 2.0);
+        assert_eq!(settings.rate_limit_kbps, 2.0 * 1024f64.powi(3) / 1e3);
+    }
+
+    #[test]
+    fn netem_settings_reject_other_qdiscs() {
+        assert!("qdisc prio 1: root refcnt 2 bands 16"
+            .parse::<NetemSettings>()
+            .is_err());
+        assert!("qdisc netem 10: parent 1:1 delay soon"
+            .parse::<NetemSettings>()
+            .is_err());
+    }
+
+    /// `tc qdisc show` as it reads on Linux after `apply` for two servers.
+    #[cfg(target_os = "linux")]
+    #[test]
+    fn netem_read_back_finds_each_servers_qdiscs() {
+        let shown = "\
+qdisc prio 1: root refcnt 2 bands 16 priomap 15 15 15 15 15 15 15 15 15 15 15 15 15 15 15 15
+qdisc netem 10: parent 1:1 limit 500 delay 20ms  2ms loss 1% rate 10Mbit
+qdisc netem 11: parent 1:2 limit 500 delay 30ms
+qdisc netem 12: parent 1:3 limit 500 delay 40ms
+qdisc netem 13: parent 1:4 limit 500 delay 50ms loss 3%
+";
+        let first = NetworkConfig::find_netem(shown, 0).unwrap();
+        assert_eq!(first.uplink.delay_ms, 20.0);
+        assert_eq!(first.uplink.jitter_ms, 2.0);
+        assert_eq!(first.uplink.rate_limit_kbps, 10_000.0);
+        assert_eq!(first.downlink.delay_ms, 30.0);
+
+        let second = NetworkConfig::find_netem(shown, 1).unwrap();
+        assert_eq!(second.uplink.delay_ms, 40.0);
+        assert_eq!(second.downlink.random_loss, 3.0);
+
+        assert_eq!(NetworkConfig::find_netem(shown, 2), None);
+    }
+
+    #[async_std::test]
+    async fn identical_memory_flows_are_fair() {
+        let options = Options::from_iter(&[
+            "runner",
+            "memory",
+            "-a",
+            "127.0.0.1:47601",
+            "--start-server",
+            "--skip-network-config",
+            "--flows",
+            "2",
+            "-b",
+            "0:100:200::40",
+        ]);
+        let report = runner_main(options).await.unwrap();
+        let fairness = report.fairness.unwrap();
+        assert!(fairness > 0.95, "fairness {}", fairness);
+    }
+
+    /// Runs `transfer` over a memory link with 20ms of round trip delay and
+    /// 80kbit/s each way, returning the client's summary.
+    async fn run_delayed_memory(port: u16, transfer: &str) -> client::Summary {
+        let address = format!("127.0.0.1:{}", port);
+        let options = Options::from_iter(&[
+            "runner",
+            "memory",
+            "-a",
+            &address,
+            "--start-server",
+            "--skip-network-config",
+            "--delay",
+            "20",
+            "--rate-limit-kbps",
+            "80",
+            "-b",
+            transfer,
+        ]);
+        runner_main(options).await.unwrap().client_summary
+    }
+
+    /// Runs 100 unreliable datagrams over a memory link losing 10% each
+    /// way, returning how many round trips were made.
+    async fn run_lossy_memory(port: u16, fec: &[&str]) -> usize {
+        let address = format!("127.0.0.1:{}", port);
+        let mut args = vec![
+            "runner",
+            "memory",
+            "-a",
+            &address,
+            "--start-server",
+            "--skip-network-config",
+            "--random-loss",
+            "20",
+            "-b",
+            "0u:100:200::100",
+        ];
+        args.extend(fec);
+        let report = runner_main(Options::from_iter(&args)).await.unwrap();
+        // The first round trip is summarized apart from the rest.
+        report.client_summary.trip_reports.len() + 1
+    }
+
+    #[async_std::test]
+    async fn fec_rebuilds_lost_unreliable_datagrams() {
+        let plain = run_lossy_memory(47641, &[]).await;
+        let coded = run_lossy_memory(47642, &["--fec", "1"]).await;
+        assert!(plain < 100, "{} round trips without loss", plain);
+        assert!(coded > plain, "{} round trips against {}", coded, plain);
+    }
+
+    #[async_std::test]
+    async fn unreliable_transfers_need_an_unreliable_transport() {
+        let options = Options::from_iter(&[
+            "runner",
+            "tcp",
+            "-a",
+            "127.0.0.1:47643",
+            "--start-server",
+            "--skip-network-config",
+            "-b",
+            "0u:100:200::10",
+        ]);
+        let error = runner_main(options).await.unwrap_err();
+        assert!(
+            matches!(
+                error.downcast_ref::<RunnerError>(),
+                Some(RunnerError::Unsupported { protocol, .. })
+                    if protocol.name() == "tcp"
+            ),
+            "{}",
+            error
+        );
+    }
+
+    #[async_std::test]
+    async fn fast_down_slow_up_links_impair_each_direction_apart() {
+        let options = Options::from_iter(&[
+            "runner",
+            "memory",
+            "-a",
+            "127.0.0.1:47700",
+            "--start-server",
+            "--skip-network-config",
+            "--uplink",
+            "40:0:0:80",
+            "--downlink",
+            "5:0:0:100000",
+            "-b",
+            "0:100:20::10",
+        ]);
+        let summary = runner_main(options).await.unwrap().client_summary;
+        // 40ms up and 5ms down, plus 10ms serializing 100 bytes at
+        // 80kbit/s up. A symmetric slow link would take twice as long.
+        assert!(summary.mean_ms >= 45.0, "mean {}", summary.mean_ms);
+        assert!(summary.mean_ms < 90.0, "mean {}", summary.mean_ms);
+    }
+
+    #[async_std::test]
+    async fn queueing_delay_tracks_load_on_a_delayed_memory_link() {
+        // 100 bytes take 10ms to serialize at 80kbit/s, so 20 a second fit
+        // on the link and 200 a second queue behind each other.
+        let light = run_delayed_memory(47621, "0:100:20::10").await;
+        let heavy = run_delayed_memory(47622, "0:100:200::40").await;
+
+        assert!(light.mean_ms >= 20.0, "light mean {}", light.mean_ms);
+        assert!(
+            (light.queueing_delay_ms - (light.mean_ms - 20.0)).abs() < 1e-9,
+            "light queueing delay {} of mean {}",
+            light.queueing_delay_ms,
+            light.mean_ms
+        );
+        assert!(
+            heavy.queueing_delay_ms > 2.0 * light.queueing_delay_ms + 20.0,
+            "heavy queueing delay {} against light {}",
+            heavy.queueing_delay_ms,
+            light.queueing_delay_ms
+        );
+    }
+
+    /// Sends 50 datagrams at 50Hz over TCP on loopback, flushing them under
+    /// `flush_policy`, and returns the client's summary.
+    async fn run_flushed_tcp(port: u16, flush_policy: &str) -> client::Summary {
+        let address = format!("127.0.0.1:{}", port);
+        let options = Options::from_iter(&[
+            "runner",
+            "tcp",
+            "-a",
+            &address,
+            "--start-server",
+            "--skip-network-config",
+            "--flush-policy",
+            flush_policy,
+            "-b",
+            "0:100:50::50",
+        ]);
+        runner_main(options).await.unwrap().client_summary
+    }
+
+    #[async_std::test]
+    async fn batched_flushes_trade_latency_for_fewer_writes() {
+        let per_send = run_flushed_tcp(47704, "send").await;
+        let batched = run_flushed_tcp(47705, "every:5").await;
+
+        // A batch of five sent 20ms apart waits 40ms on average to be
+        // flushed.
+        assert!(
+            per_send.mean_ms < 10.0,
+            "per send mean {}",
+            per_send.mean_ms
+        );
+        assert!(
+            batched.mean_ms > per_send.mean_ms + 20.0,
+            "batched mean {} against {}",
+            batched.mean_ms,
+            per_send.mean_ms
+        );
+        // Each batch goes out in one write, so its echoes arrive together
+        // rather than one per send.
+        assert!(
+            per_send.interarrival_p50_ms > 10.0,
+            "per send interarrival {}",
+            per_send.interarrival_p50_ms
+        );
+        assert!(
+            batched.interarrival_p50_ms < 5.0,
+            "batched interarrival {}",
+            batched.interarrival_p50_ms
+        );
+    }
+
+    #[async_std::test]
+    async fn reports_carry_the_servers_view_of_the_run() {
+        let options = Options::from_iter(&[
+            "runner",
+            "memory",
+            "-a",
+            "127.0.0.1:47707",
+            "--start-server",
+            "--skip-network-config",
+            "-b",
+            "0:100:200::10",
+            "-b",
+            "1:50:200::5",
+        ]);
+        let report = runner_main(options).await.unwrap();
+        let server = report.server_summary.unwrap();
+
+        assert_eq!(server.streams.len(), 2);
+        let received: Vec<usize> = server
+            .streams
+            .values()
+            .map(|stream| stream.datagrams_received)
+            .collect();
+        assert_eq!(received, vec![10, 5]);
+        assert!(server.streams.values().all(|s| s.detected_loss == 0));
+    }
+
+    /// Runs 500 reliable datagrams over a memory link losing `random_loss`
+    /// percent of the round trip's traffic, returning the amplification.
+    async fn run_lossy_amplification(port: u16, random_loss: &str) -> f64 {
+        let address = format!("127.0.0.1:{}", port);
+        let options = Options::from_iter(&[
+            "runner",
+            "memory",
+            "-a",
+            &address,
+            "--start-server",
+            "--skip-network-config",
+            "--random-loss",
+            random_loss,
+            "-b",
+            "0:100:1000::500",
+        ]);
+        runner_main(options)
+            .await
+            .unwrap()
+            .client_summary
+            .amplification
+    }
+
+    #[async_std::test]
+    async fn retransmissions_amplify_lossy_links() {
+        let clean = run_lossy_amplification(47711, "0").await;
+        let lossy = run_lossy_amplification(47712, "20").await;
+        assert_eq!(clean, 1.0);
+        // Each direction loses 10%, so about one datagram in nine is sent
+        // again.
+        assert!(lossy > 1.05, "amplification {}", lossy);
+        assert!(lossy < 1.25, "amplification {}", lossy);
+    }
+
+    #[async_std::test]
+    async fn fixed_totals_send_the_same_workload_on_every_transport() {
+        let run = |protocol: &'static str, address: &'static str| async move {
+            let options = Options::from_iter(&[
+                "runner",
+                protocol,
+                "-a",
+                address,
+                "--start-server",
+                "--skip-network-config",
+                "--delay",
+                "20",
+                "-b",
+                "0:100:200::30",
+            ]);
+            runner_main(options).await.unwrap()
+        };
+        // Memory impairs itself, so its round trips take 20ms longer than
+        // TCP's on loopback, yet both send the same 30 datagrams.
+        for report in &[
+            run("memory", "127.0.0.1:47714").await,
+            run("tcp", "127.0.0.1:47715").await,
+        ] {
+            let server = report.server_summary.as_ref().unwrap();
+            assert_eq!(server.streams[&StreamId(0)].datagrams_received, 30);
+            // The first round trip is measured apart from the rest.
+            assert_eq!(report.client_summary.round_trips(), 29);
+        }
+    }
+
+    #[async_std::test]
+    async fn deep_queues_turn_overload_into_latency() {
+        // 60 datagrams a second of 200B is more than 64kbit carries, and the
+        // queue is deep enough to hold everything sent.
+        let options = Options::from_iter(&[
+            "runner",
+            "memory",
+            "-a",
+            "127.0.0.1:47722",
+            "--start-server",
+            "--skip-network-config",
+            "--rate-limit-kbps",
+            "64",
+            "--queue-limit",
+            "100000",
+            "-b",
+            "0:200:60::40",
+        ]);
+        let summary = runner_main(options).await.unwrap().client_summary;
+        // Nothing is dropped...
+        assert_eq!(summary.round_trips(), 39);
+        // ...but each datagram waits behind more than the last, so the latest
+        // take far longer than the first did on the idle link.
+        assert!(
+            summary.round_trip_p99_ms > summary.first_rtt_ms + 100.0,
+            "{:?}",
+            summary
+        );
+    }
+
+    #[async_std::test]
+    async fn virtual_time_measures_modeled_delays_without_waiting() {
+        let options = Options::from_iter(&[
+            "runner",
+            "memory",
+            "-a",
+            "127.0.0.1:47728",
+            "--start-server",
+            "--skip-network-config",
+            "--virtual-time",
+            "--delay",
+            "200",
+            "-b",
+            "0:100:20::20",
+        ]);
+        let started = Instant::now();
+        let report = runner_main(options).await.unwrap();
+
+        // 20 sends 50ms apart, each with a 200ms round trip, would take
+        // over a second in real time.
+        assert!(started.elapsed() < Duration::from_millis(250));
+        let summary = report.client_summary;
+        assert_eq!(summary.round_trips(), 19);
+        assert!((summary.mean_ms - 200.0).abs() < 1.0, "{:?}", summary);
+        assert!(summary.deviation_ms < 1.0, "{:?}", summary);
+    }
+
+    #[async_std::test]
+    async fn virtual_time_needs_a_transport_inside_the_process() {
+        let options = Options::from_iter(&[
+            "runner",
+            "tcp",
+            "-a",
+            "127.0.0.1:47729",
+            "--start-server",
+            "--skip-network-config",
+            "--virtual-time",
+        ]);
+        match runner_main(options)
+            .await
+            .map_err(|e| e.downcast::<RunnerError>())
+        {
+            Err(Ok(error)) => assert!(matches!(
+                *error,
+                RunnerError::Unsupported { protocol, .. } if protocol.name() == "tcp"
+            )),
+            _ => panic!("ran tcp in virtual time"),
+        }
+    }
+
+    #[cfg(target_os = "linux")]
+    #[async_std::test]
+    async fn profiled_runs_spend_cpu_time() {
+        let profiled = |address: &'static str| {
+            let options = Options::from_iter(&[
+                "runner",
+                "memory",
+                "-a",
+                address,
+                "--start-server",
+                "--skip-network-config",
+                "--profile",
+                "-b",
+                "0:1000:500::200",
+            ]);
+            async move { runner_main(options).await.unwrap().profile.unwrap() }
+        };
+
+        let both = profile::Profiler::start().unwrap();
+        let first = profiled("127.0.0.1:47730").await;
+        let second = profiled("127.0.0.1:47731").await;
+        let both = both.finish().unwrap();
+
+        assert!(first.cpu_ms() > 0.0, "{:?}", first);
+        assert!(second.cpu_ms() > 0.0, "{:?}", second);
+        // The process's CPU time only grows, so the span of both runs spent
+        // at least what each did on its own.
+        assert!(
+            both.cpu_ms() >= first.cpu_ms() + second.cpu_ms() - 1e-6,
+            "{:?} then {:?} within {:?}",
+            first,
+            second,
+            both
+        );
+    }
+
+    #[async_std::test]
+    async fn clients_run_against_a_server_started_apart() {
+        // The server runs alone, as the server binary would on another host.
+        let server = server::server_main(server::Options::from_iter(&[
+            "server",
+            "tcp",
+            "-a",
+            "127.0.0.1:47735",
+        ]))
+        .boxed_local();
+        let client = runner_main(Options::from_iter(&[
+            "runner",
+            "tcp",
+            "-a",
+            "127.0.0.1:47735",
+            "--skip-network-config",
+            "-b",
+            "0:100:200::10",
+        ]))
+        .boxed_local();
+
+        let report = match future::select(server, client).await {
+            Either::Left((result, _)) => panic!("server stopped: {:?}", result),
+            Either::Right((report, _)) => report.unwrap(),
+        };
+        assert_eq!(report.client_summary.round_trips(), 9);
+        // The server's stats are only at hand when it runs in this process.
+        assert!(report.server_summary.is_none());
+    }
+
+    #[async_std::test]
+    async fn loopback_netem_refuses_to_impair_a_remote_server() {
+        let options = Options::from_iter(&[
+            "runner",
+            "tcp",
+            "-a",
+            "192.0.2.1:33333",
+            "--interface",
+            "lo",
+        ]);
+        let error = runner_main(options).await.err().unwrap();
+        assert!(error.to_string().contains("--interface"), "{}", error);
+    }
+
+    #[async_std::test]
+    async fn losses_block_ordered_streams_but_not_unordered_ones() {
+        let blocking = |address: &'static str, transfer: &'static str| {
+            let options = Options::from_iter(&[
+                "runner",
+                "memory",
+                "-a",
+                address,
+                "--start-server",
+                "--skip-network-config",
+                "--virtual-time",
+                "--delay",
+                "40",
+                "--random-loss",
+                "10",
+                "-b",
+                transfer,
+            ]);
+            async move { runner_main(options).await.unwrap().client_summary }
+        };
+
+        // A lost ordered datagram is retransmitted a round trip later, and
+        // the echoes behind it wait with it.
+        let ordered = blocking("127.0.0.1:47736", "0:100:100::100").await;
+        assert!(ordered.hol_blocked > 0, "{:?}", ordered);
+        assert!(ordered.hol_blocking_ms > 0.0, "{:?}", ordered);
+
+        // Unordered echoes arrive as they come, or never.
+        let unordered = blocking("127.0.0.1:47737", "0u:100:100::100").await;
+        assert!(unordered.round_trips() < 100, "{:?}", unordered);
+        assert!(unordered.hol_blocking_ms < 0.1, "{:?}", unordered);
+    }
+}
@@ -0,0 +1,508 @@
+//! A bounded send buffer which can drop unreliable datagrams when it is full.
+//!
+//! Backpressure stalls the sender until the link catches up. That suits
+//! reliable data, but a game loop sending real-time state would rather lose a
+//! stale datagram than stall, so each unreliable stream may instead drop its
+//! oldest or newest datagram to make room. Reliable datagrams always wait.
+//!
+//! The buffer is the connection's scheduler, choosing which datagram goes to
+//! the wire next, so it is what gives a connection `Connection::pause_stream`.
+//! A stream may be paused, as a game pausing world updates behind a loading
+//! screen would, to hold its datagrams in the buffer while the other streams
+//! keep draining, and resumed to send them in order.
+//!
+//! The client buffers its sends with `--send-buffer`, applying
+//! `--drop-policy` to its unreliable transfers.
+
+use crate::*;
+
+use futures::{
+    future::{self, FutureExt, LocalBoxFuture},
+    stream::FusedStream,
+    task::{Context, Poll},
+    Sink, Stream,
+};
+use std::{
+    collections::{HashMap, HashSet, VecDeque},
+    pin::Pin,
+    str::FromStr,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc,
+    },
+};
+
+/// What to do with an unreliable datagram sent while the buffer is full.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DropPolicy {
+    /// Drop the oldest buffered datagram of the same stream to make room.
+    DropOldest,
+    /// Drop the datagram being sent.
+    DropNewest,
+    /// Wait for room, like a reliable datagram.
+    Backpressure,
+}
+
+impl FromStr for DropPolicy {
+    type Err = anyhow::Error;
+    fn from_str(src: &str) -> std::result::Result<Self, Self::Err> {
+        match src {
+            "drop-oldest" => Ok(DropPolicy::DropOldest),
+            "drop-newest" => Ok(DropPolicy::DropNewest),
+            "backpressure" => Ok(DropPolicy::Backpressure),
+            _ => anyhow::bail!(
+                "expected `drop-oldest`, `drop-newest`, or `backpressure`, \
+                 got {:?}",
+                src
+            ),
+        }
+    }
+}
+
+fn is_unreliable(delivery_mode: DeliveryMode) -> bool {
+    matches!(
+        delivery_mode,
+        DeliveryMode::UnreliableSequenced(_)
+            | DeliveryMode::UnreliableUnordered
+    )
+}
+
+/// The stream a datagram is sent on, if it is sent on one.
+fn stream_of(delivery_mode: DeliveryMode) -> Option<StreamId> {
+    match delivery_mode {
+        DeliveryMode::ReliableOrdered(stream_id)
+        | DeliveryMode::ReliableSequenced(stream_id)
+        | DeliveryMode::UnreliableSequenced(stream_id) => Some(stream_id),
+        DeliveryMode::ReliableUnordered | DeliveryMode::UnreliableUnordered => {
+            None
+        }
+    }
+}
+
+/// Wraps a connection with a send buffer of `capacity` datagrams, applying a
+/// `DropPolicy` per unreliable stream once it fills.
+pub struct SendBufferConnection<C> {
+    inner: C,
+    capacity: usize,
+    policies: HashMap<DeliveryMode, DropPolicy>,
+    outgoing: VecDeque<SendCmd>,
+    dropped: Arc<AtomicU64>,
+    paused: HashSet<StreamId>,
+    /// The most datagrams the buffer has held at once.
+    peak_depth: usize,
+}
+
+impl<C> SendBufferConnection<C>
+where
+    C: Connection + Unpin,
+{
+    /// Wraps `inner` with a buffer which applies backpressure to every stream
+    /// once it holds `capacity` datagrams.
+    pub fn new(inner: C, capacity: usize) -> Self {
+        assert!(capacity > 0, "send buffers must hold at least one datagram");
+        Self {
+            inner,
+            capacity,
+            policies: HashMap::new(),
+            outgoing: VecDeque::new(),
+            dropped: Arc::new(AtomicU64::new(0)),
+            paused: HashSet::new(),
+            peak_depth: 0,
+        }
+    }
+
+    /// Applies `policy` to the unreliable stream `delivery_mode` sends on.
+    pub fn with_policy(
+        mut self,
+        delivery_mode: DeliveryMode,
+        policy: DropPolicy,
+    ) -> Self {
+        assert!(
+            is_unreliable(delivery_mode),
+            "only unreliable datagrams may be dropped"
+        );
+        self.policies.insert(delivery_mode, policy);
+        self
+    }
+
+    /// A running count of the datagrams dropped because the buffer was full.
+    pub fn dropped(&self) -> Arc<AtomicU64> {
+        self.dropped.clone()
+    }
+
+    /// Whether `cmd` is held back because its stream is paused.
+    fn holds(&self, cmd: &SendCmd) -> bool {
+        stream_of(cmd.delivery_mode)
+            .map(|stream_id| self.paused.contains(&stream_id))
+            .unwrap_or(false)
+    }
+
+    /// Drains the buffer down to the datagrams held on paused streams.
+    fn poll_drain_outgoing(&mut self, ctx: &mut Context) -> Poll<Result<()>> {
+        loop {
+            let next = self.outgoing.iter().position(|cmd| !self.holds(cmd));
+            let next = match next {
+                Some(next) => next,
+                None => return Poll::Ready(Ok(())),
+            };
+
+            match Pin::new(&mut self.inner).poll_ready(ctx) {
+                Poll::Ready(Ok(())) => {}
+                Poll::Ready(Err(e)) => return Poll::Ready(Err(e)),
+                Poll::Pending => return Poll::Pending,
+            }
+            let cmd = self.outgoing.remove(next).unwrap();
+            Pin::new(&mut self.inner).start_send(cmd)?;
+        }
+    }
+}
+
+impl<C> Connection for SendBufferConnection<C>
+where
+    C: Connection + Unpin,
+{
+    /// Resolves once the inner connection is ready and the buffer has room.
+    fn ready(&mut self) -> LocalBoxFuture<'_, Result<()>> {
+        async move {
+            self.inner.ready().await?;
+            future::poll_fn(|ctx| Pin::new(&mut *self).poll_ready(ctx)).await
+        }
+        .boxed_local()
+    }
+
+    fn wire_bytes_sent(&self) -> Option<Arc<AtomicU64>> {
+        self.inner.wire_bytes_sent()
+    }
+
+    fn peer_addr(&self) -> Option<std::net::SocketAddr> {
+        self.inner.peer_addr()
+    }
+
+    fn peer_finished(&self) -> bool {
+        self.inner.peer_finished()
+    }
+
+    /// The send queue is the buffer's, in front of the inner connection, and
+    /// the datagrams it dropped are counted with the inner connection's.
+    fn transport_state(&self) -> TransportState {
+        let inner = self.inner.transport_state();
+        TransportState {
+            send_queue_depth: Some(self.outgoing.len() as u64),
+            dropped_sends: Some(
+                self.dropped.load(Ordering::Relaxed)
+                    + inner.dropped_sends.unwrap_or(0),
+            ),
+            ..inner
+        }
+    }
+
+    /// The send queue is the buffer's, in front of the inner connection.
+    fn high_water_marks(&self) -> HighWaterMarks {
+        HighWaterMarks {
+            send_queue_depth: Some(self.peak_depth as u64),
+            ..self.inner.high_water_marks()
+        }
+    }
+
+    fn set_max_pacing_rate(&self, bytes_per_second: u64) -> bool {
+        self.inner.set_max_pacing_rate(bytes_per_second)
+    }
+    /// Holds the datagrams sent on `stream_id` in the buffer until it is
+    /// resumed. A paused stream's datagrams still take up room, so a
+    /// reliable stream paused for long enough fills the buffer and holds the
+    /// sender back, and an unreliable one drops datagrams by its policy.
+    fn pause_stream(&mut self, stream_id: StreamId) -> bool {
+        self.paused.insert(stream_id);
+        true
+    }
+
+    /// Lets the datagrams held on `stream_id` drain, oldest first, the next
+    /// time the connection is polled for readiness or flushed.
+    fn resume_stream(&mut self, stream_id: StreamId) {
+        self.paused.remove(&stream_id);
+    }
+
+    /// Whether `stream_id` is paused, holding its datagrams in the buffer.
+    fn is_paused(&self, stream_id: StreamId) -> bool {
+        self.paused.contains(&stream_id)
+    }
+}
+
+impl<C> Sink<SendCmd> for SendBufferConnection<C>
+where
+    C: Connection + Unpin,
+{
+    type Error = Box<dyn std::error::Error>;
+    /// Ready while the buffer has room. A full buffer is still ready, so that
+    /// a datagram with a drop policy can take the place of another; only a
+    /// datagram which had to wait pushes the buffer past `capacity` and holds
+    /// the sender back.
+    fn poll_ready(
+        mut self: Pin<&mut Self>,
+        ctx: &mut Context,
+    ) -> Poll<Result<()>> {
+        if let Poll::Ready(Err(e)) = self.poll_drain_outgoing(ctx) {
+            return Poll::Ready(Err(e));
+        }
+
+        if self.outgoing.len() > self.capacity {
+            Poll::Pending
+        } else {
+            Poll::Ready(Ok(()))
+        }
+    }
+    fn start_send(mut self: Pin<&mut Self>, item: SendCmd) -> Result<()> {
+        if self.outgoing.len() >= self.capacity {
+            match self.policies.get(&item.delivery_mode) {
+                Some(DropPolicy::DropNewest) => {
+                    self.dropped.fetch_add(1, Ordering::Relaxed);
+                    return Ok(());
+                }
+                Some(DropPolicy::DropOldest) => {
+                    let delivery_mode = item.delivery_mode;
+                    let oldest = self
+                        .outgoing
+                        .iter()
+                        .position(|cmd| cmd.delivery_mode == delivery_mode);
+                    self.dropped.fetch_add(1, Ordering::Relaxed);
+                    match oldest {
+                        Some(oldest) => {
+                            self.outgoing.remove(oldest);
+                        }
+                        // With none of its stream's datagrams buffered, the
+                        // datagram being sent is the oldest.
+                        None => return Ok(()),
+                    }
+                }
+                Some(DropPolicy::Backpressure) | None => {}
+            }
+        }
+
+        self.outgoing.push_back(item);
+        self.peak_depth = self.peak_depth.max(self.outgoing.len());
+        Ok(())
+    }
+    fn poll_flush(
+        mut self: Pin<&mut Self>,
+        ctx: &mut Context,
+    ) -> Poll<Result<()>> {
+        match self.poll_drain_outgoing(ctx) {
+            Poll::Ready(Ok(())) => Pin::new(&mut self.inner).poll_flush(ctx),
+            other => other,
+        }
+    }
+    fn poll_close(
+        mut self: Pin<&mut Self>,
+        ctx: &mut Context,
+    ) -> Poll<Result<()>> {
+        match self.poll_drain_outgoing(ctx) {
+            Poll::Ready(Ok(())) => Pin::new(&mut self.inner).poll_close(ctx),
+            other => other,
+        }
+    }
+}
+
+impl<C> Stream for SendBufferConnection<C>
+where
+    C: Connection + Unpin,
+{
+    type Item = Result<Datagram>;
+    fn poll_next(
+        mut self: Pin<&mut Self>,
+        ctx: &mut Context,
+    ) -> Poll<Option<Self::Item>> {
+        Pin::new(&mut self.inner).poll_next(ctx)
+    }
+}
+
+impl<C> FusedStream for SendBufferConnection<C>
+where
+    C: Connection + Unpin,
+{
+    fn is_terminated(&self) -> bool {
+        self.inner.is_terminated()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::memory::MemoryConnection;
+    use futures::{SinkExt, StreamExt};
+
+    const STATE: DeliveryMode = DeliveryMode::UnreliableSequenced(StreamId(1));
+
+    fn send(data: u8, delivery_mode: DeliveryMode) -> SendCmd {
+        SendCmd {
+            data: vec![data],
+            delivery_mode,
+            ..SendCmd::default()
+        }
+    }
+
+    /// Sends `0..sends` on the paused stream `STATE` through a buffer of
+    /// three datagrams under `policy`, then resumes it and returns the drops
+    /// and what the peer receives.
+    async fn fill(policy: DropPolicy, sends: u8) -> (u64, Vec<Vec<u8>>) {
+        let (client, server) = MemoryConnection::pair(None);
+        let mut buffer =
+            SendBufferConnection::new(client, 3).with_policy(STATE, policy);
+        buffer.pause_stream(StreamId(1));
+        for data in 0..sends {
+            buffer.feed(send(data, STATE)).await.unwrap();
+        }
+        let dropped = buffer.dropped().load(Ordering::Relaxed);
+        assert_eq!(buffer.transport_state().dropped_sends, Some(dropped));
+
+        buffer.resume_stream(StreamId(1));
+        buffer.close().await.unwrap();
+        let received = server
+            .map(|datagram| datagram.unwrap().data)
+            .collect()
+            .await;
+        (dropped, received)
+    }
+
+    #[async_std::test]
+    async fn drop_newest_keeps_the_first_datagrams() {
+        let (dropped, received) = fill(DropPolicy::DropNewest, 5).await;
+        assert_eq!(dropped, 2);
+        assert_eq!(received, vec![vec![0], vec![1], vec![2]]);
+    }
+
+    #[async_std::test]
+    async fn drop_oldest_keeps_the_last_datagrams() {
+        let (dropped, received) = fill(DropPolicy::DropOldest, 5).await;
+        assert_eq!(dropped, 2);
+        assert_eq!(received, vec![vec![2], vec![3], vec![4]]);
+    }
+
+    #[async_std::test]
+    async fn backpressure_holds_the_sender_back() {
+        let (client, _server) = MemoryConnection::pair(None);
+        let mut buffer = SendBufferConnection::new(client, 3)
+            .with_policy(STATE, DropPolicy::Backpressure);
+        buffer.pause_stream(StreamId(1));
+        // The datagram which finds the buffer full waits in it.
+        for data in 0..4 {
+            buffer.feed(send(data, STATE)).await.unwrap();
+        }
+        assert!(buffer.ready().now_or_never().is_none());
+        assert!(buffer.feed(send(4, STATE)).now_or_never().is_none());
+        assert_eq!(buffer.dropped().load(Ordering::Relaxed), 0);
+        assert_eq!(buffer.transport_state().send_queue_depth, Some(4));
+
+        buffer.resume_stream(StreamId(1));
+        buffer.ready().await.unwrap();
+        assert_eq!(buffer.transport_state().send_queue_depth, Some(0));
+    }
+
+    #[async_std::test]
+    async fn bursts_set_the_send_queue_high_water_mark() {
+        let (client, server) = MemoryConnection::pair(None);
+        let mut buffer = SendBufferConnection::new(client, 8);
+        assert_eq!(buffer.high_water_marks().send_queue_depth, Some(0));
+        buffer.pause_stream(StreamId(1));
+        for data in 0..5 {
+            buffer.feed(send(data, STATE)).await.unwrap();
+        }
+        buffer.resume_stream(StreamId(1));
+        buffer.close().await.unwrap();
+        assert_eq!(server.count().await, 5);
+
+        // The queue has drained, but its peak is the burst.
+        assert_eq!(buffer.transport_state().send_queue_depth, Some(0));
+        assert_eq!(buffer.high_water_marks().send_queue_depth, Some(5));
+    }
+
+    #[async_std::test]
+    async fn backpressure_drops_nothing() {
+        let (dropped, received) = fill(DropPolicy::Backpressure, 4).await;
+        assert_eq!(dropped, 0);
+        assert_eq!(received.len(), 4);
+    }
+
+    #[async_std::test]
+    async fn drop_oldest_drops_the_send_without_an_older_one() {
+        let (client, server) = MemoryConnection::pair(None);
+        let mut buffer = SendBufferConnection::new(client, 3)
+            .with_policy(STATE, DropPolicy::DropOldest);
+        let reliable = DeliveryMode::ReliableOrdered(StreamId(2));
+        buffer.pause_stream(StreamId(2));
+        for data in 0..3 {
+            buffer.feed(send(data, reliable)).await.unwrap();
+        }
+        buffer.feed(send(3, STATE)).await.unwrap();
+        assert_eq!(buffer.dropped().load(Ordering::Relaxed), 1);
+        assert_eq!(buffer.transport_state().send_queue_depth, Some(3));
+
+        buffer.resume_stream(StreamId(2));
+        buffer.close().await.unwrap();
+        let received: Vec<Vec<u8>> = server
+            .map(|datagram| datagram.unwrap().data)
+            .collect()
+            .await;
+        assert_eq!(received, vec![vec![0], vec![1], vec![2]]);
+    }
+
+    #[async_std::test]
+    async fn paused_streams_catch_up_once_resumed() {
+        let (client, mut server) = MemoryConnection::pair(None);
+        let mut connection: registry::BoxConnection =
+            Box::new(SendBufferConnection::new(client, 8));
+        let (paused, running) = (StreamId(1), StreamId(2));
+        assert!(connection.pause_stream(paused));
+        assert!(connection.is_paused(paused));
+        for data in 0..2 {
+            for stream_id in [paused, running] {
+                connection
+                    .send(send(data, DeliveryMode::ReliableOrdered(stream_id)))
+                    .await
+                    .unwrap();
+            }
+        }
+
+        // Only the running stream reaches the peer.
+        for data in 0..2 {
+            let datagram = server.next().await.unwrap().unwrap();
+            assert_eq!(
+                datagram.stream_position.map(|p| p.stream_id),
+                Some(running)
+            );
+            assert_eq!(datagram.data, vec![data]);
+        }
+        assert!(server.next().now_or_never().is_none());
+        assert_eq!(connection.transport_state().send_queue_depth, Some(2));
+
+        connection.resume_stream(paused);
+        assert!(!connection.is_paused(paused));
+        connection.flush().await.unwrap();
+        for (ordinal, data) in [(1, 0), (2, 1)] {
+            let datagram = server.next().await.unwrap().unwrap();
+            assert_eq!(
+                datagram.stream_position,
+                Some(StreamPosition {
+                    stream_id: paused,
+                    index: StreamIndex::Ordinal(ordinal),
+                })
+            );
+            assert_eq!(datagram.data, vec![data]);
+        }
+    }
+
+    #[test]
+    fn transports_without_a_buffer_cannot_pause() {
+        let (mut client, _server) = MemoryConnection::pair(None);
+        assert!(!client.pause_stream(StreamId(1)));
+        assert!(!client.is_paused(StreamId(1)));
+    }
+
+    #[test]
+    fn drop_policies_parse() {
+        assert_eq!(
+            "drop-oldest".parse::<DropPolicy>().ok(),
+            Some(DropPolicy::DropOldest)
+        );
+        assert!("oldest".parse::<DropPolicy>().is_err());
+    }
+}
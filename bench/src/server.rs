@@ -1,65 +1,1221 @@
 use crate::*;
 
-use async_std::net::SocketAddr;
+use async_std::net::{IpAddr, SocketAddr};
 
-use futures::prelude::*;
+use futures::{
+    future::LocalBoxFuture,
+    prelude::*,
+    stream::{FuturesUnordered, LocalBoxStream, SelectAll},
+};
 
+use rand::{rngs::StdRng, Rng, SeedableRng};
+use std::{
+    collections::{BTreeMap, HashMap},
+    str::FromStr,
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc, Mutex,
+    },
+    time::{Duration, Instant},
+};
 use structopt::StructOpt;
 
-async fn run<C>(mut server: impl Server<C> + Unpin) -> Result<()>
+/// What the server saw of one stream.
+#[derive(Debug, Clone, Default)]
+pub struct StreamSummary {
+    pub datagrams_received: usize,
+    /// Standard deviation of the gaps between consecutive arrivals.
+    pub interarrival_jitter_ms: f64,
+    /// Numbered datagrams which never arrived, judged by the highest id seen.
+    pub detected_loss: u64,
+}
+
+/// The server's view of a run, per stream.
+#[derive(Debug, Clone, Default)]
+pub struct ServerSummary {
+    pub streams: BTreeMap<StreamId, StreamSummary>,
+    /// Connections dropped at accept by the address filter.
+    pub filtered_connections: usize,
+    /// Connections dropped at accept for exceeding the accept rate.
+    pub rate_limited_connections: usize,
+    /// Pushed datagrams the clients acked, and the mean time from pushing
+    /// each until its ack arrived.
+    pub push_acks: usize,
+    pub push_ack_round_trip_ms: f64,
+}
+
+#[derive(Debug, Default)]
+struct StreamTracker {
+    datagrams_received: usize,
+    last_arrival: Option<Instant>,
+    gaps: usize,
+    gap_sum: f64,
+    gap_square_sum: f64,
+    numbered_received: u64,
+    highest_id: u64,
+}
+
+impl StreamTracker {
+    fn track(&mut self, id: u64) {
+        let now = clock::now();
+        if let Some(last_arrival) = self.last_arrival {
+            let gap = now.duration_since(last_arrival).as_secs_f64() * 1e3;
+            self.gaps += 1;
+            self.gap_sum += gap;
+            self.gap_square_sum += gap * gap;
+        }
+        self.last_arrival = Some(now);
+        self.datagrams_received += 1;
+
+        if id != ID_DO_NOT_RETURN {
+            self.numbered_received += 1;
+            self.highest_id = self.highest_id.max(id);
+        }
+    }
+
+    fn summary(&self) -> StreamSummary {
+        let interarrival_jitter_ms = match self.gaps {
+            0 => 0.0,
+            n => {
+                let mean = self.gap_sum / n as f64;
+                (self.gap_square_sum / n as f64 - mean * mean)
+                    .max(0.0)
+                    .sqrt()
+            }
+        };
+
+        StreamSummary {
+            datagrams_received: self.datagrams_received,
+            interarrival_jitter_ms,
+            detected_loss: self
+                .highest_id
+                .saturating_sub(self.numbered_received),
+        }
+    }
+}
+
+/// Statistics the server collects as it echoes, shared with whoever launched
+/// it in the same process.
+#[derive(Debug, Clone, Default)]
+pub struct ServerStats {
+    streams: Arc<Mutex<BTreeMap<StreamId, StreamTracker>>>,
+    filtered_connections: Arc<AtomicUsize>,
+    rate_limited_connections: Arc<AtomicUsize>,
+    /// How many pushes were acked, and the sum of their round trips.
+    push_acks: Arc<Mutex<(usize, f64)>>,
+}
+
+impl ServerStats {
+    fn track(&self, stream_id: StreamId, id: u64) {
+        self.streams
+            .lock()
+            .expect("server stats lock")
+            .entry(stream_id)
+            .or_default()
+            .track(id);
+    }
+
+    fn track_filtered(&self) {
+        self.filtered_connections.fetch_add(1, Ordering::Relaxed);
+    }
+
+    fn track_rate_limited(&self) {
+        self.rate_limited_connections
+            .fetch_add(1, Ordering::Relaxed);
+    }
+
+    fn track_push_ack(&self, round_trip_ms: f64) {
+        let mut push_acks = self.push_acks.lock().expect("server stats lock");
+        push_acks.0 += 1;
+        push_acks.1 += round_trip_ms;
+    }
+
+    pub fn summary(&self) -> ServerSummary {
+        let streams = self.streams.lock().expect("server stats lock");
+        let (push_acks, push_round_trip_sum) =
+            *self.push_acks.lock().expect("server stats lock");
+        ServerSummary {
+            streams: streams
+                .iter()
+                .map(|(stream_id, tracker)| (*stream_id, tracker.summary()))
+                .collect(),
+            filtered_connections: self
+                .filtered_connections
+                .load(Ordering::Relaxed),
+            rate_limited_connections: self
+                .rate_limited_connections
+                .load(Ordering::Relaxed),
+            push_acks,
+            push_ack_round_trip_ms: match push_acks {
+                0 => 0.0,
+                n => push_round_trip_sum / n as f64,
+            },
+        }
+    }
+}
+
+/// How long the server spends on each echo, drawn per datagram.
+#[derive(Debug, Clone, PartialEq)]
+pub enum ProcessingDelay {
+    /// Always the given milliseconds.
+    Constant(f64),
+    /// Uniformly between the given low and high milliseconds.
+    Uniform(f64, f64),
+    /// Normally distributed with the given mean and standard deviation in
+    /// milliseconds, clamped at zero.
+    Normal(f64, f64),
+    /// One of the given milliseconds, chosen in proportion to its weight.
+    Histogram(Vec<(f64, u32)>),
+}
+
+impl FromStr for ProcessingDelay {
+    type Err = anyhow::Error;
+    /// Parses `constant:ms`, `uniform:low:high`, `normal:mean:deviation`, or
+    /// `histogram:ms=weight,ms=weight,...`.
+    fn from_str(src: &str) -> std::result::Result<Self, Self::Err> {
+        let args: Vec<&str> = src.split(':').collect();
+        let delay = match args.as_slice() {
+            ["constant", ms] => ProcessingDelay::Constant(ms.parse()?),
+            ["uniform", low, high] => {
+                let (low, high) = (low.parse()?, high.parse()?);
+                if low > high {
+                    anyhow::bail!("uniform delay needs low <= high");
+                }
+                ProcessingDelay::Uniform(low, high)
+            }
+            ["normal", mean, deviation] => {
+                ProcessingDelay::Normal(mean.parse()?, deviation.parse()?)
+            }
+            ["histogram", buckets] => {
+                let buckets = buckets
+                    .split(',')
+                    .map(|bucket| {
+                        let mut parts = bucket.splitn(2, '=');
+                        let ms = parts.next().unwrap_or("").parse()?;
+                        let weight = match parts.next() {
+                            Some(weight) => weight.parse()?,
+                            None => anyhow::bail!(
+                                "expected `ms=weight`, got {:?}",
+                                bucket
+                            ),
+                        };
+                        Ok((ms, weight))
+                    })
+                    .collect::<anyhow::Result<Vec<(f64, u32)>>>()?;
+                if buckets.iter().all(|(_, weight)| *weight == 0) {
+                    anyhow::bail!("histogram delay needs a nonzero weight");
+                }
+                ProcessingDelay::Histogram(buckets)
+            }
+            _ => anyhow::bail!(
+                "expected `constant:ms`, `uniform:low:high`, \
+                 `normal:mean:deviation`, or `histogram:ms=weight,...`, got {:?}",
+                src
+            ),
+        };
+
+        Ok(delay)
+    }
+}
+
+impl ProcessingDelay {
+    fn sample(&self, rng: &mut impl Rng) -> Duration {
+        let ms = match self {
+            ProcessingDelay::Constant(ms) => *ms,
+            ProcessingDelay::Uniform(low, high) if low < high => {
+                rng.gen_range(*low, *high)
+            }
+            ProcessingDelay::Uniform(low, _) => *low,
+            ProcessingDelay::Normal(mean, deviation) => {
+                // Box-Muller transform.
+                let u1: f64 = 1.0 - rng.gen::<f64>();
+                let u2: f64 = rng.gen();
+                let z = (-2.0 * u1.ln()).sqrt()
+                    * (2.0 * std::f64::consts::PI * u2).cos();
+                mean + deviation * z
+            }
+            ProcessingDelay::Histogram(buckets) => {
+                let total: u32 = buckets.iter().map(|(_, weight)| weight).sum();
+                let mut pick = rng.gen_range(0, total);
+                buckets
+                    .iter()
+                    .find(|(_, weight)| {
+                        if pick < *weight {
+                            return true;
+                        }
+                        pick -= weight;
+                        false
+                    })
+                    .map(|(ms, _)| *ms)
+                    .unwrap_or(0.0)
+            }
+        };
+
+        Duration::from_secs_f64(ms.max(0.0) / 1e3)
+    }
+}
+
+/// How the server returns the datagrams of a stream.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum EchoPolicy {
+    /// Echo each datagram as soon as it is processed.
+    Immediate,
+    /// Echo each datagram this long after it is processed, without holding
+    /// up the other streams meanwhile.
+    Delay(Duration),
+    /// Never echo. Transfers on the stream should not wait for returns.
+    Drop,
+    /// Gather this many echoes and return them together in one datagram, as
+    /// with `--return-batch`.
+    Batch(usize),
+}
+
+impl FromStr for EchoPolicy {
+    type Err = anyhow::Error;
+    /// Parses `immediate`, `delay:ms`, `drop`, or `batch:count`.
+    fn from_str(src: &str) -> std::result::Result<Self, Self::Err> {
+        let args: Vec<&str> = src.split(':').collect();
+        match args.as_slice() {
+            ["immediate"] => Ok(EchoPolicy::Immediate),
+            ["delay", ms] => {
+                Ok(EchoPolicy::Delay(Duration::from_millis(ms.parse()?)))
+            }
+            ["drop"] => Ok(EchoPolicy::Drop),
+            ["batch", count] => match count.parse()? {
+                0 => anyhow::bail!("cannot return echoes in batches of 0"),
+                count => Ok(EchoPolicy::Batch(count)),
+            },
+            _ => anyhow::bail!(
+                "expected `immediate`, `delay:ms`, `drop`, or `batch:count`, \
+                 got {:?}",
+                src
+            ),
+        }
+    }
+}
+
+/// The echo policy of one stream, written `stream_id=policy`.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct StreamPolicy {
+    pub stream_id: StreamId,
+    pub policy: EchoPolicy,
+}
+
+impl FromStr for StreamPolicy {
+    type Err = anyhow::Error;
+    fn from_str(src: &str) -> std::result::Result<Self, Self::Err> {
+        let mut parts = src.splitn(2, '=');
+        let stream_id = StreamId(parts.next().unwrap_or("").parse()?);
+        let policy = match parts.next() {
+            Some(policy) => policy.parse()?,
+            None => {
+                anyhow::bail!("expected `stream_id=policy`, got {:?}", src)
+            }
+        };
+        Ok(Self { stream_id, policy })
+    }
+}
+
+/// A block of addresses, written `address/prefix_length` or as a single
+/// address.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Subnet {
+    network: IpAddr,
+    prefix_length: u8,
+}
+
+impl Subnet {
+    pub fn contains(&self, address: IpAddr) -> bool {
+        match (self.network, address) {
+            (IpAddr::V4(network), IpAddr::V4(address)) => {
+                let mask = u32::MAX
+                    .checked_shl(32 - u32::from(self.prefix_length))
+                    .unwrap_or(0);
+                u32::from(network) & mask == u32::from(address) & mask
+            }
+            (IpAddr::V6(network), IpAddr::V6(address)) => {
+                let mask = u128::MAX
+                    .checked_shl(128 - u32::from(self.prefix_length))
+                    .unwrap_or(0);
+                u128::from(network) & mask == u128::from(address) & mask
+            }
+            _ => false,
+        }
+    }
+}
+
+impl FromStr for Subnet {
+    type Err = anyhow::Error;
+    fn from_str(src: &str) -> std::result::Result<Self, Self::Err> {
+        let mut parts = src.splitn(2, '/');
+        let network: IpAddr = parts.next().unwrap_or("").parse()?;
+        let max_prefix_length = match network {
+            IpAddr::V4(_) => 32,
+            IpAddr::V6(_) => 128,
+        };
+        let prefix_length = match parts.next() {
+            Some(prefix_length) => prefix_length.parse()?,
+            None => max_prefix_length,
+        };
+        if prefix_length > max_prefix_length {
+            anyhow::bail!(
+                "prefix length of {:?} is longer than {} bits",
+                src,
+                max_prefix_length
+            );
+        }
+
+        Ok(Self {
+            network,
+            prefix_length,
+        })
+    }
+}
+
+/// Which peers the server accepts connections from.
+///
+/// A peer in a denied subnet is always refused. If any subnets are allowed, a
+/// peer must also be in one of them. Connections from transports which do not
+/// know their peer's address are always accepted.
+#[derive(Debug, Clone, Default, StructOpt)]
+pub struct AddressFilter {
+    /// Accept connections only from this subnet, e.g. `10.0.0.0/8`. May be
+    /// given more than once.
+    #[structopt(long = "allow", number_of_values = 1)]
+    pub allow: Vec<Subnet>,
+    /// Refuse connections from this subnet. May be given more than once.
+    #[structopt(long = "deny", number_of_values = 1)]
+    pub deny: Vec<Subnet>,
+}
+
+impl AddressFilter {
+    pub fn accepts(&self, address: IpAddr) -> bool {
+        let denied = self.deny.iter().any(|subnet| subnet.contains(address));
+        let allowed = self.allow.is_empty()
+            || self.allow.iter().any(|subnet| subnet.contains(address));
+        allowed && !denied
+    }
+}
+
+/// How fast the server accepts connections, to bound what a flood of them
+/// costs it.
+///
+/// Transports hand over a connection once its handshake is done, so a
+/// connection over the limit is dropped as soon as it is accepted, before the
+/// server spends anything more on it.
+#[derive(Debug, Clone, Copy, StructOpt)]
+pub struct AcceptLimit {
+    /// Accept at most this many connections per second, on average. Not
+    /// limited if not given.
+    #[structopt(long)]
+    pub accept_rate: Option<f64>,
+    /// How many connections may be accepted in a burst, beyond the accept
+    /// rate.
+    #[structopt(long, default_value = "1")]
+    pub accept_burst: u32,
+}
+
+impl Default for AcceptLimit {
+    fn default() -> Self {
+        Self {
+            accept_rate: None,
+            accept_burst: 1,
+        }
+    }
+}
+
+/// A token bucket holding up to `burst` accepts, refilled at `rate` a second.
+struct AcceptBucket {
+    rate: f64,
+    burst: f64,
+    tokens: f64,
+    refilled: Instant,
+}
+
+impl AcceptBucket {
+    /// A full bucket at `now`, if `limit` limits accepts at all.
+    fn new(limit: AcceptLimit, now: Instant) -> Option<Self> {
+        let rate = limit.accept_rate?;
+        Some(Self {
+            rate,
+            burst: f64::from(limit.accept_burst),
+            tokens: f64::from(limit.accept_burst),
+            refilled: now,
+        })
+    }
+
+    /// Takes an accept from the bucket at `now`, if there is one left.
+    fn take(&mut self, now: Instant) -> bool {
+        let elapsed = now.duration_since(self.refilled).as_secs_f64();
+        self.tokens = (self.tokens + elapsed * self.rate).min(self.burst);
+        self.refilled = now;
+
+        if self.tokens < 1.0 {
+            return false;
+        }
+        self.tokens -= 1.0;
+        true
+    }
+}
+
+/// How long a partly filled batch of echoes waits for another datagram before
+/// it is sent as it is, so the last echoes of a run are not held forever.
+const BATCH_LINGER: Duration = Duration::from_millis(100);
+
+/// How many datagrams in a row must already be waiting when the server gets
+/// to them before it considers itself overloaded.
+const QUENCH_BACKLOG: usize = 8;
+
+/// The least time between two source quenches to the same client.
+const QUENCH_INTERVAL: Duration = Duration::from_millis(100);
+
+struct EchoConfig {
+    stats: Option<ServerStats>,
+    address_filter: AddressFilter,
+    accept_limit: AcceptLimit,
+    processing_delay: ProcessingDelay,
+    seed: u64,
+    return_batch: usize,
+    stream_policies: HashMap<StreamId, EchoPolicy>,
+}
+
+impl EchoConfig {
+    /// How to return the datagrams of `stream_id`: by its own policy if it
+    /// has one, and otherwise in batches of `return_batch`.
+    fn policy(&self, stream_id: StreamId) -> EchoPolicy {
+        match self.stream_policies.get(&stream_id) {
+            Some(policy) => *policy,
+            None if self.return_batch > 1 => {
+                EchoPolicy::Batch(self.return_batch)
+            }
+            None => EchoPolicy::Immediate,
+        }
+    }
+}
+
+/// Serves clients until the server stops listening, so a client which
+/// reconnects finds the server still there.
+async fn run<C>(
+    server: impl Server<C> + Unpin,
+    config: EchoConfig,
+) -> Result<()>
+where
+    C: Connection + Unpin,
+{
+    // Clients are echoed concurrently, so that several can share the link.
+    let config = &config;
+    let mut accept_bucket =
+        AcceptBucket::new(config.accept_limit, clock::now());
+    server
+        .try_for_each_concurrent(None, |client| {
+            let filtered = client
+                .peer_addr()
+                .map(|peer_addr| !config.address_filter.accepts(peer_addr.ip()))
+                .unwrap_or(false);
+            // Filtered connections are refused before they can spend the
+            // accept rate of everyone else.
+            let rate_limited = !filtered
+                && accept_bucket
+                    .as_mut()
+                    .map(|bucket| !bucket.take(clock::now()))
+                    .unwrap_or(false);
+            async move {
+                if filtered || rate_limited {
+                    if let Some(stats) = &config.stats {
+                        if filtered {
+                            stats.track_filtered();
+                        } else {
+                            stats.track_rate_limited();
+                        }
+                    }
+                    return Ok(());
+                }
+
+                // A client dropping mid-echo only ends its own session.
+                let _ = echo(client, config).await;
+                Ok(())
+            }
+        })
+        .await
+}
+
+/// What the echo loop waits on besides the client's datagrams.
+enum Wake {
+    Wire(Option<Result<Datagram>>),
+    /// A partly filled batch has lingered long enough.
+    Linger,
+    /// A push of the given id is due on the stream.
+    Push(StreamId, u64),
+    /// A delayed echo is due, to be sent as its datagram was.
+    Delayed(DeliveryMode, Vec<u8>),
+}
+
+/// Echoes held back by `EchoPolicy::Delay`, each resolving to how its
+/// datagram was sent and the datagram once it is due.
+type DelayedEchoes =
+    FuturesUnordered<LocalBoxFuture<'static, (DeliveryMode, Vec<u8>)>>;
+
+/// Waits for the client's next datagram, the next push or delayed echo due,
+/// or `linger` to end, preferring the client's datagram.
+async fn next_wake<S>(
+    client_stream: &mut S,
+    pushes: &mut SelectAll<LocalBoxStream<'static, (StreamId, u64)>>,
+    delayed: &mut DelayedEchoes,
+    linger: Option<future::BoxFuture<'static, ()>>,
+) -> Wake
+where
+    S: Stream<Item = Result<Datagram>> + Unpin,
+{
+    let wire = client_stream.next().map(Wake::Wire);
+    // An empty `SelectAll` ends at once, so it only races while pushes are
+    // due.
+    let push = async {
+        match pushes.next().await {
+            Some((stream_id, id)) => Wake::Push(stream_id, id),
+            None => future::pending().await,
+        }
+    };
+    let delayed = async {
+        match delayed.next().await {
+            Some((delivery_mode, data)) => Wake::Delayed(delivery_mode, data),
+            None => future::pending().await,
+        }
+    };
+    let linger = async {
+        match linger {
+            Some(linger) => {
+                linger.await;
+                Wake::Linger
+            }
+            None => future::pending().await,
+        }
+    };
+
+    let others = future::select_all(vec![
+        push.boxed_local(),
+        delayed.boxed_local(),
+        linger.boxed_local(),
+    ]);
+    match future::select(wire, others).await {
+        future::Either::Left((wake, _)) => wake,
+        future::Either::Right(((wake, _, _), _)) => wake,
+    }
+}
+
+/// Returns the ids of `push`'s datagrams as they come due.
+fn push_ticks(push: ServerPush) -> LocalBoxStream<'static, (StreamId, u64)> {
+    ticker(push.hertz)
+        .take(push.count)
+        .enumerate()
+        .map(move |(index, _)| (push.stream_id, index as u64 + 1))
+        .boxed_local()
+}
+
+async fn echo<C>(client: C, config: &EchoConfig) -> Result<()>
 where
     C: Connection + Unpin,
 {
-    let client = server.next().await.expect("client").expect("Ok(client)");
     let (mut client_sink, mut client_stream) = client.split();
 
-    while let Some(Ok(wire_datagram)) = client_stream.next().await {
-        let position = wire_datagram.stream_position.expect("position");
-        let stream_id = position.stream_id;
+    let mut rng = StdRng::seed_from_u64(config.seed);
+    let mut waiting = None;
+    let mut backlog = 0;
+    let mut last_quench: Option<Instant> = None;
+    let mut batches: BTreeMap<StreamId, Vec<BenchmarkDatagram>> =
+        BTreeMap::new();
+    let mut pushes = SelectAll::new();
+    let mut delayed = DelayedEchoes::new();
+    // The datagram size of each stream the client asked us to push on.
+    let mut push_sizes: BTreeMap<StreamId, usize> = BTreeMap::new();
+    // When each push awaiting its ack was sent.
+    let mut pushed: BTreeMap<(StreamId, u64), Instant> = BTreeMap::new();
+    loop {
+        let wire_datagram = match waiting.take() {
+            Some(wire_datagram) => wire_datagram,
+            None => {
+                let linger = match batches.is_empty() {
+                    true => None,
+                    false => Some(clock::sleep(BATCH_LINGER)),
+                };
+                let wake = next_wake(
+                    &mut client_stream,
+                    &mut pushes,
+                    &mut delayed,
+                    linger,
+                )
+                .await;
+                match wake {
+                    Wake::Wire(wire_datagram) => wire_datagram,
+                    Wake::Delayed(delivery_mode, data) => {
+                        client_sink
+                            .send(SendCmd {
+                                delivery_mode,
+                                data,
+                                ..SendCmd::default()
+                            })
+                            .await?;
+                        continue;
+                    }
+                    Wake::Linger => {
+                        let batches = std::mem::take(&mut batches);
+                        for (stream_id, batch) in batches {
+                            send_batch(&mut client_sink, stream_id, batch)
+                                .await?;
+                        }
+                        continue;
+                    }
+                    Wake::Push(stream_id, id) => {
+                        let size = push_sizes[&stream_id];
+                        let mut data =
+                            wall_clock_micros().to_le_bytes().to_vec();
+                        data.resize(size, 0);
+                        let delivery_mode =
+                            DeliveryMode::ReliableOrdered(stream_id);
+                        let push = BenchmarkDatagram {
+                            stream_id,
+                            delivery_mode,
+                            id,
+                            data,
+                        };
+                        pushed.insert((stream_id, id), clock::now());
+                        client_sink
+                            .send(SendCmd {
+                                delivery_mode,
+                                data: bincode::serialize(&push)?,
+                                ..SendCmd::default()
+                            })
+                            .await?;
+                        continue;
+                    }
+                }
+            }
+        };
+        let wire_datagram = match wire_datagram {
+            Some(Ok(wire_datagram)) => wire_datagram,
+            // The client has finished, so close our end in turn.
+            None => return client_sink.close().await,
+            Some(Err(_)) => break,
+        };
+
         let benchmark_datagram: BenchmarkDatagram =
             bincode::deserialize(&wire_datagram.data).expect("valid datagram");
-        if benchmark_datagram.id != ID_DO_NOT_RETURN {
+        let stream_id = match wire_datagram.stream_position {
+            Some(position) => position.stream_id,
+            None => benchmark_datagram.stream_id,
+        };
+        let delivery_mode = benchmark_datagram.delivery_mode;
+        if benchmark_datagram.id == ID_PUSH_REQUEST {
+            let push: ServerPush =
+                bincode::deserialize(&benchmark_datagram.data)?;
+            push_sizes.insert(stream_id, push.payload_size);
+            pushes.push(push_ticks(ServerPush { stream_id, ..push }));
+            continue;
+        }
+        if push_sizes.contains_key(&stream_id) {
+            // An ack of one of our pushes.
+            let sent = pushed.remove(&(stream_id, benchmark_datagram.id));
+            if let (Some(stats), Some(sent)) = (&config.stats, sent) {
+                stats.track_push_ack(clock::since(sent).as_secs_f64() * 1e3);
+            }
+            continue;
+        }
+        if let Some(stats) = &config.stats {
+            stats.track(stream_id, benchmark_datagram.id);
+        }
+        let delay = config.processing_delay.sample(&mut rng);
+        if delay > Duration::from_secs(0) {
+            clock::sleep(delay).await;
+        }
+        match config.policy(stream_id) {
+            _ if benchmark_datagram.id == ID_DO_NOT_RETURN => {}
+            EchoPolicy::Immediate => {
+                client_sink
+                    .send(SendCmd {
+                        delivery_mode,
+                        data: wire_datagram.data,
+                        ..SendCmd::default()
+                    })
+                    .await?;
+            }
+            EchoPolicy::Delay(delay) => {
+                let data = wire_datagram.data;
+                delayed.push(
+                    async move {
+                        clock::sleep(delay).await;
+                        (delivery_mode, data)
+                    }
+                    .boxed_local(),
+                );
+            }
+            EchoPolicy::Drop => {}
+            EchoPolicy::Batch(size) => {
+                let batch = batches.entry(stream_id).or_default();
+                batch.push(benchmark_datagram);
+                if batch.len() >= size {
+                    let batch = batches.remove(&stream_id).unwrap_or_default();
+                    send_batch(&mut client_sink, stream_id, batch).await?;
+                }
+            }
+        }
+
+        // A datagram which is already waiting queued up while we were busy.
+        waiting = client_stream.next().now_or_never();
+        backlog = if waiting.is_some() { backlog + 1 } else { 0 };
+
+        let quenched_recently = last_quench
+            .map(|at| clock::since(at) < QUENCH_INTERVAL)
+            .unwrap_or(false);
+        if backlog >= QUENCH_BACKLOG && !quenched_recently {
+            let quench = BenchmarkDatagram {
+                stream_id,
+                delivery_mode: DeliveryMode::ReliableOrdered(stream_id),
+                id: ID_SOURCE_QUENCH,
+                data: vec![],
+            };
             client_sink
                 .send(SendCmd {
                     delivery_mode: DeliveryMode::ReliableOrdered(stream_id),
-                    data: wire_datagram.data,
+                    data: bincode::serialize(&quench)?,
                     ..SendCmd::default()
                 })
                 .await?;
+            last_quench = Some(clock::now());
+            backlog = 0;
         }
     }
 
     Ok(())
 }
 
+/// Sends `batch` back to the client as one datagram on `stream_id`.
+async fn send_batch<S>(
+    client_sink: &mut S,
+    stream_id: StreamId,
+    batch: Vec<BenchmarkDatagram>,
+) -> Result<()>
+where
+    S: Sink<SendCmd, Error = Box<dyn std::error::Error>> + Unpin,
+{
+    let delivery_mode = DeliveryMode::ReliableOrdered(stream_id);
+    let batch = BenchmarkDatagram {
+        stream_id,
+        delivery_mode,
+        id: ID_ECHO_BATCH,
+        data: bincode::serialize(&batch)?,
+    };
+    client_sink
+        .send(SendCmd {
+            delivery_mode,
+            data: bincode::serialize(&batch)?,
+            ..SendCmd::default()
+        })
+        .await
+}
+
 #[derive(Debug, StructOpt)]
 pub struct Options {
     /// Address to serve the benchmark on.
     #[structopt(short = "a", default_value = "127.0.0.1:33333")]
     pub address: SocketAddr,
-    /// The protocol to benchmark.
-    #[structopt(subcommand)]
+    /// The protocol to benchmark, by its registered name.
     pub protocol: Protocol,
+    /// Time to spend on each echo, to model server-side variance or a server
+    /// which can't keep up. One of `constant:ms`, `uniform:low:high`,
+    /// `normal:mean:deviation`, or `histogram:ms=weight,...`.
+    #[structopt(long, default_value = "constant:0")]
+    pub processing_delay: ProcessingDelay,
+    /// Seed for drawing processing delays, so runs are reproducible.
+    #[structopt(long, default_value = "0")]
+    pub seed: u64,
+    /// Gather this many echoes on a stream and return them together in one
+    /// datagram. A partly filled batch is sent once no datagram has arrived
+    /// for a while.
+    #[structopt(long, default_value = "1")]
+    pub return_batch: usize,
+    /// How to return one stream's datagrams, overriding `--return-batch`,
+    /// written `stream_id=policy` where `policy` is `immediate`, `delay:ms`,
+    /// `drop`, or `batch:count`. May be given once per stream.
+    #[structopt(long = "stream-policy", number_of_values = 1)]
+    pub stream_policies: Vec<StreamPolicy>,
+    /// Code unreliable datagrams with forward error correction, as the
+    /// client's `--fec` does.
+    #[structopt(long)]
+    pub fec: Option<fec::Fec>,
+    #[structopt(flatten)]
+    pub address_filter: AddressFilter,
+    #[structopt(flatten)]
+    pub accept_limit: AcceptLimit,
+    /// Where to collect what the server sees, when it runs in-process.
+    #[structopt(skip)]
+    pub stats: Option<ServerStats>,
 }
 
 pub async fn server_main(options: Options) -> Result<()> {
-    match options.protocol {
-        Protocol::Tcp => match tcp::TcpServer::bind(options.address).await {
-            Ok(server) => run(server).await,
-            Err(e) => panic!("Failed to bind server: {:?}", e),
-        },
-        Protocol::Enet => {
-            run(enet::EnetServer::bind(options.address).await).await
-        }
-        Protocol::Kcp => {
-            run(kcp::KcpServer::bind(kcp::KcpMode::Normal, options.address)
-                .await?)
-            .await
-        }
-        Protocol::KcpTurbo => {
-            run(kcp::KcpServer::bind(kcp::KcpMode::Turbo, options.address)
-                .await?)
-            .await
+    if options.return_batch == 0 {
+        return Err("cannot return echoes in batches of 0".into());
+    }
+    if let Some(accept_rate) = options.accept_limit.accept_rate {
+        if accept_rate <= 0.0 {
+            return Err("the accept rate must be positive".into());
+        }
+        if options.accept_limit.accept_burst == 0 {
+            return Err("cannot accept connections in bursts of 0".into());
+        }
+    }
+
+    let mut stream_policies = HashMap::new();
+    for StreamPolicy { stream_id, policy } in options.stream_policies {
+        if stream_policies.insert(stream_id, policy).is_some() {
+            return Err(format!(
+                "{:?} has more than one echo policy",
+                stream_id
+            )
+            .into());
+        }
+    }
+
+    let config = EchoConfig {
+        stats: options.stats,
+        address_filter: options.address_filter,
+        accept_limit: options.accept_limit,
+        processing_delay: options.processing_delay,
+        seed: options.seed,
+        return_batch: options.return_batch,
+        stream_policies,
+    };
+    let server = (options.protocol.transport().bind)(options.address).await?;
+    match options.fec {
+        Some(fec) => run(fec::FecServer::new(server, fec), config).await,
+        None => run(server, config).await,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Runs a memory server at `port` under `server` alongside a client
+    /// run under `client`, returning the client's summary and what the
+    /// server saw.
+    async fn serve(
+        port: u16,
+        server: &[&str],
+        client: &[&str],
+    ) -> (client::Summary, ServerSummary) {
+        let address = format!("127.0.0.1:{}", port);
+        let stats = ServerStats::default();
+        let server = server_main(Options {
+            stats: Some(stats.clone()),
+            ..Options::from_iter(
+                ["server", "memory", "-a", &address].iter().chain(server),
+            )
+        })
+        .boxed_local();
+        let client = client::client_main(client::Options::from_iter(
+            ["client", "memory", "-a", &address].iter().chain(client),
+        ))
+        .boxed_local();
+
+        let summary = match future::select(server, client).await {
+            future::Either::Left((result, _)) => {
+                panic!("server stopped: {:?}", result)
+            }
+            future::Either::Right((result, _)) => result.unwrap(),
+        };
+        (summary, stats.summary())
+    }
+
+    /// Connects to a memory server at `port` under `server` `count` times,
+    /// `interval` apart, holding every connection open, and returns what the
+    /// server saw once it has had time to accept or refuse the last.
+    async fn connect_repeatedly(
+        port: u16,
+        server: &[&str],
+        count: usize,
+        interval: Duration,
+    ) -> ServerSummary {
+        let address = format!("127.0.0.1:{}", port);
+        let stats = ServerStats::default();
+        let server = server_main(Options {
+            stats: Some(stats.clone()),
+            ..Options::from_iter(
+                ["server", "memory", "-a", &address].iter().chain(server),
+            )
+        })
+        .boxed_local();
+        let address = address.parse().unwrap();
+        let clients = async move {
+            let mut connections = vec![];
+            for _ in 0..count {
+                let (_, connection) =
+                    client::connect_memory(address).await.unwrap();
+                connections.push(connection);
+                async_std::task::sleep(interval).await;
+            }
+            async_std::task::sleep(Duration::from_millis(20)).await;
+        }
+        .boxed_local();
+
+        if let future::Either::Left((result, _)) =
+            future::select(server, clients).await
+        {
+            panic!("server stopped: {:?}", result);
+        }
+        stats.summary()
+    }
+
+    #[test]
+    fn subnets_match_addresses_under_their_prefix() {
+        let subnet = |src: &str| src.parse::<Subnet>().unwrap();
+        let ip = |src: &str| src.parse::<IpAddr>().unwrap();
+
+        let everything = subnet("0.0.0.0/0");
+        assert!(everything.contains(ip("0.0.0.0")));
+        assert!(everything.contains(ip("255.255.255.255")));
+        assert!(!everything.contains(ip("::1")));
+        assert!(subnet("::/0").contains(ip("2001:db8::1")));
+
+        // A lone address is a subnet of one.
+        assert_eq!(subnet("10.1.2.3"), subnet("10.1.2.3/32"));
+        assert!(subnet("10.1.2.3").contains(ip("10.1.2.3")));
+        assert!(!subnet("10.1.2.3").contains(ip("10.1.2.4")));
+        assert!(subnet("::1").contains(ip("::1")));
+        assert!(!subnet("::1").contains(ip("::2")));
+
+        // A /22 spans four /24s, from 192.168.4.0 to 192.168.7.255.
+        let block = subnet("192.168.4.0/22");
+        assert!(!block.contains(ip("192.168.3.255")));
+        assert!(block.contains(ip("192.168.4.0")));
+        assert!(block.contains(ip("192.168.7.255")));
+        assert!(!block.contains(ip("192.168.8.0")));
+
+        for src in &["10.0.0.0/33", "::/129", "10.0.0.0/x", "10.0.0/8", ""] {
+            assert!(src.parse::<Subnet>().is_err(), "{} parsed", src);
         }
     }
+
+    #[test]
+    fn denied_subnets_win_over_allowed_ones() {
+        let ip = |src: &str| src.parse::<IpAddr>().unwrap();
+        let filter = |args: &[&str]| {
+            AddressFilter::from_iter(["filter"].iter().chain(args))
+        };
+
+        let open = filter(&[]);
+        assert!(open.accepts(ip("203.0.113.9")));
+        assert!(open.accepts(ip("::1")));
+
+        let allowing = filter(&["--allow", "10.0.0.0/8", "--allow", "::1"]);
+        assert!(allowing.accepts(ip("10.200.0.1")));
+        assert!(allowing.accepts(ip("::1")));
+        assert!(!allowing.accepts(ip("11.0.0.1")));
+
+        let denying =
+            filter(&["--allow", "10.0.0.0/8", "--deny", "10.0.0.0/24"]);
+        assert!(!denying.accepts(ip("10.0.0.255")));
+        assert!(denying.accepts(ip("10.0.1.0")));
+        assert!(!filter(&["--deny", "0.0.0.0/0"]).accepts(ip("127.0.0.1")));
+    }
+
+    #[async_std::test]
+    async fn filtered_peers_are_refused_and_counted() {
+        // Memory connections come from loopback peers.
+        let refused = connect_repeatedly(
+            47723,
+            &["--deny", "127.0.0.0/8"],
+            3,
+            Duration::from_millis(0),
+        )
+        .await;
+        assert_eq!(refused.filtered_connections, 3);
+
+        let (summary, served) =
+            serve(47724, &["--allow", "127.0.0.0/8"], &["-b", "0:100:200::5"])
+                .await;
+        assert_eq!(served.filtered_connections, 0);
+        assert_eq!(summary.round_trips(), 4);
+    }
+
+    #[test]
+    fn accept_buckets_refill_at_their_rate_up_to_their_burst() {
+        let limit = AcceptLimit {
+            accept_rate: Some(10.0),
+            accept_burst: 3,
+        };
+        let start = Instant::now();
+        let at = |ms| start + Duration::from_millis(ms);
+        let mut bucket = AcceptBucket::new(limit, start).unwrap();
+
+        // The burst is there from the start, and then it is spent.
+        assert!((0..3).all(|_| bucket.take(start)));
+        assert!(!bucket.take(start));
+        // One accept comes back every 100ms...
+        assert!(!bucket.take(at(50)));
+        assert!(bucket.take(at(150)));
+        assert!(!bucket.take(at(150)));
+        assert!(bucket.take(at(250)));
+        // ...but no more than the burst builds up, however long it idles.
+        assert_eq!((0..10).filter(|_| bucket.take(at(10_000))).count(), 3);
+
+        assert!(AcceptBucket::new(AcceptLimit::default(), start).is_none());
+    }
+
+    #[async_std::test]
+    async fn accept_floods_are_throttled_to_the_rate() {
+        let (count, burst, rate) = (40, 3.0, 20.0);
+        let started = Instant::now();
+        let flooded = connect_repeatedly(
+            47733,
+            &["--accept-rate", "20", "--accept-burst", "3"],
+            count,
+            Duration::from_millis(10),
+        )
+        .await;
+        let elapsed = started.elapsed().as_secs_f64();
+
+        assert!(flooded.rate_limited_connections > 0);
+        let accepted = (count - flooded.rate_limited_connections) as f64;
+        let expected = burst + rate * elapsed;
+        assert!(
+            (accepted - expected).abs() <= 2.0,
+            "{} accepted in {}s against {}",
+            accepted,
+            elapsed,
+            expected
+        );
+    }
+
+    #[async_std::test]
+    async fn pushes_arrive_at_their_rate_and_are_acked() {
+        let (summary, served) =
+            serve(47734, &[], &["--push", "1:100:50:20", "-b", "0:100:50::5"])
+                .await;
+
+        assert_eq!(summary.pushes_received, 20);
+        assert!(
+            (summary.push_rate_hz - 50.0).abs() < 5.0,
+            "{} pushes a second",
+            summary.push_rate_hz
+        );
+        assert_eq!(served.push_acks, 20);
+        assert!(served.push_ack_round_trip_ms < 20.0, "{:?}", served);
+    }
+
+    #[async_std::test]
+    async fn seeded_processing_delays_set_the_round_trip_variance() {
+        // The sends are 50ms apart, so no echo waits behind another.
+        let (summary, _) = serve(
+            47713,
+            &["--processing-delay", "normal:20:5", "--seed", "7"],
+            &["-b", "0:100:20::60"],
+        )
+        .await;
+
+        // The server draws the same delays for every run with the seed. The
+        // first round trip is left out of the statistics, and so is its
+        // delay.
+        let mut rng = StdRng::seed_from_u64(7);
+        let delay = ProcessingDelay::Normal(20.0, 5.0);
+        let delays: Vec<f64> = (0..60)
+            .map(|_| delay.sample(&mut rng).as_secs_f64() * 1e3)
+            .skip(1)
+            .collect();
+        let mean = delays.iter().sum::<f64>() / delays.len() as f64;
+        let variance = delays.iter().map(|d| (d - mean).powi(2)).sum::<f64>()
+            / (delays.len() - 1) as f64;
+
+        assert!(
+            (summary.deviation_ms - variance.sqrt()).abs() < 1.0,
+            "deviation {} against drawn {}",
+            summary.deviation_ms,
+            variance.sqrt()
+        );
+        assert!(
+            (summary.mean_ms - mean).abs() < 3.0,
+            "mean {} against drawn {}",
+            summary.mean_ms,
+            mean
+        );
+    }
+
+    #[async_std::test]
+    async fn streams_are_echoed_by_their_own_policies() {
+        let (summary, _) = serve(
+            47741,
+            &[
+                "--stream-policy",
+                "0=immediate",
+                "--stream-policy",
+                "1=delay:50",
+                "--stream-policy",
+                "2=drop",
+                "--stream-policy",
+                "3=batch:3",
+            ],
+            &[
+                "-b",
+                "0:100:100::6",
+                "-b",
+                "1:100:100::6",
+                "-b",
+                "2u:100:100::3",
+                "-b",
+                "3:100:100::6",
+            ],
+        )
+        .await;
+
+        let round_trips = |stream_id| -> Vec<f64> {
+            summary
+                .trip_reports
+                .iter()
+                .filter(|trip| trip.stream_id() == StreamId(stream_id))
+                .map(|trip| trip.round_trip_ms())
+                .collect()
+        };
+        let mean =
+            |trips: &[f64]| trips.iter().sum::<f64>() / trips.len() as f64;
+
+        let immediate = round_trips(0);
+        assert!(mean(&immediate) < 5.0, "immediate {:?}", immediate);
+        let delayed = round_trips(1);
+        assert!(!delayed.is_empty());
+        assert!(
+            delayed.iter().all(|&ms| (45.0..60.0).contains(&ms)),
+            "delayed {:?}",
+            delayed
+        );
+        assert!(round_trips(2).is_empty());
+        // Sends 10ms apart, returned three at a time: the first of a batch
+        // waits about 20ms, and the last hardly at all.
+        let batched = round_trips(3);
+        let slowest = batched.iter().cloned().fold(0.0, f64::max);
+        assert!((slowest - 20.0).abs() < 5.0, "batched {:?}", batched);
+        assert!((mean(&batched) - 10.0).abs() < 4.0, "batched {:?}", batched);
+    }
+
+    #[async_std::test]
+    async fn batched_echoes_are_timed_against_their_own_sends() {
+        // Sends 10ms apart, returned four at a time: the first of a batch
+        // waits about 30ms for the rest, and the last hardly at all.
+        let (summary, _) =
+            serve(47726, &["--return-batch", "4"], &["-b", "0:100:100::12"])
+                .await;
+
+        // Every send is timed, though only three datagrams came back. The
+        // first round trip is left out.
+        assert_eq!(summary.round_trips(), 11);
+        let expected_mean = (20.0 + 10.0 + 0.0 + 2.0 * 60.0) / 11.0;
+        assert!(
+            (summary.mean_ms - expected_mean).abs() < 4.0,
+            "mean {} against {}",
+            summary.mean_ms,
+            expected_mean
+        );
+        assert!(
+            (summary.round_trip_p99_ms - 30.0).abs() < 5.0,
+            "slowest {}",
+            summary.round_trip_p99_ms
+        );
+    }
 }
@@ -8,6 +8,8 @@ use async_std::{
 };
 
 use futures::{
+    future::{FutureExt, LocalBoxFuture},
+    io::{AsyncRead, AsyncWrite, AsyncWriteExt},
     sink::SinkExt,
     stream::{
         self, Fuse, FusedStream, LocalBoxStream, StreamExt, TryStreamExt,
@@ -15,7 +17,7 @@ use futures::{
     Sink, Stream,
 };
 
-use std::{marker::Unpin, pin::Pin};
+use std::{cell::Cell, marker::Unpin, pin::Pin, rc::Rc, time::Duration};
 
 use tokio_serde::{formats::*, SymmetricallyFramed};
 use tokio_util::{codec::*, compat::*};
@@ -71,6 +73,19 @@ pub struct TcpConnection {
     sender:
         Pin<Box<dyn Sink<SendCmd, Error = Box<dyn std::error::Error>> + Unpin>>,
     peer_addr: SocketAddr,
+    /// Set once the peer shuts down its write half and every datagram it sent
+    /// has been read.
+    peer_finished: bool,
+    /// The socket under a plain TCP connection. Closing the sink only flushes
+    /// it, so it is shut down for writing by hand to tell the peer this end is
+    /// finished.
+    write_half: Option<TcpStream>,
+    /// The notifiers of datagrams sent since the last flush, which are sent
+    /// once it completes.
+    unflushed: Vec<SendNotifier>,
+    /// How many ordered datagrams have been framed, shared with the sink's
+    /// gate so that a batch written past the sink numbers them the same.
+    ordered_sent: Rc<Cell<u32>>,
 }
 
 impl TcpConnection {
@@ -80,36 +95,59 @@ impl TcpConnection {
         Ok(TcpConnection::from((tcp_stream, peer_addr)))
     }
 
-    pub fn peer_addr(&self) -> SocketAddr {
-        self.peer_addr
-    }
-
-    fn send_gate() -> impl FnMut(
+    fn send_gate(
+        ordered_sent: Rc<Cell<u32>>,
+    ) -> impl FnMut(
         SendCmd,
     ) -> stream::Iter<
         <Option<Result<Datagram>> as IntoIterator>::IntoIter,
     > {
-        let mut total_sent = 0;
         move |send_cmd: SendCmd| {
-            stream::iter(match send_cmd.delivery_mode {
-                DeliveryMode::ReliableOrdered(stream_id) => {
-                    total_sent += 1;
-                    Some(Ok(Datagram {
-                        data: send_cmd.data,
-                        stream_position: Some(StreamPosition {
-                            stream_id,
-                            index: StreamIndex::Ordinal(total_sent),
-                        }),
-                    }))
-                }
-                _ => None,
-            })
+            stream::iter(Self::frame(&ordered_sent, send_cmd).map(Ok))
+        }
+    }
+
+    /// The datagram an ordered send is framed as, numbered on the
+    /// connection's one stream; other sends are not framed.
+    fn frame(ordered_sent: &Cell<u32>, send_cmd: SendCmd) -> Option<Datagram> {
+        match send_cmd.delivery_mode {
+            DeliveryMode::ReliableOrdered(stream_id) => {
+                ordered_sent.set(ordered_sent.get() + 1);
+                Some(Datagram {
+                    data: send_cmd.data,
+                    header: send_cmd.header,
+                    stream_position: Some(StreamPosition {
+                        stream_id,
+                        index: StreamIndex::Ordinal(ordered_sent.get()),
+                    }),
+                })
+            }
+            _ => None,
         }
     }
 }
 
 impl From<(TcpStream, SocketAddr)> for TcpConnection {
     fn from((stream, peer_addr): (TcpStream, SocketAddr)) -> Self {
+        let write_half = stream.clone();
+        Self {
+            write_half: Some(write_half),
+            ..Self::from_stream(stream, peer_addr)
+        }
+    }
+}
+
+impl TcpConnection {
+    /// Frames datagrams over any reliable byte stream, such as a TLS session
+    /// on top of a TCP stream.
+    ///
+    /// Each frame stays buffered until the stream has taken all of it, so a
+    /// stream which accepts only part of a write per poll, as a socket on a
+    /// slow link does, still delivers every datagram whole and in order.
+    pub(crate) fn from_stream<S>(stream: S, peer_addr: SocketAddr) -> Self
+    where
+        S: AsyncRead + AsyncWrite + Unpin + 'static,
+    {
         let framer = LengthDelimitedCodec::new();
         let stream = Framed::new(stream.compat(), framer);
         let codec = SymmetricalBincode::default();
@@ -119,17 +157,146 @@ impl From<(TcpStream, SocketAddr)> for TcpConnection {
         let wire = wire.map_err(Into::into);
         let (wire_sink, wire_stream) = wire.split();
 
-        let wire_sink = wire_sink.with_flat_map(Box::new(Self::send_gate()));
+        let ordered_sent = Rc::new(Cell::new(0));
+        let wire_sink = wire_sink
+            .with_flat_map(Box::new(Self::send_gate(ordered_sent.clone())));
 
         Self {
             receiver: wire_stream.boxed_local(),
             sender: Pin::new(Box::new(wire_sink)),
             peer_addr,
+            peer_finished: false,
+            write_half: None,
+            unflushed: vec![],
+            ordered_sent,
+        }
+    }
+}
+
+impl TcpConnection {
+    fn notify_flushed(&mut self) {
+        for notifier in self.unflushed.drain(..) {
+            notifier.notify(SendEvent::Sent);
         }
     }
 }
 
-impl Connection for TcpConnection {}
+impl Connection for TcpConnection {
+    /// A plain TCP connection frames the whole batch into one buffer and
+    /// writes it to the socket at once, after flushing whatever was sent
+    /// before it. The frames are those the sink writes, so the peer cannot
+    /// tell a batch from the same sends made one by one. A TLS session's
+    /// socket is out of reach, so its batches go through the sink.
+    fn send_all_batch(
+        &mut self,
+        cmds: Vec<SendCmd>,
+    ) -> LocalBoxFuture<'_, Result<()>> {
+        async move {
+            let mut socket = match &self.write_half {
+                Some(socket) => socket.clone(),
+                None => {
+                    for cmd in cmds {
+                        self.feed(cmd).await?;
+                    }
+                    return self.flush().await;
+                }
+            };
+            self.flush().await?;
+
+            let mut frames = vec![];
+            let mut notifiers = vec![];
+            for mut cmd in cmds {
+                let notify = cmd.notify.take();
+                let datagram = match Self::frame(&self.ordered_sent, cmd) {
+                    Some(datagram) => datagram,
+                    None => continue,
+                };
+                let frame = bincode::serialize(&datagram)?;
+                frames.extend_from_slice(&(frame.len() as u32).to_be_bytes());
+                frames.extend_from_slice(&frame);
+                notifiers.extend(notify);
+            }
+            socket.write_all(&frames).await?;
+            for notifier in notifiers {
+                notifier.notify(SendEvent::Sent);
+            }
+            Ok(())
+        }
+        .boxed_local()
+    }
+
+    fn peer_addr(&self) -> Option<SocketAddr> {
+        Some(self.peer_addr)
+    }
+
+    fn peer_finished(&self) -> bool {
+        self.peer_finished
+    }
+
+    /// Only a plain TCP connection's socket can be paced; a TLS session's is
+    /// out of reach.
+    fn set_max_pacing_rate(&self, bytes_per_second: u64) -> bool {
+        self.write_half
+            .as_ref()
+            .map(|socket| set_max_pacing_rate(socket, bytes_per_second))
+            .unwrap_or(false)
+    }
+
+    /// Only a plain TCP connection's socket can be asked for its state; a
+    /// TLS session's is out of reach.
+    fn transport_state(&self) -> TransportState {
+        self.write_half
+            .as_ref()
+            .map(transport_state)
+            .unwrap_or_default()
+    }
+}
+
+/// The kernel's congestion state for `socket`, from `TCP_INFO`. Its windows
+/// are counted in segments, which are converted to bytes at the segment
+/// size.
+#[cfg(target_os = "linux")]
+fn transport_state(socket: &TcpStream) -> TransportState {
+    use std::os::unix::io::AsRawFd;
+
+    // The kernel's marker for a slow start threshold not yet set.
+    const INFINITE_SSTHRESH: u32 = 0x7fff_ffff;
+
+    // `tcp_info` has bitfields, so it cannot be built field by field.
+    let mut info: libc::tcp_info = unsafe { std::mem::zeroed() };
+    let mut len = std::mem::size_of::<libc::tcp_info>() as libc::socklen_t;
+    let result = unsafe {
+        libc::getsockopt(
+            socket.as_raw_fd(),
+            libc::IPPROTO_TCP,
+            libc::TCP_INFO,
+            &mut info as *mut libc::tcp_info as *mut libc::c_void,
+            &mut len,
+        )
+    };
+    if result != 0 {
+        return TransportState::default();
+    }
+
+    let mss = u64::from(info.tcpi_snd_mss);
+    let us = |us: u32| Duration::from_micros(u64::from(us));
+    TransportState {
+        cwnd: Some(u64::from(info.tcpi_snd_cwnd) * mss),
+        ssthresh: Some(info.tcpi_snd_ssthresh)
+            .filter(|&ssthresh| ssthresh < INFINITE_SSTHRESH)
+            .map(|ssthresh| u64::from(ssthresh) * mss),
+        bytes_in_flight: Some(u64::from(info.tcpi_unacked) * mss),
+        srtt: Some(us(info.tcpi_rtt)),
+        rto: Some(us(info.tcpi_rto)),
+        ..TransportState::default()
+    }
+}
+
+/// `TCP_INFO` is Linux's, so elsewhere no state is exposed.
+#[cfg(not(target_os = "linux"))]
+fn transport_state(_socket: &TcpStream) -> TransportState {
+    TransportState::default()
+}
 
 impl Sink<SendCmd> for TcpConnection {
     type Error = Box<dyn std::error::Error>;
@@ -141,26 +308,46 @@ impl Sink<SendCmd> for TcpConnection {
             .poll_ready(ctx)
             .map_err(Into::into)
     }
-    fn start_send(mut self: Pin<&mut Self>, item: SendCmd) -> Result<()> {
+    /// TCP's acks are the kernel's, so sends only ever report
+    /// `SendEvent::Sent`, once they are flushed to the socket.
+    fn start_send(mut self: Pin<&mut Self>, mut item: SendCmd) -> Result<()> {
+        // Only ordered sends are framed; the rest are dropped.
+        let notify = match item.delivery_mode {
+            DeliveryMode::ReliableOrdered(_) => item.notify.take(),
+            _ => None,
+        };
         Pin::new(&mut self.sender)
             .start_send(item)
-            .map_err(Into::into)
+            .map_err(Into::<Box<dyn std::error::Error>>::into)?;
+        self.unflushed.extend(notify);
+        Ok(())
     }
     fn poll_flush(
         mut self: Pin<&mut Self>,
         ctx: &mut Context,
     ) -> Poll<Result<()>> {
-        Pin::new(&mut self.sender)
+        let flushed = Pin::new(&mut self.sender)
             .poll_flush(ctx)
-            .map_err(Into::into)
+            .map_err(Into::into);
+        if let Poll::Ready(Ok(())) = flushed {
+            self.notify_flushed();
+        }
+        flushed
     }
     fn poll_close(
         mut self: Pin<&mut Self>,
         ctx: &mut Context,
     ) -> Poll<Result<()>> {
-        Pin::new(&mut self.sender)
+        let closed = Pin::new(&mut self.sender)
             .poll_close(ctx)
-            .map_err(Into::into)
+            .map_err(Into::into);
+        if let Poll::Ready(Ok(())) = closed {
+            self.notify_flushed();
+            if let Some(write_half) = self.write_half.take() {
+                write_half.shutdown(Shutdown::Write)?;
+            }
+        }
+        closed
     }
 }
 
@@ -170,12 +357,384 @@ impl Stream for TcpConnection {
         mut self: Pin<&mut Self>,
         ctx: &mut Context,
     ) -> Poll<Option<Self::Item>> {
-        Pin::new(&mut self.receiver).poll_next(ctx)
+        if self.peer_finished {
+            return Poll::Ready(None);
+        }
+
+        let next = Pin::new(&mut self.receiver).poll_next(ctx);
+        if let Poll::Ready(None) = next {
+            self.peer_finished = true;
+        }
+        next
     }
 }
 
 impl FusedStream for TcpConnection {
     fn is_terminated(&self) -> bool {
-        false
+        self.peer_finished
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn send(delivery_mode: DeliveryMode, data: &[u8]) -> SendCmd {
+        SendCmd {
+            data: data.to_vec(),
+            delivery_mode,
+            ..SendCmd::default()
+        }
+    }
+
+    /// A stream which takes at most one byte per write and is not ready on
+    /// every other poll, as a socket on a slow link keeps filling up. It
+    /// never has anything to read.
+    struct Trickle {
+        written: Rc<std::cell::RefCell<Vec<u8>>>,
+        ready: bool,
+    }
+
+    impl AsyncRead for Trickle {
+        fn poll_read(
+            self: Pin<&mut Self>,
+            _: &mut Context,
+            _: &mut [u8],
+        ) -> Poll<std::io::Result<usize>> {
+            Poll::Pending
+        }
+    }
+
+    impl AsyncWrite for Trickle {
+        fn poll_write(
+            mut self: Pin<&mut Self>,
+            ctx: &mut Context,
+            buf: &[u8],
+        ) -> Poll<std::io::Result<usize>> {
+            if !self.ready {
+                self.ready = true;
+                ctx.waker().wake_by_ref();
+                return Poll::Pending;
+            }
+            self.ready = false;
+            self.written.borrow_mut().extend_from_slice(&buf[..1]);
+            Poll::Ready(Ok(1))
+        }
+
+        fn poll_flush(
+            self: Pin<&mut Self>,
+            _: &mut Context,
+        ) -> Poll<std::io::Result<()>> {
+            Poll::Ready(Ok(()))
+        }
+
+        fn poll_close(
+            self: Pin<&mut Self>,
+            _: &mut Context,
+        ) -> Poll<std::io::Result<()>> {
+            Poll::Ready(Ok(()))
+        }
+    }
+
+    #[async_std::test]
+    async fn frames_survive_writes_of_one_byte_at_a_time() {
+        let address = SocketAddr::from(([127, 0, 0, 1], 0));
+        let written = Rc::new(std::cell::RefCell::new(vec![]));
+        let trickle = Trickle {
+            written: written.clone(),
+            ready: false,
+        };
+        let mut sender = TcpConnection::from_stream(trickle, address);
+        let sent: Vec<Vec<u8>> = (0..20u8)
+            .map(|i| vec![i; 75 * usize::from(i + 1)])
+            .collect();
+        for (i, data) in sent.iter().enumerate() {
+            let cmd = send(DeliveryMode::ReliableOrdered(StreamId(1)), data);
+            // Half are fed and left to the final flush, half flushed alone.
+            match i % 2 {
+                0 => sender.feed(cmd).await.unwrap(),
+                _ => sender.send(cmd).await.unwrap(),
+            }
+        }
+        sender.close().await.unwrap();
+
+        let written = futures::io::Cursor::new(written.take());
+        let receiver = TcpConnection::from_stream(written, address);
+        let received: Vec<Datagram> =
+            receiver.map(|datagram| datagram.unwrap()).collect().await;
+        let data: Vec<Vec<u8>> = received
+            .iter()
+            .map(|datagram| datagram.data.clone())
+            .collect();
+        assert_eq!(data, sent);
+        for (i, datagram) in received.iter().enumerate() {
+            assert_eq!(
+                datagram.stream_position.map(|position| position.index),
+                Some(StreamIndex::Ordinal(i as u32 + 1))
+            );
+        }
+    }
+
+    /// What the server receives when the client sends a few datagrams, by
+    /// `send_all_batch` if `batch` and one by one if not.
+    async fn received(port: u16, batch: bool) -> Vec<Datagram> {
+        let address = SocketAddr::from(([127, 0, 0, 1], port));
+        let mut server = TcpServer::bind(address).await.unwrap();
+        let mut client = TcpConnection::connect(address).await.unwrap();
+        let peer = server.next().await.unwrap().unwrap();
+
+        // A send left unflushed before the batch must still go first.
+        client
+            .feed(send(DeliveryMode::ReliableOrdered(StreamId(1)), b"first"))
+            .await
+            .unwrap();
+        let cmds = vec![
+            send(DeliveryMode::ReliableOrdered(StreamId(1)), b"a"),
+            send(DeliveryMode::UnreliableUnordered, b"dropped"),
+            send(DeliveryMode::ReliableOrdered(StreamId(2)), b"b"),
+            send(DeliveryMode::ReliableOrdered(StreamId(1)), b"c"),
+        ];
+        if batch {
+            client.send_all_batch(cmds).await.unwrap();
+        } else {
+            for cmd in cmds {
+                client.send(cmd).await.unwrap();
+            }
+        }
+        client.close().await.unwrap();
+
+        peer.map(|datagram| datagram.unwrap()).collect().await
+    }
+
+    #[cfg(target_os = "linux")]
+    #[async_std::test]
+    async fn datagrams_received_before_a_reset_still_surface() {
+        use std::os::unix::io::AsRawFd;
+
+        let address = SocketAddr::from(([127, 0, 0, 1], 47748));
+        let mut server = TcpServer::bind(address).await.unwrap();
+        let mut client = TcpConnection::connect(address).await.unwrap();
+        let peer = server.next().await.unwrap().unwrap();
+        for data in 0..5u8 {
+            client
+                .send(send(DeliveryMode::ReliableOrdered(StreamId(1)), &[data]))
+                .await
+                .unwrap();
+        }
+        // Once the peer's kernel holds the datagrams, the client aborts
+        // with a reset rather than closing.
+        async_std::task::sleep(Duration::from_millis(50)).await;
+        let linger = libc::linger {
+            l_onoff: 1,
+            l_linger: 0,
+        };
+        let result = unsafe {
+            libc::setsockopt(
+                client.write_half.as_ref().unwrap().as_raw_fd(),
+                libc::SOL_SOCKET,
+                libc::SO_LINGER,
+                &linger as *const libc::linger as *const libc::c_void,
+                std::mem::size_of::<libc::linger>() as libc::socklen_t,
+            )
+        };
+        assert_eq!(result, 0, "{}", std::io::Error::last_os_error());
+        drop(client);
+        async_std::task::sleep(Duration::from_millis(50)).await;
+
+        let received: Vec<Result<Datagram>> = peer.collect().await;
+        let data: Vec<Vec<u8>> = received
+            .iter()
+            .map_while(|datagram| datagram.as_ref().ok())
+            .map(|datagram| datagram.data.clone())
+            .collect();
+        assert_eq!(data, (0..5u8).map(|data| vec![data]).collect::<Vec<_>>());
+        // The reset ends the stream with an error, after the datagrams.
+        assert!(matches!(received.last(), Some(Err(_))));
+    }
+
+    #[async_std::test]
+    async fn batches_arrive_as_their_sends_would() {
+        let one_by_one = received(47671, false).await;
+        let batched = received(47672, true).await;
+        assert_eq!(batched, one_by_one);
+        let data: Vec<&[u8]> =
+            batched.iter().map(|datagram| &datagram.data[..]).collect();
+        assert_eq!(data, vec![&b"first"[..], b"a", b"b", b"c"]);
+    }
+
+    #[async_std::test]
+    async fn streams_end_once_the_peer_finishes_sending() {
+        let address = SocketAddr::from(([127, 0, 0, 1], 47718));
+        let mut server = TcpServer::bind(address).await.unwrap();
+        let mut client = TcpConnection::connect(address).await.unwrap();
+        let mut peer = server.next().await.unwrap().unwrap();
+
+        client
+            .send(send(DeliveryMode::ReliableOrdered(StreamId(1)), b"last"))
+            .await
+            .unwrap();
+        assert!(!peer.peer_finished());
+        client.close().await.unwrap();
+
+        let datagram = peer.next().await.unwrap().unwrap();
+        assert_eq!(datagram.data, b"last");
+        assert!(!peer.is_terminated());
+        assert!(peer.next().await.is_none());
+        assert!(peer.peer_finished());
+        assert!(peer.is_terminated());
+        assert!(peer.next().await.is_none());
+    }
+
+    #[async_std::test]
+    async fn empty_datagrams_keep_their_place_in_the_stream() {
+        let address = SocketAddr::from(([127, 0, 0, 1], 47720));
+        let mut server = TcpServer::bind(address).await.unwrap();
+        let mut client = TcpConnection::connect(address).await.unwrap();
+        let peer = server.next().await.unwrap().unwrap();
+
+        for data in &[&b"a"[..], b"", b"b"] {
+            client
+                .send(send(DeliveryMode::ReliableOrdered(StreamId(3)), data))
+                .await
+                .unwrap();
+        }
+        client.close().await.unwrap();
+
+        let received: Vec<Datagram> =
+            peer.map(|datagram| datagram.unwrap()).collect().await;
+        let data: Vec<&[u8]> =
+            received.iter().map(|datagram| &datagram.data[..]).collect();
+        assert_eq!(data, vec![&b"a"[..], b"", b"b"]);
+        assert_eq!(
+            received[1].stream_position,
+            Some(StreamPosition {
+                stream_id: StreamId(3),
+                index: StreamIndex::Ordinal(2),
+            })
+        );
+    }
+
+    #[async_std::test]
+    async fn headers_arrive_with_their_data() {
+        let address = SocketAddr::from(([127, 0, 0, 1], 47727));
+        let mut server = TcpServer::bind(address).await.unwrap();
+        let mut client = TcpConnection::connect(address).await.unwrap();
+        let mut peer = server.next().await.unwrap().unwrap();
+
+        client
+            .send(SendCmd {
+                header: Some(b"type=input".to_vec()),
+                ..send(DeliveryMode::ReliableOrdered(StreamId(1)), b"payload")
+            })
+            .await
+            .unwrap();
+        let datagram = peer.next().await.unwrap().unwrap();
+        assert_eq!(datagram.header, Some(b"type=input".to_vec()));
+        assert_eq!(datagram.data, b"payload");
+    }
+
+    /// Sets the TCP socket option `name` on `socket` to `value`.
+    #[cfg(target_os = "linux")]
+    fn set_tcp_option(
+        socket: &impl std::os::unix::io::AsRawFd,
+        name: libc::c_int,
+        value: &[u8],
+    ) {
+        let result = unsafe {
+            libc::setsockopt(
+                socket.as_raw_fd(),
+                libc::IPPROTO_TCP,
+                name,
+                value.as_ptr() as *const libc::c_void,
+                value.len() as libc::socklen_t,
+            )
+        };
+        assert_eq!(result, 0, "{}", std::io::Error::last_os_error());
+    }
+
+    #[cfg(target_os = "linux")]
+    #[async_std::test]
+    async fn pacing_rates_are_set_on_the_socket() {
+        use std::os::unix::io::AsRawFd;
+
+        let address = SocketAddr::from(([127, 0, 0, 1], 47743));
+        let listener = TcpListener::bind(address).await.unwrap();
+        let (_accepted, client) = futures::future::join(
+            listener.accept(),
+            TcpConnection::connect(address),
+        )
+        .await;
+        let client = client.unwrap();
+        assert!(client.set_max_pacing_rate(100_000));
+
+        let mut rate = 0u64;
+        let mut len = std::mem::size_of::<u64>() as libc::socklen_t;
+        let result = unsafe {
+            libc::getsockopt(
+                client.write_half.as_ref().unwrap().as_raw_fd(),
+                libc::SOL_SOCKET,
+                libc::SO_MAX_PACING_RATE,
+                &mut rate as *mut u64 as *mut libc::c_void,
+                &mut len,
+            )
+        };
+        assert_eq!(result, 0, "{}", std::io::Error::last_os_error());
+        assert_eq!(rate, 100_000);
+    }
+
+    #[cfg(target_os = "linux")]
+    #[async_std::test]
+    async fn idle_gaps_restart_the_congestion_window() {
+        let address = SocketAddr::from(([127, 0, 0, 1], 47732));
+        // Loopback's segments are so large that ten of them outlast any
+        // burst, so the listener asks for small ones to leave the window
+        // room to open.
+        let listener = TcpListener::bind(address).await.unwrap();
+        set_tcp_option(&listener, libc::TCP_MAXSEG, &1000i32.to_ne_bytes());
+        let mut client = TcpConnection::connect(address).await.unwrap();
+        let (stream, peer_addr) = listener.accept().await.unwrap();
+        let mut peer = TcpConnection::from((stream, peer_addr));
+        // Congestion controls which model the path themselves, such as BBR,
+        // skip the restart, so the test holds to Reno, which never does.
+        set_tcp_option(
+            client.write_half.as_ref().unwrap(),
+            libc::TCP_CONGESTION,
+            b"reno",
+        );
+
+        // A burst big enough to open the window past its initial ten
+        // segments.
+        let burst = async {
+            for _ in 0..2000 {
+                let data = [0; 4000];
+                let cmd =
+                    send(DeliveryMode::ReliableOrdered(StreamId(1)), &data);
+                client.feed(cmd).await.unwrap();
+            }
+            client.flush().await.unwrap();
+        };
+        let received = async {
+            for _ in 0..2000 {
+                peer.next().await.unwrap().unwrap();
+            }
+        };
+        futures::future::join(burst, received).await;
+        let opened = client.transport_state();
+
+        // Idle for several retransmission timeouts, then send again.
+        async_std::task::sleep(Duration::from_millis(1200)).await;
+        let cmd = send(DeliveryMode::ReliableOrdered(StreamId(1)), b"again");
+        client.send(cmd).await.unwrap();
+        peer.next().await.unwrap().unwrap();
+        let restarted = client.transport_state();
+
+        let idle = Duration::from_millis(1200);
+        assert!(opened.rto.unwrap() < idle, "{:?}", opened);
+        assert!(
+            restarted.cwnd.unwrap() < opened.cwnd.unwrap(),
+            "{:?} then {:?}",
+            opened,
+            restarted
+        );
     }
 }
@@ -0,0 +1,473 @@
+//! Several TCP connections behind one `Connection`, as browsers opened them
+//! before HTTP/2 multiplexed requests over one.
+//!
+//! Each stream is carried by one of the connections, chosen by its
+//! `StreamId`, so a loss on one connection only holds up the streams it
+//! carries. Datagrams of the unordered delivery modes take the connections in
+//! turn, but TCP drops those as it does for a single connection.
+//!
+//! The client opens every connection before sending on any, and introduces
+//! each to the server with a `Hello` naming their session. The server holds
+//! the connections of a session back until all of them have arrived, then
+//! surfaces them as one connection.
+
+use crate::*;
+
+use futures::{
+    future::LocalBoxFuture,
+    stream::{Fuse, FusedStream, FuturesUnordered},
+    task::{Context, Poll},
+    Sink, SinkExt, Stream, StreamExt,
+};
+use serde::{Deserialize, Serialize};
+use std::{collections::HashMap, io, pin::Pin};
+
+/// The first datagram on each of a session's connections.
+#[derive(Debug, Clone, Copy, Serialize, Deserialize)]
+struct Hello {
+    session: u64,
+    /// Which of the session's connections this is.
+    index: usize,
+    /// How many connections the session has.
+    count: usize,
+}
+
+type Greeting = LocalBoxFuture<'static, Result<(Hello, tcp::TcpConnection)>>;
+
+pub struct TcpMultiplexedServer {
+    incoming: Fuse<tcp::TcpServer>,
+    /// Connections whose `Hello` has not been read yet.
+    greetings: FuturesUnordered<Greeting>,
+    /// The connections of each session which has not fully arrived.
+    sessions: HashMap<u64, Vec<Option<tcp::TcpConnection>>>,
+}
+
+impl TcpMultiplexedServer {
+    pub async fn bind(address: SocketAddr) -> Result<Self> {
+        Ok(Self {
+            incoming: tcp::TcpServer::bind(address).await?.fuse(),
+            greetings: FuturesUnordered::new(),
+            sessions: HashMap::new(),
+        })
+    }
+
+    fn greet(mut connection: tcp::TcpConnection) -> Greeting {
+        Box::pin(async move {
+            let datagram = connection.next().await.ok_or_else(|| {
+                io::Error::new(
+                    io::ErrorKind::UnexpectedEof,
+                    "connection closed before saying hello",
+                )
+            })??;
+            let hello: Hello = bincode::deserialize(&datagram.data)?;
+            Ok((hello, connection))
+        })
+    }
+
+    /// Adds `connection` to its session, returning the session's connection
+    /// once every one has arrived.
+    fn admit(
+        &mut self,
+        hello: Hello,
+        connection: tcp::TcpConnection,
+    ) -> Result<Option<TcpMultiplexedConnection>> {
+        let slots = self
+            .sessions
+            .entry(hello.session)
+            .or_insert_with(|| (0..hello.count).map(|_| None).collect());
+        let count = slots.len();
+        match slots.get_mut(hello.index) {
+            Some(slot @ None) if count == hello.count => {
+                *slot = Some(connection)
+            }
+            _ => {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    format!("unexpected hello {:?}", hello),
+                )
+                .into())
+            }
+        }
+        if slots.iter().any(Option::is_none) {
+            return Ok(None);
+        }
+
+        let connections = self
+            .sessions
+            .remove(&hello.session)
+            .unwrap_or_default()
+            .into_iter()
+            .flatten()
+            .collect();
+        Ok(Some(TcpMultiplexedConnection::new(connections)))
+    }
+}
+
+impl Server<TcpMultiplexedConnection> for TcpMultiplexedServer {}
+
+impl Stream for TcpMultiplexedServer {
+    type Item = Result<TcpMultiplexedConnection>;
+    fn poll_next(
+        mut self: Pin<&mut Self>,
+        ctx: &mut Context,
+    ) -> Poll<Option<Self::Item>> {
+        loop {
+            while let Poll::Ready(Some(connection)) =
+                Pin::new(&mut self.incoming).poll_next(ctx)
+            {
+                match connection {
+                    Ok(connection) => {
+                        self.greetings.push(Self::greet(connection))
+                    }
+                    Err(e) => return Poll::Ready(Some(Err(e))),
+                }
+            }
+
+            match Pin::new(&mut self.greetings).poll_next(ctx) {
+                Poll::Ready(Some(Ok((hello, connection)))) => {
+                    match self.admit(hello, connection) {
+                        Ok(Some(connection)) => {
+                            return Poll::Ready(Some(Ok(connection)))
+                        }
+                        Ok(None) => continue,
+                        Err(e) => return Poll::Ready(Some(Err(e))),
+                    }
+                }
+                Poll::Ready(Some(Err(e))) => return Poll::Ready(Some(Err(e))),
+                Poll::Ready(None) | Poll::Pending => {}
+            }
+
+            return match self.is_terminated() {
+                true => Poll::Ready(None),
+                false => Poll::Pending,
+            };
+        }
+    }
+}
+
+impl FusedStream for TcpMultiplexedServer {
+    fn is_terminated(&self) -> bool {
+        self.incoming.is_terminated() && self.greetings.is_empty()
+    }
+}
+
+pub struct TcpMultiplexedConnection {
+    connections: Vec<tcp::TcpConnection>,
+    /// The connection the next unordered datagram is sent on.
+    next_unordered: usize,
+    /// The connection polled first for the next datagram, so that a busy one
+    /// does not starve the rest.
+    next_polled: usize,
+    /// How many datagrams have surfaced on each ordered stream.
+    surfaced: HashMap<StreamId, u32>,
+}
+
+impl TcpMultiplexedConnection {
+    fn new(connections: Vec<tcp::TcpConnection>) -> Self {
+        Self {
+            connections,
+            next_unordered: 0,
+            next_polled: 0,
+            surfaced: HashMap::new(),
+        }
+    }
+
+    /// Introduces `connections`, all to the same server, as one session and
+    /// carries the streams between them.
+    pub async fn introduce(
+        mut connections: Vec<tcp::TcpConnection>,
+    ) -> Result<Self> {
+        if connections.is_empty() {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "multiplexing needs at least one connection",
+            )
+            .into());
+        }
+
+        let session = rand::random();
+        let count = connections.len();
+        for (index, connection) in connections.iter_mut().enumerate() {
+            let hello = Hello {
+                session,
+                index,
+                count,
+            };
+            connection
+                .send(SendCmd {
+                    delivery_mode: DeliveryMode::ReliableOrdered(StreamId(0)),
+                    data: bincode::serialize(&hello)?,
+                    ..SendCmd::default()
+                })
+                .await?;
+        }
+        Ok(Self::new(connections))
+    }
+
+    /// Which connection carries datagrams sent with `delivery_mode`.
+    fn carrier(&mut self, delivery_mode: DeliveryMode) -> usize {
+        match delivery_mode {
+            DeliveryMode::ReliableOrdered(stream_id)
+            | DeliveryMode::ReliableSequenced(stream_id)
+            | DeliveryMode::UnreliableSequenced(stream_id) => {
+                usize::from(stream_id.0) % self.connections.len()
+            }
+            DeliveryMode::ReliableUnordered
+            | DeliveryMode::UnreliableUnordered => {
+                let carrier = self.next_unordered;
+                self.next_unordered = (carrier + 1) % self.connections.len();
+                carrier
+            }
+        }
+    }
+
+    /// Numbers a datagram among those surfaced on its stream, rather than
+    /// among those on the connection which carried it.
+    fn renumber(&mut self, mut datagram: Datagram) -> Datagram {
+        if let Some(StreamPosition {
+            stream_id,
+            index: StreamIndex::Ordinal(_),
+        }) = datagram.stream_position
+        {
+            let surfaced = self.surfaced.entry(stream_id).or_insert(0);
+            *surfaced += 1;
+            datagram.stream_position = Some(StreamPosition {
+                stream_id,
+                index: StreamIndex::Ordinal(*surfaced),
+            });
+        }
+        datagram
+    }
+
+    /// Polls every connection with `poll`, finishing once all of them have.
+    fn poll_all(
+        &mut self,
+        ctx: &mut Context,
+        mut poll: impl FnMut(
+            Pin<&mut tcp::TcpConnection>,
+            &mut Context,
+        ) -> Poll<Result<()>>,
+    ) -> Poll<Result<()>> {
+        let mut pending = false;
+        for connection in &mut self.connections {
+            match poll(Pin::new(connection), ctx) {
+                Poll::Ready(Ok(())) => {}
+                Poll::Ready(Err(e)) => return Poll::Ready(Err(e)),
+                Poll::Pending => pending = true,
+            }
+        }
+        match pending {
+            true => Poll::Pending,
+            false => Poll::Ready(Ok(())),
+        }
+    }
+}
+
+impl Connection for TcpMultiplexedConnection {
+    fn peer_addr(&self) -> Option<SocketAddr> {
+        self.connections[0].peer_addr()
+    }
+
+    fn peer_finished(&self) -> bool {
+        self.connections
+            .iter()
+            .all(|connection| connection.peer_finished())
+    }
+
+    /// The connections' windows and queues together, and the longest of
+    /// their round trips.
+    fn transport_state(&self) -> TransportState {
+        self.connections
+            .iter()
+            .map(|connection| connection.transport_state())
+            .reduce(|a, b| TransportState {
+                cwnd: sum(a.cwnd, b.cwnd),
+                ssthresh: sum(a.ssthresh, b.ssthresh),
+                rwnd: sum(a.rwnd, b.rwnd),
+                bytes_in_flight: sum(a.bytes_in_flight, b.bytes_in_flight),
+                send_queue_depth: sum(a.send_queue_depth, b.send_queue_depth),
+                srtt: a.srtt.max(b.srtt),
+                rto: a.rto.max(b.rto),
+                dropped_sends: sum(a.dropped_sends, b.dropped_sends),
+            })
+            .unwrap_or_default()
+    }
+
+    /// The connections' peaks added up, which bounds the session's from
+    /// above, as they need not have peaked at once.
+    fn high_water_marks(&self) -> HighWaterMarks {
+        self.connections
+            .iter()
+            .map(|connection| connection.high_water_marks())
+            .reduce(|a, b| HighWaterMarks {
+                bytes_in_flight: sum(a.bytes_in_flight, b.bytes_in_flight),
+                send_queue_depth: sum(a.send_queue_depth, b.send_queue_depth),
+                reassembly_bytes: sum(a.reassembly_bytes, b.reassembly_bytes),
+            })
+            .unwrap_or_default()
+    }
+
+    /// Paces each connection at its share of the rate, so that together
+    /// they keep to it.
+    fn set_max_pacing_rate(&self, bytes_per_second: u64) -> bool {
+        let share = bytes_per_second / self.connections.len() as u64;
+        let mut paced = true;
+        for connection in &self.connections {
+            paced &= connection.set_max_pacing_rate(share);
+        }
+        paced
+    }
+}
+
+/// The total of two connections' counts, of those which track it.
+fn sum(a: Option<u64>, b: Option<u64>) -> Option<u64> {
+    match (a, b) {
+        (Some(a), Some(b)) => Some(a + b),
+        (a, b) => a.or(b),
+    }
+}
+
+impl Sink<SendCmd> for TcpMultiplexedConnection {
+    type Error = Box<dyn std::error::Error>;
+    fn poll_ready(
+        mut self: Pin<&mut Self>,
+        ctx: &mut Context,
+    ) -> Poll<Result<()>> {
+        self.poll_all(ctx, |connection, ctx| connection.poll_ready(ctx))
+    }
+    fn start_send(mut self: Pin<&mut Self>, item: SendCmd) -> Result<()> {
+        let carrier = self.carrier(item.delivery_mode);
+        Pin::new(&mut self.connections[carrier]).start_send(item)
+    }
+    fn poll_flush(
+        mut self: Pin<&mut Self>,
+        ctx: &mut Context,
+    ) -> Poll<Result<()>> {
+        self.poll_all(ctx, |connection, ctx| connection.poll_flush(ctx))
+    }
+    fn poll_close(
+        mut self: Pin<&mut Self>,
+        ctx: &mut Context,
+    ) -> Poll<Result<()>> {
+        self.poll_all(ctx, |connection, ctx| connection.poll_close(ctx))
+    }
+}
+
+impl Stream for TcpMultiplexedConnection {
+    type Item = Result<Datagram>;
+    fn poll_next(
+        mut self: Pin<&mut Self>,
+        ctx: &mut Context,
+    ) -> Poll<Option<Self::Item>> {
+        let count = self.connections.len();
+        for offset in 0..count {
+            let index = (self.next_polled + offset) % count;
+            let connection = &mut self.connections[index];
+            if connection.is_terminated() {
+                continue;
+            }
+            match Pin::new(connection).poll_next(ctx) {
+                Poll::Ready(Some(Ok(datagram))) => {
+                    self.next_polled = (index + 1) % count;
+                    return Poll::Ready(Some(Ok(self.renumber(datagram))));
+                }
+                Poll::Ready(Some(Err(e))) => return Poll::Ready(Some(Err(e))),
+                Poll::Ready(None) | Poll::Pending => {}
+            }
+        }
+
+        match self.is_terminated() {
+            true => Poll::Ready(None),
+            false => Poll::Pending,
+        }
+    }
+}
+
+impl FusedStream for TcpMultiplexedConnection {
+    fn is_terminated(&self) -> bool {
+        self.connections
+            .iter()
+            .all(|connection| connection.is_terminated())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[async_std::test]
+    async fn streams_travel_on_their_own_connections() {
+        let address = SocketAddr::from(([127, 0, 0, 1], 47681));
+        let mut server = TcpMultiplexedServer::bind(address).await.unwrap();
+        let mut connections = vec![];
+        for _ in 0..2 {
+            connections
+                .push(tcp::TcpConnection::connect(address).await.unwrap());
+        }
+        let mut client = TcpMultiplexedConnection::introduce(connections)
+            .await
+            .unwrap();
+        let mut peer = server.next().await.unwrap().unwrap();
+
+        // The server sees each of the client's connections from its own port.
+        assert_ne!(
+            peer.connections[0].peer_addr(),
+            peer.connections[1].peer_addr()
+        );
+
+        for stream_id in [StreamId(0), StreamId(1)] {
+            client
+                .send(SendCmd {
+                    delivery_mode: DeliveryMode::ReliableOrdered(stream_id),
+                    data: vec![stream_id.0],
+                    ..SendCmd::default()
+                })
+                .await
+                .unwrap();
+        }
+        for (index, connection) in peer.connections.iter_mut().enumerate() {
+            let datagram = connection.next().await.unwrap().unwrap();
+            assert_eq!(datagram.data, vec![index as u8]);
+            assert_eq!(
+                datagram.stream_position.map(|p| p.stream_id),
+                Some(StreamId(index as u8))
+            );
+        }
+    }
+
+    #[cfg(target_os = "linux")]
+    #[async_std::test]
+    async fn transport_state_adds_up_the_connections() {
+        let address = SocketAddr::from(([127, 0, 0, 1], 47740));
+        let mut server = TcpMultiplexedServer::bind(address).await.unwrap();
+        let mut connections = vec![];
+        for _ in 0..2 {
+            connections
+                .push(tcp::TcpConnection::connect(address).await.unwrap());
+        }
+        let client = TcpMultiplexedConnection::introduce(connections)
+            .await
+            .unwrap();
+        let _peer = server.next().await.unwrap().unwrap();
+        // Let the hellos' acknowledgements settle the windows.
+        async_std::task::sleep(std::time::Duration::from_millis(50)).await;
+
+        let inner: Vec<TransportState> = client
+            .connections
+            .iter()
+            .map(|connection| connection.transport_state())
+            .collect();
+        let state = client.transport_state();
+        let cwnds: Vec<u64> =
+            inner.iter().map(|state| state.cwnd.unwrap()).collect();
+        assert_eq!(state.cwnd, Some(cwnds.iter().sum()));
+        assert!(state.cwnd > Some(cwnds[0]));
+        assert_eq!(
+            state.srtt,
+            inner.iter().map(|state| state.srtt).max().unwrap()
+        );
+        assert_eq!(state.rwnd, None);
+        // Plain TCP tracks no peaks, so neither does the session.
+        assert_eq!(client.high_water_marks(), HighWaterMarks::default());
+    }
+}
@@ -0,0 +1,230 @@
+//! A connection adapter which copies its traffic to an observer.
+//!
+//! `Tee` forwards everything through the connection it wraps unchanged, and
+//! offers a copy of each sent and received datagram to a bounded channel. An
+//! observer that falls behind loses copies instead of slowing the connection;
+//! the copies lost are counted.
+
+use crate::*;
+
+use futures::{
+    channel::mpsc,
+    future::LocalBoxFuture,
+    stream::FusedStream,
+    task::{Context, Poll},
+    Sink, Stream,
+};
+use std::{
+    pin::Pin,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc,
+    },
+};
+
+/// A copy of traffic on a teed connection.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Observed {
+    Sent(SendCmd),
+    Received(Datagram),
+}
+
+pub struct Tee<C> {
+    inner: C,
+    observer: mpsc::Sender<Observed>,
+    dropped: Arc<AtomicU64>,
+}
+
+impl<C> Tee<C>
+where
+    C: Connection + Unpin,
+{
+    /// Wraps `inner`, returning the receiving end of an observer channel.
+    /// The channel holds `capacity` copies, plus the one slot futures channels
+    /// keep for their sender.
+    pub fn new(inner: C, capacity: usize) -> (Self, mpsc::Receiver<Observed>) {
+        let (observer, observed) = mpsc::channel(capacity);
+        let tee = Self {
+            inner,
+            observer,
+            dropped: Arc::new(AtomicU64::new(0)),
+        };
+        (tee, observed)
+    }
+
+    /// A running count of the copies the observer was too slow to take.
+    pub fn dropped(&self) -> Arc<AtomicU64> {
+        self.dropped.clone()
+    }
+
+    fn observe(&mut self, observed: Observed) {
+        match self.observer.try_send(observed) {
+            Err(e) if e.is_full() => {
+                self.dropped.fetch_add(1, Ordering::Relaxed);
+            }
+            // Once the observer hangs up there is nobody to count for.
+            _ => {}
+        }
+    }
+}
+
+impl<C> Connection for Tee<C>
+where
+    C: Connection + Unpin,
+{
+    fn ready(&mut self) -> LocalBoxFuture<'_, Result<()>> {
+        self.inner.ready()
+    }
+
+    fn wire_bytes_sent(&self) -> Option<Arc<AtomicU64>> {
+        self.inner.wire_bytes_sent()
+    }
+
+    fn peer_addr(&self) -> Option<std::net::SocketAddr> {
+        self.inner.peer_addr()
+    }
+
+    fn peer_finished(&self) -> bool {
+        self.inner.peer_finished()
+    }
+
+    fn transport_state(&self) -> TransportState {
+        self.inner.transport_state()
+    }
+
+    fn high_water_marks(&self) -> HighWaterMarks {
+        self.inner.high_water_marks()
+    }
+
+    fn set_max_pacing_rate(&self, bytes_per_second: u64) -> bool {
+        self.inner.set_max_pacing_rate(bytes_per_second)
+    }
+
+    fn pause_stream(&mut self, stream_id: StreamId) -> bool {
+        self.inner.pause_stream(stream_id)
+    }
+
+    fn resume_stream(&mut self, stream_id: StreamId) {
+        self.inner.resume_stream(stream_id)
+    }
+
+    fn is_paused(&self, stream_id: StreamId) -> bool {
+        self.inner.is_paused(stream_id)
+    }
+}
+
+impl<C> Sink<SendCmd> for Tee<C>
+where
+    C: Connection + Unpin,
+{
+    type Error = Box<dyn std::error::Error>;
+    fn poll_ready(
+        mut self: Pin<&mut Self>,
+        ctx: &mut Context,
+    ) -> Poll<Result<()>> {
+        Pin::new(&mut self.inner).poll_ready(ctx)
+    }
+    fn start_send(mut self: Pin<&mut Self>, item: SendCmd) -> Result<()> {
+        // The tap sees the send but not its notifier, which is the
+        // transport's to report to.
+        self.observe(Observed::Sent(SendCmd {
+            notify: None,
+            ..item.clone()
+        }));
+        Pin::new(&mut self.inner).start_send(item)
+    }
+    fn poll_flush(
+        mut self: Pin<&mut Self>,
+        ctx: &mut Context,
+    ) -> Poll<Result<()>> {
+        Pin::new(&mut self.inner).poll_flush(ctx)
+    }
+    fn poll_close(
+        mut self: Pin<&mut Self>,
+        ctx: &mut Context,
+    ) -> Poll<Result<()>> {
+        Pin::new(&mut self.inner).poll_close(ctx)
+    }
+}
+
+impl<C> Stream for Tee<C>
+where
+    C: Connection + Unpin,
+{
+    type Item = Result<Datagram>;
+    fn poll_next(
+        mut self: Pin<&mut Self>,
+        ctx: &mut Context,
+    ) -> Poll<Option<Self::Item>> {
+        let next = Pin::new(&mut self.inner).poll_next(ctx);
+        if let Poll::Ready(Some(Ok(datagram))) = &next {
+            self.observe(Observed::Received(datagram.clone()));
+        }
+        next
+    }
+}
+
+impl<C> FusedStream for Tee<C>
+where
+    C: Connection + Unpin,
+{
+    fn is_terminated(&self) -> bool {
+        self.inner.is_terminated()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::memory::MemoryConnection;
+    use futures::{FutureExt, SinkExt, StreamExt};
+
+    fn send(data: &[u8]) -> SendCmd {
+        SendCmd {
+            data: data.to_vec(),
+            delivery_mode: DeliveryMode::ReliableOrdered(StreamId(1)),
+            ..SendCmd::default()
+        }
+    }
+
+    #[async_std::test]
+    async fn the_peer_and_the_observer_both_see_the_traffic() {
+        let (client, mut server) = MemoryConnection::pair(None);
+        let (mut tee, mut observed) = Tee::new(client, 4);
+
+        tee.send(send(b"ping")).await.unwrap();
+        assert_eq!(server.next().await.unwrap().unwrap().data, b"ping");
+        match observed.next().await {
+            Some(Observed::Sent(cmd)) => assert_eq!(cmd.data, b"ping"),
+            other => panic!("observed {:?}", other),
+        }
+
+        server.send(send(b"pong")).await.unwrap();
+        let received = tee.next().await.unwrap().unwrap();
+        assert_eq!(received.data, b"pong");
+        assert_eq!(observed.next().await, Some(Observed::Received(received)));
+        assert_eq!(tee.dropped().load(Ordering::Relaxed), 0);
+    }
+
+    #[async_std::test]
+    async fn a_full_observer_loses_copies_not_traffic() {
+        let (client, server) = MemoryConnection::pair(None);
+        // With no room of its own, the channel holds only its sender's slot.
+        let (mut tee, mut observed) = Tee::new(client, 0);
+        for data in [b"a", b"b", b"c"] {
+            tee.send(send(data)).await.unwrap();
+        }
+        tee.close().await.unwrap();
+
+        let received: Vec<Vec<u8>> = server
+            .map(|datagram| datagram.unwrap().data)
+            .collect()
+            .await;
+        assert_eq!(received, vec![b"a".to_vec(), b"b".to_vec(), b"c".to_vec()]);
+        assert_eq!(tee.dropped().load(Ordering::Relaxed), 2);
+        match observed.next().now_or_never() {
+            Some(Some(Observed::Sent(cmd))) => assert_eq!(cmd.data, b"a"),
+            other => panic!("observed {:?}", other),
+        }
+    }
+}
@@ -0,0 +1,154 @@
+//! Per-datagram timestamp trails, to break down where a round trip's latency
+//! goes.
+//!
+//! With `--trace-timings` the client stamps each datagram it sends as it
+//! reaches each `Stage` of its round trip. Stages are seen from the client,
+//! so time inside the transport and the server is all spent in flight.
+//! Without the flag nothing is stamped.
+//!
+//! `write_chrome_trace` exports trails in the Chrome trace event format,
+//! which `chrome://tracing`, Perfetto and speedscope can load, with each
+//! datagram's round trip as a span broken into a span per stage.
+
+use crate::*;
+
+use serde::Serialize;
+use std::io::Write;
+
+/// A point on a datagram's round trip, in the order they are reached.
+#[derive(Serialize, Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Stage {
+    /// The transfer came due to send the datagram.
+    Enqueued,
+    Serialized,
+    /// The connection took the datagram. Under the `send` flush policy it
+    /// was flushed too.
+    Sent,
+    /// The connection returned the echo.
+    Received,
+    Deserialized,
+    /// The echo was matched to its send and its round trip recorded.
+    Delivered,
+}
+
+impl Stage {
+    /// What happens on the way to the stage.
+    fn span_name(self) -> &'static str {
+        match self {
+            Stage::Enqueued => "enqueue",
+            Stage::Serialized => "serialize",
+            Stage::Sent => "send",
+            Stage::Received => "in flight",
+            Stage::Deserialized => "deserialize",
+            Stage::Delivered => "deliver",
+        }
+    }
+}
+
+/// When one datagram reached each stage of its round trip, in milliseconds
+/// since the run began.
+#[derive(Serialize, Debug, Clone, PartialEq)]
+pub struct TimingTrail {
+    pub stream_id: StreamId,
+    pub index: u64,
+    pub stages: Vec<(Stage, f64)>,
+}
+
+/// One complete event in the Chrome trace event format. Times are in
+/// microseconds.
+#[derive(Serialize)]
+struct TraceEvent {
+    name: &'static str,
+    ph: &'static str,
+    ts: f64,
+    dur: f64,
+    /// Each stream is a process, and each datagram a thread within it.
+    pid: u8,
+    tid: u64,
+}
+
+/// Writes `trails` to `writer` as a Chrome trace.
+pub fn write_chrome_trace(
+    trails: &[TimingTrail],
+    writer: impl Write,
+) -> Result<()> {
+    let mut events = vec![];
+    for trail in trails {
+        let event = |name, from: f64, to: f64| TraceEvent {
+            name,
+            ph: "X",
+            ts: from * 1e3,
+            dur: (to - from) * 1e3,
+            pid: trail.stream_id.0,
+            tid: trail.index,
+        };
+
+        if let (Some((_, first)), Some((_, last))) =
+            (trail.stages.first(), trail.stages.last())
+        {
+            events.push(event("round trip", *first, *last));
+        }
+        for pair in trail.stages.windows(2) {
+            let ((_, from), (stage, to)) = (pair[0], pair[1]);
+            events.push(event(stage.span_name(), from, to));
+        }
+    }
+
+    serde_json::to_writer(
+        writer,
+        &serde_json::json!({ "traceEvents": events }),
+    )?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn chrome_traces_span_each_stage_within_the_round_trip() {
+        let trail = TimingTrail {
+            stream_id: StreamId(2),
+            index: 7,
+            stages: vec![
+                (Stage::Enqueued, 1.0),
+                (Stage::Serialized, 1.5),
+                (Stage::Sent, 2.0),
+                (Stage::Received, 12.0),
+                (Stage::Deserialized, 12.25),
+                (Stage::Delivered, 13.0),
+            ],
+        };
+        let mut written = vec![];
+        write_chrome_trace(&[trail], &mut written).unwrap();
+
+        let trace: serde_json::Value =
+            serde_json::from_slice(&written).unwrap();
+        let events = trace["traceEvents"].as_array().unwrap();
+        let spans: Vec<(&str, f64, f64)> = events
+            .iter()
+            .map(|event| {
+                assert_eq!(
+                    (event["pid"].as_u64(), event["tid"].as_u64()),
+                    (Some(2), Some(7))
+                );
+                (
+                    event["name"].as_str().unwrap(),
+                    event["ts"].as_f64().unwrap(),
+                    event["dur"].as_f64().unwrap(),
+                )
+            })
+            .collect();
+        assert_eq!(
+            spans,
+            vec![
+                ("round trip", 1000.0, 12000.0),
+                ("serialize", 1000.0, 500.0),
+                ("send", 1500.0, 500.0),
+                ("in flight", 2000.0, 10000.0),
+                ("deserialize", 12000.0, 250.0),
+                ("deliver", 12250.0, 750.0),
+            ]
+        );
+    }
+}
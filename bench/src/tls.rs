@@ -0,0 +1,115 @@
+//! TLS over the TCP adapter, to weigh the cost of encrypting a reliable
+//! stream.
+//!
+//! Each server generates a self-signed certificate when it binds, and clients
+//! accept any certificate. That is fine on loopback: the benchmark measures
+//! what TLS costs, not what it protects.
+
+use crate::*;
+
+use async_std::{
+    net::*,
+    task::{Context, Poll},
+};
+use async_tls::{TlsAcceptor, TlsConnector};
+use futures::stream::{Fuse, FusedStream, LocalBoxStream, Stream, StreamExt};
+use rustls::{
+    client::{ServerCertVerified, ServerCertVerifier},
+    Certificate, ClientConfig, PrivateKey, ServerConfig, ServerName,
+};
+use std::{pin::Pin, sync::Arc, time::SystemTime};
+
+/// The name servers put on their certificates and clients ask for.
+const SERVER_NAME: &str = "localhost";
+
+pub struct TlsServer {
+    peers: Fuse<LocalBoxStream<'static, Result<tcp::TcpConnection>>>,
+}
+
+impl Server<tcp::TcpConnection> for TlsServer {}
+
+impl TlsServer {
+    pub async fn bind(addrs: impl ToSocketAddrs) -> Result<Self> {
+        let certificate =
+            rcgen::generate_simple_self_signed(vec![SERVER_NAME.to_string()])?;
+        let config = ServerConfig::builder()
+            .with_safe_defaults()
+            .with_no_client_auth()
+            .with_single_cert(
+                vec![Certificate(certificate.serialize_der()?)],
+                PrivateKey(certificate.serialize_private_key_der()),
+            )?;
+        let acceptor = TlsAcceptor::from(Arc::new(config));
+
+        let listener = TcpListener::bind(addrs).await?;
+        let listener = Box::leak(Box::new(listener));
+
+        let peers = listener.incoming().then(move |tcp_stream| {
+            let acceptor = acceptor.clone();
+            async move {
+                let tcp_stream = tcp_stream?;
+                tcp_stream.set_nodelay(true)?;
+                let peer_addr = tcp_stream.peer_addr()?;
+                let tls_stream = acceptor.accept(tcp_stream).await?;
+                Ok(tcp::TcpConnection::from_stream(tls_stream, peer_addr))
+            }
+        });
+
+        Ok(Self {
+            peers: peers.boxed_local().fuse(),
+        })
+    }
+}
+
+impl Stream for TlsServer {
+    type Item = Result<tcp::TcpConnection>;
+    fn poll_next(
+        mut self: Pin<&mut Self>,
+        ctx: &mut Context,
+    ) -> Poll<Option<Self::Item>> {
+        Pin::new(&mut self.peers).poll_next(ctx)
+    }
+}
+
+impl FusedStream for TlsServer {
+    fn is_terminated(&self) -> bool {
+        self.peers.is_terminated()
+    }
+}
+
+struct AcceptAnyCertificate;
+
+impl ServerCertVerifier for AcceptAnyCertificate {
+    fn verify_server_cert(
+        &self,
+        _end_entity: &Certificate,
+        _intermediates: &[Certificate],
+        _server_name: &ServerName,
+        _scts: &mut dyn Iterator<Item = &[u8]>,
+        _ocsp_response: &[u8],
+        _now: SystemTime,
+    ) -> std::result::Result<ServerCertVerified, rustls::Error> {
+        Ok(ServerCertVerified::assertion())
+    }
+}
+
+/// Connects to a `TlsServer`, completing both the TCP and TLS handshakes.
+///
+/// Both ends disable Nagle's algorithm. TLS writes each record in more than
+/// one segment, and Nagle would hold the tail of every record until the peer
+/// acknowledged its head, which at high send rates is the next send.
+pub async fn connect(
+    address: impl ToSocketAddrs,
+) -> Result<tcp::TcpConnection> {
+    let config = ClientConfig::builder()
+        .with_safe_defaults()
+        .with_custom_certificate_verifier(Arc::new(AcceptAnyCertificate))
+        .with_no_client_auth();
+    let connector = TlsConnector::from(Arc::new(config));
+
+    let tcp_stream = TcpStream::connect(address).await?;
+    tcp_stream.set_nodelay(true)?;
+    let peer_addr = tcp_stream.peer_addr()?;
+    let tls_stream = connector.connect(SERVER_NAME, tcp_stream).await?;
+    Ok(tcp::TcpConnection::from_stream(tls_stream, peer_addr))
+}
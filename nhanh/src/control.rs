@@ -0,0 +1,371 @@
+//! A reliable control channel beside a connection's application streams.
+//!
+//! `Connection::control` splits a connection in two: a `Control` channel of
+//! messages on the reliable ordered stream `StreamId::CONTROL`, and
+//! `Channels`, a connection for every other stream. Either half may be polled
+//! for its datagrams; whichever pulls a datagram meant for the other buffers
+//! it until the other is polled.
+
+use crate::{demux::Wakers, *};
+
+use futures::task::{waker_ref, ArcWake, Context, Poll};
+use std::{cell::RefCell, collections::VecDeque, io, pin::Pin, rc::Rc};
+
+/// Which half of a split connection a datagram belongs to, keyed as in
+/// `Wakers`.
+fn half_of(datagram: &Datagram) -> Option<StreamId> {
+    match datagram.stream_position {
+        Some(StreamPosition {
+            stream_id: StreamId::CONTROL,
+            index: StreamIndex::Ordinal(_),
+        }) => Some(StreamId::CONTROL),
+        _ => None,
+    }
+}
+
+struct ControlState<C> {
+    inner: C,
+    done: bool,
+    control: VecDeque<Datagram>,
+    channels: VecDeque<Datagram>,
+    wakers: Arc<Wakers>,
+}
+
+impl<C> ControlState<C>
+where
+    C: Connection + Unpin,
+{
+    fn buffer(&mut self, half: Option<StreamId>) -> &mut VecDeque<Datagram> {
+        match half {
+            Some(_) => &mut self.control,
+            None => &mut self.channels,
+        }
+    }
+
+    /// Polls for the next datagram for `half`, buffering any for the other.
+    fn poll_half(
+        &mut self,
+        half: Option<StreamId>,
+        ctx: &mut Context,
+    ) -> Poll<Option<Result<Datagram>>> {
+        loop {
+            if let Some(datagram) = self.buffer(half).pop_front() {
+                return Poll::Ready(Some(Ok(datagram)));
+            }
+            if self.done {
+                return Poll::Ready(None);
+            }
+
+            self.wakers
+                .waiting
+                .lock()
+                .expect("control wakers lock")
+                .insert(half, ctx.waker().clone());
+            let waker = waker_ref(&self.wakers);
+            let mut inner_ctx = Context::from_waker(&waker);
+            let datagram = match Pin::new(&mut self.inner)
+                .poll_next(&mut inner_ctx)
+            {
+                Poll::Pending => return Poll::Pending,
+                Poll::Ready(None) => {
+                    self.done = true;
+                    Wakers::wake_by_ref(&self.wakers);
+                    return Poll::Ready(None);
+                }
+                Poll::Ready(Some(Err(e))) => return Poll::Ready(Some(Err(e))),
+                Poll::Ready(Some(Ok(datagram))) => datagram,
+            };
+
+            let other = half_of(&datagram);
+            if other == half {
+                return Poll::Ready(Some(Ok(datagram)));
+            }
+            self.buffer(other).push_back(datagram);
+            let waker = self
+                .wakers
+                .waiting
+                .lock()
+                .expect("control wakers lock")
+                .remove(&other);
+            if let Some(waker) = waker {
+                waker.wake();
+            }
+        }
+    }
+}
+
+/// Splits `inner`. See `Connection::control`.
+pub(crate) fn split<C>(inner: C) -> (Control<C>, Channels<C>)
+where
+    C: Connection + Unpin,
+{
+    let state = Rc::new(RefCell::new(ControlState {
+        inner,
+        done: false,
+        control: VecDeque::new(),
+        channels: VecDeque::new(),
+        wakers: Arc::new(Wakers::default()),
+    }));
+    let control = Control {
+        state: state.clone(),
+    };
+    (control, Channels { state })
+}
+
+/// A connection's control channel: a sink and stream of messages on the
+/// reliable ordered stream `StreamId::CONTROL`.
+///
+/// Closing the channel only flushes it. The connection closes with
+/// `Channels`.
+pub struct Control<C> {
+    state: Rc<RefCell<ControlState<C>>>,
+}
+
+impl<C> Sink<Vec<u8>> for Control<C>
+where
+    C: Connection + Unpin,
+{
+    type Error = Box<dyn std::error::Error>;
+    fn poll_ready(self: Pin<&mut Self>, ctx: &mut Context) -> Poll<Result<()>> {
+        Pin::new(&mut self.state.borrow_mut().inner).poll_ready(ctx)
+    }
+    fn start_send(self: Pin<&mut Self>, item: Vec<u8>) -> Result<()> {
+        Pin::new(&mut self.state.borrow_mut().inner).start_send(SendCmd {
+            data: item,
+            delivery_mode: DeliveryMode::ReliableOrdered(StreamId::CONTROL),
+            ..SendCmd::default()
+        })
+    }
+    fn poll_flush(self: Pin<&mut Self>, ctx: &mut Context) -> Poll<Result<()>> {
+        Pin::new(&mut self.state.borrow_mut().inner).poll_flush(ctx)
+    }
+    fn poll_close(self: Pin<&mut Self>, ctx: &mut Context) -> Poll<Result<()>> {
+        self.poll_flush(ctx)
+    }
+}
+
+impl<C> Stream for Control<C>
+where
+    C: Connection + Unpin,
+{
+    type Item = Result<Vec<u8>>;
+    fn poll_next(
+        self: Pin<&mut Self>,
+        ctx: &mut Context,
+    ) -> Poll<Option<Self::Item>> {
+        self.state
+            .borrow_mut()
+            .poll_half(Some(StreamId::CONTROL), ctx)
+            .map(|datagram| datagram.map(|datagram| datagram.map(|d| d.data)))
+    }
+}
+
+/// A connection's streams other than its control channel. Sends on the
+/// control stream are refused.
+pub struct Channels<C> {
+    state: Rc<RefCell<ControlState<C>>>,
+}
+
+impl<C> Connection for Channels<C>
+where
+    C: Connection + Unpin,
+{
+    /// Borrows the connection only while polling its `ready`, so the
+    /// `Control` half stays usable while this waits.
+    fn ready(&mut self) -> LocalBoxFuture<'_, Result<()>> {
+        let state = self.state.clone();
+        future::poll_fn(move |ctx| {
+            state.borrow_mut().inner.ready().poll_unpin(ctx)
+        })
+        .boxed_local()
+    }
+
+    fn wire_bytes_sent(&self) -> Option<Arc<AtomicU64>> {
+        self.state.borrow().inner.wire_bytes_sent()
+    }
+
+    fn peer_addr(&self) -> Option<SocketAddr> {
+        self.state.borrow().inner.peer_addr()
+    }
+
+    fn peer_finished(&self) -> bool {
+        self.state.borrow().inner.peer_finished()
+    }
+
+    fn transport_state(&self) -> TransportState {
+        self.state.borrow().inner.transport_state()
+    }
+
+    fn high_water_marks(&self) -> HighWaterMarks {
+        self.state.borrow().inner.high_water_marks()
+    }
+
+    fn set_max_pacing_rate(&self, bytes_per_second: u64) -> bool {
+        self.state
+            .borrow()
+            .inner
+            .set_max_pacing_rate(bytes_per_second)
+    }
+
+    fn pause_stream(&mut self, stream_id: StreamId) -> bool {
+        self.state.borrow_mut().inner.pause_stream(stream_id)
+    }
+
+    fn resume_stream(&mut self, stream_id: StreamId) {
+        self.state.borrow_mut().inner.resume_stream(stream_id)
+    }
+
+    fn is_paused(&self, stream_id: StreamId) -> bool {
+        self.state.borrow().inner.is_paused(stream_id)
+    }
+}
+
+impl<C> Sink<SendCmd> for Channels<C>
+where
+    C: Connection + Unpin,
+{
+    type Error = Box<dyn std::error::Error>;
+    fn poll_ready(self: Pin<&mut Self>, ctx: &mut Context) -> Poll<Result<()>> {
+        Pin::new(&mut self.state.borrow_mut().inner).poll_ready(ctx)
+    }
+    fn start_send(self: Pin<&mut Self>, item: SendCmd) -> Result<()> {
+        if item.delivery_mode
+            == DeliveryMode::ReliableOrdered(StreamId::CONTROL)
+        {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "the control stream is reserved for the control channel",
+            )
+            .into());
+        }
+        Pin::new(&mut self.state.borrow_mut().inner).start_send(item)
+    }
+    fn poll_flush(self: Pin<&mut Self>, ctx: &mut Context) -> Poll<Result<()>> {
+        Pin::new(&mut self.state.borrow_mut().inner).poll_flush(ctx)
+    }
+    fn poll_close(self: Pin<&mut Self>, ctx: &mut Context) -> Poll<Result<()>> {
+        Pin::new(&mut self.state.borrow_mut().inner).poll_close(ctx)
+    }
+}
+
+impl<C> Stream for Channels<C>
+where
+    C: Connection + Unpin,
+{
+    type Item = Result<Datagram>;
+    fn poll_next(
+        self: Pin<&mut Self>,
+        ctx: &mut Context,
+    ) -> Poll<Option<Self::Item>> {
+        self.state.borrow_mut().poll_half(None, ctx)
+    }
+}
+
+impl<C> FusedStream for Channels<C>
+where
+    C: Connection + Unpin,
+{
+    fn is_terminated(&self) -> bool {
+        let state = self.state.borrow();
+        state.done && state.channels.is_empty()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::*;
+
+    use futures::{executor::block_on, FutureExt, SinkExt, StreamExt};
+
+    #[test]
+    fn control_messages_travel_on_the_control_stream() {
+        block_on(async {
+            let (a, b) = pair();
+            let (mut control, channels) = a.control();
+            control.send(b"hello".to_vec()).await.unwrap();
+            control.send(b"again".to_vec()).await.unwrap();
+            drop(control);
+            drop(channels);
+
+            assert_eq!(
+                drain(b).await,
+                vec![
+                    (position(u8::MAX, 1), b"hello".to_vec()),
+                    (position(u8::MAX, 2), b"again".to_vec()),
+                ]
+            );
+        });
+    }
+
+    #[test]
+    fn halves_buffer_each_others_datagrams() {
+        block_on(async {
+            let (mut a, b) = pair();
+            let (control, channels) = b.control();
+            for cmd in [
+                ordered(1, b"a"),
+                ordered(u8::MAX, b"hello"),
+                ordered(1, b"b"),
+                ordered(u8::MAX, b"again"),
+            ] {
+                a.send(cmd).await.unwrap();
+            }
+            a.close().await.unwrap();
+
+            // Draining the control channel buffers the channels' datagrams,
+            // and both halves end with the connection.
+            let messages: Vec<Vec<u8>> =
+                control.map(|message| message.unwrap()).collect().await;
+            assert_eq!(messages, vec![b"hello".to_vec(), b"again".to_vec()]);
+            assert_eq!(
+                drain(channels).await,
+                vec![
+                    (position(1, 1), b"a".to_vec()),
+                    (position(1, 2), b"b".to_vec())
+                ]
+            );
+        });
+    }
+
+    #[test]
+    fn control_and_channels_reach_a_split_peer() {
+        block_on(async {
+            let (a, b) = pair();
+            let (mut a_control, mut a_channels) = a.control();
+            let (b_control, mut b_channels) = b.control();
+            a_channels.send(ordered(1, b"a")).await.unwrap();
+            a_control.send(b"hello".to_vec()).await.unwrap();
+            a_channels.close().await.unwrap();
+
+            let datagram = b_channels.next().await.unwrap().unwrap();
+            assert_eq!(datagram.stream_position, position(1, 1));
+            assert_eq!(datagram.data, b"a");
+            let messages: Vec<Vec<u8>> =
+                b_control.map(|message| message.unwrap()).collect().await;
+            assert_eq!(messages, vec![b"hello".to_vec()]);
+            assert!(b_channels.next().await.is_none());
+            assert!(b_channels.is_terminated());
+        });
+    }
+
+    #[test]
+    fn channels_wait_for_the_connection_to_be_ready() {
+        let (mut a, _b) = pair();
+        let handshaken = a.handshake();
+        let (_control, mut channels) = a.control();
+        assert!(channels.ready().now_or_never().is_none());
+
+        handshaken.set(true);
+        assert!(matches!(channels.ready().now_or_never(), Some(Ok(()))));
+    }
+
+    #[test]
+    fn channels_refuse_the_control_stream() {
+        block_on(async {
+            let (a, _b) = pair();
+            let (_control, mut channels) = a.control();
+            assert!(channels.send(ordered(u8::MAX, b"x")).await.is_err());
+        });
+    }
+}
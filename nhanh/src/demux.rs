@@ -0,0 +1,296 @@
+//! Adapters which route datagrams by the stream they surfaced on.
+
+use crate::*;
+
+use futures::task::{waker_ref, ArcWake, Context, Poll, Waker};
+use std::{
+    cell::RefCell,
+    collections::{HashMap, VecDeque},
+    pin::Pin,
+    rc::Rc,
+    sync::Mutex,
+};
+
+/// Returns the stream `datagram` surfaced on, or `None` if it surfaced on the
+/// unordered stream.
+fn stream_of(datagram: &Datagram) -> Option<StreamId> {
+    datagram.stream_position.map(|position| position.stream_id)
+}
+
+/// Adapters for a stream of datagrams, such as a `Connection` or its read
+/// half.
+///
+/// Datagrams from the unordered stream carry no `StreamPosition`. They belong
+/// to the `None` stream. Ordered and sequenced streams which share a
+/// `StreamId` are routed together.
+pub trait DatagramStreamExt: Stream<Item = Result<Datagram>> + Sized {
+    /// Keeps only the datagrams which surfaced on `stream_id`. Errors pass
+    /// through.
+    fn by_stream(self, stream_id: StreamId) -> ByStream<Self> {
+        ByStream {
+            inner: self,
+            stream_id,
+        }
+    }
+
+    /// Splits the datagrams into one stream for each of `stream_ids`.
+    ///
+    /// Polling any of the streams pulls from the source, buffering datagrams
+    /// meant for the others until they are polled. Datagrams on streams not in
+    /// `stream_ids` are dropped, and an error surfaces on whichever stream
+    /// pulled it.
+    fn demux(
+        self,
+        stream_ids: impl IntoIterator<Item = Option<StreamId>>,
+    ) -> HashMap<Option<StreamId>, Demuxed<Self>> {
+        let buffers: HashMap<Option<StreamId>, VecDeque<Datagram>> = stream_ids
+            .into_iter()
+            .map(|stream_id| (stream_id, VecDeque::new()))
+            .collect();
+        let stream_ids: Vec<Option<StreamId>> =
+            buffers.keys().copied().collect();
+        let state = Rc::new(RefCell::new(DemuxState {
+            inner: self,
+            done: false,
+            buffers,
+            wakers: Arc::new(Wakers::default()),
+        }));
+
+        stream_ids
+            .into_iter()
+            .map(|stream_id| {
+                let demuxed = Demuxed {
+                    state: state.clone(),
+                    stream_id,
+                };
+                (stream_id, demuxed)
+            })
+            .collect()
+    }
+}
+
+impl<S> DatagramStreamExt for S where S: Stream<Item = Result<Datagram>> {}
+
+/// The datagrams of one stream. See `DatagramStreamExt::by_stream`.
+pub struct ByStream<S> {
+    inner: S,
+    stream_id: StreamId,
+}
+
+impl<S> Stream for ByStream<S>
+where
+    S: Stream<Item = Result<Datagram>> + Unpin,
+{
+    type Item = Result<Datagram>;
+    fn poll_next(
+        mut self: Pin<&mut Self>,
+        ctx: &mut Context,
+    ) -> Poll<Option<Self::Item>> {
+        loop {
+            match Pin::new(&mut self.inner).poll_next(ctx) {
+                Poll::Ready(Some(Ok(datagram)))
+                    if stream_of(&datagram) != Some(self.stream_id) =>
+                {
+                    continue
+                }
+                other => return other,
+            }
+        }
+    }
+}
+
+/// Wakes every demuxed stream waiting on the shared source, since the source
+/// only remembers one waker.
+#[derive(Default)]
+pub(crate) struct Wakers {
+    pub(crate) waiting: Mutex<HashMap<Option<StreamId>, Waker>>,
+}
+
+impl ArcWake for Wakers {
+    fn wake_by_ref(arc_self: &Arc<Self>) {
+        let waiting = std::mem::take(
+            &mut *arc_self.waiting.lock().expect("demux wakers lock"),
+        );
+        waiting.into_iter().for_each(|(_, waker)| waker.wake());
+    }
+}
+
+struct DemuxState<S> {
+    inner: S,
+    done: bool,
+    buffers: HashMap<Option<StreamId>, VecDeque<Datagram>>,
+    wakers: Arc<Wakers>,
+}
+
+/// The datagrams of one stream. See `DatagramStreamExt::demux`.
+pub struct Demuxed<S> {
+    state: Rc<RefCell<DemuxState<S>>>,
+    stream_id: Option<StreamId>,
+}
+
+impl<S> Stream for Demuxed<S>
+where
+    S: Stream<Item = Result<Datagram>> + Unpin,
+{
+    type Item = Result<Datagram>;
+    fn poll_next(
+        self: Pin<&mut Self>,
+        ctx: &mut Context,
+    ) -> Poll<Option<Self::Item>> {
+        let mut state = self.state.borrow_mut();
+        let state = &mut *state;
+        loop {
+            let buffer = state.buffers.get_mut(&self.stream_id).unwrap();
+            if let Some(datagram) = buffer.pop_front() {
+                return Poll::Ready(Some(Ok(datagram)));
+            }
+            if state.done {
+                return Poll::Ready(None);
+            }
+
+            state
+                .wakers
+                .waiting
+                .lock()
+                .expect("demux wakers lock")
+                .insert(self.stream_id, ctx.waker().clone());
+            let waker = waker_ref(&state.wakers);
+            let mut inner_ctx = Context::from_waker(&waker);
+            let datagram = match Pin::new(&mut state.inner)
+                .poll_next(&mut inner_ctx)
+            {
+                Poll::Pending => return Poll::Pending,
+                Poll::Ready(None) => {
+                    state.done = true;
+                    Wakers::wake_by_ref(&state.wakers);
+                    return Poll::Ready(None);
+                }
+                Poll::Ready(Some(Err(e))) => return Poll::Ready(Some(Err(e))),
+                Poll::Ready(Some(Ok(datagram))) => datagram,
+            };
+
+            let stream_id = stream_of(&datagram);
+            if stream_id == self.stream_id {
+                return Poll::Ready(Some(Ok(datagram)));
+            }
+            if let Some(buffer) = state.buffers.get_mut(&stream_id) {
+                buffer.push_back(datagram);
+                let waker = state
+                    .wakers
+                    .waiting
+                    .lock()
+                    .expect("demux wakers lock")
+                    .remove(&stream_id);
+                if let Some(waker) = waker {
+                    waker.wake();
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::*;
+
+    use futures::{executor::block_on, future, stream, SinkExt, StreamExt};
+    use std::io;
+
+    fn unordered(data: &[u8]) -> SendCmd {
+        SendCmd {
+            data: data.to_vec(),
+            delivery_mode: DeliveryMode::UnreliableUnordered,
+            ..SendCmd::default()
+        }
+    }
+
+    #[test]
+    fn by_stream_keeps_one_stream() {
+        block_on(async {
+            let (mut a, b) = pair();
+            for cmd in [
+                ordered(1, b"a"),
+                ordered(2, b"b"),
+                unordered(b"c"),
+                ordered(1, b"d"),
+            ] {
+                a.send(cmd).await.unwrap();
+            }
+            a.close().await.unwrap();
+
+            assert_eq!(
+                drain(b.by_stream(StreamId(1))).await,
+                vec![
+                    (position(1, 1), b"a".to_vec()),
+                    (position(1, 2), b"d".to_vec())
+                ]
+            );
+        });
+    }
+
+    #[test]
+    fn by_stream_passes_errors_through() {
+        let failed: Result<Datagram> = Err(io::Error::other("failed").into());
+        let mut filtered = stream::iter(vec![failed]).by_stream(StreamId(1));
+        assert!(block_on(filtered.next()).unwrap().is_err());
+    }
+
+    #[test]
+    fn demux_routes_each_stream() {
+        block_on(async {
+            let (mut a, b) = pair();
+            let mut demuxed = b.demux(vec![Some(StreamId(1)), None]);
+            let ones = demuxed.remove(&Some(StreamId(1))).unwrap();
+            let unordered_stream = demuxed.remove(&None).unwrap();
+
+            // Stream 3 is not demuxed, so it is dropped.
+            for cmd in [
+                ordered(3, b"x"),
+                unordered(b"u1"),
+                ordered(1, b"a"),
+                unordered(b"u2"),
+                ordered(1, b"b"),
+            ] {
+                a.send(cmd).await.unwrap();
+            }
+            a.close().await.unwrap();
+
+            // Draining one buffers the other's datagrams until it is polled.
+            assert_eq!(
+                drain(ones).await,
+                vec![
+                    (position(1, 1), b"a".to_vec()),
+                    (position(1, 2), b"b".to_vec())
+                ]
+            );
+            assert_eq!(
+                drain(unordered_stream).await,
+                vec![(None, b"u1".to_vec()), (None, b"u2".to_vec())]
+            );
+        });
+    }
+
+    #[test]
+    fn demux_wakes_streams_waiting_on_the_source() {
+        block_on(async {
+            let (mut a, b) = pair();
+            let mut demuxed = b.demux((1..=2).map(|id| Some(StreamId(id))));
+            let ones = demuxed.remove(&Some(StreamId(1))).unwrap();
+            let twos = demuxed.remove(&Some(StreamId(2))).unwrap();
+
+            // Both readers wait before anything is sent, so whichever pulls
+            // the other's datagram must wake it.
+            let send = async move {
+                for cmd in [ordered(2, b"b"), ordered(1, b"a")] {
+                    a.send(cmd).await.unwrap();
+                }
+                a.close().await.unwrap();
+            };
+            let (ones, twos, ()) =
+                future::join3(drain(ones), drain(twos), send).await;
+            assert_eq!(ones, vec![(position(1, 1), b"a".to_vec())]);
+            assert_eq!(twos, vec![(position(2, 1), b"b".to_vec())]);
+        });
+    }
+}
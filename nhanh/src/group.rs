@@ -0,0 +1,360 @@
+//! Ordered streams which surface in one order across all of them.
+//!
+//! Ordered streams buffer independently, so a datagram on one may surface
+//! before an older datagram on another. A `StreamGroup` gives up that
+//! independence for some streams, such as commands and their acks, so that
+//! their datagrams surface in the order they were sent across the whole group.
+//!
+//! `Grouped` does this over any connection by sending every ordered datagram
+//! for the group on one carrier stream, tagged with the stream it was sent
+//! on, and restoring that stream as it surfaces. Both endpoints must group
+//! the same streams.
+
+use crate::*;
+
+use futures::task::{Context, Poll};
+use std::{collections::HashMap, io, pin::Pin};
+
+/// Ordered streams which share one ordering. The group's datagrams travel on
+/// the ordered stream of its first member.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct StreamGroup {
+    members: Vec<StreamId>,
+}
+
+impl StreamGroup {
+    /// Groups `members`, which must not be empty.
+    pub fn new(members: impl IntoIterator<Item = StreamId>) -> Self {
+        let members: Vec<StreamId> = members.into_iter().collect();
+        assert!(!members.is_empty(), "stream groups need a member");
+        Self { members }
+    }
+
+    pub fn members(&self) -> &[StreamId] {
+        &self.members
+    }
+
+    fn carrier(&self) -> StreamId {
+        self.members[0]
+    }
+}
+
+/// A connection whose ordered streams are grouped. See the module docs.
+///
+/// Only `DeliveryMode::ReliableOrdered` sends are grouped; other delivery
+/// modes on a member's `StreamId` pass through on their own streams.
+pub struct Grouped<C> {
+    inner: C,
+    /// The carrier of the group each grouped stream belongs to.
+    carriers: HashMap<StreamId, StreamId>,
+    /// How many datagrams have surfaced on each grouped stream.
+    surfaced: HashMap<StreamId, u32>,
+}
+
+impl<C> Grouped<C>
+where
+    C: Connection + Unpin,
+{
+    /// Wraps `inner`, grouping the streams in each of `groups`. A stream may
+    /// only belong to one group.
+    pub fn new(
+        inner: C,
+        groups: impl IntoIterator<Item = StreamGroup>,
+    ) -> Self {
+        let mut carriers = HashMap::new();
+        for group in groups {
+            for member in group.members() {
+                let previous = carriers.insert(*member, group.carrier());
+                assert!(
+                    previous.is_none(),
+                    "{:?} belongs to more than one stream group",
+                    member
+                );
+            }
+        }
+
+        Self {
+            inner,
+            carriers,
+            surfaced: HashMap::new(),
+        }
+    }
+
+    pub fn into_inner(self) -> C {
+        self.inner
+    }
+
+    /// The stream `stream_id`'s ordered datagrams travel on: its group's
+    /// carrier if it is grouped, or itself.
+    fn carrier(&self, stream_id: StreamId) -> StreamId {
+        self.carriers.get(&stream_id).copied().unwrap_or(stream_id)
+    }
+
+    /// Restores the stream a datagram on a carrier was sent on, numbering it
+    /// among that stream's datagrams.
+    fn ungroup(&mut self, mut datagram: Datagram) -> Result<Datagram> {
+        let position = match datagram.stream_position {
+            Some(position)
+                if matches!(position.index, StreamIndex::Ordinal(_))
+                    && self.carriers.get(&position.stream_id)
+                        == Some(&position.stream_id) =>
+            {
+                position
+            }
+            _ => return Ok(datagram),
+        };
+
+        if datagram.data.is_empty() {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!(
+                    "datagram on the carrier of {:?}'s group is untagged",
+                    position.stream_id
+                ),
+            )
+            .into());
+        }
+        let stream_id = StreamId(datagram.data.remove(0));
+        if self.carriers.get(&stream_id) != Some(&position.stream_id) {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!(
+                    "datagram tagged {:?} is not in {:?}'s group",
+                    stream_id, position.stream_id
+                ),
+            )
+            .into());
+        }
+
+        let surfaced = self.surfaced.entry(stream_id).or_insert(0);
+        *surfaced += 1;
+        datagram.stream_position = Some(StreamPosition {
+            stream_id,
+            index: StreamIndex::Ordinal(*surfaced),
+        });
+        Ok(datagram)
+    }
+}
+
+impl<C> Connection for Grouped<C>
+where
+    C: Connection + Unpin,
+{
+    fn ready(&mut self) -> LocalBoxFuture<'_, Result<()>> {
+        self.inner.ready()
+    }
+
+    fn wire_bytes_sent(&self) -> Option<Arc<AtomicU64>> {
+        self.inner.wire_bytes_sent()
+    }
+
+    fn peer_addr(&self) -> Option<SocketAddr> {
+        self.inner.peer_addr()
+    }
+
+    fn peer_finished(&self) -> bool {
+        self.inner.peer_finished()
+    }
+
+    fn transport_state(&self) -> TransportState {
+        self.inner.transport_state()
+    }
+
+    fn high_water_marks(&self) -> HighWaterMarks {
+        self.inner.high_water_marks()
+    }
+
+    fn set_max_pacing_rate(&self, bytes_per_second: u64) -> bool {
+        self.inner.set_max_pacing_rate(bytes_per_second)
+    }
+
+    /// A grouped stream's datagrams travel on its group's carrier, and
+    /// surface in order with the rest of the group, so pausing any member
+    /// pauses the whole group.
+    fn pause_stream(&mut self, stream_id: StreamId) -> bool {
+        let carrier = self.carrier(stream_id);
+        self.inner.pause_stream(carrier)
+    }
+
+    fn resume_stream(&mut self, stream_id: StreamId) {
+        let carrier = self.carrier(stream_id);
+        self.inner.resume_stream(carrier)
+    }
+
+    fn is_paused(&self, stream_id: StreamId) -> bool {
+        self.inner.is_paused(self.carrier(stream_id))
+    }
+}
+
+impl<C> Sink<SendCmd> for Grouped<C>
+where
+    C: Connection + Unpin,
+{
+    type Error = Box<dyn std::error::Error>;
+    fn poll_ready(
+        mut self: Pin<&mut Self>,
+        ctx: &mut Context,
+    ) -> Poll<Result<()>> {
+        Pin::new(&mut self.inner).poll_ready(ctx)
+    }
+    fn start_send(mut self: Pin<&mut Self>, mut item: SendCmd) -> Result<()> {
+        if let DeliveryMode::ReliableOrdered(stream_id) = item.delivery_mode {
+            if let Some(carrier) = self.carriers.get(&stream_id) {
+                item.data.insert(0, stream_id.0);
+                item.delivery_mode = DeliveryMode::ReliableOrdered(*carrier);
+            }
+        }
+        Pin::new(&mut self.inner).start_send(item)
+    }
+    fn poll_flush(
+        mut self: Pin<&mut Self>,
+        ctx: &mut Context,
+    ) -> Poll<Result<()>> {
+        Pin::new(&mut self.inner).poll_flush(ctx)
+    }
+    fn poll_close(
+        mut self: Pin<&mut Self>,
+        ctx: &mut Context,
+    ) -> Poll<Result<()>> {
+        Pin::new(&mut self.inner).poll_close(ctx)
+    }
+}
+
+impl<C> Stream for Grouped<C>
+where
+    C: Connection + Unpin,
+{
+    type Item = Result<Datagram>;
+    fn poll_next(
+        mut self: Pin<&mut Self>,
+        ctx: &mut Context,
+    ) -> Poll<Option<Self::Item>> {
+        match Pin::new(&mut self.inner).poll_next(ctx) {
+            Poll::Ready(Some(Ok(datagram))) => {
+                Poll::Ready(Some(self.ungroup(datagram)))
+            }
+            other => other,
+        }
+    }
+}
+
+impl<C> FusedStream for Grouped<C>
+where
+    C: Connection + Unpin,
+{
+    fn is_terminated(&self) -> bool {
+        self.inner.is_terminated()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::*;
+
+    use futures::{executor::block_on, SinkExt, StreamExt};
+
+    fn grouped(connection: Loopback) -> Grouped<Loopback> {
+        Grouped::new(
+            connection,
+            vec![StreamGroup::new(vec![StreamId(1), StreamId(2)])],
+        )
+    }
+
+    #[test]
+    fn grouped_streams_keep_their_ids_and_ordinals() {
+        block_on(async {
+            let (a, b) = pair();
+            let (mut a, b) = (grouped(a), grouped(b));
+            for cmd in [
+                ordered(2, b"a"),
+                ordered(1, b"b"),
+                ordered(3, b"c"),
+                ordered(2, b"d"),
+            ] {
+                a.send(cmd).await.unwrap();
+            }
+            a.close().await.unwrap();
+
+            assert_eq!(
+                drain(b).await,
+                vec![
+                    (position(2, 1), b"a".to_vec()),
+                    (position(1, 1), b"b".to_vec()),
+                    (position(3, 1), b"c".to_vec()),
+                    (position(2, 2), b"d".to_vec()),
+                ]
+            );
+        });
+    }
+
+    #[test]
+    fn grouped_datagrams_travel_tagged_on_the_carrier() {
+        block_on(async {
+            let (a, b) = pair();
+            let mut a = grouped(a);
+            for cmd in [ordered(2, b"a"), ordered(1, b"b"), ordered(3, b"c")] {
+                a.send(cmd).await.unwrap();
+            }
+            a.close().await.unwrap();
+
+            assert_eq!(
+                drain(b).await,
+                vec![
+                    (position(1, 1), b"\x02a".to_vec()),
+                    (position(1, 2), b"\x01b".to_vec()),
+                    (position(3, 1), b"c".to_vec()),
+                ]
+            );
+        });
+    }
+
+    #[test]
+    fn ungrouping_rejects_bad_tags() {
+        block_on(async {
+            let (mut a, b) = pair();
+            let mut b = grouped(b);
+            for cmd in [ordered(1, b""), ordered(1, b"\x03c")] {
+                a.send(cmd).await.unwrap();
+            }
+
+            assert!(b.next().await.unwrap().is_err());
+            assert!(b.next().await.unwrap().is_err());
+        });
+    }
+
+    #[test]
+    fn pausing_a_member_pauses_its_group() {
+        let (a, _b) = pair();
+        let mut a = grouped(a);
+        assert!(a.pause_stream(StreamId(2)));
+        assert!(a.is_paused(StreamId(1)) && a.is_paused(StreamId(2)));
+        assert!(!a.is_paused(StreamId(3)));
+
+        a.resume_stream(StreamId(1));
+        assert!(!a.is_paused(StreamId(2)));
+
+        // Streams outside every group pause alone.
+        assert!(a.pause_stream(StreamId(3)));
+        assert!(a.into_inner().is_paused(StreamId(3)));
+    }
+
+    #[test]
+    #[should_panic(expected = "stream groups need a member")]
+    fn groups_need_a_member() {
+        StreamGroup::new(vec![]);
+    }
+
+    #[test]
+    #[should_panic(expected = "belongs to more than one stream group")]
+    fn streams_belong_to_one_group() {
+        let (a, _b) = pair();
+        Grouped::new(
+            a,
+            vec![
+                StreamGroup::new(vec![StreamId(1), StreamId(2)]),
+                StreamGroup::new(vec![StreamId(2), StreamId(3)]),
+            ],
+        );
+    }
+}
@@ -51,14 +51,33 @@
 //!
 //! Streams buffer independently. For example, one ordered stream waiting on an
 //! older datagram before surfacing new ones should have no effect on other
-//! ordered streams.
+//! ordered streams. Ordered streams which must surface in one order across
+//! all of them can be joined in a `StreamGroup`.
 
 use futures::{
-    sink::Sink,
+    channel::mpsc,
+    future::{self, FutureExt, LocalBoxFuture},
+    sink::{Sink, SinkExt},
     stream::{FusedStream, Stream},
 };
 use serde::{Deserialize, Serialize};
-use std::marker::PhantomData;
+use std::{
+    hash::{Hash, Hasher},
+    marker::PhantomData,
+    net::SocketAddr,
+    sync::{atomic::AtomicU64, Arc},
+    time::Duration,
+};
+
+mod control;
+mod demux;
+mod group;
+#[cfg(test)]
+mod testing;
+
+pub use control::{Channels, Control};
+pub use demux::*;
+pub use group::*;
 
 pub type Result<T> = std::result::Result<T, Box<dyn std::error::Error>>;
 
@@ -84,6 +103,13 @@ pub type Result<T> = std::result::Result<T, Box<dyn std::error::Error>>;
 )]
 pub struct StreamId(pub u8);
 
+impl StreamId {
+    /// The ordered stream reserved for a connection's control channel. See
+    /// `Connection::control`. Applications may still use this id for
+    /// sequenced streams, which have their own id space.
+    pub const CONTROL: StreamId = StreamId(u8::MAX);
+}
+
 /// A position of a datagram in a stream.
 #[derive(Copy, Clone, Debug, Serialize, Deserialize, Eq, PartialEq, Hash)]
 pub struct StreamPosition {
@@ -137,6 +163,18 @@ pub enum DeliveryMode {
     UnreliableUnordered,
 }
 
+impl DeliveryMode {
+    /// Whether datagrams sent in this mode are guaranteed to arrive.
+    pub fn is_reliable(&self) -> bool {
+        matches!(
+            self,
+            DeliveryMode::ReliableOrdered(_)
+                | DeliveryMode::ReliableSequenced(_)
+                | DeliveryMode::ReliableUnordered
+        )
+    }
+}
+
 /// A block of bytes received from the connected endpoint.
 #[derive(Clone, Debug, Deserialize, Serialize, PartialEq, Eq, Hash)]
 pub struct Datagram {
@@ -144,14 +182,23 @@ pub struct Datagram {
     /// is present if the datagram was sent in a sequenced or ordered stream. If
     /// the datagram was sent on the unordered stream, this will be `None`.
     pub stream_position: Option<StreamPosition>,
-    /// The bytes the other endpoint sent.
+    /// The bytes the other endpoint sent. These may be empty: an empty
+    /// datagram surfaces like any other, for use as a marker or keepalive.
     pub data: Vec<u8>,
+    /// Metadata the other endpoint sent alongside the data, such as a message
+    /// type to branch on without parsing the data.
+    pub header: Option<Vec<u8>>,
 }
 
 #[derive(Debug, Clone, PartialEq, Eq, Hash)]
 pub struct SendCmd {
     pub data: Vec<u8>,
     pub delivery_mode: DeliveryMode,
+    /// Metadata to deliver alongside the data. It travels in the same
+    /// datagram, so it counts against the same size limits.
+    pub header: Option<Vec<u8>>,
+    /// Where to report what becomes of the datagram. See `SendNotifier`.
+    pub notify: Option<SendNotifier>,
     #[doc(hidden)]
     pub ___non_exhaustive: PhantomData<()>,
 }
@@ -161,7 +208,118 @@ impl Default for SendCmd {
         Self {
             data: vec![],
             delivery_mode: DeliveryMode::UnreliableUnordered,
-            ___non_exhaustive: PhantomData::default(),
+            header: None,
+            notify: None,
+            ___non_exhaustive: PhantomData,
+        }
+    }
+}
+
+/// What has become of a sent datagram.
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Hash)]
+pub enum SendEvent {
+    /// The transport handed the datagram to its socket.
+    Sent,
+    /// The peer's transport acknowledged the datagram. Only reliable sends
+    /// are acknowledged, and never before they are sent.
+    Acked,
+}
+
+/// Reports the `SendEvent`s of one datagram, as its transport sees them.
+///
+/// Each event is reported at most once. Transports which cannot see an event
+/// never report it, and a datagram dropped before it is sent reports
+/// nothing; either way the channel closes once the transport is done with
+/// the datagram.
+#[derive(Clone, Debug)]
+pub struct SendNotifier {
+    sender: mpsc::UnboundedSender<SendEvent>,
+}
+
+impl SendNotifier {
+    /// Returns a notifier and the channel its events arrive on.
+    pub fn new() -> (Self, mpsc::UnboundedReceiver<SendEvent>) {
+        let (sender, receiver) = mpsc::unbounded();
+        (Self { sender }, receiver)
+    }
+
+    /// Reports `event`. Events nobody is listening for are discarded.
+    pub fn notify(&self, event: SendEvent) {
+        let _ = self.sender.unbounded_send(event);
+    }
+}
+
+/// Notifiers are equal if they report to the same channel.
+impl PartialEq for SendNotifier {
+    fn eq(&self, other: &Self) -> bool {
+        self.sender.same_receiver(&other.sender)
+    }
+}
+
+impl Eq for SendNotifier {}
+
+impl Hash for SendNotifier {
+    fn hash<H: Hasher>(&self, _: &mut H) {}
+}
+
+/// A snapshot of a connection's congestion and flow control, for telemetry.
+///
+/// Each field is `None` if the implementer does not track or expose it.
+#[derive(Copy, Clone, Debug, Default, PartialEq)]
+pub struct TransportState {
+    /// The congestion window, in bytes.
+    pub cwnd: Option<u64>,
+    /// The slow start threshold, in bytes.
+    pub ssthresh: Option<u64>,
+    /// The receive window the remote endpoint last advertised, in bytes.
+    pub rwnd: Option<u64>,
+    /// Bytes sent but not yet acknowledged.
+    pub bytes_in_flight: Option<u64>,
+    /// Datagrams queued to send, waiting for room in the congestion or send
+    /// window.
+    pub send_queue_depth: Option<u64>,
+    /// The smoothed round trip time.
+    pub srtt: Option<Duration>,
+    /// The retransmission timeout.
+    pub rto: Option<Duration>,
+    /// Datagrams dropped rather than sent, as by a full send buffer, over
+    /// the connection's lifetime.
+    pub dropped_sends: Option<u64>,
+}
+
+/// The peaks a connection's buffers reached over its lifetime, to show
+/// whether their limits were approached.
+///
+/// Each field is `None` if the implementer does not track it.
+#[derive(Copy, Clone, Debug, Default, PartialEq)]
+pub struct HighWaterMarks {
+    /// The most bytes sent but not yet acknowledged at once.
+    pub bytes_in_flight: Option<u64>,
+    /// The most datagrams queued to send at once, waiting for room in the
+    /// congestion or send window.
+    pub send_queue_depth: Option<u64>,
+    /// The most bytes received out of order and held for reassembly at once.
+    pub reassembly_bytes: Option<u64>,
+}
+
+impl HighWaterMarks {
+    /// The higher of each mark in `self` and `other`, such as the marks of
+    /// several connections.
+    pub fn max(self, other: Self) -> Self {
+        let max = |a: Option<u64>, b: Option<u64>| match (a, b) {
+            (Some(a), Some(b)) => Some(a.max(b)),
+            (a, b) => a.or(b),
+        };
+        Self {
+            bytes_in_flight: max(self.bytes_in_flight, other.bytes_in_flight),
+            send_queue_depth: max(
+                self.send_queue_depth,
+                other.send_queue_depth,
+            ),
+            reassembly_bytes: max(
+                self.reassembly_bytes,
+                other.reassembly_bytes,
+            ),
         }
     }
 }
@@ -185,10 +343,185 @@ pub trait Server<Connection: crate::Connection>:
 ///
 /// If the connection closes, the stream of datagrams will end. An error will
 /// be emitted from the stream before close if the disconnection was not
-/// correct according to the implementer's protocol.
+/// correct according to the implementer's protocol. Either way, datagrams
+/// which arrived before the connection closed surface before the stream ends.
 pub trait Connection:
     Stream<Item = Result<Datagram>>
     + FusedStream
     + Sink<SendCmd, Error = Box<dyn std::error::Error>>
 {
+    /// Resolves once the connection is fully established, so that sends are
+    /// transmitted rather than queued behind the handshake. Resolves with an
+    /// error if the handshake fails.
+    ///
+    /// Implementers which finish their handshake before handing out a
+    /// connection can rely on the default, which resolves immediately.
+    fn ready(&mut self) -> LocalBoxFuture<'_, Result<()>> {
+        future::ready(Ok(())).boxed_local()
+    }
+
+    /// Sends every command in `cmds`, in order, as one batch.
+    ///
+    /// The default queues each command on the sink and flushes once at the
+    /// end, so implementers which buffer writes can coalesce the batch.
+    /// Implementers may override it where the wire allows a cheaper batch.
+    fn send_all_batch(
+        &mut self,
+        cmds: Vec<SendCmd>,
+    ) -> LocalBoxFuture<'_, Result<()>>
+    where
+        Self: Unpin,
+    {
+        async move {
+            for cmd in cmds {
+                self.feed(cmd).await?;
+            }
+            self.flush().await
+        }
+        .boxed_local()
+    }
+
+    /// Splits the connection into its control channel, for messages such as
+    /// chat or commands on the reserved ordered stream `StreamId::CONTROL`,
+    /// and `Channels`, a connection for every other stream.
+    ///
+    /// `Channels` forwards `ready`, so the connection may be split before its
+    /// handshake completes.
+    fn control(self) -> (Control<Self>, Channels<Self>)
+    where
+        Self: Unpin + Sized,
+    {
+        control::split(self)
+    }
+
+    /// A running count of the bytes this connection has put on the wire,
+    /// headers and retransmissions included.
+    ///
+    /// The default returns `None`, for transports which do not expose it.
+    fn wire_bytes_sent(&self) -> Option<Arc<AtomicU64>> {
+        None
+    }
+
+    /// The address of the remote endpoint.
+    ///
+    /// The default returns `None`, for transports without one.
+    fn peer_addr(&self) -> Option<SocketAddr> {
+        None
+    }
+
+    /// Whether the remote endpoint has finished sending, so that the stream
+    /// of datagrams has ended. The remote endpoint finishes sending by closing
+    /// its sink.
+    ///
+    /// The default returns `false`, for transports which cannot tell.
+    fn peer_finished(&self) -> bool {
+        false
+    }
+
+    /// The connection's current congestion and flow control state.
+    ///
+    /// The default returns a state with every field `None`, for transports
+    /// which do not expose it.
+    fn transport_state(&self) -> TransportState {
+        TransportState::default()
+    }
+
+    /// The peaks the connection's buffers have reached so far.
+    ///
+    /// The default returns marks with every field `None`, for transports
+    /// which do not track them.
+    fn high_water_marks(&self) -> HighWaterMarks {
+        HighWaterMarks::default()
+    }
+
+    /// Asks the kernel to pace the connection's egress at no more than
+    /// `bytes_per_second`, returning whether it will.
+    ///
+    /// The default returns false, for transports whose sends the kernel
+    /// cannot pace.
+    fn set_max_pacing_rate(&self, bytes_per_second: u64) -> bool {
+        let _ = bytes_per_second;
+        false
+    }
+
+    /// Stops draining the datagrams sent on `stream_id` to the wire, holding
+    /// them until the stream is resumed while the connection's other streams
+    /// carry on, and returns whether it will. A paused stream keeps its
+    /// state, so it picks up where it left off.
+    ///
+    /// The default returns false, for transports which hand sends straight
+    /// to the wire and have nowhere to hold them.
+    fn pause_stream(&mut self, stream_id: StreamId) -> bool {
+        let _ = stream_id;
+        false
+    }
+
+    /// Lets the datagrams held on `stream_id` drain, oldest first.
+    ///
+    /// The default does nothing, as the default cannot pause.
+    fn resume_stream(&mut self, stream_id: StreamId) {
+        let _ = stream_id;
+    }
+
+    /// Whether `stream_id` is paused.
+    ///
+    /// The default returns false, as the default cannot pause.
+    fn is_paused(&self, stream_id: StreamId) -> bool {
+        let _ = stream_id;
+        false
+    }
+}
+
+/// Boxed connections are connections, so that different implementers can be
+/// used interchangeably as `Box<dyn Connection + Unpin>`.
+impl<C> Connection for Box<C>
+where
+    C: Connection + Unpin + ?Sized,
+{
+    fn ready(&mut self) -> LocalBoxFuture<'_, Result<()>> {
+        (**self).ready()
+    }
+
+    fn send_all_batch(
+        &mut self,
+        cmds: Vec<SendCmd>,
+    ) -> LocalBoxFuture<'_, Result<()>> {
+        (**self).send_all_batch(cmds)
+    }
+
+    fn wire_bytes_sent(&self) -> Option<Arc<AtomicU64>> {
+        (**self).wire_bytes_sent()
+    }
+
+    fn peer_addr(&self) -> Option<SocketAddr> {
+        (**self).peer_addr()
+    }
+
+    fn peer_finished(&self) -> bool {
+        (**self).peer_finished()
+    }
+
+    fn transport_state(&self) -> TransportState {
+        (**self).transport_state()
+    }
+
+    fn high_water_marks(&self) -> HighWaterMarks {
+        (**self).high_water_marks()
+    }
+
+    fn set_max_pacing_rate(&self, bytes_per_second: u64) -> bool {
+        (**self).set_max_pacing_rate(bytes_per_second)
+    }
+
+    fn pause_stream(&mut self, stream_id: StreamId) -> bool {
+        (**self).pause_stream(stream_id)
+    }
+
+    fn resume_stream(&mut self, stream_id: StreamId) {
+        (**self).resume_stream(stream_id)
+    }
+
+    fn is_paused(&self, stream_id: StreamId) -> bool {
+        (**self).is_paused(stream_id)
+    }
 }
@@ -0,0 +1,169 @@
+//! An in-process connection pair for testing adapters.
+
+use crate::*;
+
+use futures::{
+    stream::StreamExt,
+    task::{Context, Poll},
+};
+use std::{
+    cell::Cell,
+    collections::{HashMap, HashSet},
+    pin::Pin,
+    rc::Rc,
+};
+
+/// One end of a `pair`. Every datagram arrives, in order, and is numbered on
+/// its stream as a transport numbers it.
+pub(crate) struct Loopback {
+    sender: mpsc::UnboundedSender<Datagram>,
+    receiver: mpsc::UnboundedReceiver<Datagram>,
+    sent: HashMap<DeliveryMode, u32>,
+    handshaken: Rc<Cell<bool>>,
+    /// The streams paused, which are only recorded; their datagrams still
+    /// go straight through.
+    paused: HashSet<StreamId>,
+}
+
+/// Returns two connected ends.
+pub(crate) fn pair() -> (Loopback, Loopback) {
+    let (a_sender, b_receiver) = mpsc::unbounded();
+    let (b_sender, a_receiver) = mpsc::unbounded();
+    let end = |sender, receiver| Loopback {
+        sender,
+        receiver,
+        sent: HashMap::new(),
+        handshaken: Rc::new(Cell::new(true)),
+        paused: HashSet::new(),
+    };
+    (end(a_sender, a_receiver), end(b_sender, b_receiver))
+}
+
+/// A command sending `data` on the ordered stream `stream_id`.
+pub(crate) fn ordered(stream_id: u8, data: &[u8]) -> SendCmd {
+    SendCmd {
+        data: data.to_vec(),
+        delivery_mode: DeliveryMode::ReliableOrdered(StreamId(stream_id)),
+        ..SendCmd::default()
+    }
+}
+
+/// Reads every datagram until the stream ends, as the stream and data of
+/// each.
+pub(crate) async fn drain<S>(
+    stream: S,
+) -> Vec<(Option<StreamPosition>, Vec<u8>)>
+where
+    S: Stream<Item = Result<Datagram>>,
+{
+    stream
+        .map(|datagram| {
+            let datagram = datagram.expect("datagram");
+            (datagram.stream_position, datagram.data)
+        })
+        .collect()
+        .await
+}
+
+/// The position of the `ordinal`th datagram on the ordered stream
+/// `stream_id`.
+pub(crate) fn position(stream_id: u8, ordinal: u32) -> Option<StreamPosition> {
+    Some(StreamPosition {
+        stream_id: StreamId(stream_id),
+        index: StreamIndex::Ordinal(ordinal),
+    })
+}
+
+impl Loopback {
+    /// Holds `ready` pending until the returned flag is set, as a transport
+    /// still handshaking would. Nothing is woken when it is set, so poll
+    /// `ready` again rather than awaiting it.
+    pub(crate) fn handshake(&mut self) -> Rc<Cell<bool>> {
+        self.handshaken.set(false);
+        self.handshaken.clone()
+    }
+}
+
+impl Connection for Loopback {
+    fn ready(&mut self) -> LocalBoxFuture<'_, Result<()>> {
+        let handshaken = self.handshaken.clone();
+        future::poll_fn(move |_| match handshaken.get() {
+            true => Poll::Ready(Ok(())),
+            false => Poll::Pending,
+        })
+        .boxed_local()
+    }
+
+    fn pause_stream(&mut self, stream_id: StreamId) -> bool {
+        self.paused.insert(stream_id);
+        true
+    }
+
+    fn resume_stream(&mut self, stream_id: StreamId) {
+        self.paused.remove(&stream_id);
+    }
+
+    fn is_paused(&self, stream_id: StreamId) -> bool {
+        self.paused.contains(&stream_id)
+    }
+}
+
+impl Sink<SendCmd> for Loopback {
+    type Error = Box<dyn std::error::Error>;
+    fn poll_ready(self: Pin<&mut Self>, _: &mut Context) -> Poll<Result<()>> {
+        Poll::Ready(Ok(()))
+    }
+    fn start_send(mut self: Pin<&mut Self>, item: SendCmd) -> Result<()> {
+        let stream_position = match item.delivery_mode {
+            DeliveryMode::ReliableOrdered(stream_id) => {
+                Some((stream_id, StreamIndex::Ordinal as fn(u32) -> _))
+            }
+            DeliveryMode::ReliableSequenced(stream_id)
+            | DeliveryMode::UnreliableSequenced(stream_id) => {
+                Some((stream_id, StreamIndex::Sequence as fn(u32) -> _))
+            }
+            DeliveryMode::ReliableUnordered
+            | DeliveryMode::UnreliableUnordered => None,
+        }
+        .map(|(stream_id, index)| {
+            let sent = self.sent.entry(item.delivery_mode).or_insert(0);
+            *sent += 1;
+            StreamPosition {
+                stream_id,
+                index: index(*sent),
+            }
+        });
+        self.sender
+            .unbounded_send(Datagram {
+                stream_position,
+                data: item.data,
+                header: item.header,
+            })
+            .map_err(|e| e.into_send_error().into())
+    }
+    fn poll_flush(self: Pin<&mut Self>, _: &mut Context) -> Poll<Result<()>> {
+        Poll::Ready(Ok(()))
+    }
+    fn poll_close(self: Pin<&mut Self>, _: &mut Context) -> Poll<Result<()>> {
+        self.sender.close_channel();
+        Poll::Ready(Ok(()))
+    }
+}
+
+impl Stream for Loopback {
+    type Item = Result<Datagram>;
+    fn poll_next(
+        mut self: Pin<&mut Self>,
+        ctx: &mut Context,
+    ) -> Poll<Option<Self::Item>> {
+        Pin::new(&mut self.receiver)
+            .poll_next(ctx)
+            .map(|datagram| datagram.map(Ok))
+    }
+}
+
+impl FusedStream for Loopback {
+    fn is_terminated(&self) -> bool {
+        self.receiver.is_terminated()
+    }
+}